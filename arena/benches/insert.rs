@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use arena::{Arena, NewArena};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+const ENTRIES: usize = 100_000;
+
+fn paths() -> Vec<PathBuf> {
+    (0..ENTRIES)
+        .map(|i| PathBuf::from(format!("/{}/{}/{i}", i % 16, i % 256)))
+        .collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let paths = paths();
+    let mut group = c.benchmark_group("insert_100k");
+    group.sample_size(10);
+    group.bench_function("new", |b| {
+        b.iter_batched(
+            NewArena::default,
+            |mut arena| {
+                for (id, path) in paths.iter().enumerate() {
+                    arena.add_file(path, id).unwrap();
+                }
+                black_box(arena)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("with_capacity", |b| {
+        b.iter_batched(
+            || NewArena::with_capacity(ENTRIES + ENTRIES / 4),
+            |mut arena| {
+                for (id, path) in paths.iter().enumerate() {
+                    arena.add_file(path, id).unwrap();
+                }
+                black_box(arena)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, insert);
+criterion_main!(benches);
@@ -1,8 +1,8 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
     ffi::{OsStr, OsString},
     fmt::Debug,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     str::FromStr,
 };
 
@@ -10,17 +10,94 @@ use tracing::{debug, error, instrument};
 
 use crate::{
     arena_types::{Arena, Entry},
-    ArenaError,
+    ArenaError, Collation, IntegrityError, MAX_DEPTH,
 };
 
 pub struct NewArena<T> {
     data: HashMap<usize, NewArenaElement<T>>,
+    /// Ids are never reused, so a removal can't make a later insert alias a live node.
+    next_id: usize,
+    collation: Collation,
+    /// `(parent, collation key, name)` of every child, unless `Exact`: each directory's
+    /// children in the collation's order, and the names stored under each key.
+    folded: BTreeSet<(usize, OsString, OsString)>,
 }
 impl<T> Default for NewArena<T> {
     fn default() -> Self {
-        let mut data = HashMap::new();
-        data.insert(0, NewArenaElement::Root(HashMap::new()));
-        Self { data }
+        Self::with_capacity(0)
+    }
+}
+impl<T> NewArena<T> {
+    /// An empty arena with room for `capacity` nodes before it rehashes.
+    ///
+    /// Only the node map is pre-sized: branch child maps are B-trees, which grow by node
+    /// and never rehash.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut data = HashMap::with_capacity(capacity.saturating_add(1));
+        data.insert(0, NewArenaElement::Root(BTreeMap::new()));
+        Self {
+            data,
+            next_id: 1,
+            collation: Collation::default(),
+            folded: BTreeSet::new(),
+        }
+    }
+
+    /// Compare names by `collation` from now on. Names already in the arena are
+    /// re-indexed; any that now collide stay as they are, but only one can be found.
+    pub fn with_collation(mut self, collation: Collation) -> Self {
+        self.collation = collation;
+        self.folded.clear();
+        if collation != Collation::Exact {
+            for (id, element) in &self.data {
+                for name in element.children().into_iter().flat_map(BTreeMap::keys) {
+                    self.folded.insert((*id, collation.key(name), name.clone()));
+                }
+            }
+        }
+        self
+    }
+
+    pub fn collation(&self) -> Collation {
+        self.collation
+    }
+
+    /// The name the child of `parent_id` matching `name` is stored under.
+    fn stored_name<'a>(&'a self, parent_id: usize, name: &'a OsStr) -> Option<&'a OsStr> {
+        let children = self.data.get(&parent_id)?.children()?;
+        if let Some((stored, _)) = children.get_key_value(name) {
+            return Some(stored);
+        }
+        match self.collation {
+            Collation::Exact => None,
+            _ => {
+                let key = self.collation.key(name);
+                self.folded
+                    .range((parent_id, key.clone(), OsString::new())..)
+                    .next()
+                    .filter(|(parent, folded, _)| *parent == parent_id && *folded == key)
+                    .map(|(_, _, stored)| stored.as_os_str())
+            }
+        }
+    }
+
+    /// Names of the children of `parent_id` in the collation's order, unless `Exact`.
+    fn folded_names(&self, parent_id: usize) -> impl Iterator<Item = &OsString> {
+        self.folded
+            .range((parent_id, OsString::new(), OsString::new())..)
+            .take_while(move |(parent, _, _)| *parent == parent_id)
+            .map(|(_, _, name)| name)
+    }
+
+    /// Id of the child of `parent_id` matching `name`.
+    fn child_id(&self, parent_id: usize, name: &OsStr) -> Option<usize> {
+        let stored = self.stored_name(parent_id, name)?;
+        self.data.get(&parent_id)?.children()?.get(stored).copied()
+    }
+
+    /// Make room for at least `additional` more nodes.
+    pub fn reserve(&mut self, additional: usize) {
+        self.data.reserve(additional);
     }
 }
 impl<T> Debug for NewArena<T> {
@@ -54,45 +131,53 @@ where
             "add_file"
         );
 
+        let names = names(file)?;
+        let Some((file_name, dirs)) = names.split_last() else {
+            return Err(ArenaError::InvalidPath(file.to_path_buf()));
+        };
         let mut parent_id = 0_usize;
-        for component in file.parent().unwrap().components() {
-            debug!(component = debug(component), "find parent");
-            parent_id = match component {
-                std::path::Component::RootDir => 0_usize,
-                std::path::Component::Normal(component_name) => {
-                    match self.upsert(
-                        parent_id,
-                        component_name,
-                        NewArenaElement::Branch(HashMap::new()),
-                    ) {
-                        Err(e) => return Err(e),
-                        Ok(id) => id,
-                    }
-                }
-                _ => unreachable!(),
-            }
+        for name in dirs {
+            debug!(name = debug(name), "find parent");
+            parent_id = self.upsert(parent_id, name, NewArenaElement::Branch(BTreeMap::new()))?;
         }
-        let file_name = file.file_name().unwrap();
         self.upsert(parent_id, file_name, NewArenaElement::Leaf(entry))
             .map(|_id| ())
     }
 
     #[instrument(level = "debug")]
     fn find(&self, path: &Path) -> Self::Entry {
-        debug!(path = debug(path), data = debug(&self.data), "find");
+        self.find_id(path)
+            .and_then(|id| self.data.get(&id))
+            .cloned()
+            .unwrap_or(Self::Entry::None)
+    }
+}
+
+impl<T> NewArena<T> {
+    /// Id of the node at `path`, which tells whether two lookups found the same entry:
+    /// ids are never reused, so a node removed and added again gets a new one.
+    pub fn find_id(&self, path: &Path) -> Option<usize> {
+        debug!(path = debug(path), "find");
 
+        let mut found_id = 0_usize;
         let mut found = self.data.get(&0).unwrap();
         for component in path.components() {
             debug!(component = debug(component), "find parent");
             found = match component {
-                std::path::Component::RootDir => self.data.get(&0).unwrap(),
+                std::path::Component::RootDir => {
+                    found_id = 0;
+                    self.data.get(&0).unwrap()
+                }
                 std::path::Component::Normal(p) => {
                     debug!("search for {p:?} in children of {found:?}");
                     match found.children() {
-                        Some(children) => {
-                            let f = match children.get(p) {
-                                None => return Self::Entry::None,
-                                Some(c) => self.data.get(c).unwrap(),
+                        Some(_) => {
+                            let f = match self.child_id(found_id, p) {
+                                None => return None,
+                                Some(c) => {
+                                    found_id = c;
+                                    self.data.get(&c).unwrap()
+                                }
                             };
                             debug!(
                                 parent = debug(found),
@@ -104,28 +189,28 @@ where
                         }
                         _ => {
                             error!("{:?} has no children, expected at least {:?}", found, p);
-                            return Self::Entry::None;
+                            return None;
                         }
                     }
                 }
-                _ => unreachable!(),
+                _ => return None,
             }
         }
         debug!(
-            seek = debug(path.components().last()),
+            seek = debug(path.components().next_back()),
             found = debug(found),
             "find"
         );
-        match path.components().last() {
-            Some(std::path::Component::RootDir) if found.is_root() => found.clone(),
-            Some(std::path::Component::Normal(_)) => found.clone(),
-            _ => Self::Entry::None,
+        match path.components().next_back() {
+            Some(std::path::Component::RootDir) if found.is_root() => Some(found_id),
+            Some(std::path::Component::Normal(_)) => Some(found_id),
+            _ => None,
         }
     }
 }
 
 impl<T: Clone + Copy> NewArena<T> {
-    fn find_parent_mut(&mut self, path: &Path) -> Option<&mut NewArenaElement<T>> {
+    fn find_parent_id(&self, path: &Path) -> Option<usize> {
         let binding = PathBuf::from_str("/").unwrap();
         let path = match path.parent() {
             None => binding.as_path(),
@@ -140,46 +225,168 @@ impl<T: Clone + Copy> NewArena<T> {
                 std::path::Component::RootDir => 0_usize,
                 std::path::Component::Normal(p) => {
                     debug!("search for {p:?} in children of {parent_id:?}");
-                    match self.data.get(&parent_id).and_then(|p| p.children()) {
-                        Some(children) => {
-                            let f = match children.get(p) {
-                                None => return None,
-                                Some(c) => c,
-                            };
-                            debug!(needle = debug(p), found = debug(f), "found child");
-                            *f
-                        }
-                        _ => {
-                            error!("{:?} has no children, expected at least {:?}", parent_id, p);
-                            return None;
-                        }
+                    if self
+                        .data
+                        .get(&parent_id)
+                        .and_then(|p| p.children())
+                        .is_none()
+                    {
+                        error!("{:?} has no children, expected at least {:?}", parent_id, p);
+                        return None;
                     }
+                    let f = self.child_id(parent_id, p)?;
+                    debug!(needle = debug(p), found = debug(f), "found child");
+                    f
                 }
-                _ => unreachable!(),
+                _ => return None,
             }
         }
-        let found = self.data.get_mut(&parent_id);
         debug!(
-            seek = debug(path.components().last()),
-            found = debug(&found),
+            seek = debug(path.components().next_back()),
+            found = debug(parent_id),
             "find"
         );
-        found
+        Some(parent_id)
     }
 
+    /// Remove the node at `path`. Removing a file also removes the directories above it
+    /// that it leaves empty, up to but not including the root.
     pub fn remove(&mut self, path: &Path) -> bool {
-        let parent = self.find_parent_mut(path);
-        if let Some(parent) = parent {
-            if let Some(children) = parent.children_mut() {
-                debug!(path = debug(path), children = debug(&children), "remove");
-                if let Some(id) = children.remove(path.file_name().unwrap()) {
-                    let dropped = self.data.remove(&id);
-                    debug!(dropped = debug(&dropped), id, path = debug(path), "dropped");
-                    return dropped.is_some();
+        let Some(dropped) = self.remove_node(path) else {
+            return false;
+        };
+        if let NewArenaElement::Leaf(_) = dropped {
+            for dir in path.ancestors().skip(1) {
+                match self.get(dir) {
+                    Some(NewArenaElement::Branch(children)) if children.is_empty() => {
+                        debug!(dir = debug(dir), "prune");
+                        self.remove_node(dir);
+                    }
+                    _ => break,
+                }
+            }
+        }
+        true
+    }
+
+    fn remove_node(&mut self, path: &Path) -> Option<NewArenaElement<T>> {
+        let parent_id = self.find_parent_id(path)?;
+        let name = self
+            .stored_name(parent_id, path.file_name()?)
+            .map(OsStr::to_os_string)?;
+        if let Some(children) = self.data.get_mut(&parent_id).and_then(|p| p.children_mut()) {
+            debug!(path = debug(path), children = debug(&children), "remove");
+            if let Some(id) = children.remove(&name) {
+                self.folded
+                    .remove(&(parent_id, self.collation.key(&name), name.clone()));
+                let dropped = self.data.remove(&id);
+                debug!(dropped = debug(&dropped), id, path = debug(path), "dropped");
+                return dropped;
+            }
+        }
+        None
+    }
+}
+
+impl<T> NewArena<T> {
+    /// Id of the node at `path`.
+    fn id(&self, path: &Path) -> Option<usize> {
+        let mut id = 0_usize;
+        for component in path.components() {
+            id = match component {
+                std::path::Component::RootDir => 0,
+                std::path::Component::Normal(name) => self.child_id(id, name)?,
+                _ => return None,
+            };
+        }
+        Some(id)
+    }
+
+    /// Node at `path`, borrowed rather than cloned.
+    fn get(&self, path: &Path) -> Option<&NewArenaElement<T>> {
+        self.data.get(&self.id(path)?)
+    }
+
+    /// Number of children of the directory at `path`.
+    pub fn child_count(&self, path: &Path) -> Option<usize> {
+        self.get(path)?.children().map(|children| children.len())
+    }
+
+    /// The `n`th child of the directory at `path`, in the collation's order. Either way
+    /// it's walked to in order as stored, never sorted.
+    pub fn nth_child(&self, path: &Path, n: usize) -> Option<(OsString, NewArenaElement<T>)>
+    where
+        T: Clone,
+    {
+        let dir_id = self.id(path)?;
+        let children = self.data.get(&dir_id)?.children()?;
+        let (name, id) = match self.collation {
+            Collation::Exact => children.iter().nth(n)?,
+            _ => {
+                let name = self.folded_names(dir_id).nth(n)?;
+                (name, children.get(name)?)
+            }
+        };
+        Some((name.to_owned(), self.data.get(id)?.clone()))
+    }
+
+    /// Every leaf's payload, in no particular order, borrowed straight from the node map.
+    pub fn leaves(&self) -> impl Iterator<Item = &T> {
+        self.data.values().filter_map(NewArenaElement::entry)
+    }
+
+    /// Check that the node graph is a tree rooted at node 0.
+    pub fn validate_integrity(&self) -> Result<(), Vec<IntegrityError>> {
+        let mut errors = Vec::new();
+        if !matches!(self.data.get(&0), Some(NewArenaElement::Root(_))) {
+            errors.push(IntegrityError::MissingRoot);
+        }
+
+        let mut ids = self.data.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        let mut parents = HashMap::new();
+        for id in &ids {
+            let element = self.data.get(id).unwrap();
+            if *id != 0 && element.is_root() {
+                errors.push(IntegrityError::UnexpectedRoot(*id));
+            }
+            let mut children = element
+                .children()
+                .map(|c| c.iter().collect::<Vec<_>>())
+                .unwrap_or_default();
+            children.sort();
+            for (name, child) in children {
+                if *child == 0 {
+                    errors.push(IntegrityError::RootAsChild {
+                        parent: *id,
+                        name: name.to_owned(),
+                    });
+                } else if !self.data.contains_key(child) {
+                    errors.push(IntegrityError::DanglingChild {
+                        parent: *id,
+                        name: name.to_owned(),
+                        child: *child,
+                    });
+                }
+                if let Some(other) = parents.insert(*child, *id) {
+                    errors.push(IntegrityError::SharedChild {
+                        child: *child,
+                        parents: (other, *id),
+                    });
                 }
             }
         }
-        false
+        for id in ids {
+            if id != 0 && !parents.contains_key(&id) {
+                errors.push(IntegrityError::Orphan(id));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
 }
 
@@ -191,32 +398,71 @@ impl<T: Debug> NewArena<T> {
         element: NewArenaElement<T>,
     ) -> Result<usize, ArenaError> {
         debug!("upsert {name:?}=>{element:?} in children of {parent_id}");
-        let branch_id = 1 + self.data.len();
+        let branch_id = self.next_id;
+        let existing = self.child_id(parent_id, name);
 
-        let children = match self.data.get_mut(&parent_id).and_then(|p| p.children_mut()) {
-            None => return Err(ArenaError::Unknown),
-            Some(c) => c,
+        let children = match self.data.get_mut(&parent_id) {
+            None => return Err(ArenaError::ParentNotFound),
+            Some(parent) => parent.children_mut().ok_or(ArenaError::ParentIsLeaf)?,
         };
 
-        let (id, insert) = match children.get(name) {
+        let (id, insert) = match existing {
             None => {
                 children.insert(name.into(), branch_id);
+                if self.collation != Collation::Exact {
+                    self.folded
+                        .insert((parent_id, self.collation.key(name), name.into()));
+                }
                 (branch_id, true)
             }
-            Some(b) => (*b, false),
+            Some(_) if element.is_file() => return Err(ArenaError::Exists(name.into())),
+            Some(b) => (b, false),
         };
         if insert {
+            self.next_id += 1;
             self.data.insert(branch_id, element);
         }
         Ok(id)
     }
+
+    /// Add the directory `path`, and any missing ancestors, with no files in it.
+    pub fn add_dir(&mut self, path: &Path) -> Result<(), ArenaError> {
+        let mut parent_id = 0_usize;
+        for name in names(path)? {
+            parent_id = self.upsert(parent_id, name, NewArenaElement::Branch(BTreeMap::new()))?;
+        }
+        match self.data.get(&parent_id) {
+            Some(NewArenaElement::Leaf(_)) => Err(ArenaError::Exists(
+                path.file_name().unwrap_or_default().to_os_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
 }
 
+/// The names along `path` below the root, or why it can't be added.
+fn names(path: &Path) -> Result<Vec<&OsStr>, ArenaError> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir => {}
+            Component::Normal(name) => names.push(name),
+            _ => return Err(ArenaError::InvalidPath(path.to_path_buf())),
+        }
+    }
+    if names.len() > MAX_DEPTH {
+        return Err(ArenaError::DepthExceeded(names.len()));
+    }
+    Ok(names)
+}
+
+/// Directories keep their children in a `BTreeMap`, which is what makes `children`,
+/// `nth_child` and any walk built on them name-ordered.
 #[derive(Clone, PartialEq)]
 pub enum NewArenaElement<T> {
-    Root(HashMap<OsString, usize>),
+    Root(BTreeMap<OsString, usize>),
     Leaf(T),
-    Branch(HashMap<OsString, usize>),
+    Branch(BTreeMap<OsString, usize>),
     None,
 }
 
@@ -231,7 +477,40 @@ impl<T> NewArenaElement<T> {
         }
     }
 
-    fn children(&self) -> Option<&HashMap<std::ffi::OsString, usize>> {
+    /// Borrow a leaf's payload; `None` for the root and branches.
+    pub fn entry(&self) -> Option<&T> {
+        match self {
+            Self::Leaf(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    /// Number of children, straight from the branch map; `0` for a leaf.
+    pub fn child_count(&self) -> usize {
+        self.children().map_or(0, BTreeMap::len)
+    }
+
+    /// Children, in `arena`'s collation order, collected into a `Vec` sized up front.
+    pub fn children_sorted<'a>(
+        &'a self,
+        arena: &'a NewArena<T>,
+    ) -> Vec<(&'a OsString, &'a NewArenaElement<T>)> {
+        let mut children = Vec::with_capacity(self.child_count());
+        if let Some(names) = self.children() {
+            children.extend(
+                names
+                    .iter()
+                    .filter_map(|(name, id)| arena.data.get(id).map(|child| (name, child))),
+            );
+        }
+        if arena.collation != Collation::Exact {
+            // Each name's key is made once, not once per comparison.
+            children.sort_by_cached_key(|(name, _)| (arena.collation.key(name), *name));
+        }
+        children
+    }
+
+    fn children(&self) -> Option<&BTreeMap<std::ffi::OsString, usize>> {
         match self {
             NewArenaElement::Root(c) => Some(c),
             NewArenaElement::Leaf(_) => None,
@@ -240,7 +519,7 @@ impl<T> NewArenaElement<T> {
         }
     }
 
-    fn children_mut(&mut self) -> Option<&mut HashMap<std::ffi::OsString, usize>> {
+    fn children_mut(&mut self) -> Option<&mut BTreeMap<std::ffi::OsString, usize>> {
         match self {
             NewArenaElement::Root(c) => Some(c),
             NewArenaElement::Leaf(_) => None,
@@ -250,7 +529,10 @@ impl<T> NewArenaElement<T> {
     }
 }
 impl<T> Entry for NewArenaElement<T> {
-    type Children<'a> = Children<'a, T> where Self: 'a;
+    type Children<'a>
+        = Children<'a, T>
+    where
+        Self: 'a;
     type Arena = NewArena<T>;
 
     fn is_root(&self) -> bool {
@@ -290,10 +572,10 @@ impl<T> Debug for NewArenaElement<T> {
 }
 pub struct Children<'a, T> {
     arena: &'a NewArena<T>,
-    children: Option<std::collections::hash_map::Iter<'a, OsString, usize>>,
+    children: Option<std::collections::btree_map::Iter<'a, OsString, usize>>,
 }
 impl<'a, T> Children<'a, T> {
-    fn from(arena: &'a NewArena<T>, value: Option<&'a HashMap<OsString, usize>>) -> Self {
+    fn from(arena: &'a NewArena<T>, value: Option<&'a BTreeMap<OsString, usize>>) -> Self {
         Self {
             arena,
             children: value.map(|c| c.iter()),
@@ -329,4 +611,383 @@ mod tests {
         let mut arena = NewArena::default();
         assert!(arena.add_file(&PathBuf::from("/f1/f2/f3/file"), 1).is_ok());
     }
+
+    #[test]
+    #[traced_test]
+    fn child_count_and_sorted() {
+        let mut arena = NewArena::default();
+        for (id, path) in ["/d/c", "/d/a", "/d/b/x", "/e"].iter().enumerate() {
+            arena.add_file(&PathBuf::from(path), id).unwrap();
+        }
+        let d = arena.find(&PathBuf::from("/d"));
+        assert_eq!(d.child_count(), 3);
+        let sorted = d.children_sorted(&arena);
+        assert_eq!(
+            sorted
+                .iter()
+                .map(|(name, _)| name.as_os_str())
+                .collect::<Vec<_>>(),
+            ["a", "b", "c"]
+        );
+        assert!(sorted[1].1.is_directory());
+        assert_eq!(sorted[2].1.inner(), Some(0));
+
+        assert_eq!(arena.find(&PathBuf::from("/")).child_count(), 2);
+        assert_eq!(arena.find(&PathBuf::from("/e")).child_count(), 0);
+        assert!(arena
+            .find(&PathBuf::from("/e"))
+            .children_sorted(&arena)
+            .is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn add_dir() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/a/file"), 1).unwrap();
+        assert!(arena.add_dir(&PathBuf::from("/b/c")).is_ok());
+        assert!(arena.find(&PathBuf::from("/b/c")).is_directory());
+        assert_eq!(arena.child_count(&PathBuf::from("/b/c")), Some(0));
+        assert!(arena.add_dir(&PathBuf::from("/a")).is_ok());
+        assert_eq!(arena.child_count(&PathBuf::from("/a")), Some(1));
+        assert_eq!(
+            arena.add_dir(&PathBuf::from("/a/file")),
+            Err(ArenaError::Exists("file".into()))
+        );
+        assert_eq!(
+            arena.add_dir(&PathBuf::from("/a/file/d")),
+            Err(ArenaError::ParentIsLeaf)
+        );
+        assert_eq!(arena.validate_integrity(), Ok(()));
+    }
+
+    #[test]
+    #[traced_test]
+    fn add_file_conflicts() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/a/file"), 1).unwrap();
+        assert!(matches!(
+            arena.add_file(&PathBuf::from("/a/file/nested"), 2),
+            Err(ArenaError::ParentIsLeaf)
+        ));
+        assert!(matches!(
+            arena.add_file(&PathBuf::from("/a"), 3),
+            Err(ArenaError::Exists(name)) if name == "a"
+        ));
+        assert!(matches!(
+            arena.add_file(&PathBuf::from("/a/file"), 4),
+            Err(ArenaError::Exists(_))
+        ));
+        assert_eq!(arena.find(&PathBuf::from("/a/file")).entry(), Some(&1));
+        assert_eq!(arena.len(), 3);
+        assert_eq!(arena.validate_integrity(), Ok(()));
+    }
+
+    #[test]
+    #[traced_test]
+    fn add_errors() {
+        let mut arena = NewArena::default();
+        for path in ["/", "", "/a/../b", "./a"] {
+            assert_eq!(
+                arena.add_file(&PathBuf::from(path), 1),
+                Err(ArenaError::InvalidPath(path.into())),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            arena.add_dir(&PathBuf::from("/a/..")),
+            Err(ArenaError::InvalidPath("/a/..".into()))
+        );
+        assert!(!arena.remove(&PathBuf::from("/")));
+        assert_eq!(arena.find(&PathBuf::from("/a/../b")), NewArenaElement::None);
+
+        let deep = (0..MAX_DEPTH).fold(PathBuf::from("/"), |path, _| path.join("d"));
+        assert_eq!(
+            arena.add_file(&deep.join("file"), 1),
+            Err(ArenaError::DepthExceeded(MAX_DEPTH + 1))
+        );
+        assert_eq!(arena.add_file(&deep, 1), Ok(()));
+
+        // Only reachable if the arena is corrupt: a child whose node is gone.
+        arena.add_dir(&PathBuf::from("/a")).unwrap();
+        let id = arena.child_id(0, OsStr::new("a")).unwrap();
+        arena.data.remove(&id);
+        assert_eq!(
+            arena.add_file(&PathBuf::from("/a/file"), 2),
+            Err(ArenaError::ParentNotFound)
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn case_fold() {
+        let mut arena = NewArena::default().with_collation(Collation::CaseFold);
+        arena
+            .add_file(&PathBuf::from("/Photos/IMG.jpg"), 1)
+            .unwrap();
+        // A fold-equal directory is the same directory...
+        arena.add_file(&PathBuf::from("/photos/b.jpg"), 2).unwrap();
+        arena.add_file(&PathBuf::from("/a.txt"), 3).unwrap();
+        assert_eq!(arena.child_count(&PathBuf::from("/")), Some(2));
+        // ... and a fold-equal file collides.
+        assert!(matches!(
+            arena.add_file(&PathBuf::from("/PHOTOS/img.JPG"), 4),
+            Err(ArenaError::Exists(_))
+        ));
+        assert!(matches!(
+            arena.add_file(&PathBuf::from("/photos"), 5),
+            Err(ArenaError::Exists(_))
+        ));
+
+        assert_eq!(
+            arena.find(&PathBuf::from("/PHOTOS/img.JPG")).entry(),
+            Some(&1)
+        );
+        let root = arena.find(&PathBuf::from("/"));
+        let names = root
+            .children_sorted(&arena)
+            .into_iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![OsString::from("a.txt"), "Photos".into()]);
+        let nth = (0..)
+            .map_while(|n| arena.nth_child(&PathBuf::from("/"), n))
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(nth, names);
+
+        assert!(arena.remove(&PathBuf::from("/photos/IMG.JPG")));
+        assert!(!arena.find(&PathBuf::from("/Photos/IMG.jpg")).is_file());
+        arena
+            .add_file(&PathBuf::from("/photos/img.jpg"), 6)
+            .unwrap();
+        assert_eq!(arena.validate_integrity(), Ok(()));
+
+        let mut exact = NewArena::default().with_collation(Collation::Exact);
+        exact
+            .add_file(&PathBuf::from("/Photos/IMG.jpg"), 1)
+            .unwrap();
+        assert!(!exact.find(&PathBuf::from("/photos/IMG.jpg")).is_file());
+    }
+
+    #[test]
+    #[traced_test]
+    fn with_capacity_equivalent() {
+        let paths = ["/a/b/1", "/a/c/2", "/d/3", "/4"];
+        let mut plain = NewArena::default();
+        let mut sized = NewArena::with_capacity(100);
+        for (id, path) in paths.iter().enumerate() {
+            plain.add_file(&PathBuf::from(path), id).unwrap();
+            sized.add_file(&PathBuf::from(path), id).unwrap();
+        }
+        assert!(sized.data.capacity() >= 100);
+        assert_eq!(sized.len(), plain.len());
+        assert_eq!(sized.data, plain.data);
+        assert_eq!(sized.validate_integrity(), Ok(()));
+    }
+
+    #[test]
+    #[traced_test]
+    fn remove_then_add() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/f1/a"), 1).unwrap();
+        arena.add_file(&PathBuf::from("/f1/b"), 2).unwrap();
+        assert!(arena.remove(&PathBuf::from("/f1/a")));
+        arena.add_file(&PathBuf::from("/f2/c"), 3).unwrap();
+        assert_eq!(arena.validate_integrity(), Ok(()));
+        assert_eq!(arena.find(&PathBuf::from("/f1/b")).inner(), Some(2));
+        assert_eq!(arena.find(&PathBuf::from("/f2/c")).inner(), Some(3));
+    }
+
+    #[test]
+    #[traced_test]
+    fn entry() {
+        let mut arena = NewArena::default();
+        arena
+            .add_file(&PathBuf::from("/d/file"), ("name", 42))
+            .unwrap();
+        let found = arena.find(&PathBuf::from("/d/file"));
+        let (name, size) = found.entry().unwrap();
+        assert_eq!(*name, "name");
+        assert_eq!(*size, 42);
+        assert_eq!(arena.find(&PathBuf::from("/d")).entry(), None);
+    }
+
+    #[test]
+    #[traced_test]
+    fn find_id() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/d/a"), 1).unwrap();
+        arena.add_file(&PathBuf::from("/d/b"), 1).unwrap();
+        let a = arena.find_id(&PathBuf::from("/d/a"));
+        assert!(a.is_some());
+        assert_eq!(arena.find_id(&PathBuf::from("/d/a")), a);
+        assert_eq!(arena.find_id(&PathBuf::from("/d/./a")), a);
+        // Equal payloads, different entries.
+        assert_ne!(arena.find_id(&PathBuf::from("/d/b")), a);
+        assert_eq!(arena.find_id(&PathBuf::from("/")), Some(0));
+        assert_eq!(arena.find_id(&PathBuf::from("/d/c")), None);
+
+        assert!(arena.remove(&PathBuf::from("/d/a")));
+        arena.add_file(&PathBuf::from("/d/a"), 1).unwrap();
+        assert_ne!(arena.find_id(&PathBuf::from("/d/a")), a);
+    }
+
+    #[test]
+    #[traced_test]
+    fn remove_prunes_empty_dirs() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/a/b/1"), 1).unwrap();
+        assert!(arena.remove(&PathBuf::from("/a/b/1")));
+        assert_eq!(arena.len(), 1);
+        assert!(!arena.find(&PathBuf::from("/a")).is_directory());
+        assert!(!arena.find(&PathBuf::from("/a/b")).is_directory());
+        assert_eq!(arena.validate_integrity(), Ok(()));
+
+        // Only as far up as the directories are empty.
+        arena.add_file(&PathBuf::from("/a/b/1"), 1).unwrap();
+        arena.add_file(&PathBuf::from("/a/c/2"), 2).unwrap();
+        assert!(arena.remove(&PathBuf::from("/a/b/1")));
+        assert!(!arena.find(&PathBuf::from("/a/b")).is_directory());
+        assert!(arena.find(&PathBuf::from("/a/c/2")).is_file());
+
+        // Removing an empty directory leaves its parent.
+        arena.add_dir(&PathBuf::from("/d/e")).unwrap();
+        assert!(arena.remove(&PathBuf::from("/d/e")));
+        assert!(arena.find(&PathBuf::from("/d")).is_directory());
+    }
+
+    #[test]
+    #[traced_test]
+    fn leaves() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/a/1"), 1).unwrap();
+        arena.add_file(&PathBuf::from("/a/b/2"), 2).unwrap();
+        arena.add_file(&PathBuf::from("/3"), 3).unwrap();
+        arena.add_dir(&PathBuf::from("/empty")).unwrap();
+        let mut leaves = arena.leaves().copied().collect::<Vec<_>>();
+        leaves.sort();
+        assert_eq!(leaves, vec![1, 2, 3]);
+        assert!(arena.remove(&PathBuf::from("/a/1")));
+        assert_eq!(arena.leaves().count(), 2);
+    }
+
+    #[test]
+    #[traced_test]
+    fn children_sorted() {
+        let names = |arena: &NewArena<usize>| {
+            Entry::children(&arena.find(&PathBuf::from("/d")), arena)
+                .map(|(name, _)| name.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        let mut arena = NewArena::default();
+        for (id, name) in ["m", "b", "z", "a", "k"].into_iter().enumerate() {
+            arena
+                .add_file(&PathBuf::from(format!("/d/{name}")), id)
+                .unwrap();
+        }
+        assert_eq!(names(&arena), ["a", "b", "k", "m", "z"]);
+        assert_eq!(names(&arena), names(&arena));
+
+        // Churn enough to resize any hashed storage, then check the order again.
+        for id in 0..1000 {
+            arena
+                .add_file(&PathBuf::from(format!("/e/{id}")), id)
+                .unwrap();
+        }
+        assert!(arena.remove(&PathBuf::from("/d/b")));
+        arena.add_file(&PathBuf::from("/d/c"), 5).unwrap();
+        assert_eq!(names(&arena), ["a", "c", "k", "m", "z"]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn nth_child() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/d/c"), 3).unwrap();
+        arena.add_file(&PathBuf::from("/d/a"), 1).unwrap();
+        arena.add_file(&PathBuf::from("/d/b/file"), 2).unwrap();
+        let dir = PathBuf::from("/d");
+        assert_eq!(arena.child_count(&dir), Some(3));
+        assert_eq!(arena.child_count(&PathBuf::from("/d/a")), None);
+        assert_eq!(
+            arena.nth_child(&dir, 0),
+            Some(("a".into(), NewArenaElement::Leaf(1)))
+        );
+        let (name, middle) = arena.nth_child(&dir, 1).unwrap();
+        assert_eq!(name, "b");
+        assert!(middle.is_directory());
+        assert_eq!(arena.nth_child(&dir, 3), None);
+        assert_eq!(arena.nth_child(&PathBuf::from("/missing"), 0), None);
+    }
+
+    #[test]
+    #[traced_test]
+    fn validate_integrity_ok() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/f1/f2/file"), 1).unwrap();
+        arena.add_file(&PathBuf::from("/f1/file"), 2).unwrap();
+        assert_eq!(arena.validate_integrity(), Ok(()));
+    }
+
+    #[test]
+    #[traced_test]
+    fn validate_integrity_corrupt() {
+        let mut arena = NewArena::default();
+        arena.add_file(&PathBuf::from("/f1/f2/file"), 1).unwrap();
+
+        // f1 -> f2 -> file; share f2 with the root as well, and leave a stray leaf.
+        let f1 = *arena.data[&0]
+            .children()
+            .unwrap()
+            .get(OsStr::new("f1"))
+            .unwrap();
+        let f2 = *arena.data[&f1]
+            .children()
+            .unwrap()
+            .get(OsStr::new("f2"))
+            .unwrap();
+        arena
+            .data
+            .get_mut(&0)
+            .unwrap()
+            .children_mut()
+            .unwrap()
+            .insert("alias".into(), f2);
+        arena.data.insert(100, NewArenaElement::Leaf(2));
+
+        let errors = arena.validate_integrity().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                IntegrityError::SharedChild {
+                    child: f2,
+                    parents: (0, f1)
+                },
+                IntegrityError::Orphan(100),
+            ]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn validate_integrity_dangling() {
+        let mut arena = NewArena::<i32>::default();
+        arena
+            .data
+            .get_mut(&0)
+            .unwrap()
+            .children_mut()
+            .unwrap()
+            .insert("missing".into(), 7);
+        arena.data.insert(3, NewArenaElement::Root(BTreeMap::new()));
+
+        let errors = arena.validate_integrity().unwrap_err();
+        assert!(errors.contains(&IntegrityError::DanglingChild {
+            parent: 0,
+            name: "missing".into(),
+            child: 7
+        }));
+        assert!(errors.contains(&IntegrityError::UnexpectedRoot(3)));
+    }
 }
@@ -1,5 +1,6 @@
-use std::fmt::Debug;
-use std::path::Path;
+use std::ffi::OsString;
+use std::fmt::{Debug, Display};
+use std::path::{Path, PathBuf};
 
 pub trait Entry {
     type Children<'a>
@@ -21,6 +22,8 @@ pub trait Entry {
             None
         }
     }
+    /// Children of a directory, sorted by name byte for byte, whatever the arena's
+    /// collation, so every traversal is reproducible.
     fn children<'a, 'b>(&'a self, arena: &'b Self::Arena) -> Self::Children<'b>
     where
         'a: 'b;
@@ -35,7 +38,82 @@ pub trait Arena<T>: Debug + Send + Sync {
     fn find(&self, path: &Path) -> Self::Entry;
 }
 
-#[derive(Debug)]
+/// Paths with more components than this can't be added, which bounds every walk of
+/// an arena.
+pub const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ArenaError {
-    Unknown,
+    /// A file can't be added where its directory already has something of that name.
+    Exists(OsString),
+    /// The directory to add to isn't in the arena.
+    ParentNotFound,
+    /// A directory is needed where there is a file.
+    ParentIsLeaf,
+    /// The path has this many components, more than `MAX_DEPTH`.
+    DepthExceeded(usize),
+    /// The path has `..` or a leading `.`, or names nothing below the root.
+    InvalidPath(PathBuf),
+}
+
+impl Display for ArenaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exists(name) => write!(f, "{name:?} already exists"),
+            Self::ParentNotFound => write!(f, "parent directory not found"),
+            Self::ParentIsLeaf => write!(f, "a file is in the way of a directory"),
+            Self::DepthExceeded(depth) => {
+                write!(f, "path is {depth} deep, deeper than {MAX_DEPTH}")
+            }
+            Self::InvalidPath(path) => write!(f, "{path:?} can't be added"),
+        }
+    }
+}
+
+impl std::error::Error for ArenaError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityError {
+    MissingRoot,
+    UnexpectedRoot(usize),
+    RootAsChild {
+        parent: usize,
+        name: OsString,
+    },
+    DanglingChild {
+        parent: usize,
+        name: OsString,
+        child: usize,
+    },
+    SharedChild {
+        child: usize,
+        parents: (usize, usize),
+    },
+    Orphan(usize),
+}
+
+impl Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingRoot => write!(f, "node 0 is not the root"),
+            Self::UnexpectedRoot(id) => write!(f, "node {id} is a root but is not node 0"),
+            Self::RootAsChild { parent, name } => {
+                write!(f, "node {parent} has the root as child {name:?}")
+            }
+            Self::DanglingChild {
+                parent,
+                name,
+                child,
+            } => write!(
+                f,
+                "node {parent} child {name:?} refers to missing node {child}"
+            ),
+            Self::SharedChild { child, parents } => write!(
+                f,
+                "node {child} is a child of both {} and {}",
+                parents.0, parents.1
+            ),
+            Self::Orphan(id) => write!(f, "node {id} is not reachable from any parent"),
+        }
+    }
 }
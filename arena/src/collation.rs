@@ -0,0 +1,58 @@
+use std::{
+    cmp::Ordering,
+    ffi::{OsStr, OsString},
+};
+
+/// How names within a directory compare: which name `find` matches, which names collide
+/// when added, and the order a directory is listed in all follow from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Collation {
+    /// Byte for byte, as the host compares them.
+    #[default]
+    Exact,
+    /// Ignoring case, by Unicode lowercase; names that aren't UTF-8 compare exactly.
+    CaseFold,
+}
+
+impl Collation {
+    /// The form under which names compare equal.
+    pub fn key(&self, name: &OsStr) -> OsString {
+        match (self, name.to_str()) {
+            (Self::CaseFold, Some(name)) => name.to_lowercase().into(),
+            _ => name.to_os_string(),
+        }
+    }
+
+    pub fn eq(&self, a: &OsStr, b: &OsStr) -> bool {
+        a == b || (*self != Self::Exact && self.key(a) == self.key(b))
+    }
+
+    /// Listing order: by key, then exactly, so it is total and stable.
+    pub fn cmp(&self, a: &OsStr, b: &OsStr) -> Ordering {
+        match self {
+            Self::Exact => a.cmp(b),
+            Self::CaseFold => self.key(a).cmp(&self.key(b)).then_with(|| a.cmp(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn case_fold() {
+        let fold = Collation::CaseFold;
+        assert!(fold.eq("Photos".as_ref(), "PHOTOS".as_ref()));
+        assert!(!Collation::Exact.eq("Photos".as_ref(), "photos".as_ref()));
+        assert_eq!(fold.cmp("B".as_ref(), "a".as_ref()), Ordering::Greater);
+        assert_eq!(
+            Collation::Exact.cmp("B".as_ref(), "a".as_ref()),
+            Ordering::Less
+        );
+        assert_eq!(fold.cmp("A".as_ref(), "a".as_ref()), Ordering::Less);
+    }
+}
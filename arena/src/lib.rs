@@ -1,6 +1,9 @@
 mod arena_new;
 
+mod collation;
+
 mod arena_types;
 
 pub use arena_new::NewArena;
-pub use arena_types::{Arena, ArenaError, Entry};
+pub use arena_types::{Arena, ArenaError, Entry, IntegrityError, MAX_DEPTH};
+pub use collation::Collation;
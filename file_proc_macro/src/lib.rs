@@ -4,62 +4,223 @@ use proc_macro::TokenStream;
 use quote::quote;
 use syn::{parse_macro_input, DeriveInput};
 
-fn gen_mapping(field: &syn::Field) -> Vec<quote::__private::TokenStream> {
-    let syn::Field { attrs, .. } = field;
+/// The string in `#[<name> = "..."]`, or an error spanning `attr` if it isn't of that form.
+fn attr_value(attr: &syn::Attribute, name: &str) -> syn::Result<String> {
+    match &attr.meta {
+        syn::Meta::NameValue(syn::MetaNameValue {
+            value:
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(v),
+                    ..
+                }),
+            ..
+        }) => Ok(v.value()),
+        syn::Meta::NameValue(syn::MetaNameValue { value, .. }) => Err(syn::Error::new_spanned(
+            value,
+            format!("expected a string literal, as in `#[{name} = \"...\"]`"),
+        )),
+        meta => Err(syn::Error::new_spanned(
+            meta,
+            format!("expected `#[{name} = \"...\"]`"),
+        )),
+    }
+}
+
+/// The values of `field`'s `#[<name> = "..."]` attributes.
+fn attr_values(field: &syn::Field, name: &str) -> syn::Result<Vec<String>> {
+    field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident(name))
+        .map(|attr| attr_value(attr, name))
+        .collect()
+}
+
+/// What one `#[fsfile ...]` attribute maps: `key` to the field, passed through `with`
+/// if given.
+struct Mapping {
+    key: String,
+    with: Option<syn::Path>,
+}
+
+/// `#[fsfile = "key"]`, or `#[fsfile(key = "key", with = "path::to::format")]`.
+fn mapping(attr: &syn::Attribute) -> syn::Result<Mapping> {
+    if !matches!(attr.meta, syn::Meta::List(_)) {
+        return attr_value(attr, "fsfile").map(|key| Mapping { key, with: None });
+    }
+    let mut key = None;
+    let mut with = None;
+    attr.parse_nested_meta(|meta| {
+        let value: syn::LitStr = meta.value()?.parse()?;
+        if meta.path.is_ident("key") {
+            key = Some(value.value());
+        } else if meta.path.is_ident("with") {
+            with = Some(value.parse()?);
+        } else {
+            return Err(meta.error("expected `key` or `with`"));
+        }
+        Ok(())
+    })?;
+    let key = key.ok_or_else(|| syn::Error::new_spanned(attr, "missing `key = \"...\"`"))?;
+    Ok(Mapping { key, with })
+}
 
+/// Whether `ty` is `String` or `&str`, so a value can be lent out as it is.
+fn is_string(ty: &syn::Type) -> bool {
+    let is_ident = |ty: &syn::Type, name: &str| match ty {
+        syn::Type::Path(syn::TypePath { path, .. }) => {
+            path.segments.last().is_some_and(|s| s.ident == name)
+        }
+        _ => false,
+    };
+    match ty {
+        syn::Type::Reference(syn::TypeReference { elem, .. }) => is_ident(elem, "str"),
+        ty => is_ident(ty, "String"),
+    }
+}
+
+/// `String` and `&str` fields are lent out; others are formatted on each lookup, with
+/// `with` or else `Display`.
+fn gen_mapping(field: &syn::Field) -> syn::Result<Vec<quote::__private::TokenStream>> {
     let ident = field.ident.as_ref().unwrap();
-    attrs
+    field
+        .attrs
         .iter()
-        .map(|attr| match &attr.meta {
-            syn::Meta::NameValue(syn::MetaNameValue {
-                path,
-                eq_token: _,
-                value: syn::Expr::Lit(v),
-            }) if path.is_ident("fsfile") => {
-                if let syn::Lit::Str(v) = &v.lit {
-                    v.value()
-                } else {
-                    panic!("gen mapping found unexpected '{:?}'", v);
-                }
-            }
-            _ => panic!("unexpected meta '{:?}", attr.meta),
+        .filter(|attr| attr.path().is_ident("fsfile"))
+        .map(|attr| {
+            let Mapping { key, with } = mapping(attr)?;
+            Ok(match with {
+                Some(with) => quote! {
+                    #key => ::std::borrow::Cow::Owned(#with(&self.#ident))
+                },
+                None if is_string(&field.ty) => quote! {
+                    #key => ::std::borrow::Cow::Borrowed(&*self.#ident)
+                },
+                None => quote! {
+                    #key => ::std::borrow::Cow::Owned(self.#ident.to_string())
+                },
+            })
         })
-        .map(|key| {
+        .collect()
+}
+
+/// `#[fsfile_prefix = "dir"]` on a list field maps `dir1`, `dir2`, ... to its elements.
+fn gen_prefix_mapping(field: &syn::Field) -> syn::Result<Vec<quote::__private::TokenStream>> {
+    let ident = field.ident.as_ref().unwrap();
+    Ok(attr_values(field, "fsfile_prefix")?
+        .into_iter()
+        .map(|prefix| {
             quote! {
-                #key => &*self.#ident
+                if let Some(n) = index
+                    .strip_prefix(#prefix)
+                    .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                {
+                    // Too large to parse is as far past the end as any other out-of-range N.
+                    return n
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|n| n.checked_sub(1))
+                        .and_then(|n| self.#ident.get(n))
+                        .map_or(::std::borrow::Cow::Borrowed("unknown"), |v| {
+                            ::std::borrow::Cow::Borrowed(&**v)
+                        });
+                }
             }
         })
-        .collect::<Vec<_>>()
+        .collect())
+}
+
+fn gen_mappings(fields: &syn::Fields) -> syn::Result<Vec<quote::__private::TokenStream>> {
+    Ok(fields
+        .iter()
+        .map(gen_mapping)
+        .collect::<syn::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+fn gen_prefix_mappings(fields: &syn::Fields) -> syn::Result<Vec<quote::__private::TokenStream>> {
+    Ok(fields
+        .iter()
+        .map(gen_prefix_mapping)
+        .collect::<syn::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Every key a `#[fsfile ...]` attribute in `fields` maps.
+fn gen_keys(fields: &syn::Fields) -> syn::Result<Vec<String>> {
+    fields
+        .iter()
+        .flat_map(|field| &field.attrs)
+        .filter(|attr| attr.path().is_ident("fsfile"))
+        .map(|attr| mapping(attr).map(|mapping| mapping.key))
+        .collect()
 }
 
-fn gen_mappings(fields: syn::Fields) -> Vec<quote::__private::TokenStream> {
-    fields.iter().flat_map(gen_mapping).collect()
+/// Every prefix a `#[fsfile_prefix = "..."]` attribute in `fields` maps.
+fn gen_key_prefixes(fields: &syn::Fields) -> syn::Result<Vec<String>> {
+    Ok(fields
+        .iter()
+        .map(|field| attr_values(field, "fsfile_prefix"))
+        .collect::<syn::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect())
 }
 
-#[proc_macro_derive(FsFile, attributes(fsfile, fail))]
+#[proc_macro_derive(FsFile, attributes(fsfile, fsfile_prefix, fail))]
 pub fn file_derive(input: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(input);
-    let mappings = match input.data {
-        syn::Data::Struct(syn::DataStruct { fields, .. }) => gen_mappings(fields),
-        _ => panic!("Unexpected input: {:?}", input.data),
+    let fields = match &input.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: fields @ syn::Fields::Named(_),
+            ..
+        }) => fields,
+        _ => {
+            return syn::Error::new_spanned(
+                &input.ident,
+                "FsFile can only be derived for structs with named fields",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+    let generated = gen_mappings(fields).and_then(|mappings| {
+        Ok((
+            mappings,
+            gen_prefix_mappings(fields)?,
+            gen_keys(fields)?,
+            gen_key_prefixes(fields)?,
+        ))
+    });
+    let (mappings, prefix_mappings, keys, key_prefixes) = match generated {
+        Ok(generated) => generated,
+        Err(e) => return e.to_compile_error().into(),
     };
     let ident = &input.ident;
     let generics = &input.generics;
 
     let output = quote! {
-        impl #generics FsFile for #ident #generics {}
-        impl #generics Index<&str> for #ident #generics {
-            type Output = str;
+        impl #generics FsFile for #ident #generics {
+            fn keys() -> &'static [&'static str] {
+                &[#(#keys),*]
+            }
 
-            fn index(&self, index: &str) -> &Self::Output {
+            fn key_prefixes() -> &'static [&'static str] {
+                &[#(#key_prefixes),*]
+            }
+
+            fn value(&self, index: &str) -> ::std::borrow::Cow<'_, str> {
+                #(#prefix_mappings)*
                 match index {
                     #(#mappings,)*
                     _ => unimplemented!("No mapping for {} in {}", index, stringify!(#ident)),
                 }
             }
-
         }
-
     };
     output.into()
 }
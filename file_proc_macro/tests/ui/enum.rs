@@ -0,0 +1,10 @@
+use file_proc_macro::FsFile;
+
+trait FsFile {}
+
+#[derive(FsFile)]
+enum File {
+    Size(String),
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use file_proc_macro::FsFile;
+
+trait FsFile {}
+
+#[derive(FsFile)]
+struct File {
+    #[fsfile]
+    size: String,
+}
+
+fn main() {}
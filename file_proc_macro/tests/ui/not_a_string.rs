@@ -0,0 +1,13 @@
+use file_proc_macro::FsFile;
+
+trait FsFile {}
+
+#[derive(FsFile)]
+struct File {
+    #[fsfile = 1]
+    size: String,
+    #[fsfile_prefix = "dir"]
+    dirs: Vec<String>,
+}
+
+fn main() {}
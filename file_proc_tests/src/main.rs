@@ -1,8 +1,10 @@
-use std::ops::Index;
-
 use file_proc_macro::FsFile;
 use organizefs::common::FsFile;
 
+#[cfg(test)]
+use organizefs::common::expand;
+
+#[allow(dead_code)]
 #[derive(FsFile)]
 struct One {
     #[fsfile = "meta"]
@@ -12,6 +14,7 @@ struct One {
     _data: String,
 }
 
+#[allow(dead_code)]
 #[derive(FsFile)]
 struct Two<'a> {
     #[fsfile = "meta"]
@@ -19,6 +22,45 @@ struct Two<'a> {
     data: &'a str,
 }
 
+#[allow(dead_code)]
+#[derive(FsFile)]
+struct Three {
+    #[fsfile = "meta"]
+    meta: String,
+    #[fsfile_prefix = "dir"]
+    dirs: Vec<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, FsFile)]
+struct Four {
+    #[fsfile = "meta"]
+    meta: String,
+    #[fsfile = "size"]
+    size: String,
+    #[fsfile = "mdate"]
+    mdate: String,
+    #[fsfile = "ext"]
+    ext: String,
+    #[fsfile_prefix = "dir"]
+    dirs: Vec<String>,
+}
+
+#[allow(dead_code)]
+fn kilobytes(size: &u64) -> String {
+    format!("{}k", size / 1000)
+}
+
+#[allow(dead_code)]
+#[derive(FsFile)]
+struct Five {
+    #[fsfile = "size_exact"]
+    #[fsfile(key = "size", with = "kilobytes")]
+    size: u64,
+    #[fsfile = "mdate"]
+    mdate: &'static str,
+}
+
 fn main() -> std::io::Result<()> {
     println!("test");
     Ok(())
@@ -26,6 +68,8 @@ fn main() -> std::io::Result<()> {
 
 #[cfg(test)]
 mod test {
+    use std::borrow::Cow;
+
     use super::*;
 
     #[test]
@@ -40,14 +84,59 @@ mod test {
             one_size: "s".into(),
             _data: "d".into(),
         };
-        assert_eq!(&one["meta"], "m");
-        assert_eq!(&one["size"], "s");
+        assert_eq!(one.value("meta"), "m");
+        assert_eq!(one.value("size"), "s");
     }
 
     #[test]
     fn two() {
         let two = Two { data: "joint" };
-        assert_eq!(&two["meta"], "joint");
-        assert_eq!(&two["size"], "joint");
+        assert_eq!(two.value("meta"), "joint");
+        assert_eq!(two.value("size"), "joint");
+    }
+
+    #[test]
+    fn three() {
+        let three = Three {
+            meta: "m".into(),
+            dirs: vec!["a".into(), "b".into()],
+        };
+        assert_eq!(three.value("meta"), "m");
+        assert_eq!(three.value("dir1"), "a");
+        assert_eq!(three.value("dir2"), "b");
+        assert_eq!(three.value("dir3"), "unknown");
+        assert_eq!(three.value("dir0"), "unknown");
+        assert_eq!(Three::keys(), ["meta"]);
+        assert_eq!(Three::key_prefixes(), ["dir"]);
+        assert_eq!(Two::keys(), ["meta", "size"]);
+    }
+
+    #[test]
+    fn four_expand() {
+        // The same `expand` the filesystem uses, with its full placeholder set.
+        let four = Four {
+            meta: "m".into(),
+            size: "s".into(),
+            mdate: "2023-08-04".into(),
+            ext: "jpg".into(),
+            dirs: vec!["a".into()],
+        };
+        let component = std::path::Component::Normal("{dir1}-{meta}-{size}-{mdate}.{ext}".as_ref());
+        assert_eq!(expand(&component, &four), "a-m-s-2023-08-04.jpg");
+        // Keys only other files have are left alone.
+        let component = std::path::Component::Normal("{meta}-{year}".as_ref());
+        assert_eq!(expand(&component, &four), "m-{year}");
+    }
+
+    #[test]
+    fn five_formatted() {
+        let five = Five {
+            size: 12288,
+            mdate: "2023-08-04",
+        };
+        assert_eq!(five.value("size_exact"), "12288");
+        assert_eq!(five.value("size"), "12k");
+        assert!(matches!(five.value("mdate"), Cow::Borrowed("2023-08-04")));
+        assert!(matches!(five.value("size"), Cow::Owned(_)));
     }
 }
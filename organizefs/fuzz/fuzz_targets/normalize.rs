@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf};
+
+use libfuzzer_sys::fuzz_target;
+use organizefs::common::Normalize;
+
+fuzz_target!(|data: &[u8]| {
+    let path = PathBuf::from(OsStr::from_bytes(data));
+    let normalized = path.normalize();
+    assert_eq!(normalized.normalize(), normalized);
+});
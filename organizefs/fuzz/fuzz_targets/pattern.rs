@@ -0,0 +1,38 @@
+#![no_main]
+
+use std::path::Path;
+
+use file_proc_macro::FsFile;
+use libfuzzer_sys::fuzz_target;
+use organizefs::common::{expand_optional, validate_pattern, FsFile, ParentDirPolicy};
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, FsFile)]
+struct File {
+    #[fsfile = "meta"]
+    meta: String,
+    #[fsfile = "size"]
+    size: String,
+    #[fsfile = "mdate"]
+    mdate: String,
+    #[fsfile = "ext"]
+    ext: String,
+    #[fsfile_prefix = "dir"]
+    dirs: Vec<String>,
+}
+
+fuzz_target!(|pattern: &str| {
+    let _ = validate_pattern::<File>(pattern, ParentDirPolicy::Reject);
+    let _ = validate_pattern::<File>(pattern, ParentDirPolicy::Clamp);
+
+    let file = File {
+        meta: "text_plain".into(),
+        size: String::new(),
+        mdate: "2023-08-04".into(),
+        ext: "jpg".into(),
+        dirs: vec!["photos".into()],
+    };
+    for component in Path::new(pattern).components() {
+        let _ = expand_optional(&component, &file);
+    }
+});
@@ -0,0 +1,91 @@
+use std::fmt::Display;
+
+pub const USAGE: &str = "usage: organizefs [options] <root> <mountpoint>
+       organizefs --no-mount [options] <root>
+       organizefs --dry-run [options] <root>
+       organizefs --init-pattern-from-existing [options] <root>";
+
+/// What's wrong with the command line; shown above `USAGE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsageError {
+    /// The flag takes a value, but came last.
+    MissingValue(String),
+    /// The positional argument so named wasn't given.
+    MissingArgument(&'static str),
+}
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingValue(flag) => write!(f, "{flag} needs a value"),
+            Self::MissingArgument(name) => write!(f, "missing <{name}>"),
+        }
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Remove `flag` and the value after it from `args`, returning the value, or `None` if
+/// `flag` isn't there.
+pub fn take_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, UsageError> {
+    let Some(i) = args.iter().position(|arg| arg == flag) else {
+        return Ok(None);
+    };
+    if i + 1 >= args.len() {
+        return Err(UsageError::MissingValue(flag.to_string()));
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    Ok(Some(value))
+}
+
+/// The positional argument at `index` in `args`, called `name` in `USAGE`.
+pub fn positional<'a>(
+    args: &'a [String],
+    index: usize,
+    name: &'static str,
+) -> Result<&'a str, UsageError> {
+    args.get(index)
+        .map(String::as_str)
+        .ok_or(UsageError::MissingArgument(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    #[traced_test]
+    fn take_flag_value() {
+        let mut given = args(&["organizefs", "--pattern", "/{meta}", "root"]);
+        assert_eq!(
+            take_value(&mut given, "--pattern"),
+            Ok(Some("/{meta}".to_string()))
+        );
+        assert_eq!(given, args(&["organizefs", "root"]));
+        assert_eq!(take_value(&mut given, "--pattern"), Ok(None));
+
+        let mut given = args(&["organizefs", "root", "--snapshot"]);
+        assert_eq!(
+            take_value(&mut given, "--snapshot"),
+            Err(UsageError::MissingValue("--snapshot".to_string()))
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn missing_positional() {
+        let given = args(&["organizefs", "root"]);
+        assert_eq!(positional(&given, 1, "root"), Ok("root"));
+        assert_eq!(
+            positional(&given, 2, "mountpoint"),
+            Err(UsageError::MissingArgument("mountpoint"))
+        );
+    }
+}
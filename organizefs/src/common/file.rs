@@ -1,23 +1,143 @@
 use std::{
+    borrow::Cow,
     fmt::Debug,
-    ops::Index,
     path::{Component, Path},
 };
 
 use tracing::{debug, instrument};
 
-pub trait FsFile: for<'a> Index<&'a str, Output = str> {}
+pub trait FsFile {
+    /// The placeholder keys `value` answers for, e.g. `meta`.
+    fn keys() -> &'static [&'static str]
+    where
+        Self: Sized;
+    /// Prefixes `value` answers for when followed by a number from 1, e.g. `dir` for
+    /// `{dir1}`, `{dir2}`, ...
+    fn key_prefixes() -> &'static [&'static str]
+    where
+        Self: Sized;
+    /// What `{key}` expands to for this file; borrowed where the file holds it as text.
+    fn value(&self, key: &str) -> Cow<'_, str>;
+}
+
+/// What `{dirN}` expands to past the file's last host directory.
+const UNKNOWN: &str = "unknown";
+
+/// Whether `{key}` is a placeholder `T` can fill.
+pub(crate) fn is_known_key<T: FsFile>(key: &str) -> bool {
+    T::keys().contains(&key)
+        || T::key_prefixes().iter().any(|prefix| {
+            key.strip_prefix(prefix)
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        })
+}
 
+/// Expand `component`; a component whose optional placeholder has no value expands to
+/// nothing.
 #[instrument(level = "debug")]
 pub fn expand<T>(component: &Component, file: &T) -> String
+where
+    T: Debug + Clone + FsFile,
+{
+    expand_optional(component, file).unwrap_or_default()
+}
+
+/// A piece of a pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Token<'a> {
+    /// Text kept as it is; `{{` and `}}` come out as `{` and `}`.
+    Literal(&'a str),
+    /// `{key}`, or `{key?}` when `optional`. The key may not be a known one.
+    Placeholder { key: &'a str, optional: bool },
+}
+
+/// Split `pattern` into literal text and placeholders. `{{` and `}}` escape a brace, and
+/// a `{` with no `}` before the next `{` is literal.
+pub(crate) fn tokens(pattern: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = pattern;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("{{") {
+            tokens.push(Token::Literal("{"));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            tokens.push(Token::Literal("}"));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            match after
+                .find(['{', '}'])
+                .filter(|end| after[*end..].starts_with('}'))
+            {
+                Some(end) => {
+                    let inner = &after[..end];
+                    tokens.push(match inner.strip_suffix('?') {
+                        Some(key) => Token::Placeholder {
+                            key,
+                            optional: true,
+                        },
+                        None => Token::Placeholder {
+                            key: inner,
+                            optional: false,
+                        },
+                    });
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    tokens.push(Token::Literal("{"));
+                    rest = after;
+                }
+            }
+        } else {
+            // A lone `}` is literal too.
+            let first = rest.chars().next().map_or(0, char::len_utf8);
+            let end = rest[first..]
+                .find(['{', '}'])
+                .map_or(rest.len(), |end| end + first);
+            tokens.push(Token::Literal(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    tokens
+}
+
+/// Expand `component`, or `None` if one of its optional placeholders (`{key?}`) has no
+/// value, in which case the component is left out of the path altogether.
+///
+/// Every `{key}` naming a known placeholder is replaced with `file.value(key)` in one
+/// pass, so a value is never itself expanded, looking each distinct key up once however
+/// often it appears. Unknown placeholders are left as they are.
+#[instrument(level = "debug")]
+pub fn expand_optional<T>(component: &Component, file: &T) -> Option<String>
 where
     T: Debug + Clone + FsFile,
 {
     let component = component.as_os_str().to_string_lossy();
-    component
-        .replace("{meta}", &file["meta"])
-        .replace("{size}", &file["size"])
-        .replace("{mdate}", &file["mdate"])
+    let mut values: Vec<(&str, Cow<str>)> = Vec::new();
+    let mut result = String::with_capacity(component.len());
+    for token in tokens(&component) {
+        match token {
+            Token::Literal(text) => result.push_str(text),
+            Token::Placeholder { key, optional } if is_known_key::<T>(key) => {
+                if !values.iter().any(|(k, _)| *k == key) {
+                    values.push((key, file.value(key)));
+                }
+                let (_, value) = values.iter().find(|(k, _)| *k == key).unwrap();
+                if optional && (value.is_empty() || value == UNKNOWN) {
+                    return None;
+                }
+                result.push_str(value);
+            }
+            Token::Placeholder { key, optional } => {
+                result.push('{');
+                result.push_str(key);
+                if optional {
+                    result.push('?');
+                }
+                result.push('}');
+            }
+        }
+    }
+    Some(result)
 }
 
 #[instrument(level = "debug")]
@@ -60,9 +180,167 @@ mod tests {
         size: &'a str,
         #[fsfile = "mdate"]
         mdate: &'a str,
+        #[fsfile = "year"]
+        year: &'a str,
+        #[fsfile = "month"]
+        month: &'a str,
+        #[fsfile = "day"]
+        day: &'a str,
+        #[fsfile = "ext"]
+        ext: &'a str,
+        #[fsfile = "mime_type"]
+        mime_type: &'a str,
+        #[fsfile = "mime_subtype"]
+        mime_subtype: &'a str,
         id: usize,
     }
 
+    #[derive(Debug, Clone, FsFile)]
+    struct TestDirFile {
+        #[fsfile = "meta"]
+        meta: String,
+        #[fsfile = "size"]
+        size: String,
+        #[fsfile = "mdate"]
+        mdate: String,
+        #[fsfile = "year"]
+        year: String,
+        #[fsfile = "month"]
+        month: String,
+        #[fsfile = "day"]
+        day: String,
+        #[fsfile = "ext"]
+        ext: String,
+        #[fsfile = "mime_type"]
+        mime_type: String,
+        #[fsfile = "mime_subtype"]
+        mime_subtype: String,
+        #[fsfile_prefix = "dir"]
+        dirs: Vec<String>,
+    }
+
+    #[test]
+    fn expand_dirs() {
+        let file = TestDirFile {
+            meta: "m".into(),
+            size: "s".into(),
+            mdate: "d".into(),
+            year: "2023".into(),
+            month: "08".into(),
+            day: "04".into(),
+            ext: "jpg".into(),
+            mime_type: "image".into(),
+            mime_subtype: "jpeg".into(),
+            dirs: vec!["photos".into(), "2023".into()],
+        };
+        let expand = |c: &str| super::expand(&Component::Normal(c.as_ref()), &file);
+        assert_eq!(expand("{dir1}"), "photos");
+        assert_eq!(expand("{dir2}_{meta}"), "2023_m");
+        assert_eq!(expand("{meta}.{ext}"), "m.jpg");
+        assert_eq!(expand("{mime_type}-{mime_subtype}"), "image-jpeg");
+        assert_eq!(expand("{year}/{month}/{day}"), "2023/08/04");
+        assert_eq!(expand("{dir1}{dir2}{dir1}"), "photos2023photos");
+        assert_eq!(expand("{dir3}"), "unknown");
+        assert_eq!(expand("{dir0}"), "unknown");
+        assert_eq!(expand("{dir99999999999999999999999}"), "unknown");
+        assert_eq!(expand("{dir}{dirx}"), "{dir}{dirx}");
+    }
+
+    /// Counts lookups, to check each placeholder is looked up once per component.
+    #[derive(Debug, Clone, Default)]
+    struct CountingFile {
+        lookups: std::cell::Cell<usize>,
+    }
+
+    impl FsFile for CountingFile {
+        fn keys() -> &'static [&'static str] {
+            &["meta", "size"]
+        }
+
+        fn key_prefixes() -> &'static [&'static str] {
+            &["dir"]
+        }
+
+        fn value(&self, key: &str) -> Cow<'_, str> {
+            self.lookups.set(self.lookups.get() + 1);
+            Cow::Borrowed(match key {
+                "meta" => "m",
+                "size" => "s",
+                _ => "{meta}",
+            })
+        }
+    }
+
+    #[test]
+    fn expand_repeated() {
+        let file = CountingFile::default();
+        let expand = |c: &str| super::expand(&Component::Normal(c.as_ref()), &file);
+        assert_eq!(expand("{meta}_{meta}"), "m_m");
+        assert_eq!(file.lookups.get(), 1);
+        assert_eq!(expand("{meta}{size}{meta}x{size}"), "msmxs");
+        assert_eq!(file.lookups.get(), 3);
+        // A value that looks like a placeholder is left as it is.
+        assert_eq!(expand("{dir1}-{meta}"), "{meta}-m");
+
+        let pattern = Path::new("/{meta}/{meta}_{size}");
+        let expanded = pattern
+            .components()
+            .map(|c| super::expand(&c, &file))
+            .collect::<Vec<_>>();
+        assert_eq!(expanded, vec!["/", "m", "m_s"]);
+    }
+
+    #[test]
+    fn expand_escaped_braces() {
+        let file = CountingFile::default();
+        let expand = |c: &str| super::expand(&Component::Normal(c.as_ref()), &file);
+        assert_eq!(expand("literal_{{size}}"), "literal_{size}");
+        assert_eq!(expand("{{{size}}}"), "{s}");
+        assert_eq!(expand("{{meta}}_{meta}"), "{meta}_m");
+        assert_eq!(expand("a}b{c"), "a}b{c");
+        assert_eq!(expand("é{{}}"), "é{}");
+        assert_eq!(
+            tokens("{{x}}{meta?}"),
+            vec![
+                Token::Literal("{"),
+                Token::Literal("x"),
+                Token::Literal("}"),
+                Token::Placeholder {
+                    key: "meta",
+                    optional: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_optional() {
+        let file = TestDirFile {
+            meta: "m".into(),
+            size: "".into(),
+            mdate: "d".into(),
+            year: "2023".into(),
+            month: "08".into(),
+            day: "04".into(),
+            ext: "jpg".into(),
+            mime_type: "image".into(),
+            mime_subtype: "jpeg".into(),
+            dirs: vec!["photos".into()],
+        };
+        let expand = |c: &str| super::expand_optional(&Component::Normal(c.as_ref()), &file);
+        assert_eq!(expand("{dir1?}"), Some("photos".into()));
+        assert_eq!(expand("{meta?}-{dir1}"), Some("m-photos".into()));
+        assert_eq!(expand("{dir2?}"), None);
+        assert_eq!(expand("x{size?}"), None);
+        assert_eq!(expand("{dir2}"), Some("unknown".into()));
+        // Not a known key: left as it is.
+        assert_eq!(expand("{artist?}"), Some("{artist?}".into()));
+        assert_eq!(
+            super::expand(&Component::Normal("{dir2?}".as_ref()), &file),
+            ""
+        );
+    }
+
     #[test]
     fn get_child_files_root() {
         let files = vec![
@@ -70,12 +348,24 @@ mod tests {
                 meta: "1",
                 size: "1",
                 mdate: "2023/08/04",
+                year: "2023",
+                month: "08",
+                day: "04",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 0,
             },
             TestFile {
                 meta: "1",
                 size: "2",
                 mdate: "2023/08/05",
+                year: "2023",
+                month: "08",
+                day: "05",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 1,
             },
         ];
@@ -94,18 +384,36 @@ mod tests {
                 meta: "1",
                 size: "1",
                 mdate: "2023/08/04",
+                year: "2023",
+                month: "08",
+                day: "04",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 0,
             },
             TestFile {
                 meta: "1",
                 size: "2",
                 mdate: "2023/08/04",
+                year: "2023",
+                month: "08",
+                day: "04",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 1,
             },
             TestFile {
                 meta: "2",
                 size: "0",
                 mdate: "2023/08/04",
+                year: "2023",
+                month: "08",
+                day: "04",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 2,
             },
         ];
@@ -124,24 +432,48 @@ mod tests {
                 meta: "1",
                 size: "1",
                 mdate: "2023/08/04",
+                year: "2023",
+                month: "08",
+                day: "04",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 0,
             },
             TestFile {
                 meta: "1",
                 size: "2",
                 mdate: "2023/08/04",
+                year: "2023",
+                month: "08",
+                day: "04",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 1,
             },
             TestFile {
                 meta: "1",
                 size: "2",
                 mdate: "2023/08/04",
+                year: "2023",
+                month: "08",
+                day: "04",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 2,
             },
             TestFile {
                 meta: "2",
                 size: "0",
                 mdate: "2023/08/04",
+                year: "2023",
+                month: "08",
+                day: "04",
+                ext: "jpg",
+                mime_type: "image",
+                mime_subtype: "jpeg",
                 id: 3,
             },
         ];
@@ -1,7 +1,7 @@
 use core::fmt::Debug;
-use std::{ffi::OsStr, fs, path::Path, time::SystemTime};
+use std::{ffi::OsStr, fs, os::unix::fs::FileTypeExt, path::Path, time::SystemTime};
 
-use mockall::automock;
+use mockall::{automock, mock};
 
 #[automock]
 pub trait DirEntry: Debug {
@@ -17,20 +17,103 @@ impl DirEntry for walkdir::DirEntry {
     }
 }
 
-#[automock]
+/// What kind of thing a host path is, as its (not followed) metadata says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
 pub trait Metadata: Debug {
     fn len(&self) -> u64;
-    fn is_empty(&self) -> bool;
+    /// Always derived from `len`, so no implementation can disagree with it.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn modified(&self) -> std::io::Result<SystemTime>;
+    fn file_type(&self) -> FileKind;
+    /// Derived from `file_type`, as `is_empty` is from `len`.
+    fn is_dir(&self) -> bool {
+        self.file_type() == FileKind::Dir
+    }
+    fn is_symlink(&self) -> bool {
+        self.file_type() == FileKind::Symlink
+    }
+}
+
+// Written out rather than `automock`ed, so the mock keeps the provided `is_empty`.
+mock! {
+    #[derive(Debug)]
+    pub Metadata {}
+    impl Metadata for Metadata {
+        fn len(&self) -> u64;
+        fn modified(&self) -> std::io::Result<SystemTime>;
+        fn file_type(&self) -> FileKind;
+    }
 }
+
 impl Metadata for fs::Metadata {
     fn len(&self) -> u64 {
         self.len()
     }
-    fn is_empty(&self) -> bool {
-        self.len() == 0
-    }
     fn modified(&self) -> std::io::Result<SystemTime> {
         self.modified()
     }
+    fn file_type(&self) -> FileKind {
+        let file_type = self.file_type();
+        if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_fifo() {
+            FileKind::Fifo
+        } else if file_type.is_socket() {
+            FileKind::Socket
+        } else if file_type.is_block_device() {
+            FileKind::BlockDevice
+        } else if file_type.is_char_device() {
+            FileKind::CharDevice
+        } else {
+            FileKind::File
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_empty_follows_len() {
+        let mut empty = MockMetadata::new();
+        empty.expect_len().return_const(0_u64);
+        assert!(empty.is_empty());
+
+        let mut sized = MockMetadata::new();
+        sized.expect_len().return_const(5_u64);
+        assert!(!sized.is_empty());
+    }
+
+    #[test]
+    fn kind_of_host_paths() {
+        let dir = std::env::temp_dir().join(format!("organizefs-kind-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, "x").unwrap();
+        let link = dir.join("link");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        let kind = |path: &Path| Metadata::file_type(&fs::symlink_metadata(path).unwrap());
+        assert_eq!(kind(&file), FileKind::File);
+        assert_eq!(kind(&dir), FileKind::Dir);
+        assert_eq!(kind(&link), FileKind::Symlink);
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
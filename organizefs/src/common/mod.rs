@@ -1,7 +1,10 @@
 mod file;
 pub mod mock_traits;
 mod normalize;
+mod pattern;
 
-pub use file::{expand, FsFile};
-pub use mock_traits::{DirEntry, Metadata};
+pub use file::{expand, expand_optional, get_child_files, FsFile};
+pub(crate) use file::{is_known_key, tokens, Token};
+pub use mock_traits::{DirEntry, FileKind, Metadata};
 pub use normalize::Normalize;
+pub use pattern::{suggest_pattern, validate_pattern, ParentDirPolicy, Pattern, PatternError};
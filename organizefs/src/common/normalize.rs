@@ -12,7 +12,11 @@ impl Normalize for PathBuf {
 
         for c in self.components() {
             match c {
-                std::path::Component::Prefix(_) => todo!(),
+                // A prefix (Windows only) starts the path over, as a root does.
+                std::path::Component::Prefix(_) => {
+                    comps.clear();
+                    comps.push(c);
+                }
                 std::path::Component::RootDir => {
                     comps.clear();
                     comps.push(c);
@@ -35,11 +39,10 @@ impl Normalize for PathBuf {
             }
             res.push(c.as_os_str());
 
-            need_sep = match c {
-                std::path::Component::RootDir => false,
-                std::path::Component::Prefix(_) => todo!(),
-                _ => true,
-            }
+            need_sep = !matches!(
+                c,
+                std::path::Component::RootDir | std::path::Component::Prefix(_)
+            )
         }
         debug!(source = debug(self), target = debug(&res), "normalize");
         PathBuf::from(&res)
@@ -61,4 +64,19 @@ mod tests {
             result.to_str().unwrap()
         );
     }
+
+    #[test]
+    fn normalize_idempotent() {
+        // The seeds of the `normalize` fuzz target.
+        for input in [
+            "/../s/../t/./m_{meta}/s_{size}/{meta}_{size}",
+            "a/../..",
+            "//a//b/.",
+            "../a/./b/..",
+            "",
+        ] {
+            let once = PathBuf::from(input).normalize();
+            assert_eq!(once.normalize(), once, "{input:?}");
+        }
+    }
 }
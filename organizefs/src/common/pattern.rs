@@ -0,0 +1,334 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+use super::{is_known_key, tokens, FsFile, Normalize, Token};
+
+/// How a pattern `..` that would climb above the root is treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParentDirPolicy {
+    /// Drop the `..`, as `Normalize` does.
+    Clamp,
+    /// Refuse the pattern.
+    #[default]
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    EscapesRoot(String),
+    /// `{key?}` names a key no file can have.
+    UnknownOptional {
+        pattern: String,
+        key: String,
+    },
+    /// `{key}` names a key no file can have, so it would be left in paths verbatim.
+    UnknownPlaceholder {
+        pattern: String,
+        key: String,
+    },
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EscapesRoot(pattern) => write!(f, "pattern {pattern:?} escapes the root"),
+            Self::UnknownOptional { pattern, key } => write!(
+                f,
+                "pattern {pattern:?} has an optional placeholder for unknown key {key:?}"
+            ),
+            Self::UnknownPlaceholder { pattern, key } => {
+                write!(
+                    f,
+                    "pattern {pattern:?} has unknown placeholder \"{{{key}}}\""
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// A pattern in normalized form, as the store files entries under it; tools can look
+/// inside it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern(String);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Self(
+            PathBuf::from(pattern)
+                .normalize()
+                .to_string_lossy()
+                .to_string(),
+        )
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn as_path(&self) -> &Path {
+        Path::new(&self.0)
+    }
+
+    /// Directory levels, root first: `/{meta}/./{mdate}/` => `["{meta}", "{mdate}"]`.
+    pub fn components(&self) -> Vec<&str> {
+        self.0.split('/').filter(|c| !c.is_empty()).collect()
+    }
+
+    /// Placeholder keys, once each, in the order they first appear; `{key?}` counts as
+    /// `key`. Unknown keys are included.
+    pub fn keys_used(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        for token in tokens(&self.0) {
+            if let Token::Placeholder { key, .. } = token {
+                if !key.is_empty() && !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Check `pattern` stays below the root under `policy`, and only has placeholders `T`
+/// can fill.
+#[instrument(level = "debug")]
+pub fn validate_pattern<T: FsFile>(
+    pattern: &str,
+    policy: ParentDirPolicy,
+) -> Result<(), PatternError> {
+    if policy == ParentDirPolicy::Reject && escapes_root(Path::new(pattern)) {
+        return Err(PatternError::EscapesRoot(pattern.to_string()));
+    }
+    for token in tokens(pattern) {
+        let Token::Placeholder { key, optional } = token else {
+            continue;
+        };
+        if is_known_key::<T>(key) {
+            continue;
+        }
+        let (pattern, key) = (pattern.to_string(), key.to_string());
+        return Err(if optional {
+            PatternError::UnknownOptional { pattern, key }
+        } else {
+            PatternError::UnknownPlaceholder { pattern, key }
+        });
+    }
+    Ok(())
+}
+
+/// Suggest a pattern for a sample of files, from what varies across it.
+///
+/// Collections that are mostly images are laid out by type, then year. Otherwise each of
+/// the top-level host directory, type and date gets a level if the sample spans more than
+/// one value of it. Either way, sizes spanning more than one `{size_bucket}` add a level
+/// for it last.
+#[instrument(level = "debug", skip(sample))]
+pub fn suggest_pattern<T>(sample: &[T]) -> String
+where
+    T: FsFile,
+{
+    let distinct = |key: &str| {
+        sample
+            .iter()
+            .map(|file| file.value(key))
+            .filter(|value| value != "unknown")
+            .collect::<HashSet<_>>()
+            .len()
+    };
+    let images = sample
+        .iter()
+        .filter(|file| file.value("meta").starts_with("image_"))
+        .count();
+    debug!(sample = sample.len(), images, "suggest pattern");
+
+    let mut components = if !sample.is_empty() && images * 2 >= sample.len() {
+        vec!["{meta}", "{year}"]
+    } else {
+        [("dir1", "{dir1}"), ("meta", "{meta}"), ("mdate", "{mdate}")]
+            .into_iter()
+            .filter(|(key, _)| distinct(key) > 1)
+            .map(|(_, component)| component)
+            .collect()
+    };
+    if distinct("size_bucket") > 1 {
+        components.push("{size_bucket}");
+    }
+    format!("/{}", components.join("/"))
+}
+
+fn escapes_root(pattern: &Path) -> bool {
+    let mut depth = 0_usize;
+    for component in pattern.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => depth = 0,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return true,
+            },
+            Component::Normal(_) => depth += 1,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use file_proc_macro::FsFile;
+
+    use super::*;
+
+    #[derive(Debug, Clone, FsFile)]
+    struct TestFile {
+        #[fsfile = "meta"]
+        meta: &'static str,
+        #[fsfile = "mdate"]
+        mdate: &'static str,
+        #[fsfile = "size"]
+        size: &'static str,
+        #[fsfile = "size_bucket"]
+        size_bucket: &'static str,
+        #[fsfile = "year"]
+        year: &'static str,
+        #[fsfile = "month"]
+        month: &'static str,
+        #[fsfile_prefix = "dir"]
+        dirs: Vec<String>,
+    }
+
+    fn test_file(dir: &str, meta: &'static str, mdate: &'static str) -> TestFile {
+        TestFile {
+            meta,
+            mdate,
+            size: "",
+            size_bucket: "under-1kB",
+            year: &mdate[..4],
+            month: &mdate[5..7],
+            dirs: vec![dir.to_string()],
+        }
+    }
+
+    #[test]
+    fn validate_pattern_reject() {
+        assert_eq!(
+            validate_pattern::<TestFile>("/../../etc/{meta}", ParentDirPolicy::Reject),
+            Err(PatternError::EscapesRoot("/../../etc/{meta}".into()))
+        );
+        assert_eq!(
+            validate_pattern::<TestFile>("/t/../../{meta}", ParentDirPolicy::Reject),
+            Err(PatternError::EscapesRoot("/t/../../{meta}".into()))
+        );
+        assert!(
+            validate_pattern::<TestFile>("/s/../t/./{meta}/{size}", ParentDirPolicy::Reject)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn validate_pattern_optional() {
+        assert!(validate_pattern::<TestFile>("/{dir1?}/{meta}", ParentDirPolicy::Reject).is_ok());
+        assert!(
+            validate_pattern::<TestFile>("/{dir12?}x{mdate?}", ParentDirPolicy::Reject).is_ok()
+        );
+        assert_eq!(
+            validate_pattern::<TestFile>("/{artist?}/{meta}", ParentDirPolicy::Reject),
+            Err(PatternError::UnknownOptional {
+                pattern: "/{artist?}/{meta}".into(),
+                key: "artist".into()
+            })
+        );
+        assert!(validate_pattern::<TestFile>("/{?}", ParentDirPolicy::Reject).is_err());
+    }
+
+    #[test]
+    fn validate_pattern_unknown_placeholder() {
+        assert!(
+            validate_pattern::<TestFile>("/{dir2}/{year}-{month}", ParentDirPolicy::Reject).is_ok()
+        );
+        let err =
+            validate_pattern::<TestFile>("/{meta}/{colour}", ParentDirPolicy::Reject).unwrap_err();
+        assert_eq!(
+            err,
+            PatternError::UnknownPlaceholder {
+                pattern: "/{meta}/{colour}".into(),
+                key: "colour".into(),
+            }
+        );
+        assert!(err.to_string().contains("\"{colour}\""));
+        assert!(validate_pattern::<TestFile>("/{}", ParentDirPolicy::Reject).is_err());
+        assert!(validate_pattern::<TestFile>(
+            "/literal_{{colour}}/{meta}",
+            ParentDirPolicy::Reject
+        )
+        .is_ok());
+        assert_eq!(Pattern::new("/{{size}}/{meta}").keys_used(), ["meta"]);
+        assert!(validate_pattern::<TestFile>("/{dir}", ParentDirPolicy::Reject).is_err());
+    }
+
+    #[test]
+    fn validate_pattern_clamp() {
+        assert!(validate_pattern::<TestFile>("/../../etc/{meta}", ParentDirPolicy::Clamp).is_ok());
+        assert!(
+            validate_pattern::<TestFile>("/s/../t/./{meta}/{size}", ParentDirPolicy::Clamp).is_ok()
+        );
+    }
+
+    #[test]
+    fn pattern_keys_used() {
+        let pattern = Pattern::new("/{meta}/./by-{dir1?}/{mdate}-{meta}/{size}x{dir1}//");
+        assert_eq!(
+            pattern.as_str(),
+            "/{meta}/by-{dir1?}/{mdate}-{meta}/{size}x{dir1}"
+        );
+        assert_eq!(
+            pattern.components(),
+            vec!["{meta}", "by-{dir1?}", "{mdate}-{meta}", "{size}x{dir1}"]
+        );
+        assert_eq!(pattern.keys_used(), vec!["meta", "dir1", "mdate", "size"]);
+        assert!(Pattern::new("/photos/{?}").keys_used().is_empty());
+        assert!(Pattern::new("/").components().is_empty());
+    }
+
+    #[test]
+    fn suggest_pattern_images() {
+        let sample = vec![
+            test_file("camera", "image_jpeg", "2023-08-04"),
+            test_file("camera", "image_jpeg", "2023-08-05"),
+            test_file("phone", "image_png", "2023-09-01"),
+            test_file("phone", "text_plain", "2023-09-01"),
+        ];
+        let pattern = suggest_pattern(&sample);
+        assert_eq!(pattern, "/{meta}/{year}");
+        assert!(validate_pattern::<TestFile>(&pattern, ParentDirPolicy::Reject).is_ok());
+    }
+
+    #[test]
+    fn suggest_pattern_mixed() {
+        let sample = vec![
+            test_file("docs", "text_plain", "2023-08-04"),
+            test_file("music", "audio_mpeg", "2023-08-04"),
+        ];
+        assert_eq!(suggest_pattern(&sample), "/{dir1}/{meta}");
+        assert_eq!(suggest_pattern::<TestFile>(&[]), "/");
+
+        let mut large = test_file("docs", "text_plain", "2023-08-04");
+        large.size_bucket = "1MB-1GB";
+        let sample = [sample[0].clone(), large];
+        assert_eq!(suggest_pattern(&sample), "/{size_bucket}");
+    }
+}
@@ -1,7 +1,34 @@
-//mod arena;
+mod args;
 pub mod common;
 mod libc_wrapper;
+mod log_level;
+mod metrics;
+mod mime_cache;
 mod organizefs;
+mod pattern_file;
+mod preflight;
+mod read_cache;
+mod readahead;
+mod rules;
 mod server;
-pub use crate::organizefs::{OrganizeFS, OrganizeFSStore};
-pub use server::server;
+mod sidecar;
+mod watcher;
+#[cfg(feature = "webdav")]
+mod webdav;
+pub use crate::organizefs::{
+    EntryDetail, NameCount, OrganizeFS, OrganizeFSEntry, OrganizeFSStore, OrganizeFsConfig,
+    ScanOptions, StoreEvent, TreeNode, DEFAULT_EXCLUDED_DIRS, DEFAULT_MAX_OPEN_FDS,
+    DEFAULT_PATH_HASH_LEN, DEFAULT_SNIFF_BYTES, DEFAULT_UMASK, DEFAULT_UNSORTED_DIR, NO_EXTENSION,
+};
+pub use arena::Collation;
+pub use args::{positional, take_value, UsageError, USAGE};
+pub use log_level::{resolve_log_level, take_verbosity};
+pub use metrics::ReadMetrics;
+pub use mime_cache::MimeCache;
+pub use pattern_file::{apply_pattern_file, read_pattern_file, watch_pattern_file};
+pub use preflight::{preflight, PreflightError};
+pub use read_cache::DEFAULT_READ_CACHE_BYTES;
+pub use readahead::DEFAULT_READAHEAD_BYTES;
+pub use rules::{Rule, Rules};
+pub use server::{bind, serve_mounted, server, SERVER_ADDR};
+pub use watcher::{EventQueue, WatchEvent, DEFAULT_EVENT_CAPACITY};
@@ -11,8 +11,35 @@ pub trait LibcWrapper {
     fn lstat(&self, path: PathBuf) -> io::Result<libc::stat>;
     fn open(&self, path: PathBuf, flags: i32) -> io::Result<i32>;
     fn close(&self, fd: i32) -> io::Result<()>;
-    fn read(&self, fd: i32, offset: i64, count: u32) -> io::Result<Vec<u8>>;
+    fn read(&self, fd: i32, offset: i64, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&self, fd: i32, offset: i64, data: &[u8]) -> io::Result<usize>;
+    fn ftruncate(&self, fd: i32, size: i64) -> io::Result<()>;
+    fn truncate(&self, path: PathBuf, size: i64) -> io::Result<()>;
     fn unlink(&self, path: PathBuf) -> io::Result<()>;
+    fn mkdir(&self, path: PathBuf, mode: u32) -> io::Result<()>;
+    fn rmdir(&self, path: PathBuf) -> io::Result<()>;
+    fn rename(&self, from: PathBuf, to: PathBuf) -> io::Result<()>;
+    /// `rename`, failing with `EEXIST` rather than replacing an existing `to`.
+    fn rename_noreplace(&self, from: PathBuf, to: PathBuf) -> io::Result<()>;
+    fn write_file(&self, path: PathBuf, contents: &[u8]) -> io::Result<()>;
+}
+
+/// Make the libc call `f` (one returning -1 with `errno` set on failure) again for as
+/// long as a signal interrupts it, so callers never see `EINTR`.
+fn retry_eintr<R>(mut f: impl FnMut() -> R) -> io::Result<R>
+where
+    R: Copy + PartialEq + From<i8>,
+{
+    loop {
+        let result = f();
+        if result != R::from(-1) {
+            return Ok(result);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
 }
 
 pub struct LibcWrapperReal;
@@ -26,103 +53,191 @@ impl LibcWrapper for LibcWrapperReal {
         let mut stat = MaybeUninit::<libc::statfs>::zeroed();
 
         let cstr = CString::new(path.clone().into_os_string().as_bytes())?;
-        let result = unsafe { libc::statfs(cstr.as_ptr(), stat.as_mut_ptr()) };
-
-        if -1 == result {
-            let e = io::Error::last_os_error();
+        if let Err(e) = retry_eintr(|| unsafe { libc::statfs(cstr.as_ptr(), stat.as_mut_ptr()) }) {
             error!("statfs({:?}): {}", &path, e);
-            Err(e)
-        } else {
-            let stat = unsafe { stat.assume_init() };
-            Ok(stat)
+            return Err(e);
         }
+        let stat = unsafe { stat.assume_init() };
+        Ok(stat)
     }
 
     fn fstat(&self, fh: u64) -> io::Result<libc::stat> {
         let mut stat = MaybeUninit::<libc::stat>::uninit();
 
-        let result = unsafe { libc::fstat(fh as libc::c_int, stat.as_mut_ptr()) };
-        if -1 == result {
-            let e = io::Error::last_os_error();
+        if let Err(e) = retry_eintr(|| unsafe { libc::fstat(fh as libc::c_int, stat.as_mut_ptr()) })
+        {
             error!("fstat({:?}): {}", fh, e);
-            Err(e)
-        } else {
-            let stat = unsafe { stat.assume_init() };
-            Ok(stat)
+            return Err(e);
         }
+        let stat = unsafe { stat.assume_init() };
+        Ok(stat)
     }
 
     fn lstat(&self, path: PathBuf) -> io::Result<libc::stat> {
         let mut stat = MaybeUninit::<libc::stat>::uninit();
 
         let cstr = CString::new(path.clone().into_os_string().as_bytes())?;
-        let result = unsafe { libc::lstat(cstr.as_ptr(), stat.as_mut_ptr()) };
+        if let Err(e) = retry_eintr(|| unsafe { libc::lstat(cstr.as_ptr(), stat.as_mut_ptr()) }) {
+            error!("lstat({:?}): {}", path, e);
+            return Err(e);
+        }
+        let stat = unsafe { stat.assume_init() };
+        Ok(stat)
+    }
+
+    fn open(&self, path: PathBuf, flags: i32) -> io::Result<i32> {
+        let cstr = CString::new(path.clone().into_os_string().as_bytes())?;
+        retry_eintr(|| unsafe { libc::open(cstr.as_ptr(), flags) })
+            .inspect_err(|e| error!("open({:?}): {}", path, e))
+    }
+
+    fn close(&self, fd: i32) -> io::Result<()> {
+        // Not retried: Linux releases the descriptor even when close is interrupted, so
+        // a retry could close one just handed to another thread.
+        let result = unsafe { libc::close(fd) };
         if -1 == result {
             let e = io::Error::last_os_error();
-            error!("lstat({:?}): {}", path, e);
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            error!("close({:?}): {}", fd, e);
             Err(e)
         } else {
-            let stat = unsafe { stat.assume_init() };
-            Ok(stat)
+            Ok(())
         }
     }
 
-    fn open(&self, path: PathBuf, flags: i32) -> io::Result<i32> {
+    fn read(&self, fd: i32, offset: i64, buf: &mut [u8]) -> io::Result<usize> {
+        retry_eintr(|| unsafe {
+            libc::pread64(fd, buf.as_mut_ptr() as *mut c_void, buf.len(), offset)
+        })
+        .map(|read| read as usize)
+        .inspect_err(|e| error!("read({:?}): {}", fd, e))
+    }
+
+    fn write(&self, fd: i32, offset: i64, data: &[u8]) -> io::Result<usize> {
+        retry_eintr(|| unsafe {
+            libc::pwrite64(fd, data.as_ptr() as *const c_void, data.len(), offset)
+        })
+        .map(|written| written as usize)
+        .inspect_err(|e| error!("write({:?}): {}", fd, e))
+    }
+
+    fn ftruncate(&self, fd: i32, size: i64) -> io::Result<()> {
+        retry_eintr(|| unsafe { libc::ftruncate64(fd, size) })
+            .map(|_| ())
+            .inspect_err(|e| error!("ftruncate({:?}, {}): {}", fd, size, e))
+    }
+
+    fn truncate(&self, path: PathBuf, size: i64) -> io::Result<()> {
+        let cstr = CString::new(path.clone().into_os_string().as_bytes())?;
+        retry_eintr(|| unsafe { libc::truncate64(cstr.as_ptr(), size) })
+            .map(|_| ())
+            .inspect_err(|e| error!("truncate({:?}, {}): {}", path, size, e))
+    }
+
+    fn unlink(&self, path: PathBuf) -> io::Result<()> {
         let cstr = CString::new(path.clone().into_os_string().as_bytes())?;
-        let result = unsafe { libc::open(cstr.as_ptr(), flags) };
+        let result = unsafe { libc::unlink(cstr.as_ptr()) };
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("open({:?}): {}", path, e);
             Err(e)
         } else {
-            Ok(result)
+            Ok(())
         }
     }
 
-    fn close(&self, fd: i32) -> io::Result<()> {
-        let result = unsafe { libc::close(fd) };
+    fn mkdir(&self, path: PathBuf, mode: u32) -> io::Result<()> {
+        let cstr = CString::new(path.clone().into_os_string().as_bytes())?;
+        let result = unsafe { libc::mkdir(cstr.as_ptr(), mode as libc::mode_t) };
         if -1 == result {
             let e = io::Error::last_os_error();
-            error!("close({:?}): {}", fd, e);
+            error!("mkdir({:?}, {:#o}): {}", path, mode, e);
             Err(e)
         } else {
             Ok(())
         }
     }
 
-    fn read(&self, fd: i32, offset: i64, count: u32) -> io::Result<Vec<u8>> {
-        let result = unsafe { libc::lseek64(fd, offset, libc::SEEK_SET) };
+    fn rmdir(&self, path: PathBuf) -> io::Result<()> {
+        let cstr = CString::new(path.clone().into_os_string().as_bytes())?;
+        let result = unsafe { libc::rmdir(cstr.as_ptr()) };
         if -1 == result {
             let e = io::Error::last_os_error();
-            error!("read({:?}): {}", fd, e);
-            return Err(e);
+            error!("rmdir({:?}): {}", path, e);
+            Err(e)
+        } else {
+            Ok(())
         }
-        let mut buf = vec![0; count.try_into().unwrap()];
+    }
 
-        let result = unsafe {
-            libc::read(
-                fd,
-                buf.as_mut_ptr() as *mut c_void,
-                count.try_into().unwrap(),
-            )
-        };
+    fn rename(&self, from: PathBuf, to: PathBuf) -> io::Result<()> {
+        let from_cstr = CString::new(from.clone().into_os_string().as_bytes())?;
+        let to_cstr = CString::new(to.clone().into_os_string().as_bytes())?;
+        let result = unsafe { libc::rename(from_cstr.as_ptr(), to_cstr.as_ptr()) };
         if -1 == result {
             let e = io::Error::last_os_error();
-            error!("read({:?}): {}", fd, e);
-            return Err(e);
+            error!("rename({:?}, {:?}): {}", from, to, e);
+            Err(e)
+        } else {
+            Ok(())
         }
-        Ok(buf)
     }
 
-    fn unlink(&self, path: PathBuf) -> io::Result<()> {
-        let cstr = CString::new(path.clone().into_os_string().as_bytes())?;
-        let result = unsafe { libc::unlink(cstr.as_ptr()) };
+    fn rename_noreplace(&self, from: PathBuf, to: PathBuf) -> io::Result<()> {
+        let from_cstr = CString::new(from.clone().into_os_string().as_bytes())?;
+        let to_cstr = CString::new(to.clone().into_os_string().as_bytes())?;
+        let result = unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                from_cstr.as_ptr(),
+                libc::AT_FDCWD,
+                to_cstr.as_ptr(),
+                libc::RENAME_NOREPLACE,
+            )
+        };
         if -1 == result {
             let e = io::Error::last_os_error();
-            error!("open({:?}): {}", path, e);
+            error!("renameat2({:?}, {:?}, RENAME_NOREPLACE): {}", from, to, e);
             Err(e)
         } else {
             Ok(())
         }
     }
+
+    fn write_file(&self, path: PathBuf, contents: &[u8]) -> io::Result<()> {
+        std::fs::write(&path, contents).inspect_err(|e| error!("write_file({:?}): {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    fn fail_with(errno: i32) -> i32 {
+        unsafe { *libc::__errno_location() = errno };
+        -1
+    }
+
+    #[test]
+    #[traced_test]
+    fn retry_eintr_until_done() {
+        let mut calls = 0;
+        let result = retry_eintr(|| {
+            calls += 1;
+            if calls < 3 {
+                fail_with(libc::EINTR)
+            } else {
+                3
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(calls, 3);
+
+        let result = retry_eintr(|| fail_with(libc::EBADF));
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EBADF));
+    }
 }
@@ -0,0 +1,94 @@
+use std::str::FromStr;
+
+use tracing::Level;
+
+const LEVELS: [Level; 5] = [
+    Level::ERROR,
+    Level::WARN,
+    Level::INFO,
+    Level::DEBUG,
+    Level::TRACE,
+];
+
+/// Log level from the command line's `-v`/`-q` count (`verbosity`, `-v` positive), or
+/// else from `RUST_LOG`, or else `INFO`.
+///
+/// Each `-v` is a level more verbose than `INFO` and each `-q` a level quieter; any of
+/// them override `RUST_LOG`. An unparseable `RUST_LOG` is returned alongside the level
+/// so it can be warned about once logging is up.
+pub fn resolve_log_level(verbosity: i32, rust_log: Option<&str>) -> (Level, Option<String>) {
+    if verbosity != 0 {
+        let index = (2 + verbosity).clamp(0, LEVELS.len() as i32 - 1);
+        return (LEVELS[index as usize], None);
+    }
+    match rust_log.map(|value| (value, Level::from_str(value))) {
+        None => (Level::INFO, None),
+        Some((_, Ok(level))) => (level, None),
+        Some((value, Err(_))) => (Level::INFO, Some(value.to_string())),
+    }
+}
+
+/// Remove `-v`/`--verbose` and `-q`/`--quiet` (and stacked `-vv`, `-qq`) from `args`,
+/// returning their net count.
+pub fn take_verbosity(args: &mut Vec<String>) -> i32 {
+    let mut verbosity = 0;
+    args.retain(|arg| {
+        let delta = match arg.as_str() {
+            "--verbose" => 1,
+            "--quiet" => -1,
+            flags if flags.len() > 1 && flags.starts_with('-') => {
+                let flags = &flags[1..];
+                if flags.bytes().all(|b| b == b'v') {
+                    flags.len() as i32
+                } else if flags.bytes().all(|b| b == b'q') {
+                    -(flags.len() as i32)
+                } else {
+                    return true;
+                }
+            }
+            _ => return true,
+        };
+        verbosity += delta;
+        false
+    });
+    verbosity
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn flag_over_env() {
+        assert_eq!(resolve_log_level(0, None), (Level::INFO, None));
+        assert_eq!(resolve_log_level(0, Some("debug")), (Level::DEBUG, None));
+        assert_eq!(resolve_log_level(1, Some("error")), (Level::DEBUG, None));
+        assert_eq!(resolve_log_level(-1, Some("trace")), (Level::WARN, None));
+        assert_eq!(resolve_log_level(5, None), (Level::TRACE, None));
+        assert_eq!(resolve_log_level(-5, None), (Level::ERROR, None));
+    }
+
+    #[test]
+    #[traced_test]
+    fn bad_env_reported() {
+        assert_eq!(
+            resolve_log_level(0, Some("loud")),
+            (Level::INFO, Some("loud".to_string()))
+        );
+        // A flag means the environment isn't consulted at all.
+        assert_eq!(resolve_log_level(-1, Some("loud")), (Level::WARN, None));
+    }
+
+    #[test]
+    #[traced_test]
+    fn take_verbosity_flags() {
+        let mut args = ["organizefs", "-vv", "/src", "-q", "--verbose", "/mnt", "-x"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(take_verbosity(&mut args), 2);
+        assert_eq!(args, ["organizefs", "/src", "/mnt", "-x"]);
+    }
+}
@@ -1,14 +1,28 @@
-use fuse_mt::{spawn_mount, FuseMT};
-use organizefs::{server, OrganizeFS, OrganizeFSStore};
-use std::{env, ffi::OsStr, path::PathBuf, str::FromStr, sync::Arc};
-use tracing::Level;
+use fuse_mt::{spawn_mount, FilesystemMT, FuseMT};
+use organizefs::{
+    apply_pattern_file, bind, common::Normalize, positional, preflight, resolve_log_level,
+    serve_mounted, server, take_value, take_verbosity, watch_pattern_file, Collation, OrganizeFS,
+    OrganizeFSStore, ScanOptions, UsageError, SERVER_ADDR, USAGE,
+};
+use std::{
+    env,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{error, info, warn};
 use tracing_subscriber::fmt::format::FmtSpan;
 
 #[tokio::main]
 async fn main() {
-    // install global collector configured based on RUST_LOG env var.
-    let level =
-        env::var("RUST_LOG").map_or(Level::INFO, |v| Level::from_str(&v).unwrap_or(Level::INFO));
+    let mut args: Vec<String> = env::args().collect();
+    // install global collector at the level from -v/-q, else the RUST_LOG env var.
+    let verbosity = take_verbosity(&mut args);
+    let rust_log = env::var("RUST_LOG").ok();
+    let (level, bad_rust_log) = resolve_log_level(verbosity, rust_log.as_deref());
     tracing_subscriber::fmt()
         .with_span_events(FmtSpan::ACTIVE)
         .with_thread_ids(true)
@@ -17,8 +31,20 @@ async fn main() {
         .with_line_number(true)
         .with_max_level(level)
         .init();
+    if let Some(rust_log) = bad_rust_log {
+        warn!(rust_log, "ignoring unparseable RUST_LOG");
+    }
 
-    let args: Vec<String> = env::args().collect();
+    // `--pattern-file <file>` takes the pattern from a file, re-applied whenever it changes.
+    let pattern_file = take_value(&mut args, "--pattern-file")
+        .unwrap_or_else(usage)
+        .map(PathBuf::from);
+
+    // `--snapshot <file>` restores the tree saved there by the last run, rescanning only
+    // files changed since, and saves it again on unmount.
+    let snapshot = take_value(&mut args, "--snapshot")
+        .unwrap_or_else(usage)
+        .map(PathBuf::from);
 
     let fuse_args = [
         OsStr::new("-o"),
@@ -29,13 +55,274 @@ async fn main() {
         // OsStr::new("auto_unmount"),
     ];
 
+    // `--case-fold` matches, collides and lists names ignoring case.
+    let collation = match args.iter().position(|arg| arg == "--case-fold") {
+        Some(i) => {
+            args.remove(i);
+            Collation::CaseFold
+        }
+        None => Collation::Exact,
+    };
+
     let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-    let stats = Arc::new(parking_lot::RwLock::new(OrganizeFSStore::new(
-        PathBuf::from("/../s/../t/./{meta}/{size}"),
-    )));
-    let organizefs = OrganizeFS::new(&args[1], stats.clone(), tx);
-    let fs = spawn_mount(FuseMT::new(organizefs, 1), &args[2], &fuse_args[..]).unwrap();
+    let stats = Arc::new(parking_lot::RwLock::new(
+        OrganizeFSStore::new(PathBuf::from("/../s/../t/./{meta}/{size}")).with_collation(collation),
+    ));
+    if let Some(pattern_file) = pattern_file {
+        apply_pattern_file(&stats, &pattern_file);
+        if let Err(e) = watch_pattern_file(stats.clone(), pattern_file.clone()) {
+            warn!(
+                pattern_file = debug(pattern_file),
+                error = display(e),
+                "pattern file not watched"
+            );
+        }
+    }
+    let mut options = ScanOptions::default();
+    // Dates are rendered in UTC unless a fixed offset (e.g. "+01:00") is configured.
+    if let Ok(offset) = env::var("ORGANIZEFS_UTC_OFFSET") {
+        match ScanOptions::parse_utc_offset(&offset) {
+            Ok(offset) => options.utc_offset = offset,
+            Err(e) => warn!(offset, error = display(e), "ignoring ORGANIZEFS_UTC_OFFSET"),
+        }
+    }
+    if let Ok(sniff_bytes) = env::var("ORGANIZEFS_SNIFF_BYTES") {
+        match sniff_bytes.parse() {
+            Ok(sniff_bytes) => options.sniff_bytes = sniff_bytes,
+            Err(e) => warn!(
+                sniff_bytes,
+                error = display(e),
+                "ignoring ORGANIZEFS_SNIFF_BYTES"
+            ),
+        }
+    }
+    if let Ok(path_hash_len) = env::var("ORGANIZEFS_PATH_HASH_LEN") {
+        match path_hash_len.parse() {
+            Ok(path_hash_len) => options.path_hash_len = path_hash_len,
+            Err(e) => warn!(
+                path_hash_len,
+                error = display(e),
+                "ignoring ORGANIZEFS_PATH_HASH_LEN"
+            ),
+        }
+    }
+    // `--strip-prefix <dir>` sets the host directory `{dirN}` placeholders count from.
+    if let Some(prefix) = take_value(&mut args, "--strip-prefix").unwrap_or_else(usage) {
+        options.strip_prefix = Some(env::current_dir().unwrap().join(prefix).normalize());
+    }
+    // `--exclude-dirs <name,...>` replaces the directories the scan skips; empty for none.
+    if let Some(names) = take_value(&mut args, "--exclude-dirs").unwrap_or_else(usage) {
+        options.excluded_dirs = names
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+    // `--one-file-system` keeps the scan from crossing into other mounted filesystems.
+    if let Some(i) = args.iter().position(|arg| arg == "--one-file-system") {
+        args.remove(i);
+        options.one_filesystem = true;
+    }
+    // `--follow-links` indexes symlinked files and descends into symlinked directories.
+    if let Some(i) = args.iter().position(|arg| arg == "--follow-links") {
+        args.remove(i);
+        options.follow_links = true;
+    }
+    // `--lazy` skips the upfront scan, indexing directories as they're first listed.
+    if let Some(i) = args.iter().position(|arg| arg == "--lazy") {
+        args.remove(i);
+        options.lazy = true;
+    }
+    // `--watch` keeps indexing host changes after the scan, so the tree stays live.
+    if let Some(i) = args.iter().position(|arg| arg == "--watch") {
+        args.remove(i);
+        options.watch = true;
+    }
+    // `--pattern <pattern>` organizes the tree with it instead of the default.
+    if let Some(pattern) = take_value(&mut args, "--pattern").unwrap_or_else(usage) {
+        if let Err(e) = stats.read().validate_pattern(&pattern) {
+            eprintln!("invalid pattern: {e}");
+            std::process::exit(1);
+        }
+        stats.write().set_pattern(&pattern);
+    }
+    // `--symlinks` shows files as symlinks to their host paths instead of proxying them.
+    let symlink_view = match args.iter().position(|arg| arg == "--symlinks") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    // `--no-mount <root>` serves the REST endpoints, and WebDAV when built with it, without
+    // mounting, for hosts without FUSE. Runs until interrupted.
+    let no_mount = match args.iter().position(|arg| arg == "--no-mount") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let command = args.get(1).map(String::as_str);
+    // `--dry-run <root>` prints the tree that would be mounted, as a manifest, and exits.
+    if command == Some("--dry-run") {
+        let root = positional(&args, 2, "root").unwrap_or_else(usage);
+        match OrganizeFS::dry_run(root, &options, &stats, &mut std::io::stdout().lock()) {
+            Ok(count) => eprintln!("{count} files"),
+            Err(e) => {
+                eprintln!("dry run failed: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if command == Some("--init-pattern-from-existing") {
+        let root = positional(&args, 2, "root").unwrap_or_else(usage);
+        match OrganizeFS::suggest_pattern(root, &options) {
+            Ok(pattern) => println!("{pattern}"),
+            Err(e) => eprintln!("no pattern suggested: {e}"),
+        }
+        return;
+    }
+    let root = positional(&args, 1, "root").unwrap_or_else(usage);
+    let mountpoint = (!no_mount).then(|| positional(&args, 2, "mountpoint").unwrap_or_else(usage));
+    // Explain a mount that would fail before spending time on the scan.
+    let allow_other = fuse_args.contains(&OsStr::new("allow_other"));
+    if let Some(mountpoint) = mountpoint {
+        if let Err(e) = preflight(Path::new(mountpoint), allow_other) {
+            eprintln!("cannot mount: {e}");
+            std::process::exit(1);
+        }
+    } else if !cfg!(feature = "webdav") {
+        warn!("built without webdav, serving the REST endpoints only");
+    }
+    // Listen before mounting, so a port in use leaves nothing mounted.
+    let listener = match bind(&SERVER_ADDR.parse().unwrap()) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("cannot serve on {SERVER_ADDR}: {e}");
+            std::process::exit(1);
+        }
+    };
+    // A lazy tree is only what was listed, so it's neither restored nor saved over a
+    // complete one.
+    let snapshot = snapshot.filter(|snapshot| {
+        if options.lazy {
+            warn!(snapshot = debug(snapshot), "lazy, snapshot not used");
+        }
+        !options.lazy
+    });
+    if let Some(snapshot) = &snapshot {
+        match File::open(snapshot).and_then(|file| stats.write().restore(BufReader::new(file))) {
+            Ok(files) => info!(snapshot = debug(snapshot), files, "snapshot restored"),
+            Err(e) => warn!(
+                snapshot = debug(snapshot),
+                error = display(e),
+                "snapshot not restored"
+            ),
+        }
+    }
+    let mut organizefs =
+        OrganizeFS::with_options(root, stats.clone(), tx, options).with_symlink_view(symlink_view);
+    if let Ok(slow_op_ms) = env::var("ORGANIZEFS_SLOW_OP_MS") {
+        match slow_op_ms.parse() {
+            Ok(slow_op_ms) => {
+                organizefs = organizefs.with_slow_op_threshold(Duration::from_millis(slow_op_ms))
+            }
+            Err(e) => warn!(
+                slow_op_ms,
+                error = display(e),
+                "ignoring ORGANIZEFS_SLOW_OP_MS"
+            ),
+        }
+    }
+    if let Ok(max_open_fds) = env::var("ORGANIZEFS_MAX_OPEN_FDS") {
+        match max_open_fds.parse() {
+            Ok(max_open_fds) => organizefs = organizefs.with_max_open_fds(max_open_fds),
+            Err(e) => warn!(
+                max_open_fds,
+                error = display(e),
+                "ignoring ORGANIZEFS_MAX_OPEN_FDS"
+            ),
+        }
+    }
+    if let Ok(readahead_bytes) = env::var("ORGANIZEFS_READAHEAD_BYTES") {
+        match readahead_bytes.parse() {
+            Ok(readahead_bytes) => organizefs = organizefs.with_readahead_bytes(readahead_bytes),
+            Err(e) => warn!(
+                readahead_bytes,
+                error = display(e),
+                "ignoring ORGANIZEFS_READAHEAD_BYTES"
+            ),
+        }
+    }
+    if let Ok(umask) = env::var("ORGANIZEFS_UMASK") {
+        match u32::from_str_radix(&umask, 8) {
+            Ok(umask) => organizefs = organizefs.with_umask(umask),
+            Err(e) => warn!(umask, error = display(e), "ignoring ORGANIZEFS_UMASK"),
+        }
+    }
+    let read_metrics = organizefs.read_metrics();
+    let config = organizefs.config();
+    let options = organizefs.scan_options();
+    let failed = if let Some(mountpoint) = mountpoint {
+        let fs = spawn_mount(FuseMT::new(organizefs, 1), mountpoint, &fuse_args[..]).unwrap();
+        // Unmounting signals the server to stop; a failed server unmounts.
+        match serve_mounted(
+            fs,
+            server(listener, stats.clone(), read_metrics, config, options, rx),
+            drop,
+        )
+        .await
+        {
+            Ok(fs) => {
+                fs.join();
+                false
+            }
+            Err(e) => {
+                error!(error = display(e), "server failed, unmounted");
+                true
+            }
+        }
+    } else {
+        // Stands in for the unmount that would otherwise stop the server.
+        tokio::spawn(async move {
+            tokio::signal::ctrl_c().await.ok();
+            organizefs.destroy();
+        });
+        match server(listener, stats.clone(), read_metrics, config, options, rx).await {
+            Ok(()) => false,
+            Err(e) => {
+                error!(error = display(e), "server failed");
+                true
+            }
+        }
+    };
+    if let Some(snapshot) = snapshot {
+        // Written aside and renamed over, so a failed save leaves the last one intact.
+        let partial = snapshot.with_extension("partial");
+        let saved = File::create(&partial)
+            .and_then(|file| {
+                let mut w = BufWriter::new(file);
+                stats.read().save(&mut w)?;
+                w.into_inner().map_err(|e| e.into_error())?.sync_all()
+            })
+            .and_then(|()| fs::rename(&partial, &snapshot));
+        if let Err(e) = saved {
+            warn!(
+                snapshot = debug(snapshot),
+                error = display(e),
+                "snapshot not saved"
+            );
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
 
-    server(stats, rx).await.unwrap();
-    fs.join();
+/// Explain what's wrong with the command line, and how it's used, and exit.
+fn usage<T>(e: UsageError) -> T {
+    eprintln!("{e}\n{USAGE}");
+    std::process::exit(1);
 }
@@ -0,0 +1,92 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Counters for the read path, cheap enough to bump on every read.
+#[derive(Debug, Default)]
+pub struct ReadMetrics {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    bytes_from_cache: AtomicU64,
+    bytes_from_disk: AtomicU64,
+}
+
+impl ReadMetrics {
+    pub fn record_hit(&self, bytes: usize) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        self.bytes_from_cache
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self, bytes: usize) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        self.bytes_from_disk
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn cache_misses(&self) -> u64 {
+        self.cache_misses.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_from_cache(&self) -> u64 {
+        self.bytes_from_cache.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_from_disk(&self) -> u64 {
+        self.bytes_from_disk.load(Ordering::Relaxed)
+    }
+
+    /// Prometheus text exposition.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "organizefs_read_cache_hits_total {}",
+            self.cache_hits()
+        );
+        let _ = writeln!(
+            out,
+            "organizefs_read_cache_misses_total {}",
+            self.cache_misses()
+        );
+        let _ = writeln!(
+            out,
+            "organizefs_read_bytes_total{{source=\"cache\"}} {}",
+            self.bytes_from_cache()
+        );
+        let _ = writeln!(
+            out,
+            "organizefs_read_bytes_total{{source=\"disk\"}} {}",
+            self.bytes_from_disk()
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn render() {
+        let metrics = ReadMetrics::default();
+        metrics.record_miss(10);
+        metrics.record_hit(10);
+        metrics.record_hit(5);
+        assert_eq!(
+            metrics.render(),
+            "organizefs_read_cache_hits_total 2\n\
+             organizefs_read_cache_misses_total 1\n\
+             organizefs_read_bytes_total{source=\"cache\"} 15\n\
+             organizefs_read_bytes_total{source=\"disk\"} 10\n"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+/// A file's modification time and size, and the type detected while it had them.
+type Cached = (Option<SystemTime>, u64, String);
+
+/// Types detected so far, by host path; a file changed since is detected again.
+#[derive(Debug, Default)]
+struct Detected {
+    types: Mutex<HashMap<PathBuf, Cached>>,
+    detections: AtomicU64,
+}
+
+/// Detected file types, reused while files are unchanged, so a rescan only reads the
+/// files that changed. Clones share the cache.
+#[derive(Debug, Default, Clone)]
+pub struct MimeCache(Arc<Detected>);
+
+impl MimeCache {
+    /// The type of `path`, last modified at `modified` and `size` bytes long: as cached
+    /// if it was seen like that, else from `detect`.
+    pub fn get_or_detect(
+        &self,
+        path: &Path,
+        modified: Option<SystemTime>,
+        size: u64,
+        detect: impl FnOnce() -> String,
+    ) -> String {
+        if let Some((cached_modified, cached_size, mime)) = self.0.types.lock().unwrap().get(path) {
+            if (*cached_modified, *cached_size) == (modified, size) {
+                return mime.to_owned();
+            }
+        }
+        // Detected unlocked, as it reads the file.
+        let mime = detect();
+        self.0.detections.fetch_add(1, Ordering::Relaxed);
+        self.insert(path, modified, size, mime.clone());
+        mime
+    }
+
+    /// Record `path`'s type as `mime`, e.g. as detected by an earlier run.
+    pub fn insert(&self, path: &Path, modified: Option<SystemTime>, size: u64, mime: String) {
+        self.0
+            .types
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, size, mime));
+    }
+
+    /// Forget the files `keep` rejects, e.g. those a rescan no longer found.
+    pub fn retain(&self, mut keep: impl FnMut(&Path) -> bool) {
+        self.0.types.lock().unwrap().retain(|path, _| keep(path));
+    }
+
+    /// How many types have been detected rather than reused.
+    pub fn detections(&self) -> u64 {
+        self.0.detections.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.0.types.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn redetects_changed() {
+        let cache = MimeCache::default();
+        let path = Path::new("/host/a");
+        let modified = Some(SystemTime::UNIX_EPOCH);
+        let text = || "text/plain".to_string();
+        assert_eq!(cache.get_or_detect(path, modified, 4, text), "text/plain");
+        assert_eq!(
+            cache.get_or_detect(path, modified, 4, || unreachable!()),
+            "text/plain"
+        );
+        assert_eq!(cache.detections(), 1);
+
+        let png = || "image/png".to_string();
+        assert_eq!(cache.get_or_detect(path, modified, 8, png), "image/png");
+        assert_eq!(cache.get_or_detect(path, None, 8, png), "image/png");
+        assert_eq!(cache.detections(), 3);
+
+        cache.clone().retain(|path| path != Path::new("/host/a"));
+        assert_eq!(cache.len(), 0);
+    }
+}
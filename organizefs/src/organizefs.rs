@@ -1,65 +1,403 @@
-use crate::common::{DirEntry, Metadata};
-use arena::{Arena, Entry, NewArena};
+use crate::common::{DirEntry, FileKind, Metadata};
 use crate::{
-//    arena::{Arena, Entry, NewArena},
-    common::{expand, FsFile, Normalize},
+    common::{
+        expand_optional, is_known_key, suggest_pattern, tokens, validate_pattern, FsFile,
+        Normalize, ParentDirPolicy, Pattern, PatternError, Token,
+    },
     libc_wrapper::{LibcWrapper, LibcWrapperReal},
+    metrics::ReadMetrics,
+    mime_cache::MimeCache,
+    read_cache::{ReadCache, DEFAULT_READ_CACHE_BYTES},
+    readahead::{Readahead, DEFAULT_READAHEAD_BYTES},
+    rules::Rules,
+    sidecar,
+    watcher::{watch_tree, EventQueue, WatchEvent},
 };
+use arena::{Arena, ArenaError, Collation, Entry, IntegrityError, NewArena};
 use file_proc_macro::FsFile;
 use fuse_mt::{
-    CallbackResult, DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, ResultEmpty,
-    ResultEntry, ResultOpen, ResultReaddir, ResultSlice, ResultStatfs, Statfs,
+    CallbackResult, DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, ResultData,
+    ResultEmpty, ResultEntry, ResultOpen, ResultReaddir, ResultSlice, ResultStatfs, ResultWrite,
+    ResultXattr, Statfs, Xattr,
 };
 use humansize::FormatSize;
-use std::collections::HashMap;
+use notify::RecommendedWatcher;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
-use std::ops::{AddAssign, Index};
+use std::io::{Read, Write};
+use std::ops::{AddAssign, Bound};
+use std::os::unix::{
+    ffi::OsStrExt,
+    fs::{MetadataExt, OpenOptionsExt},
+};
 use std::{
     ffi::OsString,
     fmt::Display,
     fs,
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
 };
-use time::macros::format_description;
-use tracing::{debug, info, instrument};
+use time::{macros::format_description, UtcOffset};
+use tracing::{debug, info, instrument, warn};
 use walkdir::WalkDir;
 
 lazy_static::lazy_static! {
     static ref FORMAT: humansize::FormatSizeOptions = humansize::DECIMAL.space_after_value(false).decimal_zeroes(2);
 }
 static TTL: Duration = Duration::from_secs(1);
+static STATFS_TTL: Duration = Duration::from_secs(1);
+static SLOW_OP_THRESHOLD: Duration = Duration::from_millis(500);
+/// How long the host tree must be still before watched changes are applied.
+static WATCH_QUIET: Duration = Duration::from_millis(500);
+/// Permission bits cleared from synthesized modes.
+pub const DEFAULT_UMASK: u32 = 0o022;
+/// First `opendir` handle; file handles count up from 1, so stay below it.
+const DIR_HANDLE_BASE: u64 = 1 << 32;
+/// Host descriptors kept open at once for files opened through FUSE, by default.
+pub const DEFAULT_MAX_OPEN_FDS: usize = 512;
+
+/// Bytes of a file's content read to detect its type.
+pub const DEFAULT_SNIFF_BYTES: usize = 8 * 1024;
+
+/// Hex digits `{path_hash}` expands to, by default: 256 buckets.
+pub const DEFAULT_PATH_HASH_LEN: usize = 2;
+
+/// Directories the scan doesn't go into unless told otherwise: version control and
+/// build or dependency output, which nobody wants organized.
+pub const DEFAULT_EXCLUDED_DIRS: &[&str] = &[
+    ".git",
+    ".hg",
+    ".svn",
+    "node_modules",
+    "target",
+    "__pycache__",
+];
+
+/// What `{mime_type}` and `{mime_subtype}` expand to when a file's type wasn't detected.
+const UNKNOWN_MIME: &str = "unknown";
+
+/// What `{ext}` expands to for a file without an extension.
+pub const NO_EXTENSION: &str = "_none_";
+
+/// Directory, under the root, for entries the pattern couldn't place.
+pub const DEFAULT_UNSORTED_DIR: &str = "_unsorted";
+/// Number of files looked at when suggesting a pattern.
+const SUGGEST_SAMPLE: usize = 1000;
+/// Bumped whenever what `OrganizeFSStore::save` writes changes shape.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Extended attributes of files, recording where they came from.
+const XATTR_HOST_PATH: &str = "user.organizefs.host_path";
+const XATTR_MIME: &str = "user.organizefs.mime";
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, FsFile)]
-struct OrganizeFSEntry {
+thread_local! {
+    static READ_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, FsFile)]
+pub struct OrganizeFSEntry {
     name: OsString,
     host_path: PathBuf,
-    #[fsfile = "size"]
-    size: String,
+    /// `host_path` below the scan's strip prefix; what logs and `{dirN}` see.
+    relative_path: PathBuf,
+    /// In bytes; `{size_exact}` as it is, `{size}` human-readable, `{size_bucket}` to the
+    /// nearest power of 1000 below.
+    #[fsfile = "size_exact"]
+    #[fsfile(key = "size", with = "format_size")]
+    #[fsfile(key = "size_bucket", with = "size_bucket")]
+    size: u64,
     #[fsfile = "meta"]
     mime: String,
+    /// `mime`'s halves, e.g. `image` and `jpeg`; `unknown` when undetected.
+    #[fsfile = "mime_type"]
+    mime_type: String,
+    #[fsfile = "mime_subtype"]
+    mime_subtype: String,
+    /// When the host file was last modified, if known; what tells a rescan it changed.
+    modified: Option<SystemTime>,
     #[fsfile = "mdate"]
     modified_date: String,
+    /// `modified_date`'s parts; month and day are zero-padded so they sort as numbers.
+    #[fsfile = "year"]
+    year: String,
+    #[fsfile = "month"]
+    month: String,
+    #[fsfile = "day"]
+    day: String,
+    /// Lowercased, so `JPG` and `jpg` files land together.
+    #[fsfile = "ext"]
+    extension: String,
+    /// Leading hex digits of a hash of `host_path`, for sharding into balanced buckets.
+    #[fsfile = "path_hash"]
+    path_hash: String,
+    /// Host directories between the root and the file, for `{dir1}`, `{dir2}`, ...
+    #[fsfile_prefix = "dir"]
+    dirs: Vec<String>,
+    /// Placeholder values pinned by the host file's sidecar.
+    overrides: BTreeMap<String, String>,
+    /// Placeholder values set by the store's rules, recomputed without touching the host.
+    tags: BTreeMap<String, String>,
+    /// `n` when other files were already filed under this one's name, which it then
+    /// takes as `name (n)`; 0 otherwise.
+    duplicate: usize,
+    /// Where it came in the order entries were filed under the same directory above
+    /// `{counter}`, from 0; see `OrganizeFSStore::file_entry`.
+    #[fsfile = "counter"]
+    counter: usize,
+    /// Where `insert_at` put the entry, which the pattern then has no say in.
+    pinned: Option<PathBuf>,
+}
+
+/// An entry as the pattern sees it: sidecar overrides win over rule tags, which win
+/// over scanned values.
+#[derive(Debug, Clone)]
+struct Placement<'a>(&'a OrganizeFSEntry);
+impl FsFile for Placement<'_> {
+    fn keys() -> &'static [&'static str] {
+        OrganizeFSEntry::keys()
+    }
+
+    fn key_prefixes() -> &'static [&'static str] {
+        OrganizeFSEntry::key_prefixes()
+    }
+
+    fn value(&self, key: &str) -> Cow<'_, str> {
+        match self.0.overrides.get(key).or(self.0.tags.get(key)) {
+            Some(value) => Cow::Borrowed(value),
+            None => self.0.value(key),
+        }
+    }
+}
+
+/// Settings that shape how host files are turned into entries.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Offset used when rendering dates; UTC unless configured.
+    pub utc_offset: UtcOffset,
+    /// Host directory whose descendants' paths feed `{dir1}`, `{dir2}`, ...; the scan
+    /// root unless configured. Files outside it fall back to the scan root.
+    pub strip_prefix: Option<PathBuf>,
+    /// At most this much of each file is read to detect its type.
+    pub sniff_bytes: usize,
+    /// Don't descend into directories on another filesystem than the root, e.g. bind
+    /// mounts or external drives mounted below it.
+    pub one_filesystem: bool,
+    /// Index what symlinks point at, as if it were where the link is, and descend into
+    /// linked directories. Links looping back to an ancestor are skipped.
+    pub follow_links: bool,
+    /// Skip the upfront scan, and index host directories as their virtual directories
+    /// are first looked at. See `OrganizeFS::populate`.
+    pub lazy: bool,
+    /// Keep watching the host tree after the scan, indexing files as they appear or
+    /// change and dropping them as they go. Not combined with `lazy`.
+    pub watch: bool,
+    /// Hex digits in `{path_hash}`, between 1 and 16.
+    pub path_hash_len: usize,
+    /// Names of directories not descended into, wherever they are below the root.
+    pub excluded_dirs: Vec<String>,
+    /// Types detected by earlier scans, shared by clones of these options, e.g. the
+    /// ones `POST /rescan` uses.
+    pub mime_cache: MimeCache,
+}
+
+impl ScanOptions {
+    /// Whether a directory called `name` is left out of the scan.
+    pub fn excludes(&self, name: &std::ffi::OsStr) -> bool {
+        self.excluded_dirs
+            .iter()
+            .any(|excluded| name == excluded.as_str())
+    }
+
+    /// Parse a fixed offset such as `+01:00` or `-05:30`.
+    pub fn parse_utc_offset(value: &str) -> Result<UtcOffset, time::error::Parse> {
+        UtcOffset::parse(
+            value,
+            format_description!("[offset_hour sign:mandatory]:[offset_minute]"),
+        )
+    }
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            utc_offset: UtcOffset::UTC,
+            strip_prefix: None,
+            sniff_bytes: DEFAULT_SNIFF_BYTES,
+            one_filesystem: false,
+            follow_links: false,
+            lazy: false,
+            watch: false,
+            path_hash_len: DEFAULT_PATH_HASH_LEN,
+            excluded_dirs: DEFAULT_EXCLUDED_DIRS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            mime_cache: MimeCache::default(),
+        }
+    }
+}
+
+/// Detect `path`'s type from at most `limit` bytes of it. Opening doesn't block, so a
+/// FIFO reached through a symlink can't hang the scan; unreadable files have no type.
+fn sniff_mime(path: &Path, limit: usize) -> String {
+    let sniffed = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .and_then(|file| {
+            let mut buffer = Vec::with_capacity(limit.min(DEFAULT_SNIFF_BYTES));
+            file.take(limit as u64).read_to_end(&mut buffer)?;
+            Ok(buffer)
+        });
+    match sniffed {
+        Ok(buffer) => tree_magic_mini::from_u8(&buffer).to_string(),
+        Err(e) => {
+            debug!(path = debug(path), error = display(e), "not sniffed");
+            String::new()
+        }
+    }
+}
+
+/// The errno a FUSE caller sees for a change the tree refused.
+fn arena_errno(error: &ArenaError) -> libc::c_int {
+    match error {
+        ArenaError::Exists(_) => libc::EEXIST,
+        ArenaError::ParentNotFound => libc::ENOENT,
+        ArenaError::ParentIsLeaf => libc::ENOTDIR,
+        ArenaError::DepthExceeded(_) => libc::ENAMETOOLONG,
+        ArenaError::InvalidPath(_) => libc::EINVAL,
+    }
+}
+
+/// `12288` => `12.29kB`.
+fn format_size(size: &u64) -> String {
+    size.format_size(*FORMAT)
+}
+
+/// `12288` => `1kB-1MB`: few enough buckets that each is a useful directory.
+fn size_bucket(size: &u64) -> String {
+    match size {
+        0..=999 => "under-1kB",
+        1_000..=999_999 => "1kB-1MB",
+        1_000_000..=999_999_999 => "1MB-1GB",
+        _ => "1GB-and-over",
+    }
+    .to_string()
+}
+
+/// `path` as a manifest field: backslash, tab, newline and carriage return are escaped
+/// as `\\`, `\t`, `\n` and `\r`, and bytes that aren't UTF-8 as `\xNN`, so every field
+/// is one line without tabs and reads back to the exact path.
+fn tsv_field(path: &Path) -> String {
+    let mut field = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => field.push_str("\\\\"),
+                '\t' => field.push_str("\\t"),
+                '\n' => field.push_str("\\n"),
+                '\r' => field.push_str("\\r"),
+                c => field.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            field.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    field
+}
+
+/// `image/jpeg` => (`image`, `jpeg`).
+fn split_mime(mime: &str) -> (String, String) {
+    let (mime_type, mime_subtype) = mime.split_once('/').unwrap_or((mime, ""));
+    let or_unknown = |part: &str| match part {
+        "" => UNKNOWN_MIME.to_string(),
+        part => part.to_string(),
+    };
+    (or_unknown(mime_type), or_unknown(mime_subtype))
+}
+
+/// The first `len` hex digits of the 64-bit FNV-1a hash of `path`'s bytes. Unlike
+/// `DefaultHasher`, it's the same from one build and run to the next, so a file stays in
+/// its bucket.
+fn path_hash(path: &Path, len: usize) -> String {
+    let hash = path
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    let mut hex = format!("{hash:016x}");
+    hex.truncate(len.clamp(1, 16));
+    hex
 }
 
 impl OrganizeFSEntry {
-    fn new(root: &Path, entry: &impl DirEntry, meta: &impl Metadata) -> Self {
-        debug!(
-            root = debug(root.join(entry.path()).normalize()),
-            "normalize"
-        );
-        let host_path = root.join(entry.path()).normalize();
-        let size = meta.len().format_size(*FORMAT);
-        let mime = tree_magic_mini::from_filepath(&host_path)
-            .unwrap_or_default()
-            .replace('/', "_");
+    fn new(
+        root: &Path,
+        entry: &impl DirEntry,
+        meta: &impl Metadata,
+        options: &ScanOptions,
+    ) -> Self {
+        // WalkDir yields absolute paths for an absolute root; only relative ones need it.
+        let path = entry.path();
+        let host_path = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            root.join(path)
+        }
+        .normalize();
+        debug!(host_path = debug(&host_path), "normalize");
+        let relative = options
+            .strip_prefix
+            .as_deref()
+            .and_then(|prefix| host_path.strip_prefix(prefix).ok())
+            .or_else(|| host_path.strip_prefix(root).ok());
+        let dirs = relative
+            .and_then(Path::parent)
+            .map(|relative| {
+                relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let relative_path = relative.unwrap_or(&host_path).to_path_buf();
+        let size = meta.len();
+        let mime = options
+            .mime_cache
+            .get_or_detect(&host_path, meta.modified().ok(), size, || {
+                sniff_mime(&host_path, options.sniff_bytes)
+            });
+        let (mime_type, mime_subtype) = split_mime(&mime);
+        let mime = mime.replace('/', "_");
         let name = entry.file_name().to_os_string();
-        let modified_date: time::OffsetDateTime =
-            meta.modified().unwrap_or(SystemTime::UNIX_EPOCH).into();
-        let modified_date = modified_date
+        let extension = Path::new(&name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_else(|| NO_EXTENSION.to_string());
+        let path_hash = path_hash(&host_path, options.path_hash_len);
+        let modified_at = meta.modified().ok();
+        let modified: time::OffsetDateTime = modified_at.unwrap_or(SystemTime::UNIX_EPOCH).into();
+        let modified = modified.to_offset(options.utc_offset);
+        let modified_date = modified
             .format(format_description!("[year]-[month]-[day]"))
             .unwrap_or_else(|_| "1970-01-01".to_string());
+        let year = format!("{:04}", modified.year());
+        let month = format!("{:02}", u8::from(modified.month()));
+        let day = format!("{:02}", modified.day());
+        let overrides = fs::read_to_string(sidecar::sidecar_path(&host_path))
+            .map(|contents| sidecar::parse(&contents))
+            .unwrap_or_default();
 
         debug!(
             root = debug(root),
@@ -68,33 +406,95 @@ impl OrganizeFSEntry {
             path = debug(&host_path),
             size,
             mime,
-            modified_date
+            modified_date,
+            overrides = debug(&overrides)
         );
         Self {
             host_path,
+            relative_path,
             name,
             size,
             mime,
+            mime_type,
+            mime_subtype,
+            modified: modified_at,
             modified_date,
+            year,
+            month,
+            day,
+            extension,
+            path_hash,
+            dirs,
+            overrides,
+            tags: BTreeMap::new(),
+            duplicate: 0,
+            counter: 0,
+            pinned: None,
         }
     }
 
-    fn local_path(&self, pattern: &Path) -> PathBuf {
-        let mut path = pattern
-            .components()
-            .map(|component| expand(&component, self))
-            .fold(PathBuf::new(), |mut acc, c| {
-                acc.push(c);
-                acc
-            });
-        path.push(&self.name);
+    /// Where `pattern` files this entry. If a placeholder expands to nothing, detection
+    /// failed for it, and the entry goes to the `unsorted` directory instead.
+    fn local_path(&self, pattern: &Path, unsorted: &str) -> PathBuf {
+        if let Some(pinned) = &self.pinned {
+            return pinned.to_owned();
+        }
+        let mut path = PathBuf::new();
+        for component in pattern.components() {
+            let Some(expanded) = expand_optional(&component, &Placement(self)) else {
+                continue;
+            };
+            if expanded.is_empty() && component.as_os_str().to_string_lossy().contains('{') {
+                return Path::new("/").join(unsorted).join(&self.name);
+            }
+            path.push(expanded);
+        }
+        path.push(self.filed_name());
         path
     }
+
+    /// The directory `pattern` files this entry under above its `{counter}` component,
+    /// which numbers the entries filed there; `None` if the pattern has no `{counter}`.
+    fn counter_context(&self, pattern: &Path) -> Option<PathBuf> {
+        let mut path = PathBuf::new();
+        for component in pattern.components() {
+            let uses_counter = tokens(&component.as_os_str().to_string_lossy())
+                .iter()
+                .any(|token| matches!(token, Token::Placeholder { key: "counter", .. }));
+            if uses_counter {
+                return Some(path);
+            }
+            if let Some(expanded) = expand_optional(&component, &Placement(self)) {
+                path.push(expanded);
+            }
+        }
+        None
+    }
+
+    /// `name`, numbered before its extension if it's a duplicate: `photo (2).jpg`.
+    fn filed_name(&self) -> OsString {
+        if self.duplicate == 0 {
+            return self.name.to_owned();
+        }
+        let name = Path::new(&self.name);
+        let mut filed = name.file_stem().unwrap_or(&self.name).to_os_string();
+        filed.push(format!(" ({})", self.duplicate));
+        if let Some(extension) = name.extension() {
+            filed.push(".");
+            filed.push(extension);
+        }
+        filed
+    }
 }
 
 impl Display for OrganizeFSEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "({} {})", self.host_path.display(), self.size)
+        write!(
+            f,
+            "({} {})",
+            self.relative_path.display(),
+            format_size(&self.size)
+        )
     }
 }
 
@@ -113,32 +513,184 @@ impl OrganizeFSStore {
     #[instrument]
     pub fn new(pattern: PathBuf) -> Self {
         Self {
-            pattern: pattern.normalize(),
+            pattern: Pattern::new(&pattern.to_string_lossy()),
             arena: ArenaType::default(),
             entries: HashMap::new(),
+            by_host: BTreeMap::new(),
             max_entries: Inode::from(0),
+            parent_dir_policy: ParentDirPolicy::default(),
+            unsorted_dir: DEFAULT_UNSORTED_DIR.to_string(),
+            rules: Rules::default(),
+            collation: Collation::default(),
+            numbering: Numbering::default(),
+            observers: Vec::new(),
         }
     }
 
-    #[instrument(level = "debug")]
+    /// A store pre-sized for about `expected_entries` files, so a large scan doesn't rehash.
+    pub fn with_capacity(pattern: PathBuf, expected_entries: usize) -> Self {
+        let mut store = Self::new(pattern);
+        store.reserve(expected_entries);
+        store
+    }
+
+    /// Make room for `additional` more entries. Each takes one leaf in the tree, plus
+    /// whatever directories it shares with the others; a quarter again covers those.
+    pub fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional);
+        self.arena
+            .reserve(additional.saturating_add(additional / 4));
+    }
+
+    pub fn with_parent_dir_policy(mut self, parent_dir_policy: ParentDirPolicy) -> Self {
+        self.parent_dir_policy = parent_dir_policy;
+        self
+    }
+
+    /// Name of the catch-all directory for entries missing a placeholder's value.
+    pub fn with_unsorted_dir(mut self, unsorted_dir: &str) -> Self {
+        self.unsorted_dir = unsorted_dir.to_string();
+        self
+    }
+
+    /// How names compare, for lookups, clashes and listing order alike.
+    pub fn with_collation(mut self, collation: Collation) -> Self {
+        self.collation = collation;
+        self.rebuild_arena();
+        self
+    }
+
+    /// Add `entry`, or log and skip it if where the pattern files it clashes with what's
+    /// already there.
     fn add_entry(&mut self, entry: OrganizeFSEntry) {
+        let host_path = entry.host_path.clone();
+        if let Err(e) = self.try_add_entry(entry) {
+            warn!(
+                host_path = debug(host_path),
+                error = display(e),
+                "not added"
+            );
+        }
+    }
+
+    /// Add `entry` where the pattern files it, numbered if files of that name are already
+    /// there (see `file_entry`). Fails, leaving the store as it was, if a directory of
+    /// that name is there, or the file would need a directory where there is a file
+    /// (e.g. a file and a category sharing a name).
+    #[instrument(level = "debug")]
+    pub fn try_add_entry(&mut self, mut entry: OrganizeFSEntry) -> Result<(), ArenaError> {
+        if !self.rules.is_empty() {
+            entry.tags = Self::tags(&self.rules, &entry);
+        }
         let id = self.max_entries;
+        let local_path = Self::file_entry(
+            &mut self.arena,
+            &mut self.numbering,
+            &mut entry,
+            self.pattern.as_path(),
+            &self.unsorted_dir,
+            id,
+        )?;
+        debug!(id = debug(&id), path = debug(&local_path), "added to arena");
         self.max_entries += 1;
-        self.entries.insert(id, entry.clone());
-
-        let local_path = entry.local_path(&self.pattern);
-        Self::add_entry_to_arena(&mut self.arena, &local_path, id);
+        self.insert_entry(id, entry);
+        self.notify(StoreEvent::Added(local_path));
+        Ok(())
     }
 
+    /// Add `entry` at the virtual path `virtual_path`, whatever the pattern says, e.g. for
+    /// entries put there by a caller rather than found by the scan. It stays there when
+    /// the pattern changes. Directories on the way are added as needed; fails, leaving
+    /// the store as it was, if anything is already at `virtual_path` or a file is in the
+    /// way of a directory.
     #[instrument(level = "debug")]
-    fn add_entry_to_arena(arena: &mut ArenaType, local_path: &Path, id: Inode) {
-        debug!(
-            arena = debug(&arena),
-            id = debug(&id),
-            path = debug(&local_path),
-            "add to arena"
-        );
-        arena.add_file(local_path, id).unwrap();
+    pub fn insert_at(
+        &mut self,
+        virtual_path: &Path,
+        mut entry: OrganizeFSEntry,
+    ) -> Result<(), ArenaError> {
+        entry.pinned = Some(virtual_path.to_path_buf());
+        self.try_add_entry(entry)
+    }
+
+    /// File `entry` as `id` where `pattern` puts it. If files are already filed under its
+    /// name it's numbered, `name (2)`, `name (3)`, ..., so which number a file gets
+    /// depends only on the order files are filed in; a pinned entry isn't numbered, and
+    /// clashes instead. Numbers freed by removals are left until everything is re-filed.
+    /// Returns where it was filed.
+    ///
+    /// `{counter}` is numbered the same way: each entry takes the next number, from 0,
+    /// of the directory the pattern files it under above `{counter}`, and re-filing
+    /// takes a new one. Re-filing everything, e.g. on `set_pattern`, goes in the order
+    /// entries were added, so gives each the number it had when first filed.
+    fn file_entry(
+        arena: &mut ArenaType,
+        numbering: &mut Numbering,
+        entry: &mut OrganizeFSEntry,
+        pattern: &Path,
+        unsorted: &str,
+        id: Inode,
+    ) -> Result<PathBuf, ArenaError> {
+        entry.duplicate = 0;
+        let context = entry
+            .pinned
+            .is_none()
+            .then(|| entry.counter_context(pattern))
+            .flatten();
+        entry.counter = context
+            .as_ref()
+            .and_then(|context| numbering.counters.get(context))
+            .copied()
+            .unwrap_or_default();
+        let name = entry.local_path(pattern, unsorted);
+        loop {
+            let local_path = entry.local_path(pattern, unsorted);
+            match arena.add_file(&local_path, id) {
+                Err(ArenaError::Exists(_))
+                    if entry.pinned.is_none() && arena.find(&local_path).is_file() =>
+                {
+                    entry.duplicate = match entry.duplicate {
+                        0 => numbering.duplicates.get(&name).copied().unwrap_or(2),
+                        duplicate => duplicate + 1,
+                    };
+                }
+                result => {
+                    if result.is_ok() {
+                        if let Some(context) = &context {
+                            numbering
+                                .counters
+                                .insert(context.to_owned(), entry.counter + 1);
+                        }
+                        if entry.duplicate > 0 {
+                            numbering.duplicates.insert(name, entry.duplicate + 1);
+                        }
+                    }
+                    return result.map(|()| local_path);
+                }
+            }
+        }
+    }
+
+    /// File `entry` as `id`, returning where; on a clash the entry stays out of the view.
+    #[instrument(level = "debug", skip(arena, numbering))]
+    fn add_entry_to_arena(
+        arena: &mut ArenaType,
+        numbering: &mut Numbering,
+        entry: &mut OrganizeFSEntry,
+        pattern: &Path,
+        unsorted: &str,
+        id: Inode,
+    ) -> Option<PathBuf> {
+        Self::file_entry(arena, numbering, entry, pattern, unsorted, id)
+            .inspect_err(|e| {
+                warn!(
+                    id = debug(&id),
+                    path = debug(entry.local_path(pattern, unsorted)),
+                    error = display(e),
+                    "not filed"
+                );
+            })
+            .ok()
     }
 
     #[instrument(level = "debug")]
@@ -161,6 +713,187 @@ impl OrganizeFSStore {
         // }
         self.find(path).filter(|e| e.is_directory()).cloned()
     }
+
+    /// Move entry `id` from `old_path` to wherever the pattern now files it.
+    #[instrument(level = "debug")]
+    fn refile(&mut self, id: Inode, old_path: &Path) {
+        self.arena.remove(old_path);
+        let new_path = self.entries.get_mut(&id).and_then(|entry| {
+            Self::add_entry_to_arena(
+                &mut self.arena,
+                &mut self.numbering,
+                entry,
+                self.pattern.as_path(),
+                &self.unsorted_dir,
+                id,
+            )
+        });
+        self.debug_validate_integrity();
+        match new_path {
+            Some(new_path) if new_path == old_path => {}
+            Some(new_path) => self.notify(StoreEvent::Moved {
+                from: old_path.to_path_buf(),
+                to: new_path,
+            }),
+            None => self.notify(StoreEvent::Removed(old_path.to_path_buf())),
+        }
+    }
+
+    /// Keep `entry` as `id`, in place of any entry already there.
+    fn insert_entry(&mut self, id: Inode, entry: OrganizeFSEntry) {
+        self.remove_entry(id);
+        self.by_host
+            .entry(entry.host_path.to_owned())
+            .or_default()
+            .push(id);
+        self.entries.insert(id, entry);
+    }
+
+    /// Forget entry `id`, returning it; it's for the caller to take it out of the tree.
+    fn remove_entry(&mut self, id: Inode) -> Option<OrganizeFSEntry> {
+        let entry = self.entries.remove(&id)?;
+        if let Some(ids) = self.by_host.get_mut(&entry.host_path) {
+            ids.retain(|indexed| *indexed != id);
+            if ids.is_empty() {
+                self.by_host.remove(&entry.host_path);
+            }
+        }
+        Some(entry)
+    }
+
+    /// The host files indexed at `host_path` or, if it's a directory, below it, with
+    /// the entries indexed from each.
+    fn indexed_below<'a>(
+        &'a self,
+        host_path: &'a Path,
+    ) -> impl Iterator<Item = (&'a PathBuf, &'a Vec<Inode>)> + 'a {
+        self.by_host
+            .range::<Path, _>((Bound::Included(host_path), Bound::Unbounded))
+            .take_while(move |(indexed, _)| indexed.starts_with(host_path))
+    }
+
+    /// The entries indexed from the host file `host_path`.
+    fn indexed_at(&self, host_path: &Path) -> &[Inode] {
+        self.by_host.get(host_path).map_or(&[], Vec::as_slice)
+    }
+
+    /// Index `entry` in place of whatever was indexed from its host file, keeping it
+    /// where that was pinned.
+    fn reindex(&mut self, mut entry: OrganizeFSEntry) {
+        let pinned = self
+            .indexed_at(&entry.host_path)
+            .iter()
+            .find_map(|id| self.entries[id].pinned.to_owned());
+        self.remove_host_file(&entry.host_path);
+        if entry.pinned.is_none() {
+            entry.pinned = pinned;
+        }
+        self.add_entry(entry);
+    }
+
+    /// Drop every entry backed by `host_path`, or by a file below it if it's a directory,
+    /// and the names they're filed under.
+    #[instrument(level = "debug", ret)]
+    fn remove_host_file(&mut self, host_path: &Path) -> Vec<Inode> {
+        let ids = self
+            .indexed_below(host_path)
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect::<Vec<_>>();
+        for id in &ids {
+            let local_path =
+                self.entries[id].local_path(self.pattern.as_path(), &self.unsorted_dir);
+            if self.find_file(&local_path) == Some(*id) {
+                self.arena.remove(&local_path);
+                self.notify(StoreEvent::Removed(local_path));
+            }
+            self.remove_entry(*id);
+        }
+        ids
+    }
+
+    /// Drop the entries whose host files are gone, checking each host file once, and
+    /// return how many went. A host file that can't be checked for another reason, e.g.
+    /// permissions, is kept. The host files are checked with `store` unlocked, so it
+    /// keeps serving meanwhile; it's only write-locked to drop what's gone.
+    #[instrument(level = "debug", skip(store, libc_wrapper), ret)]
+    pub(crate) fn prune_missing(
+        store: &parking_lot::RwLock<Self>,
+        libc_wrapper: &dyn LibcWrapper,
+    ) -> usize {
+        let host_paths = store.read().by_host.keys().cloned().collect::<Vec<_>>();
+        let gone = host_paths
+            .into_iter()
+            .filter(|host_path| {
+                matches!(
+                    libc_wrapper.lstat(host_path.to_owned()),
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT)
+                )
+            })
+            .collect::<Vec<_>>();
+        if gone.is_empty() {
+            return 0;
+        }
+        let mut store = store.write();
+        let pruned = gone
+            .iter()
+            .map(|host_path| store.remove_host_file(host_path).len())
+            .sum();
+        store.debug_validate_integrity();
+        pruned
+    }
+
+    /// Record that the file at `path` is now `size` bytes, moving it if its size placed it.
+    #[instrument(level = "debug")]
+    fn resized(&mut self, path: &Path, size: u64) {
+        let Some(id) = self.find_file(path) else {
+            return;
+        };
+        match self.entries.get_mut(&id) {
+            Some(entry) if entry.size != size => entry.size = size,
+            _ => return,
+        }
+        self.refile(id, path);
+    }
+
+    /// Placeholder values that would file an entry under `newparent` rather than `parent`.
+    ///
+    /// `None` when the two differ in a component the pattern doesn't fill from a
+    /// single `{placeholder}`, as there is no value to pin for it.
+    #[instrument(level = "debug", ret)]
+    fn category_overrides(
+        &self,
+        parent: &Path,
+        newparent: &Path,
+    ) -> Option<BTreeMap<String, String>> {
+        let depth = self.pattern.as_path().components().count();
+        if parent.components().count() != depth || newparent.components().count() != depth {
+            return None;
+        }
+        let mut overrides = BTreeMap::new();
+        for ((pattern, old), new) in self
+            .pattern
+            .as_path()
+            .components()
+            .zip(parent.components())
+            .zip(newparent.components())
+        {
+            if old == new {
+                continue;
+            }
+            let pattern = pattern.as_os_str().to_string_lossy();
+            let [Token::Placeholder { key, .. }] = tokens(&pattern)[..] else {
+                return None;
+            };
+            if !is_known_key::<OrganizeFSEntry>(key) {
+                return None;
+            }
+            overrides.insert(
+                key.to_string(),
+                new.as_os_str().to_string_lossy().to_string(),
+            );
+        }
+        Some(overrides)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
@@ -178,694 +911,5846 @@ impl AddAssign<usize> for Inode {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EntryDetail {
+    File {
+        name: String,
+        host_path: PathBuf,
+        size: String,
+        mime: String,
+        modified_date: String,
+    },
+    Directory {
+        children: usize,
+    },
+}
+
+/// The virtual tree below a directory, for UIs that render it as a tree.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TreeNode {
+    File {
+        name: String,
+    },
+    Directory {
+        name: String,
+        /// Left out past the depth limit; fetch the directory itself to expand it.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        children: Option<Vec<TreeNode>>,
+    },
+}
+
+/// How often a directory name occurs across the virtual tree, for `GET /names`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct NameCount {
+    pub name: String,
+    /// Directories with this name.
+    pub count: usize,
+    /// The levels they're at, from 1 for the root's children, ascending.
+    pub depths: Vec<usize>,
+}
+
+/// A change to where files are in the virtual tree, as passed to observers registered
+/// with `OrganizeFSStore::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEvent {
+    Added(PathBuf),
+    Removed(PathBuf),
+    Moved {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Every file was re-filed at once, e.g. for a new pattern or a rescan; anything
+    /// derived from the tree is stale.
+    Rebuilt,
+    /// The host file or directory at this path changed, or went, outside the
+    /// filesystem; anything read from it, or from below it, is stale.
+    HostChanged(PathBuf),
+}
+
+type Observer = Box<dyn Fn(&StoreEvent) + Send + Sync>;
+
+/// What `OrganizeFSStore::save` writes: enough to rebuild the tree without a scan.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// `SNAPSHOT_VERSION` when written; others aren't read, as their entries may differ.
+    version: u32,
+    pattern: String,
+    /// In the order they were added, so duplicates are numbered as they were.
+    entries: Vec<OrganizeFSEntry>,
+}
+
+impl Snapshot {
+    fn read<R: Read>(r: R) -> std::io::Result<Self> {
+        let snapshot: Self = serde_json::from_reader(r).map_err(std::io::Error::from)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("snapshot version {}", snapshot.version),
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// The numbers `OrganizeFSStore::file_entry` hands out next.
+#[derive(Debug, Default)]
+struct Numbering {
+    /// The next `{counter}` for each directory above it.
+    counters: HashMap<PathBuf, usize>,
+    /// The next duplicate number to try for each name taken more than once, so filing
+    /// the Nth file of a name doesn't try every number before.
+    duplicates: HashMap<PathBuf, usize>,
+}
+
 pub struct OrganizeFSStore {
     arena: ArenaType,
     entries: HashMap<Inode, OrganizeFSEntry>,
+    /// The entries indexed from each host file; ordered, so those below a host directory
+    /// are one range. Kept by `insert_entry` and `remove_entry`.
+    by_host: BTreeMap<PathBuf, Vec<Inode>>,
     max_entries: Inode,
-    pattern: PathBuf,
+    pattern: Pattern,
+    parent_dir_policy: ParentDirPolicy,
+    unsorted_dir: String,
+    rules: Rules,
+    collation: Collation,
+    numbering: Numbering,
+    observers: Vec<Observer>,
 }
 impl OrganizeFSStore {
+    /// The pattern in effect, in normalized form: `.` segments, trailing and repeated `/`
+    /// and resolved `..` segments are gone, so it may differ from what was set.
+    /// Setting it again is a no-op.
     pub fn get_pattern(&self) -> String {
-        self.pattern.to_string_lossy().to_string()
+        self.pattern.to_string()
     }
 
-    pub fn set_pattern(&mut self, pattern: &str) {
-        let pattern = PathBuf::from(pattern).normalize();
-        if pattern != self.pattern {
-            // Re-patterning of filesystem
-            let mut arena = ArenaType::default();
-            for (id, entry) in self.entries.iter() {
-                let local_path = entry.local_path(&pattern);
-                Self::add_entry_to_arena(&mut arena, &local_path, *id);
-            }
-            self.arena = arena;
-            self.pattern = pattern;
-        }
+    pub fn validate_integrity(&self) -> Result<(), Vec<IntegrityError>> {
+        self.arena.validate_integrity()
     }
-}
 
-pub struct OrganizeFS {
-    root: PathBuf,
-    store: Arc<parking_lot::RwLock<OrganizeFSStore>>,
-    libc_wrapper: Box<dyn LibcWrapper + Send + Sync>,
-    shutdown_signal: Mutex<Option<tokio::sync::oneshot::Sender<()>>>,
-}
-impl Debug for OrganizeFS {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("OrganizeFS")
-            .field("root", &self.root)
-            .field("store", &self.store)
-            .finish()
+    /// Debug builds verify the arena after bulk mutations, so corruption surfaces early.
+    fn debug_validate_integrity(&self) {
+        debug_assert_eq!(self.validate_integrity(), Ok(()));
+        debug_assert_eq!(
+            self.by_host.values().map(Vec::len).sum::<usize>(),
+            self.entries.len()
+        );
     }
-}
 
-impl OrganizeFS {
-    #[instrument]
-    pub fn new(
-        root: &str,
-        store: Arc<parking_lot::RwLock<OrganizeFSStore>>,
-        shutdown_signal: tokio::sync::oneshot::Sender<()>,
-    ) -> Self {
-        let root = std::env::current_dir().unwrap().as_path().join(root);
-        {
-            let mut store = store.write();
-            info!(root = debug(&root), "init");
-            for entry in Self::scan(&root) {
-                store.add_entry(entry);
-            }
-            info!(store = debug(&store), "store populated");
-        }
+    /// The entry filed at the virtual `path`, so callers can read several of its fields
+    /// from one lookup. `None` for directories and missing paths.
+    pub fn entry(&self, path: &Path) -> Option<&OrganizeFSEntry> {
+        self.find_file(path).and_then(|id| self.entries.get(&id))
+    }
 
-        Self {
-            root,
-            store,
-            shutdown_signal: Mutex::new(Some(shutdown_signal)),
-            libc_wrapper: Box::new(LibcWrapperReal::new()),
-        }
+    /// Write the pattern and every entry, as JSON, for `load` to restore on a later start.
+    pub fn save<W: Write>(&self, w: W) -> std::io::Result<()> {
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(id, _)| id.value);
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            pattern: self.get_pattern(),
+            entries: entries
+                .into_iter()
+                .map(|(_, entry)| entry.clone())
+                .collect(),
+        };
+        serde_json::to_writer(w, &snapshot).map_err(std::io::Error::from)
     }
 
-    #[instrument]
-    fn scan(root: &Path) -> impl Iterator<Item = OrganizeFSEntry> + '_ {
-        info!(root = debug(root), "scanning");
-        WalkDir::new(root)
-            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
-            .into_iter()
-            .flatten()
-            .filter_map(|entry| Self::process(root, &entry))
+    /// A store holding what `save` wrote, filed under the pattern saved with it.
+    pub fn load<R: Read>(r: R) -> std::io::Result<Self> {
+        let snapshot = Snapshot::read(r)?;
+        let mut store = Self::new(PathBuf::from(snapshot.pattern));
+        store.replace_entries(snapshot.entries);
+        Ok(store)
     }
 
-    #[instrument(level = "debug")]
-    fn process(root: &Path, entry: &walkdir::DirEntry) -> Option<OrganizeFSEntry> {
-        if entry.file_type().is_file() && entry.path().parent().is_some() {
-            if let Ok(meta) = fs::symlink_metadata(entry.path()) {
-                debug!(root = debug(root), entry = debug(entry), "found");
-                let entry = OrganizeFSEntry::new(root, entry, &meta);
-                debug!(root = debug(root), entry = display(&entry));
-                return Some(entry);
-            }
-        }
-        None
+    /// Replace every entry with those `save` wrote, as `replace_entries` does, keeping
+    /// this store's pattern and settings: entries saved under another pattern are
+    /// re-filed under this one. Returns how many files are in the tree afterwards.
+    pub fn restore<R: Read>(&mut self, r: R) -> std::io::Result<usize> {
+        let snapshot = Snapshot::read(r)?;
+        self.replace_entries(snapshot.entries);
+        Ok(self.iter_leaves().count())
     }
 
-    fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
-        Statfs {
-            blocks: statfs.f_blocks,
-            bfree: statfs.f_bfree,
-            bavail: statfs.f_bavail,
-            files: statfs.f_files,
-            ffree: statfs.f_ffree,
-            bsize: statfs.f_bsize as u32,
-            namelen: statfs.f_namelen as u32,
-            frsize: statfs.f_frsize as u32,
-        }
+    /// Call `observer` with every change to where files are, as it's made. It runs with
+    /// the store locked, so it should be quick, and must not lock the store itself.
+    pub fn subscribe(&mut self, observer: impl Fn(&StoreEvent) + Send + Sync + 'static) {
+        self.observers.push(Box::new(observer));
     }
 
-    fn mode_to_filetype(mode: libc::mode_t) -> FileType {
-        match mode & libc::S_IFMT {
-            libc::S_IFDIR => FileType::Directory,
-            libc::S_IFREG => FileType::RegularFile,
-            libc::S_IFLNK => FileType::Symlink,
-            libc::S_IFBLK => FileType::BlockDevice,
-            libc::S_IFCHR => FileType::CharDevice,
-            libc::S_IFIFO => FileType::NamedPipe,
-            libc::S_IFSOCK => FileType::Socket,
-            _ => {
-                panic!("unknown file type");
-            }
+    fn notify(&self, event: StoreEvent) {
+        for observer in &self.observers {
+            observer(&event);
         }
     }
 
-    fn stat_to_fuse(stat: libc::stat) -> FileAttr {
-        // st_mode encodes both the kind and the permissions
-        let kind = Self::mode_to_filetype(stat.st_mode);
-        let perm = (stat.st_mode & 0o7777) as u16;
+    /// Host path backing the file at the virtual `path`.
+    pub fn host_path(&self, path: &Path) -> Option<PathBuf> {
+        self.entry(path).map(|entry| entry.host_path.to_owned())
+    }
 
-        FileAttr {
-            size: stat.st_size as u64,
-            blocks: stat.st_blocks as u64,
-            atime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_atime.try_into().unwrap())
-                + Duration::from_nanos(stat.st_atime_nsec.try_into().unwrap()),
-            mtime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_mtime.try_into().unwrap())
-                + Duration::from_nanos(stat.st_mtime_nsec.try_into().unwrap()),
-            ctime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_ctime.try_into().unwrap())
-                + Duration::from_nanos(stat.st_ctime_nsec.try_into().unwrap()),
-            crtime: SystemTime::UNIX_EPOCH,
-            kind,
-            perm,
-            nlink: stat.st_nlink as u32,
-            uid: stat.st_uid,
-            gid: stat.st_gid,
-            rdev: stat.st_rdev as u32,
-            flags: 0,
-        }
+    /// Names and kinds of the children of the directory at the virtual `path`, by name.
+    pub fn list_dir(&self, path: &Path) -> Option<Vec<(OsString, FileType)>> {
+        let dir = self.find_dir(path)?;
+        let children = dir
+            .children_sorted(&self.arena)
+            .into_iter()
+            .filter_map(|(name, entry)| {
+                if entry.is_directory() {
+                    Some((name.to_owned(), FileType::Directory))
+                } else if entry.is_file() {
+                    Some((name.to_owned(), FileType::RegularFile))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        Some(children)
     }
-}
 
-impl FilesystemMT for OrganizeFS {
-    fn init(&self, req: RequestInfo) -> ResultEmpty {
-        info!(req = debug(req), "init");
-        Ok(())
+    /// Add an empty directory at the virtual `path`, e.g. a category for files yet to
+    /// come. It lasts until the tree is rebuilt for a new pattern.
+    pub fn add_dir(&mut self, path: &Path) -> Result<(), ArenaError> {
+        self.arena.add_dir(path)
     }
 
-    fn destroy(&self) {
-        info!("destroy");
-        let mut mutex = self.shutdown_signal.lock().unwrap();
-        if let Some(signal) = mutex.take() {
-            signal.send(()).unwrap();
-        }
+    /// The host directory that the virtual directory `path` mirrors, under `base`, if
+    /// the pattern down to `path` is `/{dir1}/{dir2}/...`. Other directories exist only
+    /// in the view.
+    pub fn mirrored_host_dir(&self, path: &Path, base: &Path) -> Option<PathBuf> {
+        let names = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        (!names.is_empty() && names.len() <= self.mirrored_depth()).then(|| {
+            names
+                .iter()
+                .fold(base.to_path_buf(), |dir, name| dir.join(name))
+        })
     }
 
-    fn getattr(&self, req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
-        debug!(req = debug(req), path = debug(path), fh, "getattr");
-        if let Some(fh) = fh {
-            match self.libc_wrapper.fstat(fh) {
-                Ok(stat) => Ok((TTL, Self::stat_to_fuse(stat))),
-                Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
-            }
+    /// How many leading levels of the pattern are `/{dir1}/{dir2}/...`.
+    pub fn mirrored_depth(&self) -> usize {
+        self.pattern
+            .components()
+            .into_iter()
+            .zip(1..)
+            .take_while(|(component, n)| *component == format!("{{dir{n}}}"))
+            .count()
+    }
+
+    /// Number of children of the directory at the virtual `path`.
+    pub fn child_count(&self, path: &Path) -> Option<usize> {
+        self.arena.child_count(path)
+    }
+
+    /// The `n`th child, by name, of the directory at the virtual `path`, for paging
+    /// through a listing without materialising it.
+    pub fn nth_child(&self, path: &Path, n: usize) -> Option<(OsString, FileType)> {
+        let (name, entry) = self.arena.nth_child(path, n)?;
+        let kind = if entry.is_directory() {
+            FileType::Directory
         } else {
-            let store = self.store.read();
-            let r = store.find(path);
-            debug!(found = debug(&r), "found");
-            if r.is_directory() {
-                match self.libc_wrapper.lstat(self.root.to_owned()) {
-                    Ok(stat) => Ok((TTL, Self::stat_to_fuse(stat))),
-                    Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
-                }
-            } else if r.is_file() {
-                let entry = store.entries.get(&r.inner().unwrap()).unwrap();
-                match self.libc_wrapper.lstat(entry.host_path.to_owned()) {
-                    Ok(stat) => Ok((TTL, Self::stat_to_fuse(stat))),
-                    Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
+            FileType::RegularFile
+        };
+        Some((name, kind))
+    }
+
+    /// Number of entries, including any a clash keeps out of the tree.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of nodes in the tree: the root, directories and files.
+    pub fn node_count(&self) -> usize {
+        self.arena.len()
+    }
+
+    /// Every file's virtual path and inode, each directory in the order it's listed in.
+    fn leaves(&self) -> Vec<(PathBuf, Inode)> {
+        fn walk(
+            arena: &ArenaType,
+            path: &Path,
+            node: &ArenaEntry,
+            out: &mut Vec<(PathBuf, Inode)>,
+        ) {
+            for (name, child) in node.children_sorted(arena) {
+                let child_path = path.join(name);
+                match child.inner() {
+                    Some(id) => out.push((child_path, id)),
+                    None => walk(arena, &child_path, child, out),
                 }
-            } else {
-                Err(libc::ENOENT)
             }
         }
+        let mut leaves = Vec::with_capacity(self.entries.len());
+        walk(
+            &self.arena,
+            Path::new("/"),
+            &self.find(Path::new("/")),
+            &mut leaves,
+        );
+        leaves
     }
 
-    fn statfs(&self, req: RequestInfo, path: &Path) -> ResultStatfs {
-        debug!(req = debug(req), path = debug(path), "statfs");
-        match self.libc_wrapper.statfs(self.root.to_owned()) {
-            Ok(stat) => Ok(Self::statfs_to_fuse(stat)),
-            Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
+    /// Where the host file `host` appears in the tree under the current pattern, sorted.
+    /// Usually one path; none if it was never indexed or is kept out by a clash.
+    pub fn paths_for_host(&self, host: &Path) -> Vec<PathBuf> {
+        let mut paths = self
+            .indexed_at(host)
+            .iter()
+            .map(|id| {
+                (
+                    id,
+                    self.entries[id].local_path(self.pattern.as_path(), &self.unsorted_dir),
+                )
+            })
+            .filter(|(id, local_path)| self.find_file(local_path) == Some(**id))
+            .map(|(_, local_path)| local_path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    /// Every entry filed in the tree, in no particular order. Entries kept out of the
+    /// view by a clash aren't included.
+    pub fn iter_leaves(&self) -> impl Iterator<Item = &OrganizeFSEntry> {
+        self.arena.leaves().filter_map(|id| self.entries.get(id))
+    }
+
+    /// Every file's virtual path, in listing order, so callers needn't care how entries
+    /// are stored.
+    ///
+    /// ```
+    /// # use std::{fs, path::PathBuf, sync::Arc};
+    /// # use organizefs::{OrganizeFS, OrganizeFSStore};
+    /// let root = std::env::temp_dir().join(format!("organizefs-doc-{}", std::process::id()));
+    /// fs::create_dir_all(&root).unwrap();
+    /// fs::write(root.join("b.txt"), "b").unwrap();
+    /// fs::write(root.join("a.txt"), "a").unwrap();
+    ///
+    /// let store = Arc::new(parking_lot::RwLock::new(OrganizeFSStore::new(PathBuf::from("/"))));
+    /// let (tx, _rx) = tokio::sync::oneshot::channel();
+    /// let _fs = OrganizeFS::new(root.to_str().unwrap(), store.clone(), tx);
+    /// assert_eq!(
+    ///     store.read().leaf_paths(),
+    ///     vec![PathBuf::from("/a.txt"), PathBuf::from("/b.txt")]
+    /// );
+    /// # fs::remove_dir_all(&root).unwrap();
+    /// ```
+    pub fn leaf_paths(&self) -> Vec<PathBuf> {
+        self.leaves().into_iter().map(|(path, _)| path).collect()
+    }
+
+    /// Write a `virtual_path<TAB>host_path<TAB>size` line per file, in listing order (as
+    /// `readdir` lists each directory), so the same tree always produces the same manifest. Paths are escaped as by
+    /// `tsv_field`, so names with tabs or newlines can't split a line.
+    pub fn export_to_writer<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        for (path, id) in self.leaves() {
+            let Some(entry) = self.entries.get(&id) else {
+                continue;
+            };
+            writeln!(
+                w,
+                "{}\t{}\t{}",
+                tsv_field(&path),
+                tsv_field(&entry.host_path),
+                format_size(&entry.size)
+            )?;
         }
+        Ok(())
     }
 
-    fn opendir(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
-        debug!(
-            req = debug(req),
-            path = debug(path),
-            flags,
-            "opendir (flags = {:#o})",
-            flags
-        );
-        if self.store.read().find_dir(path).is_some() {
-            Ok((0, 0))
+    pub fn entry_detail(&self, path: &Path) -> Option<EntryDetail> {
+        let found = self.find(path);
+        if found.is_directory() {
+            Some(EntryDetail::Directory {
+                children: found.children(&self.arena).count(),
+            })
+        } else if found.is_file() {
+            let entry = self.entries.get(&found.inner()?)?;
+            Some(EntryDetail::File {
+                name: entry.name.to_string_lossy().to_string(),
+                host_path: entry.host_path.to_owned(),
+                size: format_size(&entry.size),
+                mime: entry.mime.to_owned(),
+                modified_date: entry.modified_date.to_owned(),
+            })
         } else {
-            Err(libc::ENOENT)
+            None
         }
     }
 
-    #[instrument(level = "info")]
-    fn readdir(&self, req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
-        debug!(req = debug(req), path = debug(path), fh, "readdir");
+    /// The directory at the virtual `path` and what's below it, `depth` levels deep;
+    /// `depth` 0 gives the directory alone. `None` if `path` isn't a directory.
+    pub fn tree(&self, path: &Path, depth: usize) -> Option<TreeNode> {
+        let name = path.file_name().map_or_else(
+            || "/".to_string(),
+            |name| name.to_string_lossy().to_string(),
+        );
+        let children = self.list_dir(path)?;
+        let children = (depth > 0).then(|| {
+            children
+                .into_iter()
+                .filter_map(|(child, kind)| match kind {
+                    FileType::Directory => self.tree(&path.join(child), depth - 1),
+                    _ => Some(TreeNode::File {
+                        name: child.to_string_lossy().to_string(),
+                    }),
+                })
+                .collect()
+        });
+        Some(TreeNode::Directory { name, children })
+    }
 
-        let store = self.store.read();
-        let children = store
-            .find_dir(path)
-            .unwrap()
-            .children(&store.arena)
-            //.unique()
-            .filter_map(|(name, entry)| {
-                //let entry = store.entries.get(id).unwrap();
-                info!(
-                    path = debug(&path),
-                    name = debug(&name),
-                    entry = debug(&entry),
-                    "child"
-                );
-                if entry.is_directory() {
-                    Some((FileType::Directory, name))
-                } else if entry.is_file() {
-                    Some((FileType::RegularFile, name))
-                } else {
-                    None
+    /// Every directory name in the tree with how many directories have it and at which
+    /// levels, most frequent first, ties by name.
+    pub fn name_counts(&self) -> Vec<NameCount> {
+        fn walk(
+            arena: &ArenaType,
+            node: &ArenaEntry,
+            depth: usize,
+            counts: &mut BTreeMap<String, (usize, BTreeSet<usize>)>,
+        ) {
+            for (name, child) in node.children(arena) {
+                if child.is_directory() {
+                    let (count, depths) = counts
+                        .entry(name.to_string_lossy().to_string())
+                        .or_default();
+                    *count += 1;
+                    depths.insert(depth);
+                    walk(arena, child, depth + 1, counts);
                 }
+            }
+        }
+        let mut counts = BTreeMap::new();
+        walk(&self.arena, &self.find(Path::new("/")), 1, &mut counts);
+        let mut counts = counts
+            .into_iter()
+            .map(|(name, (count, depths))| NameCount {
+                name,
+                count,
+                depths: depths.into_iter().collect(),
             })
-            .fold(
-                vec![
-                    DirectoryEntry {
-                        name: ".".into(),
-                        kind: FileType::Directory,
-                    },
-                    DirectoryEntry {
-                        name: "..".into(),
-                        kind: FileType::Directory,
-                    },
-                ],
-                |mut acc, (kind, name)| {
-                    acc.push(DirectoryEntry {
-                        name: name.clone(),
-                        kind,
-                    });
-                    acc
-                },
-            );
+            .collect::<Vec<_>>();
+        // Stable, so names with the same count stay in name order.
+        counts.sort_by_key(|name| std::cmp::Reverse(name.count));
+        counts
+    }
 
-        debug!(
-            req = debug(req),
-            path = debug(path),
-            children = debug(&children),
-            fh,
-            "readdir"
-        );
-        Ok(children)
+    pub fn validate_pattern(&self, pattern: &str) -> Result<(), PatternError> {
+        validate_pattern::<OrganizeFSEntry>(pattern, self.parent_dir_policy)
     }
 
-    fn releasedir(&self, req: RequestInfo, path: &Path, fh: u64, flags: u32) -> ResultEmpty {
-        debug!(
-            req = debug(req),
-            path = debug(path),
-            fh,
-            "releasedir (flags = {:#o})",
-            flags
-        );
-        Ok(())
+    /// Suggest a pattern from a sample of the entries already scanned.
+    pub fn suggest_pattern(&self) -> Result<String, PatternError> {
+        let mut ids = self.entries.keys().collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.value);
+        let sample = ids
+            .into_iter()
+            .take(SUGGEST_SAMPLE)
+            .map(|id| self.entries[id].to_owned())
+            .collect::<Vec<_>>();
+        let pattern = suggest_pattern(&sample);
+        self.validate_pattern(&pattern)?;
+        Ok(pattern)
     }
 
-    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
-        debug!(
-            req = debug(req),
-            path = debug(path),
-            "open (flags = {:#o})",
-            flags
-        );
-        let store = self.store.read();
-        store.find_file(path).map_or_else(
-            || Err(libc::ENOENT),
-            |e| {
-                let entry = store.entries.get(&e).unwrap();
-                match self
-                    .libc_wrapper
-                    .open(entry.host_path.to_owned(), flags.try_into().unwrap())
-                {
-                    Ok(fh) => Ok((fh as u64, flags)),
-                    Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
-                }
-            },
-        )
+    /// Re-file every entry under `pattern`, stored normalized (see `get_pattern`).
+    pub fn set_pattern(&mut self, pattern: &str) {
+        let pattern = Pattern::new(pattern);
+        if pattern != self.pattern {
+            // Re-patterning of filesystem
+            self.pattern = pattern;
+            self.rebuild_arena();
+        }
     }
 
-    fn read(
-        &self,
-        req: RequestInfo,
-        path: &Path,
-        fh: u64,
-        offset: u64,
-        size: u32,
-        callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
-    ) -> CallbackResult {
-        debug!(
-            req = debug(req),
-            path = debug(path),
-            fh,
-            offset,
-            size,
-            "read"
-        );
-        if fh > 0 {
-            match self
-                .libc_wrapper
-                .read(fh.try_into().unwrap(), offset.try_into().unwrap(), size)
-            {
-                Ok(content) => callback(Ok(content.as_slice())),
-                Err(e) => callback(Err(e.raw_os_error().unwrap_or(libc::ENOENT))),
-            }
+    /// `set_pattern`, handing back the store as it was so callers can compare the two
+    /// layouts, e.g. by their `leaf_paths`. The old store keeps its own copy of the
+    /// entries; the old tree is moved out rather than copied.
+    pub fn swap_pattern(&mut self, pattern: &str) -> OrganizeFSStore {
+        let mut old = Self {
+            arena: ArenaType::default(),
+            entries: self.entries.clone(),
+            by_host: self.by_host.clone(),
+            max_entries: self.max_entries,
+            pattern: self.pattern.clone(),
+            parent_dir_policy: self.parent_dir_policy,
+            unsorted_dir: self.unsorted_dir.clone(),
+            rules: self.rules.clone(),
+            collation: self.collation,
+            numbering: Numbering::default(),
+            observers: Vec::new(),
+        };
+        let pattern = Pattern::new(pattern);
+        if pattern == self.pattern {
+            old.rebuild_arena();
         } else {
-            callback(Err(libc::ENOENT))
+            old.arena = std::mem::take(&mut self.arena);
+            self.pattern = pattern;
+            self.rebuild_arena();
         }
+        old
     }
 
-    fn flush(&self, req: RequestInfo, path: &Path, fh: u64, lock_owner: u64) -> ResultEmpty {
-        debug!(
-            req = debug(req),
-            path = debug(path),
-            fh,
-            lock_owner,
-            "flush"
-        );
-        Err(libc::ENOSYS)
+    /// Replace every entry with `f(entry)` and re-file them under the current pattern,
+    /// without rescanning the host.
+    #[instrument(level = "debug", skip(f))]
+    pub fn rebuild_with<F>(&mut self, f: F)
+    where
+        F: Fn(&OrganizeFSEntry) -> OrganizeFSEntry,
+    {
+        let ids = self.entries.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            let entry = f(&self.entries[&id]);
+            self.insert_entry(id, entry);
+        }
+        self.rebuild_arena();
     }
 
-    fn release(
-        &self,
-        req: RequestInfo,
-        path: &Path,
-        fh: u64,
-        flags: u32,
-        lock_owner: u64,
-        flush: bool,
-    ) -> ResultEmpty {
-        debug!(
-            req = debug(req),
-            path = debug(path),
-            fh,
-            lock_owner,
-            flush,
-            "release (flags = {:#o})",
-            flags
-        );
-        if fh > 0 {
-            match self.libc_wrapper.close(fh.try_into().unwrap()) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
-            }
-        } else {
-            Err(libc::ENOENT)
-        }
+    /// Re-evaluate `rules` across every entry and re-file them, without rescanning the
+    /// host. Rules only match on scanned and pinned values, which entries keep, so none
+    /// need the disk; files that change on the host pick up the rules on the next scan.
+    /// Later additions are tagged with `rules` too.
+    #[instrument(level = "debug", skip(self))]
+    pub fn apply_rules(&mut self, rules: &Rules) {
+        self.rules = rules.clone();
+        self.rebuild_with(|entry| OrganizeFSEntry {
+            tags: Self::tags(rules, entry),
+            ..entry.clone()
+        });
     }
 
-    fn unlink(&self, req: RequestInfo, parent: &Path, name: &std::ffi::OsStr) -> ResultEmpty {
-        info!(
-            req = debug(req),
-            parent = debug(parent),
-            name = debug(name),
-            "unlink",
-        );
-        let mut path = parent.to_path_buf();
-        path.push(name);
+    fn tags(rules: &Rules, entry: &OrganizeFSEntry) -> BTreeMap<String, String> {
+        rules.tags(|key| match entry.overrides.get(key) {
+            Some(value) => Cow::Borrowed(value.as_str()),
+            None => entry.value(key),
+        })
+    }
 
-        let mut store = self.store.write();
-        store.find_file(&path).map_or_else(
-            || Err(libc::ENOENT),
-            |e| {
-                let entry = store.entries.get(&e).unwrap().to_owned();
-                info!(inode = debug(e), entry = debug(&entry), "get");
-                match self.libc_wrapper.unlink(entry.host_path) {
-                    Ok(_) => {
-                        info!("unlinked");
-                        if store.arena.remove(&path) {
-                            let dropped = store.entries.remove(&e);
-                            info!(dropped = debug(dropped), "dropped");
-                        }
-                        Ok(())
-                    }
-                    Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
-                }
-            },
-        )
+    /// Replace every entry with `entries`, e.g. from a fresh scan, keeping the pattern
+    /// and settings, and where files were pinned. Directories added with `add_dir` go
+    /// too. Host files that changed size or modification time, or went, since they were
+    /// indexed are reported with `HostChanged`.
+    fn replace_entries(&mut self, mut entries: Vec<OrganizeFSEntry>) {
+        let pins = self
+            .entries
+            .values()
+            .filter_map(|entry| Some((entry.host_path.to_owned(), entry.pinned.to_owned()?)))
+            .collect::<HashMap<_, _>>();
+        for entry in &mut entries {
+            if entry.pinned.is_none() {
+                entry.pinned = pins.get(&entry.host_path).cloned();
+            }
+        }
+        let changed = {
+            let scanned = entries
+                .iter()
+                .map(|entry| (&entry.host_path, (entry.modified, entry.size)))
+                .collect::<HashMap<_, _>>();
+            self.entries
+                .values()
+                .filter(|entry| {
+                    scanned.get(&entry.host_path) != Some(&(entry.modified, entry.size))
+                })
+                .map(|entry| entry.host_path.to_owned())
+                .collect::<Vec<_>>()
+        };
+        self.entries.clear();
+        self.by_host.clear();
+        self.numbering = Numbering::default();
+        self.arena = ArenaType::default().with_collation(self.collation);
+        self.reserve(entries.len());
+        // One `Rebuilt` rather than an `Added` per entry.
+        let observers = std::mem::take(&mut self.observers);
+        for entry in entries {
+            self.add_entry(entry);
+        }
+        self.observers = observers;
+        self.debug_validate_integrity();
+        self.notify(StoreEvent::Rebuilt);
+        for host_path in changed {
+            self.notify(StoreEvent::HostChanged(host_path));
+        }
     }
 
-    fn rename(
-        &self,
-        req: RequestInfo,
-        parent: &Path,
-        name: &std::ffi::OsStr,
-        newparent: &Path,
-        newname: &std::ffi::OsStr,
-    ) -> ResultEmpty {
-        info!(
-            req = debug(req),
-            parent = debug(parent),
-            name = debug(name),
-            newparent = debug(newparent),
-            newname = debug(newname),
-            "rename",
-        );
-        Err(libc::ENOSYS)
+    fn rebuild_arena(&mut self) {
+        let mut arena = ArenaType::default().with_collation(self.collation);
+        let mut numbering = Numbering::default();
+        // In the order they were added, so duplicates and `{counter}` are numbered the
+        // same every time.
+        let mut entries = self.entries.iter_mut().collect::<Vec<_>>();
+        entries.sort_by_key(|(id, _)| id.value);
+        for (id, entry) in entries {
+            Self::add_entry_to_arena(
+                &mut arena,
+                &mut numbering,
+                entry,
+                self.pattern.as_path(),
+                &self.unsorted_dir,
+                *id,
+            );
+        }
+        self.arena = arena;
+        self.numbering = numbering;
+        self.debug_validate_integrity();
+        self.notify(StoreEvent::Rebuilt);
     }
 }
 
 #[cfg(test)]
-mod tests {
-    use std::{io, path::PathBuf};
-
-    use tracing_test::traced_test;
+impl OrganizeFSStore {
+    pub(crate) fn add_test_entry(&mut self, name: &str, host_path: &str, mime: &str) {
+        let (mime_type, mime_subtype) = split_mime(&mime.replacen('_', "/", 1));
+        self.add_entry(OrganizeFSEntry {
+            name: name.into(),
+            host_path: host_path.into(),
+            size: 0,
+            mime: mime.into(),
+            mime_type,
+            mime_subtype,
+            modified_date: "2023-08-04".into(),
+            year: "2023".into(),
+            month: "08".into(),
+            day: "04".into(),
+            ..Default::default()
+        });
+    }
+}
 
-    use libc_wrapper::MockLibcWrapper;
+/// The configuration in effect, whichever of defaults, flags or environment it came from.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct OrganizeFsConfig {
+    pub root: PathBuf,
+    pub pattern: String,
+    pub parent_dir_policy: ParentDirPolicy,
+    pub unsorted_dir: String,
+    pub case_fold: bool,
+    pub utc_offset: String,
+    pub strip_prefix: Option<PathBuf>,
+    pub sniff_bytes: usize,
+    pub one_filesystem: bool,
+    pub follow_links: bool,
+    pub lazy: bool,
+    pub watch: bool,
+    pub path_hash_len: usize,
+    pub excluded_dirs: Vec<String>,
+    pub statfs_ttl_ms: u128,
+    pub slow_op_ms: u128,
+    pub read_cache_bytes: usize,
+    pub readahead_bytes: usize,
+    pub max_open_fds: usize,
+    pub symlink_view: bool,
+    /// Octal, as it would be passed to `ORGANIZEFS_UMASK`.
+    pub umask: String,
+}
 
-    use crate::common::mock_traits::{MockDirEntry, MockMetadata};
-    use crate::libc_wrapper;
+impl OrganizeFsConfig {
+    /// Pick up the settings that can change at runtime, e.g. through `POST /pattern`.
+    pub fn refresh(&mut self, store: &OrganizeFSStore) {
+        self.pattern = store.get_pattern();
+        self.parent_dir_policy = store.parent_dir_policy;
+        self.unsorted_dir = store.unsorted_dir.clone();
+        self.case_fold = store.collation == Collation::CaseFold;
+    }
+}
 
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
-    use super::*;
+/// A file opened through FUSE. Its host descriptor may be closed while it's idle, to
+/// keep under the cap, and is reopened when it's next needed.
+#[derive(Debug)]
+struct HostHandle {
+    host_path: PathBuf,
+    /// The file and flags it's shared under, if it is.
+    shared: Option<(PathBuf, i32)>,
+    /// What it's reopened with: the open flags, less those with an effect of their own.
+    reopen_flags: i32,
+    fd: Option<i32>,
+    holders: usize,
+    /// Operations using `fd` right now, which keep it from being closed under them.
+    in_use: usize,
+    last_used: u64,
+    /// The host file was unlinked, so `fd` couldn't be reopened and is never recycled.
+    unlinked: bool,
+}
 
-    #[instrument(ret, skip(libc_wrapper))]
-    fn new_test_fs(libc_wrapper: impl LibcWrapper + Send + Sync + 'static) -> OrganizeFS {
-        let root = PathBuf::from("/");
-        let pattern = PathBuf::from("/");
-        let store = Arc::new(parking_lot::RwLock::new(OrganizeFSStore::new(pattern)));
-        let libc_wrapper = Box::new(libc_wrapper);
-        OrganizeFS {
+/// Files opened through FUSE, by handle. Every `open` of a file with the same flags
+/// shares a handle, which is released with its last holder.
+#[derive(Debug, Default)]
+struct HostHandles {
+    by_file: HashMap<(PathBuf, i32), u64>,
+    handles: HashMap<u64, HostHandle>,
+    next_handle: u64,
+    /// Handles with a host descriptor open.
+    open_fds: usize,
+    /// Ticks on every use of a handle, ordering them by when they were last used.
+    clock: u64,
+}
+
+impl HostHandles {
+    /// Follow the host file at `from`, renamed to `to`, so its handles reopen it there.
+    fn renamed(&mut self, from: &Path, to: &Path) {
+        for (fh, handle) in self
+            .handles
+            .iter_mut()
+            .filter(|(_, handle)| handle.host_path == from)
+        {
+            handle.host_path = to.to_path_buf();
+            if let Some((path, flags)) = &mut handle.shared {
+                self.by_file.remove(&(path.to_owned(), *flags));
+                *path = to.to_path_buf();
+                self.by_file.insert((path.to_owned(), *flags), *fh);
+            }
+        }
+    }
+
+    /// Handles open on `host_path`, or on files below it.
+    fn open_on(&self, host_path: &Path) -> Vec<u64> {
+        self.handles
+            .iter()
+            .filter(|(_, handle)| handle.host_path.starts_with(host_path))
+            .map(|(fh, _)| *fh)
+            .collect()
+    }
+
+    /// Keep the handles of `host_path`, about to be unlinked, from being recycled, and
+    /// from being shared with opens of whatever is created there next. Returns those
+    /// already recycled, which need reopening while they still can be.
+    fn unlinking(&mut self, host_path: &Path) -> Vec<u64> {
+        let mut recycled = Vec::new();
+        for (fh, handle) in self
+            .handles
+            .iter_mut()
+            .filter(|(_, handle)| handle.host_path == host_path)
+        {
+            handle.unlinked = true;
+            if let Some(key) = handle.shared.take() {
+                self.by_file.remove(&key);
+            }
+            if handle.fd.is_none() {
+                recycled.push(*fh);
+            }
+        }
+        recycled
+    }
+}
+
+/// What `OrganizeFS::watch` starts: host changes are queued by `watcher` and applied by
+/// `thread`, until `stop`.
+struct HostWatcher {
+    watcher: RecommendedWatcher,
+    queue: Arc<EventQueue>,
+    thread: JoinHandle<()>,
+}
+
+impl HostWatcher {
+    /// Stop queueing changes, and wait for those already queued to be applied.
+    fn stop(self) {
+        drop(self.watcher);
+        self.queue.close();
+        if self.thread.join().is_err() {
+            warn!("watch thread panicked");
+        }
+    }
+}
+
+pub struct OrganizeFS {
+    root: PathBuf,
+    options: ScanOptions,
+    store: Arc<parking_lot::RwLock<OrganizeFSStore>>,
+    libc_wrapper: Box<dyn LibcWrapper + Send + Sync>,
+    shutdown_signal: Mutex<Option<tokio::sync::oneshot::Sender<()>>>,
+    statfs_ttl: Duration,
+    statfs_cache: Mutex<Option<(Instant, Statfs)>>,
+    /// The root's `lstat`, which every directory's attributes come from, reused for
+    /// `TTL`; see `dir_attr`.
+    root_stat_cache: Mutex<Option<(Instant, libc::stat)>>,
+    slow_op_threshold: Duration,
+    /// These are shared with the store's observers; see `forget_changed_host_data`.
+    read_cache: Arc<Mutex<ReadCache>>,
+    readahead: Arc<Mutex<Readahead>>,
+    read_metrics: Arc<ReadMetrics>,
+    umask: u32,
+    /// Files are presented as symlinks to their host paths, not proxied.
+    symlink_view: bool,
+    /// Handles given out by `opendir` and not yet released.
+    dir_handles: Mutex<HashSet<u64>>,
+    next_dir_handle: AtomicU64,
+    /// Host directories indexed so far in lazy mode; `true` once everything below is too.
+    scanned_dirs: Mutex<HashMap<PathBuf, bool>>,
+    host_handles: Arc<Mutex<HostHandles>>,
+    /// At most this many host descriptors are open at once; see `make_room`.
+    max_open_fds: usize,
+    /// Handles written through since they were opened, whose size is re-read on release.
+    written: Mutex<HashSet<u64>>,
+    /// Watches the host tree until `destroy`; see `ScanOptions::watch`.
+    host_watcher: Mutex<Option<HostWatcher>>,
+}
+impl Debug for OrganizeFS {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OrganizeFS")
+            .field("root", &self.root)
+            .field("store", &self.store)
+            .field("watching", &self.host_watcher.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
+impl OrganizeFS {
+    #[instrument]
+    pub fn new(
+        root: &str,
+        store: Arc<parking_lot::RwLock<OrganizeFSStore>>,
+        shutdown_signal: tokio::sync::oneshot::Sender<()>,
+    ) -> Self {
+        Self::with_options(root, store, shutdown_signal, ScanOptions::default())
+    }
+
+    #[instrument]
+    pub fn with_options(
+        root: &str,
+        store: Arc<parking_lot::RwLock<OrganizeFSStore>>,
+        shutdown_signal: tokio::sync::oneshot::Sender<()>,
+        options: ScanOptions,
+    ) -> Self {
+        let root = std::env::current_dir().unwrap().as_path().join(root);
+        if options.lazy {
+            info!(root = debug(&root), "lazy, scan deferred");
+        } else {
+            let mut store = store.write();
+            info!(root = debug(&root), "init");
+            // A store restored with `OrganizeFSStore::load` already knows the types of
+            // files unchanged since, for later rescans to reuse.
+            for entry in store.entries.values() {
+                options.mime_cache.insert(
+                    &entry.host_path,
+                    entry.modified,
+                    entry.size,
+                    entry.mime.replacen('_', "/", 1),
+                );
+            }
+            if !store.is_empty() {
+                Self::refresh(&root, &options, &mut store);
+            } else {
+                let entries = Self::scan(&root, &options).collect::<Vec<_>>();
+                store.reserve(entries.len());
+                for entry in entries {
+                    store.add_entry(entry);
+                }
+            }
+            info!(store = debug(&store), "store populated");
+            store.debug_validate_integrity();
+        }
+        let host_watcher = Self::watch(&root, &store, &options);
+
+        let fs = Self {
             root,
+            options,
             store,
-            libc_wrapper,
-            shutdown_signal: Mutex::new(None),
+            shutdown_signal: Mutex::new(Some(shutdown_signal)),
+            libc_wrapper: Box::new(LibcWrapperReal::new()),
+            statfs_ttl: STATFS_TTL,
+            statfs_cache: Mutex::new(None),
+            root_stat_cache: Mutex::new(None),
+            slow_op_threshold: SLOW_OP_THRESHOLD,
+            read_cache: Arc::new(Mutex::new(ReadCache::new(DEFAULT_READ_CACHE_BYTES))),
+            readahead: Arc::new(Mutex::new(Readahead::new(DEFAULT_READAHEAD_BYTES))),
+            read_metrics: Arc::default(),
+            umask: DEFAULT_UMASK,
+            symlink_view: false,
+            dir_handles: Mutex::new(HashSet::new()),
+            next_dir_handle: AtomicU64::new(DIR_HANDLE_BASE),
+            scanned_dirs: Mutex::new(HashMap::new()),
+            host_handles: Arc::new(Mutex::new(HostHandles::default())),
+            max_open_fds: DEFAULT_MAX_OPEN_FDS,
+            written: Mutex::new(HashSet::new()),
+            host_watcher: Mutex::new(host_watcher),
+        };
+        fs.forget_changed_host_data();
+        fs
+    }
+
+    /// Drop data read from host files as the store hears they've changed.
+    fn forget_changed_host_data(&self) {
+        let (host_handles, read_cache, readahead) = (
+            self.host_handles.clone(),
+            self.read_cache.clone(),
+            self.readahead.clone(),
+        );
+        self.store.write().subscribe(move |event| {
+            if let StoreEvent::HostChanged(host_path) = event {
+                Self::forget_host_data(&host_handles, &read_cache, &readahead, host_path);
+            }
+        });
+    }
+
+    /// How long a `statfs` result is reused before asking the host again.
+    pub fn with_statfs_ttl(mut self, statfs_ttl: Duration) -> Self {
+        self.statfs_ttl = statfs_ttl;
+        self
+    }
+
+    /// Operations taking at least this long are logged at `warn`.
+    pub fn with_slow_op_threshold(mut self, slow_op_threshold: Duration) -> Self {
+        self.slow_op_threshold = slow_op_threshold;
+        self
+    }
+
+    /// Bytes of recently read data kept to answer repeated reads without a syscall.
+    pub fn with_read_cache_bytes(self, read_cache_bytes: usize) -> Self {
+        *self.read_cache.lock().unwrap() = ReadCache::new(read_cache_bytes);
+        self
+    }
+
+    /// Bytes read at a time for handles read sequentially, buffered to answer the reads
+    /// that follow; 0 turns readahead off.
+    pub fn with_readahead_bytes(self, readahead_bytes: usize) -> Self {
+        *self.readahead.lock().unwrap() = Readahead::new(readahead_bytes);
+        self
+    }
+
+    /// Host descriptors kept open at once. Past that, opening a file closes the least
+    /// recently used idle descriptor, to be reopened when its handle is next used.
+    pub fn with_max_open_fds(mut self, max_open_fds: usize) -> Self {
+        self.max_open_fds = max_open_fds.max(1);
+        self
+    }
+
+    /// Permission bits cleared from the mode of synthesized directories.
+    pub fn with_umask(mut self, umask: u32) -> Self {
+        self.umask = umask & 0o777;
+        self
+    }
+
+    /// Present each file as a symlink to its host path, which tools can follow to the
+    /// real file, instead of a regular file whose reads and writes are proxied.
+    pub fn with_symlink_view(mut self, symlink_view: bool) -> Self {
+        self.symlink_view = symlink_view;
+        self
+    }
+
+    /// What files are listed as: see `with_symlink_view`.
+    fn file_kind(&self) -> FileType {
+        if self.symlink_view {
+            FileType::Symlink
+        } else {
+            FileType::RegularFile
+        }
+    }
+
+    /// Attributes of a directory that exists only in the organized view: the root's
+    /// timestamps and ownership, with the mode derived from the umask.
+    fn synthesized_dir_attr(&self, root: libc::stat) -> FileAttr {
+        FileAttr {
+            kind: FileType::Directory,
+            perm: (0o777 & !self.umask) as u16,
+            ..Self::stat_to_fuse(root)
+        }
+    }
+
+    /// A handle for a directory of the organized view, valid until released.
+    fn new_dir_handle(&self) -> u64 {
+        let fh = self.next_dir_handle.fetch_add(1, Ordering::Relaxed);
+        self.dir_handles.lock().unwrap().insert(fh);
+        fh
+    }
+
+    /// Attributes shared by every directory of the organized view. Only the root's
+    /// `lstat` feeds them, so it's done at most once per `TTL` however many directories
+    /// a traversal looks at.
+    fn dir_attr(&self) -> Result<FileAttr, libc::c_int> {
+        let mut cache = self.root_stat_cache.lock().unwrap();
+        if let Some((fetched, stat)) = *cache {
+            if fetched.elapsed() < TTL {
+                return Ok(self.synthesized_dir_attr(stat));
+            }
+        }
+        match self.libc_wrapper.lstat(self.root.to_owned()) {
+            Ok(stat) => {
+                *cache = Some((Instant::now(), stat));
+                Ok(self.synthesized_dir_attr(stat))
+            }
+            Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
+        }
+    }
+
+    pub fn config(&self) -> OrganizeFsConfig {
+        let mut config = OrganizeFsConfig {
+            root: self.root.clone(),
+            utc_offset: self.options.utc_offset.to_string(),
+            strip_prefix: self.options.strip_prefix.clone(),
+            sniff_bytes: self.options.sniff_bytes,
+            one_filesystem: self.options.one_filesystem,
+            follow_links: self.options.follow_links,
+            lazy: self.options.lazy,
+            watch: self.options.watch,
+            path_hash_len: self.options.path_hash_len,
+            excluded_dirs: self.options.excluded_dirs.clone(),
+            statfs_ttl_ms: self.statfs_ttl.as_millis(),
+            slow_op_ms: self.slow_op_threshold.as_millis(),
+            read_cache_bytes: self.read_cache.lock().unwrap().capacity(),
+            readahead_bytes: self.readahead.lock().unwrap().chunk(),
+            max_open_fds: self.max_open_fds,
+            symlink_view: self.symlink_view,
+            umask: format!("{:03o}", self.umask),
+            ..OrganizeFsConfig::default()
+        };
+        config.refresh(&self.store.read());
+        config
+    }
+
+    /// Read-path counters, shared so they can be served after the filesystem is mounted.
+    pub fn read_metrics(&self) -> Arc<ReadMetrics> {
+        self.read_metrics.clone()
+    }
+
+    /// Run `f`, warning with its duration if it was slow; fast calls only pay for the clock.
+    fn timed<R>(&self, op: &'static str, path: &Path, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        if elapsed >= self.slow_op_threshold {
+            warn!(
+                op,
+                path = debug(path),
+                elapsed = debug(elapsed),
+                "slow operation"
+            );
+        }
+        result
+    }
+
+    /// The options the host tree was scanned with.
+    pub fn scan_options(&self) -> ScanOptions {
+        self.options.clone()
+    }
+
+    /// Scan `root` again and replace what `store` holds with what's there now, keeping
+    /// its pattern. Returns how many files are in the tree afterwards. The store is only
+    /// locked once the scan is done, so it keeps serving the old tree meanwhile.
+    #[instrument(skip(store))]
+    pub fn rescan(
+        root: &Path,
+        options: &ScanOptions,
+        store: &parking_lot::RwLock<OrganizeFSStore>,
+    ) -> usize {
+        let entries = Self::scan(root, options).collect::<Vec<_>>();
+        let found = entries
+            .iter()
+            .map(|entry| entry.host_path.as_path())
+            .collect::<HashSet<_>>();
+        options.mime_cache.retain(|path| found.contains(path));
+        let mut store = store.write();
+        store.replace_entries(entries);
+        info!(store = debug(&store), "rescanned");
+        store.iter_leaves().count()
+    }
+
+    /// Scan `root` into `store` and write the manifest of the tree it would mount (see
+    /// `OrganizeFSStore::export_to_writer`) to `w`, without mounting anything. Returns
+    /// how many files are in the tree.
+    #[instrument(skip(store, w))]
+    pub fn dry_run<W: Write>(
+        root: &str,
+        options: &ScanOptions,
+        store: &parking_lot::RwLock<OrganizeFSStore>,
+        w: &mut W,
+    ) -> std::io::Result<usize> {
+        let root = std::env::current_dir().unwrap().as_path().join(root);
+        let count = Self::rescan(&root, options, store);
+        store.read().export_to_writer(w)?;
+        Ok(count)
+    }
+
+    /// Suggest a pattern for the host tree at `root`, from a sample of its files.
+    #[instrument]
+    pub fn suggest_pattern(root: &str, options: &ScanOptions) -> Result<String, PatternError> {
+        let root = std::env::current_dir().unwrap().as_path().join(root);
+        let sample = Self::scan(&root, options)
+            .take(SUGGEST_SAMPLE)
+            .collect::<Vec<_>>();
+        let pattern = suggest_pattern(&sample);
+        validate_pattern::<OrganizeFSEntry>(&pattern, ParentDirPolicy::default())?;
+        Ok(pattern)
+    }
+
+    #[instrument]
+    fn scan<'a>(
+        root: &'a Path,
+        options: &'a ScanOptions,
+    ) -> impl Iterator<Item = OrganizeFSEntry> + 'a {
+        info!(root = debug(root), "scanning");
+        Self::walk(root, root, usize::MAX, options)
+            .filter_map(|entry| Self::process(root, &entry, options))
+    }
+
+    /// Bring a restored `store` up to date with `root`: only host files added, changed in
+    /// size or modification time, or gone since it was saved are indexed or dropped, so
+    /// the tree already built from the snapshot isn't built again.
+    fn refresh(root: &Path, options: &ScanOptions, store: &mut OrganizeFSStore) {
+        let indexed = store
+            .entries
+            .values()
+            .map(|entry| (entry.host_path.to_owned(), (entry.modified, entry.size)))
+            .collect::<HashMap<_, _>>();
+        let mut found = HashSet::new();
+        let mut changed = Vec::new();
+        for entry in Self::walk(root, root, usize::MAX, options) {
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let meta = if options.follow_links {
+                fs::metadata(entry.path())
+            } else {
+                fs::symlink_metadata(entry.path())
+            };
+            let Ok(meta) = meta else {
+                continue;
+            };
+            let host_path = root.join(entry.path()).normalize();
+            if meta.is_file()
+                && indexed.get(&host_path) == Some(&(meta.modified().ok(), meta.len()))
+            {
+                found.insert(host_path);
+            } else if let Some(entry) = Self::process_with(root, &entry, &meta, options) {
+                found.insert(host_path);
+                changed.push(entry);
+            }
+        }
+        let gone = indexed
+            .into_keys()
+            .filter(|host_path| !found.contains(host_path))
+            .collect::<Vec<_>>();
+        info!(
+            changed = changed.len(),
+            gone = gone.len(),
+            "refreshing restored store"
+        );
+        for host_path in gone {
+            store.remove_host_file(&host_path);
+        }
+        for entry in changed {
+            store.reindex(entry);
+        }
+    }
+
+    /// Host entries in `dir`, down to `max_depth` levels, as the scan of `root` sees them.
+    fn walk(
+        root: &Path,
+        dir: &Path,
+        max_depth: usize,
+        options: &ScanOptions,
+    ) -> impl Iterator<Item = walkdir::DirEntry> {
+        let root_dev = options
+            .one_filesystem
+            .then(|| fs::metadata(root).map(|meta| meta.dev()).ok())
+            .flatten();
+        let excluded = options.clone();
+        let root = root.to_path_buf();
+        WalkDir::new(dir)
+            .max_depth(max_depth)
+            .follow_links(options.follow_links)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            // Pruned here, so excluded directories aren't even read.
+            .filter_entry(move |entry| {
+                if entry.file_type().is_dir()
+                    && entry.path() != root
+                    && excluded.excludes(entry.file_name())
+                {
+                    debug!(path = debug(entry.path()), "excluded");
+                    return false;
+                }
+                Self::within_filesystem(root_dev, entry)
+            })
+            .filter_map(|entry| {
+                // Unreadable entries and symlink loops are passed over, not the rest.
+                entry
+                    .inspect_err(|e| warn!(error = display(e), "not scanned"))
+                    .ok()
+            })
+    }
+
+    /// In lazy mode, index what's needed to list the virtual directory `path` and its
+    /// ancestors, root first. Only directories mirroring host ones (the pattern's leading
+    /// `/{dir1}/{dir2}/...`, see `OrganizeFSStore::mirrored_depth`) can be mapped back:
+    /// above the last mirrored level a directory's own files are indexed and its host
+    /// subdirectories listed; at it, the whole host subtree is indexed, as all of it is
+    /// filed below. With no mirrored levels, that is the whole root, on first access.
+    fn populate(&self, path: &Path) {
+        if !self.options.lazy {
+            return;
+        }
+        let mut dir = PathBuf::from("/");
+        let names = path.components().filter_map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        });
+        for name in std::iter::once(None).chain(names.map(Some)) {
+            if let Some(name) = name {
+                dir.push(name);
+            }
+            if !self.populate_dir(&dir) {
+                break;
+            }
+        }
+    }
+
+    /// The host directory `{dir1}`, `{dir2}`, ... count from: `ScanOptions::strip_prefix`
+    /// if set, else the root.
+    fn host_base(&self) -> &Path {
+        self.options.strip_prefix.as_deref().unwrap_or(&self.root)
+    }
+
+    /// The host directory `path` mirrors, if it's below the root. Those that aren't, e.g.
+    /// above it under `--strip-prefix`, are left alone by `mkdir` and `rmdir`, which only
+    /// change the view there.
+    fn host_dir_below_root(&self, store: &OrganizeFSStore, path: &Path) -> Option<PathBuf> {
+        store
+            .mirrored_host_dir(path, self.host_base())
+            .filter(|host_dir| host_dir.starts_with(&self.root) && *host_dir != self.root)
+    }
+
+    /// One level of `populate`; `false` if `path` doesn't mirror a host directory.
+    #[instrument(level = "debug", skip(self))]
+    fn populate_dir(&self, path: &Path) -> bool {
+        let (host_dir, recursive) = {
+            let store = self.store.read();
+            let host_dir = match path.parent() {
+                None => Some(self.host_base().to_path_buf()),
+                Some(_) => store.mirrored_host_dir(path, self.host_base()),
+            };
+            let depth = path.components().count() - 1;
+            (host_dir, depth >= store.mirrored_depth())
+        };
+        let Some(host_dir) = host_dir else {
+            return false;
+        };
+        if !host_dir.starts_with(&self.root) {
+            return self.populate_above_root(path, &host_dir);
+        }
+        // Held throughout, so concurrent lookups wait for a directory being indexed.
+        let mut scanned_dirs = self.scanned_dirs.lock().unwrap();
+        if scanned_dirs.get(&host_dir) == Some(&true) {
+            return true;
+        }
+        let max_depth = if recursive { usize::MAX } else { 1 };
+        let mut subdirs = Vec::new();
+        let mut entries = Vec::new();
+        for entry in Self::walk(&self.root, &host_dir, max_depth, &self.options) {
+            if entry.file_type().is_dir() {
+                if entry.depth() > 0 {
+                    subdirs.push(entry);
+                }
+            } else if !entry
+                .path()
+                .parent()
+                .is_some_and(|parent| scanned_dirs.contains_key(parent))
+            {
+                entries.extend(Self::process(&self.root, &entry, &self.options));
+            }
+        }
+        info!(
+            path = debug(path),
+            host_dir = debug(&host_dir),
+            recursive,
+            entries = entries.len(),
+            "populated"
+        );
+
+        let mut store = self.store.write();
+        store.reserve(entries.len());
+        for entry in entries {
+            store.add_entry(entry);
+        }
+        for subdir in subdirs {
+            if recursive {
+                scanned_dirs.insert(subdir.into_path(), true);
+            } else if let Err(e) = store.add_dir(&path.join(subdir.file_name())) {
+                warn!(
+                    subdir = debug(subdir.path()),
+                    error = display(e),
+                    "not listed"
+                );
+            }
+        }
+        scanned_dirs.insert(host_dir, recursive);
+        true
+    }
+
+    /// `populate_dir` for a directory mirroring `host_dir`, above the root when
+    /// `ScanOptions::strip_prefix` is: all it holds is the next directory down towards
+    /// the root. `false` if `host_dir` is elsewhere, as nothing below it is scanned.
+    fn populate_above_root(&self, path: &Path, host_dir: &Path) -> bool {
+        let Some(next) = self
+            .root
+            .strip_prefix(host_dir)
+            .ok()
+            .and_then(|below| below.components().next())
+        else {
+            return false;
+        };
+        let mut store = self.store.write();
+        let subdir = path.join(next);
+        if !store.find(&subdir).is_directory() {
+            if let Err(e) = store.add_dir(&subdir) {
+                warn!(subdir = debug(subdir), error = display(e), "not listed");
+            }
+        }
+        true
+    }
+
+    /// Whether the scan may go into `entry`: anything does, unless the root's device is
+    /// given and `entry` is on another one.
+    fn within_filesystem(root_dev: Option<u64>, entry: &walkdir::DirEntry) -> bool {
+        let Some(root_dev) = root_dev else {
+            return true;
+        };
+        match entry.metadata() {
+            Ok(meta) if meta.dev() != root_dev => {
+                info!(path = debug(entry.path()), "other filesystem, skipped");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    #[instrument(level = "debug")]
+    fn process(
+        root: &Path,
+        entry: &walkdir::DirEntry,
+        options: &ScanOptions,
+    ) -> Option<OrganizeFSEntry> {
+        // Spares an `lstat` per directory; walkdir already knows those.
+        if entry.file_type().is_dir() {
+            return None;
+        }
+        let meta = if options.follow_links {
+            fs::metadata(entry.path())
+        } else {
+            fs::symlink_metadata(entry.path())
+        };
+        Self::process_with(root, entry, &meta.ok()?, options)
+    }
+
+    /// `process` with `meta` already looked up. Only regular files are indexed: not
+    /// symlinks, unless followed to one, nor FIFOs, sockets or devices, which have no
+    /// content to serve.
+    fn process_with(
+        root: &Path,
+        entry: &impl DirEntry,
+        meta: &impl Metadata,
+        options: &ScanOptions,
+    ) -> Option<OrganizeFSEntry> {
+        let kind = meta.file_type();
+        if kind != FileKind::File {
+            if kind != FileKind::Dir {
+                debug!(path = debug(entry.path()), kind = debug(kind), "not a file");
+            }
+            return None;
+        }
+        if entry.path().parent().is_none() || sidecar::is_sidecar(entry.path()) {
+            return None;
+        }
+        debug!(root = debug(root), entry = debug(entry), "found");
+        let entry = OrganizeFSEntry::new(root, entry, meta, options);
+        debug!(root = debug(root), entry = display(&entry));
+        Some(entry)
+    }
+
+    /// With `options.watch`, start applying changes below `root` to `store`, a batch at a
+    /// time once the host has been still for `WATCH_QUIET`, until the returned watcher is
+    /// stopped.
+    fn watch(
+        root: &Path,
+        store: &Arc<parking_lot::RwLock<OrganizeFSStore>>,
+        options: &ScanOptions,
+    ) -> Option<HostWatcher> {
+        if !options.watch {
+            return None;
+        }
+        if options.lazy {
+            warn!(root = debug(root), "lazy, not watching");
+            return None;
+        }
+        let queue = Arc::new(EventQueue::default());
+        let watcher = match watch_tree(root, queue.clone()) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!(root = debug(root), error = display(e), "not watching");
+                return None;
+            }
+        };
+        info!(root = debug(root), "watching");
+        let (root, store, options) = (root.to_path_buf(), store.clone(), options.clone());
+        let thread = {
+            let queue = queue.clone();
+            std::thread::spawn(move || loop {
+                let events = queue.wait_quiet(WATCH_QUIET);
+                if events.is_empty() {
+                    info!(root = debug(&root), "stopped watching");
+                    return;
+                }
+                for (path, event) in events {
+                    Self::apply_host_event(&store, &root, &options, &path, event);
+                }
+            })
+        };
+        Some(HostWatcher {
+            watcher,
+            queue,
+            thread,
+        })
+    }
+
+    /// Bring `store` up to date with a change to the host `path`. If it's gone, whatever
+    /// was indexed from it (or below it) goes too, as do the types detected for it. A
+    /// changed file is indexed afresh; a changed directory, e.g. one moved in, has the
+    /// files in it not yet indexed added, and those below subdirectories with nothing
+    /// indexed yet. Subdirectories with files indexed aren't gone into: changes below
+    /// them arrive as events of their own.
+    #[instrument(level = "debug", skip(store, options))]
+    fn apply_host_event(
+        store: &parking_lot::RwLock<OrganizeFSStore>,
+        root: &Path,
+        options: &ScanOptions,
+        path: &Path,
+        event: WatchEvent,
+    ) {
+        store
+            .read()
+            .notify(StoreEvent::HostChanged(path.to_path_buf()));
+        if event == WatchEvent::Removed || fs::symlink_metadata(path).is_err() {
+            let removed = store.write().remove_host_file(path);
+            options
+                .mime_cache
+                .retain(|cached| !cached.starts_with(path));
+            debug!(path = debug(path), removed = removed.len(), "host removed");
+            return;
+        }
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        if relative
+            .parent()
+            .into_iter()
+            .flat_map(Path::components)
+            .any(|component| options.excludes(component.as_os_str()))
+        {
+            return;
+        }
+        let indexed = store
+            .read()
+            .indexed_below(path)
+            .map(|(host_path, _)| host_path.to_owned())
+            .collect::<HashSet<_>>();
+        let known_dirs = indexed
+            .iter()
+            .flat_map(|host_path| host_path.ancestors().skip(1).take_while(|dir| *dir != path))
+            .collect::<HashSet<_>>();
+        let mut found = Vec::new();
+        for entry in Self::walk(root, path, 1, options) {
+            if entry.depth() > 0 && entry.file_type().is_dir() {
+                if !known_dirs.contains(entry.path()) {
+                    found.extend(Self::walk(root, entry.path(), usize::MAX, options));
+                }
+            } else {
+                found.push(entry);
+            }
+        }
+        // Indexed outside the lock, as detecting types reads the files.
+        let entries = found
+            .into_iter()
+            .filter(|entry| entry.path() == path || !indexed.contains(entry.path()))
+            .filter_map(|entry| Self::process(root, &entry, options))
+            .collect::<Vec<_>>();
+        let mut store = store.write();
+        for entry in entries {
+            store.reindex(entry);
+        }
+        store.debug_validate_integrity();
+    }
+
+    /// Drop what's been read, or read ahead, from the host file at `host_path`, or from
+    /// files below it, through any handle, as it changed.
+    fn forget_host_data(
+        host_handles: &Mutex<HostHandles>,
+        read_cache: &Mutex<ReadCache>,
+        readahead: &Mutex<Readahead>,
+        host_path: &Path,
+    ) {
+        let fhs = host_handles.lock().unwrap().open_on(host_path);
+        debug!(
+            host_path = debug(host_path),
+            handles = debug(&fhs),
+            "forget data"
+        );
+        let mut read_cache = read_cache.lock().unwrap();
+        for fh in &fhs {
+            read_cache.invalidate(*fh);
+        }
+        drop(read_cache);
+        let mut readahead = readahead.lock().unwrap();
+        for fh in fhs {
+            readahead.invalidate(fh);
+        }
+    }
+
+    /// Read into this thread's reusable buffer and hand the filled prefix to `f`,
+    /// so large sequential reads don't allocate per call.
+    ///
+    /// Holes in sparse files come back from the host as zeros. The buffer is sized to
+    /// the request, never the file, so reading a huge sparse file costs no more memory
+    /// than a dense one, and nothing beyond what the host filled is handed out.
+    ///
+    /// This is what FUSE `read` replies from; it's public so the read path can be
+    /// measured without a mount (see `benches/read.rs`).
+    pub fn read_into_buffer<R>(
+        &self,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        f: impl FnOnce(ResultSlice<'_>) -> R,
+    ) -> R {
+        if fh == 0 {
+            return f(Err(libc::ENOENT));
+        }
+        let key = (fh, offset, size);
+        {
+            let cache = self.read_cache.lock().unwrap();
+            if let Some(data) = cache.get(&key) {
+                self.read_metrics.record_hit(data.len());
+                return f(Ok(data));
+            }
+        }
+        let chunk = {
+            let mut readahead = self.readahead.lock().unwrap();
+            if let Some(data) = readahead.get(fh, offset, size) {
+                self.read_metrics.record_hit(data.len());
+                return f(Ok(data));
+            }
+            readahead
+                .is_sequential(fh, offset)
+                .then(|| readahead.chunk())
+        };
+        if let Some(chunk) = chunk.filter(|&chunk| chunk > 0) {
+            return self.read_ahead(fh, offset, size, chunk.max(size as usize), f);
+        }
+        READ_BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            let size = size as usize;
+            if buffer.len() < size {
+                buffer.resize(size, 0);
+            }
+            let read = self.with_fd(fh, |fd| {
+                self.libc_wrapper
+                    .read(fd, offset.try_into().unwrap(), &mut buffer[..size])
+            });
+            match read {
+                Ok(count) => {
+                    self.read_metrics.record_miss(count);
+                    self.read_cache
+                        .lock()
+                        .unwrap()
+                        .insert(key, &buffer[..count]);
+                    self.readahead.lock().unwrap().record(fh, offset, count);
+                    f(Ok(&buffer[..count]))
+                }
+                Err(e) => f(Err(e.raw_os_error().unwrap_or(libc::ENOENT))),
+            }
+        })
+    }
+
+    /// A sequential read: `chunk` bytes from `offset` are read, and the first `size`
+    /// handed to `f`, the rest kept for the reads that follow. The lock isn't held
+    /// while the host reads, so other handles aren't kept waiting.
+    fn read_ahead<R>(
+        &self,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        chunk: usize,
+        f: impl FnOnce(ResultSlice<'_>) -> R,
+    ) -> R {
+        let mut data = vec![0; chunk];
+        let read = self.with_fd(fh, |fd| {
+            self.libc_wrapper
+                .read(fd, offset.try_into().unwrap(), &mut data)
+        });
+        match read {
+            Ok(count) => {
+                self.read_metrics.record_miss(count);
+                data.truncate(count);
+                let mut readahead = self.readahead.lock().unwrap();
+                readahead.fill(fh, offset, data, count < chunk);
+                f(Ok(readahead.get(fh, offset, size).unwrap_or_default()))
+            }
+            Err(e) => f(Err(e.raw_os_error().unwrap_or(libc::ENOENT))),
+        }
+    }
+
+    /// `read_into_buffer`, noticing host files that shrank since they were indexed.
+    ///
+    /// A short read means the file ends before the requested range does (past the end,
+    /// the slice is empty), so the size is re-read with `fstat`; if it no longer matches
+    /// the store's, the entry is updated and re-filed.
+    fn read_file<R>(
+        &self,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        f: impl FnOnce(ResultSlice<'_>) -> R,
+    ) -> R {
+        let mut short = false;
+        let result = self.read_into_buffer(fh, offset, size, |result| {
+            short = matches!(&result, Ok(data) if data.len() < size as usize);
+            f(result)
+        });
+        if short {
+            match self.with_fd(fh, |fd| self.libc_wrapper.fstat(fd as u64)) {
+                Ok(stat) => self.store.write().resized(path, stat.st_size as u64),
+                Err(e) => debug!(
+                    path = debug(path),
+                    error = display(e),
+                    "no size after short read"
+                ),
+            }
+        }
+        result
+    }
+
+    /// Take idle host descriptors, least recently used first, until one more can be
+    /// opened without going over the cap; they're for the caller to close once it has
+    /// let go of `handles`. If every one is in use, the cap is exceeded rather than
+    /// failing.
+    fn make_room(&self, handles: &mut HostHandles) -> Vec<i32> {
+        let mut recycled = Vec::new();
+        while handles.open_fds >= self.max_open_fds {
+            let Some((fh, handle)) = handles
+                .handles
+                .iter_mut()
+                .filter(|(_, handle)| handle.fd.is_some() && handle.in_use == 0 && !handle.unlinked)
+                .min_by_key(|(_, handle)| handle.last_used)
+            else {
+                debug!(open_fds = handles.open_fds, "every descriptor in use");
+                break;
+            };
+            let Some(fd) = handle.fd.take() else {
+                break;
+            };
+            handles.open_fds -= 1;
+            debug!(fh, fd, "recycled");
+            recycled.push(fd);
+        }
+        recycled
+    }
+
+    /// Close the descriptors `make_room` took.
+    fn close_recycled(&self, recycled: Vec<i32>) {
+        for fd in recycled {
+            if let Err(e) = self.libc_wrapper.close(fd) {
+                debug!(fd, error = display(e), "recycled descriptor not closed");
+            }
+        }
+    }
+
+    /// Run `f` on the host descriptor behind handle `fh`, reopening it if it was
+    /// recycled. It isn't recycled while `f` runs. Descriptors are closed and opened
+    /// without holding `host_handles`, so other handles' operations don't wait on them.
+    fn with_fd<R>(&self, fh: u64, f: impl FnOnce(i32) -> std::io::Result<R>) -> std::io::Result<R> {
+        let (fd, recycled) = {
+            let mut handles = self.host_handles.lock().unwrap();
+            handles.clock += 1;
+            let clock = handles.clock;
+            let Some(handle) = handles.handles.get_mut(&fh) else {
+                return Err(std::io::Error::from_raw_os_error(libc::EBADF));
+            };
+            handle.in_use += 1;
+            handle.last_used = clock;
+            match handle.fd {
+                Some(fd) => (Ok(fd), Vec::new()),
+                None => {
+                    let reopen = (handle.host_path.to_owned(), handle.reopen_flags);
+                    let recycled = self.make_room(&mut handles);
+                    // Counted now, so concurrent reopens make room for each other.
+                    handles.open_fds += 1;
+                    (Err(reopen), recycled)
+                }
+            }
+        };
+        self.close_recycled(recycled);
+        let fd = match fd {
+            Ok(fd) => Ok(fd),
+            Err((host_path, flags)) => self.reopen(fh, host_path, flags),
+        };
+        let result = fd.and_then(f);
+        if let Some(handle) = self.host_handles.lock().unwrap().handles.get_mut(&fh) {
+            handle.in_use -= 1;
+        }
+        result
+    }
+
+    /// Reopen the recycled descriptor behind `fh`, its place under the cap already
+    /// counted, unless another operation got there first.
+    fn reopen(&self, fh: u64, host_path: PathBuf, flags: i32) -> std::io::Result<i32> {
+        let opened = self.libc_wrapper.open(host_path, flags);
+        let mut handles = self.host_handles.lock().unwrap();
+        let current = handles.handles.get(&fh).map(|handle| handle.fd);
+        let fd = match (opened, current) {
+            (Ok(fd), Some(None)) => {
+                debug!(fh, fd, "reopened");
+                if let Some(handle) = handles.handles.get_mut(&fh) {
+                    handle.fd = Some(fd);
+                }
+                return Ok(fd);
+            }
+            (Err(e), _) => {
+                handles.open_fds -= 1;
+                return Err(e);
+            }
+            (Ok(fd), _) => fd,
+        };
+        // Reopened meanwhile, or released: this descriptor isn't needed.
+        handles.open_fds -= 1;
+        drop(handles);
+        self.close_recycled(vec![fd]);
+        match current {
+            Some(Some(theirs)) => Ok(theirs),
+            _ => Err(std::io::Error::from_raw_os_error(libc::EBADF)),
+        }
+    }
+
+    /// The extended attributes of the file at `path`, none for a directory.
+    fn xattrs(&self, path: &Path) -> Result<Vec<(&'static str, Vec<u8>)>, libc::c_int> {
+        let store = self.store.read();
+        match store.entry(path) {
+            Some(entry) => Ok(vec![
+                (
+                    XATTR_HOST_PATH,
+                    entry.host_path.as_os_str().as_bytes().to_vec(),
+                ),
+                // As detected, e.g. `image/jpeg`; empty if it wasn't.
+                (XATTR_MIME, entry.mime.replacen('_', "/", 1).into_bytes()),
+            ]),
+            None if store.find(path).is_directory() => Ok(Vec::new()),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    /// `data` if it fits in `size` bytes, or its length if `size` is 0, as asked.
+    fn xattr_reply(data: Vec<u8>, size: u32) -> ResultXattr {
+        if size == 0 {
+            Ok(Xattr::Size(data.len() as u32))
+        } else if data.len() > size as usize {
+            Err(libc::ERANGE)
+        } else {
+            Ok(Xattr::Data(data))
+        }
+    }
+
+    fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
+        Statfs {
+            blocks: statfs.f_blocks,
+            bfree: statfs.f_bfree,
+            bavail: statfs.f_bavail,
+            files: statfs.f_files,
+            ffree: statfs.f_ffree,
+            bsize: statfs.f_bsize as u32,
+            namelen: statfs.f_namelen as u32,
+            frsize: statfs.f_frsize as u32,
+        }
+    }
+
+    fn mode_to_filetype(mode: libc::mode_t) -> FileType {
+        match mode & libc::S_IFMT {
+            libc::S_IFDIR => FileType::Directory,
+            libc::S_IFREG => FileType::RegularFile,
+            libc::S_IFLNK => FileType::Symlink,
+            libc::S_IFBLK => FileType::BlockDevice,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFIFO => FileType::NamedPipe,
+            libc::S_IFSOCK => FileType::Socket,
+            _ => {
+                panic!("unknown file type");
+            }
+        }
+    }
+
+    /// `blocks` is the host's allocation, so sparse files keep their holes: a file may
+    /// report fewer blocks than its `size` would need.
+    fn stat_to_fuse(stat: libc::stat) -> FileAttr {
+        // st_mode encodes both the kind and the permissions
+        let kind = Self::mode_to_filetype(stat.st_mode);
+        let perm = (stat.st_mode & 0o7777) as u16;
+
+        FileAttr {
+            size: stat.st_size as u64,
+            blocks: stat.st_blocks as u64,
+            atime: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(stat.st_atime.try_into().unwrap())
+                + Duration::from_nanos(stat.st_atime_nsec.try_into().unwrap()),
+            mtime: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(stat.st_mtime.try_into().unwrap())
+                + Duration::from_nanos(stat.st_mtime_nsec.try_into().unwrap()),
+            ctime: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(stat.st_ctime.try_into().unwrap())
+                + Duration::from_nanos(stat.st_ctime_nsec.try_into().unwrap()),
+            crtime: SystemTime::UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: stat.st_nlink as u32,
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            rdev: stat.st_rdev as u32,
+            flags: 0,
+        }
+    }
+}
+
+/// Callbacks answer from the store, reaching the host only where they must: attributes,
+/// `statfs` and file contents are read through `libc_wrapper`; `unlink` and `rename`
+/// change host files (and sidecars); `mkdir` and `rmdir` change the host only for
+/// directories that mirror host ones (see `OrganizeFSStore::mirrored_host_dir`), and are
+/// otherwise purely virtual.
+impl FilesystemMT for OrganizeFS {
+    fn init(&self, req: RequestInfo) -> ResultEmpty {
+        info!(req = debug(req), "init");
+        Ok(())
+    }
+
+    fn destroy(&self) {
+        info!("destroy");
+        if let Some(host_watcher) = self.host_watcher.lock().unwrap().take() {
+            host_watcher.stop();
+        }
+        let mut mutex = self.shutdown_signal.lock().unwrap();
+        if let Some(signal) = mutex.take() {
+            // Gone if the server failed, which is what unmounted us.
+            if signal.send(()).is_err() {
+                warn!("server already stopped");
+            }
+        }
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
+        debug!(req = debug(req), path = debug(path), fh, "getattr");
+        self.timed("getattr", path, || {
+            // Directory handles aren't host descriptors: answer as for any directory.
+            if fh.is_some_and(|fh| self.dir_handles.lock().unwrap().contains(&fh)) {
+                self.dir_attr().map(|attr| (TTL, attr))
+            } else if let Some(fh) = fh {
+                match self.with_fd(fh, |fd| self.libc_wrapper.fstat(fd as u64)) {
+                    Ok(stat) => Ok((TTL, Self::stat_to_fuse(stat))),
+                    Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
+                }
+            } else {
+                // The root is a directory whatever the store holds.
+                if path.parent().is_none() {
+                    return self.dir_attr().map(|attr| (TTL, attr));
+                }
+                if let Some(parent) = path.parent() {
+                    self.populate(parent);
+                }
+                let store = self.store.read();
+                let r = store.find(path);
+                debug!(found = debug(&r), "found");
+                if r.is_directory() {
+                    self.dir_attr().map(|attr| (TTL, attr))
+                } else if let Some(entry) = r.entry().and_then(|id| store.entries.get(id)) {
+                    let mut attr = match self.libc_wrapper.lstat(entry.host_path.to_owned()) {
+                        Ok(stat) => Self::stat_to_fuse(stat),
+                        Err(e) => return Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
+                    };
+                    // A symlink's own size is its target's length.
+                    if self.symlink_view {
+                        attr.kind = FileType::Symlink;
+                        attr.perm = 0o777;
+                        attr.size = entry.host_path.as_os_str().len() as u64;
+                        attr.blocks = 0;
+                    }
+                    Ok((TTL, attr))
+                } else {
+                    Err(libc::ENOENT)
+                }
+            }
+        })
+    }
+
+    /// Only files in the symlink view are links, to their host paths.
+    fn readlink(&self, req: RequestInfo, path: &Path) -> ResultData {
+        debug!(req = debug(req), path = debug(path), "readlink");
+        let store = self.store.read();
+        match store.entry(path) {
+            Some(entry) if self.symlink_view => Ok(entry.host_path.as_os_str().as_bytes().to_vec()),
+            Some(_) => Err(libc::EINVAL),
+            None if store.find(path).is_directory() => Err(libc::EINVAL),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    /// Files carry their host path and type; see `XATTR_HOST_PATH` and `XATTR_MIME`.
+    fn getxattr(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        name: &std::ffi::OsStr,
+        size: u32,
+    ) -> ResultXattr {
+        debug!(
+            req = debug(req),
+            path = debug(path),
+            name = debug(name),
+            size,
+            "getxattr"
+        );
+        let (_, value) = self
+            .xattrs(path)?
+            .into_iter()
+            .find(|(xattr, _)| name == *xattr)
+            .ok_or(libc::ENODATA)?;
+        Self::xattr_reply(value, size)
+    }
+
+    fn listxattr(&self, req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+        debug!(req = debug(req), path = debug(path), size, "listxattr");
+        let names = self
+            .xattrs(path)?
+            .into_iter()
+            .flat_map(|(name, _)| name.bytes().chain([0]))
+            .collect();
+        Self::xattr_reply(names, size)
+    }
+
+    fn statfs(&self, req: RequestInfo, path: &Path) -> ResultStatfs {
+        debug!(req = debug(req), path = debug(path), "statfs");
+        let mut cache = self.statfs_cache.lock().unwrap();
+        if let Some((fetched, statfs)) = *cache {
+            if fetched.elapsed() < self.statfs_ttl {
+                return Ok(statfs);
+            }
+        }
+        match self.libc_wrapper.statfs(self.root.to_owned()) {
+            Ok(stat) => {
+                let statfs = Self::statfs_to_fuse(stat);
+                *cache = Some((Instant::now(), statfs));
+                Ok(statfs)
+            }
+            Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
+        }
+    }
+
+    fn opendir(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        debug!(
+            req = debug(req),
+            path = debug(path),
+            flags,
+            "opendir (flags = {:#o})",
+            flags
+        );
+        if self.store.read().find_dir(path).is_some() {
+            Ok((self.new_dir_handle(), 0))
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    #[instrument(level = "info")]
+    fn readdir(&self, req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
+        debug!(req = debug(req), path = debug(path), fh, "readdir");
+
+        self.timed("readdir", path, || {
+            self.populate(path);
+            // Re-resolve: the tree may have changed since `opendir`.
+            let store = self.store.read();
+            let dir = store.find(path);
+            if dir.is_file() {
+                return Err(libc::ENOTDIR);
+            } else if !dir.is_directory() {
+                return Err(libc::ENOENT);
+            }
+            let mut listing = Vec::with_capacity(2 + dir.child_count());
+            listing.extend([
+                DirectoryEntry {
+                    name: ".".into(),
+                    kind: FileType::Directory,
+                },
+                DirectoryEntry {
+                    name: "..".into(),
+                    kind: FileType::Directory,
+                },
+            ]);
+            let children = dir
+                .children_sorted(&store.arena)
+                .into_iter()
+                //.unique()
+                .filter_map(|(name, entry)| {
+                    //let entry = store.entries.get(id).unwrap();
+                    info!(
+                        path = debug(&path),
+                        name = debug(&name),
+                        entry = debug(&entry),
+                        "child"
+                    );
+                    if entry.is_directory() {
+                        Some((FileType::Directory, name))
+                    } else if entry.is_file() {
+                        Some((self.file_kind(), name))
+                    } else {
+                        None
+                    }
+                })
+                .fold(listing, |mut acc, (kind, name)| {
+                    acc.push(DirectoryEntry {
+                        name: name.clone(),
+                        kind,
+                    });
+                    acc
+                });
+
+            debug!(
+                req = debug(req),
+                path = debug(path),
+                children = debug(&children),
+                fh,
+                "readdir"
+            );
+            Ok(children)
+        })
+    }
+
+    fn releasedir(&self, req: RequestInfo, path: &Path, fh: u64, flags: u32) -> ResultEmpty {
+        debug!(
+            req = debug(req),
+            path = debug(path),
+            fh,
+            "releasedir (flags = {:#o})",
+            flags
+        );
+        self.dir_handles.lock().unwrap().remove(&fh);
+        Ok(())
+    }
+
+    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        debug!(
+            req = debug(req),
+            path = debug(path),
+            "open (flags = {:#o})",
+            flags
+        );
+        self.timed("open", path, || {
+            let open_flags: i32 = flags.try_into().unwrap();
+            let host_path = {
+                let store = self.store.read();
+                // The kernel sends `opendir` for directories, but a client going through
+                // `open`, e.g. to `fdopendir` the result, gets a directory handle the same
+                // way so long as it doesn't ask to write.
+                if store.find_dir(path).is_some() {
+                    return match open_flags & libc::O_ACCMODE {
+                        libc::O_RDONLY => Ok((self.new_dir_handle(), flags)),
+                        _ => Err(libc::EISDIR),
+                    };
+                }
+                let Some(entry) = store.entry(path) else {
+                    return Err(libc::ENOENT);
+                };
+                debug!(size = entry.size, mime = entry.mime, "open entry");
+                entry.host_path.to_owned()
+            };
+            let shared = Some((host_path.clone(), open_flags));
+            let join = |handles: &mut HostHandles| {
+                let fh = *handles.by_file.get(shared.as_ref()?)?;
+                let handle = handles.handles.get_mut(&fh)?;
+                handle.holders += 1;
+                debug!(fh, holders = handle.holders, "shared");
+                Some(fh)
+            };
+            let recycled = {
+                let mut handles = self.host_handles.lock().unwrap();
+                if let Some(fh) = join(&mut handles) {
+                    return Ok((fh, flags));
+                }
+                let recycled = self.make_room(&mut handles);
+                handles.open_fds += 1;
+                recycled
+            };
+            self.close_recycled(recycled);
+            let opened = self.libc_wrapper.open(host_path.to_owned(), open_flags);
+            let mut handles = self.host_handles.lock().unwrap();
+            let fd = match opened {
+                Ok(fd) => fd,
+                Err(e) => {
+                    handles.open_fds -= 1;
+                    return Err(e.raw_os_error().unwrap_or(libc::ENOENT));
+                }
+            };
+            // Another open of the same file may have got in while this one was opening.
+            if let Some(fh) = join(&mut handles) {
+                handles.open_fds -= 1;
+                drop(handles);
+                self.close_recycled(vec![fd]);
+                return Ok((fh, flags));
+            }
+            handles.next_handle += 1;
+            handles.clock += 1;
+            let fh = handles.next_handle;
+            if let Some(key) = &shared {
+                handles.by_file.insert(key.clone(), fh);
+            }
+            let handle = HostHandle {
+                host_path,
+                shared,
+                reopen_flags: open_flags & !(libc::O_TRUNC | libc::O_CREAT | libc::O_EXCL),
+                fd: Some(fd),
+                holders: 1,
+                in_use: 0,
+                last_used: handles.clock,
+                unlinked: false,
+            };
+            handles.handles.insert(fh, handle);
+            drop(handles);
+            debug!(fh, fd, "opened");
+            Ok((fh, flags))
+        })
+    }
+
+    fn read(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
+    ) -> CallbackResult {
+        debug!(
+            req = debug(req),
+            path = debug(path),
+            fh,
+            offset,
+            size,
+            "read"
+        );
+        self.timed("read", path, || {
+            self.read_file(path, fh, offset, size, callback)
+        })
+    }
+
+    /// Writes to the host file in place. The entry is re-sized when the handle is finally
+    /// released, not on every write; its type, as sniffed when it was indexed, is kept.
+    fn write(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        data: Vec<u8>,
+        flags: u32,
+    ) -> ResultWrite {
+        debug!(
+            req = debug(req),
+            path = debug(path),
+            fh,
+            offset,
+            size = data.len(),
+            "write (flags = {:#o})",
+            flags
+        );
+        if fh == 0 {
+            return Err(libc::ENOENT);
+        }
+        self.timed("write", path, || {
+            let offset = offset.try_into().map_err(|_| libc::EINVAL)?;
+            let written = self
+                .with_fd(fh, |fd| self.libc_wrapper.write(fd, offset, &data))
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+            // Other handles on the file may have read what was overwritten, too.
+            let host_path = self
+                .host_handles
+                .lock()
+                .unwrap()
+                .handles
+                .get(&fh)
+                .map(|handle| handle.host_path.to_owned());
+            if let Some(host_path) = host_path {
+                Self::forget_host_data(
+                    &self.host_handles,
+                    &self.read_cache,
+                    &self.readahead,
+                    &host_path,
+                );
+            }
+            self.written.lock().unwrap().insert(fh);
+            Ok(written as u32)
+        })
+    }
+
+    /// Also how `O_TRUNC` opens truncate: without atomic `O_TRUNC` asked for at init,
+    /// the kernel drops the flag from `open` and sets the size to 0 here afterwards.
+    fn truncate(&self, req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+        debug!(req = debug(req), path = debug(path), fh, size, "truncate");
+        self.timed("truncate", path, || {
+            let length = size.try_into().map_err(|_| libc::EINVAL)?;
+            let host_path = match fh {
+                Some(fh) => {
+                    self.with_fd(fh, |fd| self.libc_wrapper.ftruncate(fd, length))
+                        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+                    self.host_handles
+                        .lock()
+                        .unwrap()
+                        .handles
+                        .get(&fh)
+                        .map(|handle| handle.host_path.to_owned())
+                }
+                None => {
+                    let host_path = {
+                        let store = self.store.read();
+                        if store.find_dir(path).is_some() {
+                            return Err(libc::EISDIR);
+                        }
+                        let Some(entry) = store.entry(path) else {
+                            return Err(libc::ENOENT);
+                        };
+                        entry.host_path.to_owned()
+                    };
+                    self.libc_wrapper
+                        .truncate(host_path.to_owned(), length)
+                        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+                    Some(host_path)
+                }
+            };
+            if let Some(host_path) = host_path {
+                Self::forget_host_data(
+                    &self.host_handles,
+                    &self.read_cache,
+                    &self.readahead,
+                    &host_path,
+                );
+            }
+            self.store.write().resized(path, size);
+            Ok(())
+        })
+    }
+
+    fn flush(&self, req: RequestInfo, path: &Path, fh: u64, lock_owner: u64) -> ResultEmpty {
+        debug!(
+            req = debug(req),
+            path = debug(path),
+            fh,
+            lock_owner,
+            "flush"
+        );
+        Err(libc::ENOSYS)
+    }
+
+    fn release(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> ResultEmpty {
+        debug!(
+            req = debug(req),
+            path = debug(path),
+            fh,
+            lock_owner,
+            flush,
+            "release (flags = {:#o})",
+            flags
+        );
+        if fh == 0 {
+            return Err(libc::ENOENT);
+        }
+        // From `open` on a directory.
+        if self.dir_handles.lock().unwrap().remove(&fh) {
+            return Ok(());
+        }
+        let handle = {
+            let mut handles = self.host_handles.lock().unwrap();
+            if let Some(handle) = handles
+                .handles
+                .get_mut(&fh)
+                .filter(|handle| handle.holders > 1)
+            {
+                handle.holders -= 1;
+                debug!(fh, holders = handle.holders, "still held");
+                return Ok(());
+            }
+            let Some(handle) = handles.handles.remove(&fh) else {
+                return Err(libc::EBADF);
+            };
+            if let Some(key) = &handle.shared {
+                handles.by_file.remove(key);
+            }
+            if handle.fd.is_some() {
+                handles.open_fds -= 1;
+            }
+            handle
+        };
+        self.read_cache.lock().unwrap().invalidate(fh);
+        self.readahead.lock().unwrap().invalidate(fh);
+        if self.written.lock().unwrap().remove(&fh) {
+            let stat = match handle.fd {
+                Some(fd) => self.libc_wrapper.fstat(fd as u64),
+                None => self.libc_wrapper.lstat(handle.host_path.to_owned()),
+            };
+            match stat {
+                Ok(stat) => self.store.write().resized(path, stat.st_size as u64),
+                Err(e) => debug!(
+                    path = debug(path),
+                    error = display(e),
+                    "no size after write"
+                ),
+            }
+        }
+        match handle.fd.map(|fd| self.libc_wrapper.close(fd)) {
+            None | Some(Ok(_)) => Ok(()),
+            Some(Err(e)) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
+        }
+    }
+
+    fn mkdir(
+        &self,
+        req: RequestInfo,
+        parent: &Path,
+        name: &std::ffi::OsStr,
+        mode: u32,
+    ) -> ResultEntry {
+        info!(
+            req = debug(req),
+            parent = debug(parent),
+            name = debug(name),
+            mode,
+            "mkdir",
+        );
+        let path = parent.join(name);
+        {
+            let mut store = self.store.write();
+            if !store.find(parent).is_directory() {
+                return Err(libc::ENOENT);
+            }
+            let existing = store.find(&path);
+            if existing.is_file() || existing.is_directory() {
+                return Err(libc::EEXIST);
+            }
+            if let Some(host_dir) = self.host_dir_below_root(&store, &path) {
+                match self.libc_wrapper.mkdir(host_dir, mode) {
+                    // A host directory with nothing the pattern could place.
+                    Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {}
+                    Err(e) => return Err(e.raw_os_error().unwrap_or(libc::EIO)),
+                    Ok(()) => {}
+                }
+            }
+            store.add_dir(&path).map_err(|e| arena_errno(&e))?;
+            store.debug_validate_integrity();
+        }
+        self.dir_attr().map(|attr| (TTL, attr))
+    }
+
+    fn rmdir(&self, req: RequestInfo, parent: &Path, name: &std::ffi::OsStr) -> ResultEmpty {
+        info!(
+            req = debug(req),
+            parent = debug(parent),
+            name = debug(name),
+            "rmdir",
+        );
+        let path = parent.join(name);
+        let mut store = self.store.write();
+        let dir = store.find(&path);
+        if dir.is_file() {
+            return Err(libc::ENOTDIR);
+        } else if !dir.is_directory() {
+            return Err(libc::ENOENT);
+        }
+        if store.child_count(&path) != Some(0) {
+            return Err(libc::ENOTEMPTY);
+        }
+        if let Some(host_dir) = self.host_dir_below_root(&store, &path) {
+            match self.libc_wrapper.rmdir(host_dir) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                Err(e) => return Err(e.raw_os_error().unwrap_or(libc::EIO)),
+                Ok(()) => {}
+            }
+        }
+        store.arena.remove(&path);
+        store.debug_validate_integrity();
+        Ok(())
+    }
+
+    /// Removes the host file, so every virtual name it has goes with it, not only `name`;
+    /// none is left dangling.
+    fn unlink(&self, req: RequestInfo, parent: &Path, name: &std::ffi::OsStr) -> ResultEmpty {
+        info!(
+            req = debug(req),
+            parent = debug(parent),
+            name = debug(name),
+            "unlink",
+        );
+        let mut path = parent.to_path_buf();
+        path.push(name);
+
+        let mut store = self.store.write();
+        store.find_file(&path).map_or_else(
+            || Err(libc::ENOENT),
+            |e| {
+                let entry = store.entries.get(&e).unwrap().to_owned();
+                info!(inode = debug(e), entry = debug(&entry), "get");
+                // Open handles keep reading the unlinked file, so need descriptors now.
+                let recycled = self
+                    .host_handles
+                    .lock()
+                    .unwrap()
+                    .unlinking(&entry.host_path);
+                for fh in recycled {
+                    if let Err(e) = self.with_fd(fh, |_| Ok(())) {
+                        warn!(fh, error = display(e), "handle lost to unlink");
+                    }
+                }
+                match self.libc_wrapper.unlink(entry.host_path.to_owned()) {
+                    Ok(_) => {
+                        info!("unlinked");
+                        if !entry.overrides.is_empty() {
+                            let _ = self
+                                .libc_wrapper
+                                .unlink(sidecar::sidecar_path(&entry.host_path));
+                        }
+                        store.arena.remove(&path);
+                        store.notify(StoreEvent::Removed(path.to_owned()));
+                        let dropped = store.remove_host_file(&entry.host_path);
+                        info!(dropped = debug(dropped), "dropped");
+                        store.debug_validate_integrity();
+                        Ok(())
+                    }
+                    Err(e) => Err(e.raw_os_error().unwrap_or(libc::ENOENT)),
+                }
+            },
+        )
+    }
+
+    fn rename(
+        &self,
+        req: RequestInfo,
+        parent: &Path,
+        name: &std::ffi::OsStr,
+        newparent: &Path,
+        newname: &std::ffi::OsStr,
+    ) -> ResultEmpty {
+        info!(
+            req = debug(req),
+            parent = debug(parent),
+            name = debug(name),
+            newparent = debug(newparent),
+            newname = debug(newname),
+            "rename",
+        );
+        let errno = |e: std::io::Error| e.raw_os_error().unwrap_or(libc::ENOENT);
+        let path = parent.join(name);
+        let newpath = newparent.join(newname);
+
+        let mut store = self.store.write();
+        let Some(id) = store.find_file(&path) else {
+            return Err(libc::ENOENT);
+        };
+        let target = store.find(&newpath);
+        if target.is_file() || target.is_directory() {
+            return Err(libc::EEXIST);
+        }
+        let mut entry = store.entries[&id].to_owned();
+
+        let had_sidecar = !entry.overrides.is_empty();
+        let mut pin_category = false;
+        if let Some(pinned) = &mut entry.pinned {
+            // Put where it is explicitly, so it goes wherever it's moved.
+            *pinned = newpath.to_owned();
+        } else if parent != newparent {
+            // A new category can only be expressed as pinned placeholder values.
+            let overrides = store
+                .category_overrides(parent, newparent)
+                .ok_or(libc::EXDEV)?;
+            entry.overrides.extend(overrides);
+            pin_category = true;
+        }
+        let old_host_path = entry.host_path.to_owned();
+        if name != newname {
+            // The host directory may hold a file of that name filed elsewhere in the view.
+            let host_path = entry.host_path.with_file_name(newname);
+            self.libc_wrapper
+                .rename_noreplace(entry.host_path.to_owned(), host_path.to_owned())
+                .map_err(errno)?;
+            self.host_handles
+                .lock()
+                .unwrap()
+                .renamed(&entry.host_path, &host_path);
+            // Any sidecar already there belonged to no file, so it may be replaced.
+            if had_sidecar {
+                let moved = self.libc_wrapper.rename(
+                    sidecar::sidecar_path(&entry.host_path),
+                    sidecar::sidecar_path(&host_path),
+                );
+                if let Err(e) = moved {
+                    // Put the file back too, so it stays with its sidecar and the store.
+                    let _ = self
+                        .libc_wrapper
+                        .rename(host_path.to_owned(), entry.host_path.to_owned());
+                    self.host_handles
+                        .lock()
+                        .unwrap()
+                        .renamed(&host_path, &entry.host_path);
+                    return Err(errno(e));
+                }
+            }
+            entry.host_path = host_path;
+            entry.name = newname.to_os_string();
+        }
+        if pin_category {
+            // Written once the file is where it's going, so a failed rename leaves none.
+            let written = self.libc_wrapper.write_file(
+                sidecar::sidecar_path(&entry.host_path),
+                sidecar::format(&entry.overrides).as_bytes(),
+            );
+            if let Err(e) = written {
+                if entry.host_path != old_host_path {
+                    if had_sidecar {
+                        let _ = self.libc_wrapper.rename(
+                            sidecar::sidecar_path(&entry.host_path),
+                            sidecar::sidecar_path(&old_host_path),
+                        );
+                    }
+                    let _ = self
+                        .libc_wrapper
+                        .rename(entry.host_path.to_owned(), old_host_path.to_owned());
+                    self.host_handles
+                        .lock()
+                        .unwrap()
+                        .renamed(&entry.host_path, &old_host_path);
+                }
+                return Err(errno(e));
+            }
+        }
+        info!(inode = debug(id), entry = debug(&entry), "renamed");
+        store.insert_entry(id, entry);
+        store.refile(id, &path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, io, path::PathBuf};
+
+    use tracing_test::traced_test;
+
+    use libc_wrapper::MockLibcWrapper;
+
+    use crate::common::mock_traits::{MockDirEntry, MockMetadata};
+    use crate::libc_wrapper;
+    use crate::rules::Rule;
+
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[instrument(ret, skip(libc_wrapper))]
+    fn new_test_fs(libc_wrapper: impl LibcWrapper + Send + Sync + 'static) -> OrganizeFS {
+        let root = PathBuf::from("/");
+        let pattern = PathBuf::from("/");
+        let store = Arc::new(parking_lot::RwLock::new(OrganizeFSStore::new(pattern)));
+        let libc_wrapper = Box::new(libc_wrapper);
+        OrganizeFS {
+            root,
+            options: ScanOptions::default(),
+            store,
+            libc_wrapper,
+            shutdown_signal: Mutex::new(None),
+            statfs_ttl: STATFS_TTL,
+            statfs_cache: Mutex::new(None),
+            root_stat_cache: Mutex::new(None),
+            slow_op_threshold: SLOW_OP_THRESHOLD,
+            read_cache: Arc::new(Mutex::new(ReadCache::new(DEFAULT_READ_CACHE_BYTES))),
+            readahead: Arc::new(Mutex::new(Readahead::new(DEFAULT_READAHEAD_BYTES))),
+            read_metrics: Arc::default(),
+            umask: DEFAULT_UMASK,
+            symlink_view: false,
+            dir_handles: Mutex::new(HashSet::new()),
+            next_dir_handle: AtomicU64::new(DIR_HANDLE_BASE),
+            scanned_dirs: Mutex::new(HashMap::new()),
+            host_handles: Arc::new(Mutex::new(HostHandles::default())),
+            max_open_fds: DEFAULT_MAX_OPEN_FDS,
+            written: Mutex::new(HashSet::new()),
+            host_watcher: Mutex::new(None),
+        }
+    }
+
+    /// Register the host descriptor `fd` as an open handle, for tests that skip `open`.
+    fn test_handle(fs: &OrganizeFS, fd: i32) -> u64 {
+        let mut handles = fs.host_handles.lock().unwrap();
+        handles.next_handle += 1;
+        handles.open_fds += 1;
+        let fh = handles.next_handle;
+        let handle = HostHandle {
+            host_path: PathBuf::from("/host/test"),
+            shared: None,
+            reopen_flags: libc::O_RDONLY,
+            fd: Some(fd),
+            holders: 1,
+            in_use: 0,
+            last_used: 0,
+            unlinked: false,
+        };
+        handles.handles.insert(fh, handle);
+        fh
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_new() {
+        let root = PathBuf::from("/test/data/path");
+        let entry = {
+            let mut entry = MockDirEntry::new();
+            entry.expect_path().return_const(PathBuf::from("path/"));
+            entry
+                .expect_file_name()
+                .return_const(OsString::from("file"));
+            entry
+        };
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata
+                .expect_len()
+                .return_const(1024_u64 * 1024 * 1024 * 100);
+            metadata.expect_modified().returning(|| {
+                Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(40 * 365 * 24 * 60 * 60))
+            });
+            metadata
+        };
+        let entry = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+        assert_eq!(format_size(&entry.size), "107.37GB");
+        assert_eq!(entry.name, "file");
+        assert_eq!(entry.host_path, PathBuf::from("/test/data/path/path"));
+        assert_eq!(entry.modified_date, "2009-12-22");
+        assert_eq!(entry.mime, "");
+        assert_eq!(entry.mime_type, "unknown");
+        assert_eq!(entry.mime_subtype, "unknown");
+        assert_eq!(entry.extension, NO_EXTENSION);
+        assert!(entry.dirs.is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn mime_hierarchy() {
+        assert_eq!(split_mime("image/jpeg"), ("image".into(), "jpeg".into()));
+        assert_eq!(split_mime(""), ("unknown".into(), "unknown".into()));
+        assert_eq!(split_mime("text/"), ("text".into(), "unknown".into()));
+
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{mime_type}/{mime_subtype}"));
+        store.add_test_entry("a.jpg", "/host/a.jpg", "image_jpeg");
+        store.add_test_entry("b.png", "/host/b.png", "image_png");
+        store.add_test_entry("c.txt", "/host/c.txt", "text_plain");
+        store.add_test_entry("d", "/host/d", "");
+        assert_eq!(
+            store.leaf_paths(),
+            vec![
+                PathBuf::from("/image/jpeg/a.jpg"),
+                PathBuf::from("/image/png/b.png"),
+                PathBuf::from("/text/plain/c.txt"),
+                PathBuf::from("/unknown/unknown/d"),
+            ]
+        );
+        // `{meta}` still sees the whole type.
+        store.set_pattern("/{meta}");
+        assert!(store.find(Path::new("/image_jpeg/a.jpg")).is_file());
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_extension() {
+        let root = PathBuf::from("/test/root");
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_len().return_const(0_u64);
+            metadata
+                .expect_modified()
+                .returning(|| Ok(SystemTime::UNIX_EPOCH));
+            metadata
+        };
+        let pattern = PathBuf::from("/{ext}");
+        for (name, extension) in [
+            ("IMG_0001.JPG", "jpg"),
+            ("photo.jpg", "jpg"),
+            ("archive.tar.gz", "gz"),
+            ("README", NO_EXTENSION),
+            (".bashrc", NO_EXTENSION),
+        ] {
+            let entry = {
+                let mut entry = MockDirEntry::new();
+                entry.expect_path().return_const(PathBuf::from(name));
+                entry.expect_file_name().return_const(OsString::from(name));
+                entry
+            };
+            let entry = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+            assert_eq!(entry.extension, extension, "{name}");
+            assert_eq!(
+                entry.local_path(&pattern, DEFAULT_UNSORTED_DIR),
+                Path::new("/").join(extension).join(name)
+            );
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_size_exact() {
+        let root = PathBuf::from("/test/root");
+        let entry = {
+            let mut entry = MockDirEntry::new();
+            entry.expect_path().return_const(PathBuf::from("file"));
+            entry
+                .expect_file_name()
+                .return_const(OsString::from("file"));
+            entry
+        };
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_len().return_const(12288_u64);
+            metadata
+                .expect_modified()
+                .returning(|| Ok(SystemTime::UNIX_EPOCH));
+            metadata
+        };
+        let entry = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+        assert_eq!(
+            entry.local_path(
+                &PathBuf::from("/{size_exact}/{size}/{size_bucket}"),
+                DEFAULT_UNSORTED_DIR
+            ),
+            Path::new("/12288/12.29kB/1kB-1MB/file")
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn process_only_regular_files() {
+        let root = PathBuf::from("/test/root");
+        let entry = |name: &str| {
+            let mut entry = MockDirEntry::new();
+            entry.expect_path().return_const(root.join(name));
+            entry.expect_file_name().return_const(OsString::from(name));
+            entry
+        };
+        let meta = |kind: FileKind| {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_file_type().return_const(kind);
+            metadata.expect_len().return_const(3_u64);
+            metadata
+                .expect_modified()
+                .returning(|| Ok(SystemTime::UNIX_EPOCH));
+            metadata
+        };
+        let options = ScanOptions::default();
+
+        let file = OrganizeFS::process_with(&root, &entry("file"), &meta(FileKind::File), &options);
+        assert_eq!(file.map(|file| file.name), Some(OsString::from("file")));
+        for (name, kind) in [
+            ("fifo", FileKind::Fifo),
+            ("link", FileKind::Symlink),
+            ("socket", FileKind::Socket),
+            ("dir", FileKind::Dir),
+        ] {
+            let processed = OrganizeFS::process_with(&root, &entry(name), &meta(kind), &options);
+            assert!(processed.is_none(), "{name}");
+        }
+    }
+
+    #[test]
+    fn organize_fsentry_keys() {
+        let keys = OrganizeFSEntry::keys();
+        for key in ["meta", "size", "size_exact", "mdate", "path_hash"] {
+            assert!(keys.contains(&key), "{key}");
+        }
+        assert_eq!(OrganizeFSEntry::key_prefixes(), ["dir"]);
+        assert!(crate::common::is_known_key::<OrganizeFSEntry>("dir12"));
+        assert!(!crate::common::is_known_key::<OrganizeFSEntry>("colour"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_path_hash() {
+        let root = PathBuf::from("/test/root");
+        let entry = {
+            let mut entry = MockDirEntry::new();
+            entry.expect_path().return_const(PathBuf::from("file"));
+            entry
+                .expect_file_name()
+                .return_const(OsString::from("file"));
+            entry
+        };
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_len().return_const(0_u64);
+            metadata
+                .expect_modified()
+                .returning(|| Ok(SystemTime::UNIX_EPOCH));
+            metadata
+        };
+        let pattern = PathBuf::from("/{path_hash}");
+        let first = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+        let again = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+        let bucket = path_hash(Path::new("/test/root/file"), DEFAULT_PATH_HASH_LEN);
+        assert_eq!(bucket.len(), 2);
+        assert_eq!(
+            first.local_path(&pattern, DEFAULT_UNSORTED_DIR),
+            Path::new("/").join(&bucket).join("file")
+        );
+        assert_eq!(
+            again.local_path(&pattern, DEFAULT_UNSORTED_DIR),
+            first.local_path(&pattern, DEFAULT_UNSORTED_DIR)
+        );
+
+        let options = ScanOptions {
+            path_hash_len: 5,
+            ..ScanOptions::default()
+        };
+        let longer = OrganizeFSEntry::new(&root, &entry, &meta, &options);
+        assert_eq!(longer.path_hash.len(), 5);
+        assert!(longer.path_hash.starts_with(&bucket));
+        assert_eq!(path_hash(Path::new("/a"), 0).len(), 1);
+        assert_eq!(path_hash(Path::new("/a"), 99).len(), 16);
+    }
+
+    #[test]
+    fn path_hash_distribution() {
+        let mut buckets = HashMap::new();
+        for i in 0..256 * 16 {
+            let path = PathBuf::from(format!("/photos/{}/img_{i:05}.jpg", i % 7));
+            *buckets
+                .entry(path_hash(&path, DEFAULT_PATH_HASH_LEN))
+                .or_insert(0) += 1;
+        }
+        // 16 files a bucket on average; nowhere near all of them in a few.
+        assert!(buckets.len() > 240, "{} buckets used", buckets.len());
+        let fullest = buckets.values().max().unwrap();
+        assert!(*fullest < 48, "{fullest} files in one bucket");
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_date_parts() {
+        let root = PathBuf::from("/test/root");
+        let entry = {
+            let mut entry = MockDirEntry::new();
+            entry.expect_path().return_const(PathBuf::from("file"));
+            entry
+                .expect_file_name()
+                .return_const(OsString::from("file"));
+            entry
+        };
+        // No modification time: the epoch, like `{mdate}`.
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_len().return_const(0_u64);
+            metadata
+                .expect_modified()
+                .returning(|| Err(std::io::ErrorKind::Unsupported.into()));
+            metadata
+        };
+        let entry = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+        assert_eq!(entry.modified_date, "1970-01-01");
+        assert_eq!(
+            entry.local_path(
+                &PathBuf::from("/{year}/{month}/{day}"),
+                DEFAULT_UNSORTED_DIR
+            ),
+            Path::new("/1970/01/01/file")
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_host_path() {
+        let root = PathBuf::from("/test/root");
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_len().return_const(0_u64);
+            metadata
+                .expect_modified()
+                .returning(|| Ok(SystemTime::UNIX_EPOCH));
+            metadata
+        };
+        for path in ["sub/./file.txt", "/test/root/sub/file.txt"] {
+            let entry = {
+                let mut entry = MockDirEntry::new();
+                entry.expect_path().return_const(PathBuf::from(path));
+                entry
+                    .expect_file_name()
+                    .return_const(OsString::from("file.txt"));
+                entry
+            };
+            let entry = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+            assert_eq!(
+                entry.host_path,
+                PathBuf::from("/test/root/sub/file.txt"),
+                "{path}"
+            );
+            assert_eq!(entry.dirs, vec!["sub"], "{path}");
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_dirs() {
+        let root = PathBuf::from("/test/root");
+        let entry = {
+            let mut entry = MockDirEntry::new();
+            entry
+                .expect_path()
+                .return_const(PathBuf::from("/test/root/photos/2023/march/file.jpg"));
+            entry
+                .expect_file_name()
+                .return_const(OsString::from("file.jpg"));
+            entry
+        };
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_len().return_const(0_u64);
+            metadata
+                .expect_modified()
+                .returning(|| Ok(SystemTime::UNIX_EPOCH));
+            metadata
+        };
+        let entry = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+        assert_eq!(entry.dirs, vec!["photos", "2023", "march"]);
+        assert_eq!(
+            entry.local_path(Path::new("/{dir1}/{dir2}"), DEFAULT_UNSORTED_DIR),
+            PathBuf::from("/photos/2023/file.jpg")
+        );
+        assert_eq!(
+            entry.local_path(Path::new("/{dir4}"), DEFAULT_UNSORTED_DIR),
+            PathBuf::from("/unknown/file.jpg")
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn local_path_optional() {
+        let nested = OrganizeFSEntry {
+            name: "song.mp3".into(),
+            mime: "audio_mpeg".into(),
+            dirs: vec!["albums".into()],
+            ..Default::default()
+        };
+        let loose = OrganizeFSEntry {
+            name: "song.mp3".into(),
+            mime: "audio_mpeg".into(),
+            ..Default::default()
+        };
+        let pattern = Path::new("/{meta}/{dir1?}");
+        assert_eq!(
+            nested.local_path(pattern, DEFAULT_UNSORTED_DIR),
+            PathBuf::from("/audio_mpeg/albums/song.mp3")
+        );
+        assert_eq!(
+            loose.local_path(pattern, DEFAULT_UNSORTED_DIR),
+            PathBuf::from("/audio_mpeg/song.mp3")
+        );
+        // A required placeholder with no value still sends the entry to `_unsorted`.
+        assert_eq!(
+            loose.local_path(Path::new("/{dir1?}/{mdate}"), DEFAULT_UNSORTED_DIR),
+            PathBuf::from("/_unsorted/song.mp3")
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn sniff_mime_limit() {
+        let path = std::env::temp_dir().join(format!("organizefs-sniff-{}", std::process::id()));
+        let mut contents = b"just some text\n".to_vec();
+        let text_len = contents.len();
+        contents.extend_from_slice(&[0_u8; 64]);
+        fs::write(&path, &contents).unwrap();
+
+        assert_eq!(sniff_mime(&path, text_len), "text/plain");
+        assert_eq!(
+            sniff_mime(&path, DEFAULT_SNIFF_BYTES),
+            "application/octet-stream"
+        );
+        assert_eq!(sniff_mime(Path::new("/nonexistent/file"), 16), "");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn within_filesystem() {
+        let root = std::env::temp_dir().join(format!("organizefs-xdev-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file"), "a").unwrap();
+        let dev = fs::metadata(&root).unwrap().dev();
+        let sub = WalkDir::new(&root)
+            .into_iter()
+            .flatten()
+            .find(|entry| entry.file_name() == "sub")
+            .unwrap();
+
+        assert!(OrganizeFS::within_filesystem(None, &sub));
+        assert!(OrganizeFS::within_filesystem(Some(dev), &sub));
+        assert!(!OrganizeFS::within_filesystem(
+            Some(dev.wrapping_add(1)),
+            &sub
+        ));
+
+        let options = ScanOptions {
+            one_filesystem: true,
+            ..ScanOptions::default()
+        };
+        assert_eq!(OrganizeFS::scan(&root, &options).count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn scan_excluded_dirs() {
+        let root = std::env::temp_dir().join(format!("organizefs-excl-{}", std::process::id()));
+        fs::create_dir_all(root.join(".git/objects")).unwrap();
+        fs::create_dir_all(root.join("src/node_modules/pkg")).unwrap();
+        fs::write(root.join(".git/objects/ab"), "x").unwrap();
+        fs::write(root.join("src/node_modules/pkg/index.js"), "x").unwrap();
+        fs::write(root.join("src/main.rs"), "x").unwrap();
+        // Only directories are excluded by name.
+        fs::write(root.join("target"), "x").unwrap();
+
+        let options = ScanOptions::default();
+        let walked = OrganizeFS::walk(&root, &root, usize::MAX, &options)
+            .map(|entry| entry.into_path())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            walked,
+            ["", "src", "src/main.rs", "target"].map(|path| root.join(path))
+        );
+        let store = parking_lot::RwLock::new(OrganizeFSStore::new(PathBuf::from("/")));
+        assert_eq!(OrganizeFS::rescan(&root, &options, &store), 2);
+
+        let options = ScanOptions {
+            excluded_dirs: vec![],
+            ..ScanOptions::default()
+        };
+        assert_eq!(OrganizeFS::rescan(&root, &options, &store), 4);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn suggest_pattern_for_images() {
+        let root = std::env::temp_dir().join(format!("organizefs-suggest-{}", std::process::id()));
+        fs::create_dir_all(root.join("camera")).unwrap();
+        fs::create_dir_all(root.join("phone")).unwrap();
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        let jpeg = b"\xff\xd8\xff\xe0\0\x10JFIF\0".to_vec();
+        fs::write(root.join("camera/1.jpg"), &jpeg).unwrap();
+        fs::write(root.join("camera/2.jpg"), &jpeg).unwrap();
+        fs::write(root.join("phone/3.png"), &png).unwrap();
+        fs::write(root.join("phone/notes.txt"), "text").unwrap();
+        let suggest =
+            || OrganizeFS::suggest_pattern(root.to_str().unwrap(), &ScanOptions::default());
+        assert_eq!(suggest(), Ok("/{meta}/{year}".to_string()));
+
+        // Sizes spanning buckets add a level for them.
+        let mut large = png.clone();
+        large.resize(2_000, 0);
+        fs::write(root.join("phone/4.png"), large).unwrap();
+        assert_eq!(suggest(), Ok("/{meta}/{year}/{size_bucket}".to_string()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn rescan_reuses_mime() {
+        let root = std::env::temp_dir().join(format!("organizefs-mime-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        fs::write(root.join("b.txt"), "world").unwrap();
+        let options = ScanOptions::default();
+        let first = OrganizeFS::scan(&root, &options).collect::<Vec<_>>();
+        assert_eq!(options.mime_cache.detections(), 2);
+
+        let again = OrganizeFS::scan(&root, &options.clone()).collect::<Vec<_>>();
+        assert_eq!(again, first);
+        assert_eq!(options.mime_cache.detections(), 2);
+
+        fs::write(root.join("a.txt"), "hello again").unwrap();
+        fs::remove_file(root.join("b.txt")).unwrap();
+        let store = parking_lot::RwLock::new(OrganizeFSStore::new(PathBuf::from("/")));
+        assert_eq!(OrganizeFS::rescan(&root, &options, &store), 1);
+        assert_eq!(options.mime_cache.detections(), 3);
+        assert_eq!(options.mime_cache.len(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn dry_run() {
+        let root = std::env::temp_dir().join(format!("organizefs-dry-{}", std::process::id()));
+        fs::create_dir_all(root.join("2023")).unwrap();
+        fs::write(root.join("2023/a.txt"), "hello").unwrap();
+        fs::write(root.join("b.txt"), "hi").unwrap();
+        let store = parking_lot::RwLock::new(OrganizeFSStore::new(PathBuf::from("/{dir1}/{ext}")));
+
+        let mut output = Vec::new();
+        let count = OrganizeFS::dry_run(
+            root.to_str().unwrap(),
+            &ScanOptions::default(),
+            &store,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "/2023/txt/a.txt\t{}\t5.00B\n/unknown/txt/b.txt\t{}\t2.00B\n",
+                root.join("2023/a.txt").display(),
+                root.join("b.txt").display()
+            )
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn scan_follow_links() {
+        let root = std::env::temp_dir().join(format!("organizefs-links-{}", std::process::id()));
+        let target = std::env::temp_dir().join(format!("organizefs-target-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(&target).unwrap();
+        fs::write(target.join("file"), "abc").unwrap();
+        std::os::unix::fs::symlink(target.join("file"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+
+        let host_paths = |options: &ScanOptions| {
+            OrganizeFS::scan(&root, options)
+                .map(|entry| (entry.host_path, entry.size))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(host_paths(&ScanOptions::default()), vec![]);
+        let options = ScanOptions {
+            follow_links: true,
+            ..ScanOptions::default()
+        };
+        assert_eq!(host_paths(&options), vec![(root.join("link"), 3)]);
+        assert!(logs_contain("not scanned"));
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_strip_prefix() {
+        let root = PathBuf::from("/test/root/photos");
+        let entry = {
+            let mut entry = MockDirEntry::new();
+            entry
+                .expect_path()
+                .return_const(PathBuf::from("/test/root/photos/2023/file.jpg"));
+            entry
+                .expect_file_name()
+                .return_const(OsString::from("file.jpg"));
+            entry
+        };
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_len().return_const(0_u64);
+            metadata
+                .expect_modified()
+                .returning(|| Ok(SystemTime::UNIX_EPOCH));
+            metadata
+        };
+
+        let default = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+        assert_eq!(default.dirs, vec!["2023"]);
+        assert_eq!(default.relative_path, PathBuf::from("2023/file.jpg"));
+
+        let options = ScanOptions {
+            strip_prefix: Some(PathBuf::from("/test")),
+            ..ScanOptions::default()
+        };
+        let stripped = OrganizeFSEntry::new(&root, &entry, &meta, &options);
+        assert_eq!(stripped.dirs, vec!["root", "photos", "2023"]);
+        assert_eq!(
+            stripped.relative_path,
+            PathBuf::from("root/photos/2023/file.jpg")
+        );
+        assert_eq!(
+            stripped.host_path,
+            PathBuf::from("/test/root/photos/2023/file.jpg")
+        );
+        assert_eq!(stripped.to_string(), "(root/photos/2023/file.jpg 0.00B)");
+
+        // A prefix that isn't an ancestor is ignored in favour of the scan root.
+        let options = ScanOptions {
+            strip_prefix: Some(PathBuf::from("/elsewhere")),
+            ..ScanOptions::default()
+        };
+        let unrelated = OrganizeFSEntry::new(&root, &entry, &meta, &options);
+        assert_eq!(unrelated.dirs, vec!["2023"]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn organize_fsentry_utc_offset() {
+        let root = PathBuf::from("/");
+        let entry = {
+            let mut entry = MockDirEntry::new();
+            entry.expect_path().return_const(PathBuf::from("/file"));
+            entry
+                .expect_file_name()
+                .return_const(OsString::from("file"));
+            entry
+        };
+        let meta = {
+            let mut metadata = MockMetadata::new();
+            metadata.expect_len().return_const(0_u64);
+            // 2023-08-04T23:30:00Z
+            metadata
+                .expect_modified()
+                .returning(|| Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(1_691_191_800)));
+            metadata
+        };
+        let utc = OrganizeFSEntry::new(&root, &entry, &meta, &ScanOptions::default());
+        assert_eq!(utc.modified_date, "2023-08-04");
+
+        let options = ScanOptions {
+            utc_offset: ScanOptions::parse_utc_offset("+01:00").unwrap(),
+            ..ScanOptions::default()
+        };
+        let ahead = OrganizeFSEntry::new(&root, &entry, &meta, &options);
+        assert_eq!(ahead.modified_date, "2023-08-05");
+        assert_eq!(
+            (
+                ahead.year.as_str(),
+                ahead.month.as_str(),
+                ahead.day.as_str()
+            ),
+            ("2023", "08", "05")
+        );
+
+        let options = ScanOptions {
+            utc_offset: ScanOptions::parse_utc_offset("-05:00").unwrap(),
+            ..ScanOptions::default()
+        };
+        let behind = OrganizeFSEntry::new(&root, &entry, &meta, &options);
+        assert_eq!(behind.modified_date, "2023-08-04");
+
+        assert!(ScanOptions::parse_utc_offset("01:00").is_err());
+    }
+
+    #[test]
+    #[traced_test]
+    fn mode_to_filetype() {
+        assert_eq!(
+            OrganizeFS::mode_to_filetype(libc::S_IFDIR + 0o644),
+            FileType::Directory
+        );
+        assert_eq!(
+            OrganizeFS::mode_to_filetype(libc::S_IFREG + 0o644),
+            FileType::RegularFile
+        );
+        assert_eq!(
+            OrganizeFS::mode_to_filetype(libc::S_IFLNK + 0o644),
+            FileType::Symlink
+        );
+        assert_eq!(
+            OrganizeFS::mode_to_filetype(libc::S_IFBLK + 0o644),
+            FileType::BlockDevice
+        );
+        assert_eq!(
+            OrganizeFS::mode_to_filetype(libc::S_IFCHR + 0o644),
+            FileType::CharDevice
+        );
+        assert_eq!(
+            OrganizeFS::mode_to_filetype(libc::S_IFIFO + 0o644),
+            FileType::NamedPipe
+        );
+        assert_eq!(
+            OrganizeFS::mode_to_filetype(libc::S_IFSOCK + 0o644),
+            FileType::Socket
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    #[should_panic(expected = "unknown file type")]
+    fn mode_to_filetype_err() {
+        OrganizeFS::mode_to_filetype(0);
+    }
+
+    #[test]
+    #[traced_test]
+    fn get_pattern() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        let store = fs.store.read();
+        assert_eq!("/", store.get_pattern());
+    }
+
+    #[test]
+    #[traced_test]
+    fn store_with_capacity() {
+        let mut plain = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        let mut sized = OrganizeFSStore::with_capacity(PathBuf::from("/{meta}"), 1000);
+        for store in [&mut plain, &mut sized] {
+            store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+            store.add_test_entry("2.txt", "/host/2.txt", "text_plain");
+        }
+        assert!(sized.entries.capacity() >= 1000);
+        assert_eq!(sized.get_pattern(), plain.get_pattern());
+        for path in ["/", "/image_jpeg", "/text_plain"] {
+            assert_eq!(
+                sized.list_dir(Path::new(path)),
+                plain.list_dir(Path::new(path))
+            );
+        }
+        assert_eq!(
+            sized.host_path(Path::new("/text_plain/2.txt")),
+            Some(PathBuf::from("/host/2.txt"))
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn set_pattern_round_trip() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/"));
+        for (set, normalized) in [
+            ("/{meta}", "/{meta}"),
+            ("/t/{meta}/", "/t/{meta}"),
+            ("/s/../t/{meta}/", "/t/{meta}"),
+            ("/./{meta}/./{size}", "/{meta}/{size}"),
+            ("/{meta}//{size}", "/{meta}/{size}"),
+            ("/a/b/../../{mdate}", "/{mdate}"),
+            ("/{dir1?}/./{meta}", "/{dir1?}/{meta}"),
+        ] {
+            store.set_pattern(set);
+            assert_eq!(store.get_pattern(), normalized, "{set:?}");
+            // What comes back can be sent again unchanged.
+            store.set_pattern(&store.get_pattern());
+            assert_eq!(store.get_pattern(), normalized, "{set:?}");
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn swap_pattern() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("a.txt", "/host/a.txt", "text_plain");
+
+        let old = store.swap_pattern("/{mime_type}");
+        assert_eq!(old.get_pattern(), "/{meta}");
+        assert_eq!(
+            old.leaf_paths(),
+            vec![
+                PathBuf::from("/image_jpeg/1.jpeg"),
+                PathBuf::from("/text_plain/a.txt")
+            ]
+        );
+        assert_eq!(store.get_pattern(), "/{mime_type}");
+        assert_eq!(
+            store.leaf_paths(),
+            vec![PathBuf::from("/image/1.jpeg"), PathBuf::from("/text/a.txt")]
+        );
+
+        // Unchanged: both sides show the same layout.
+        let old = store.swap_pattern("/{mime_type}/");
+        assert_eq!(old.leaf_paths(), store.leaf_paths());
+    }
+
+    #[test]
+    #[traced_test]
+    fn remove_host_dir() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("a.txt", "/host/sub/a.txt", "text_plain");
+        store.add_test_entry("b.txt", "/host/sub/deeper/b.txt", "text_plain");
+        store.add_test_entry("c.txt", "/host/sub2/c.txt", "text_plain");
+        store.add_test_entry("d.txt", "/host/sub.txt", "text_plain");
+        assert_eq!(store.remove_host_file(Path::new("/host/sub")).len(), 2);
+        assert_eq!(
+            store.leaf_paths(),
+            vec![
+                PathBuf::from("/text_plain/c.txt"),
+                PathBuf::from("/text_plain/d.txt")
+            ]
+        );
+        assert!(store.remove_host_file(Path::new("/host/sub")).is_empty());
+        store.debug_validate_integrity();
+    }
+
+    #[test]
+    #[traced_test]
+    fn paths_for_host() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("a.txt", "/host/a.txt", "text_plain");
+        store.add_test_entry("1.jpeg", "/host/other/1.jpeg", "image_jpeg");
+        assert_eq!(
+            store.paths_for_host(Path::new("/host/1.jpeg")),
+            vec![PathBuf::from("/image_jpeg/1.jpeg")]
+        );
+        assert_eq!(
+            store.paths_for_host(Path::new("/host/other/1.jpeg")),
+            vec![PathBuf::from("/image_jpeg/1 (2).jpeg")]
+        );
+        assert!(store.paths_for_host(Path::new("/host/missing")).is_empty());
+
+        store.set_pattern("/{mime_type}/{mime_subtype}");
+        assert_eq!(
+            store.paths_for_host(Path::new("/host/a.txt")),
+            vec![PathBuf::from("/text/plain/a.txt")]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn prune_missing() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_lstat()
+                .withf(|path| path == Path::new("/host/gone.txt"))
+                .times(1)
+                .returning(|_| Err(io::Error::from_raw_os_error(libc::ENOENT)));
+            libc_wrapper
+                .expect_lstat()
+                .withf(|path| path == Path::new("/host/1.jpeg"))
+                .times(1)
+                .returning(|_| {
+                    let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                    let stat = unsafe { s.assume_init_mut() };
+                    stat.st_mode = libc::S_IFREG + 0o0644;
+                    Ok(stat.to_owned())
+                });
+            libc_wrapper
+        };
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("gone.txt", "/host/gone.txt", "text_plain");
+        let store = parking_lot::RwLock::new(store);
+        assert_eq!(OrganizeFSStore::prune_missing(&store, &libc_wrapper), 1);
+        assert_eq!(
+            store.read().leaf_paths(),
+            vec![PathBuf::from("/image_jpeg/1.jpeg")]
+        );
+        // Its directory went with it.
+        assert!(!store.read().find(Path::new("/text_plain")).is_directory());
+    }
+
+    #[test]
+    #[traced_test]
+    fn iter_leaves() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("a.txt", "/host/a.txt", "text_plain");
+        store.add_test_entry("1.jpeg", "/host/other/1.jpeg", "image_jpeg");
+        let mut host_paths = store
+            .iter_leaves()
+            .map(|entry| entry.host_path.to_owned())
+            .collect::<Vec<_>>();
+        host_paths.sort();
+        assert_eq!(
+            host_paths,
+            vec![
+                PathBuf::from("/host/1.jpeg"),
+                PathBuf::from("/host/a.txt"),
+                PathBuf::from("/host/other/1.jpeg")
+            ]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn set_pattern() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        // Add file
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "present".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
+        // Alter pattern
+        {
+            let mut store = fs.store.write();
+            store.set_pattern("/t/{meta}/");
+        }
+        let store = fs.store.read();
+        assert_eq!("/t/{meta}", store.get_pattern());
+        assert_eq!(
+            store.leaf_paths(),
+            vec![PathBuf::from("/t/text_plain/present")]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn validate_pattern_parent_dir_policy() {
+        let store = OrganizeFSStore::new(PathBuf::from("/"));
+        assert!(store.validate_pattern("/../etc/{meta}").is_err());
+        assert!(store.validate_pattern("/s/../{meta}").is_ok());
+
+        let store = store.with_parent_dir_policy(ParentDirPolicy::Clamp);
+        assert!(store.validate_pattern("/../etc/{meta}").is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn store_events() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}/{size}"));
+        store.subscribe({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event.clone())
+        });
+        store.add_test_entry("a.txt", "/host/a.txt", "text_plain");
+        store.resized(Path::new("/text_plain/0.00B/a.txt"), 5);
+        store.remove_host_file(Path::new("/host/a.txt"));
+        store.set_pattern("/{meta}");
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                StoreEvent::Added("/text_plain/0.00B/a.txt".into()),
+                StoreEvent::Moved {
+                    from: "/text_plain/0.00B/a.txt".into(),
+                    to: "/text_plain/5.00B/a.txt".into(),
+                },
+                StoreEvent::Removed("/text_plain/5.00B/a.txt".into()),
+                StoreEvent::Rebuilt,
+            ]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn save_load() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("a.txt", "/host/a.txt", "text_plain");
+        store.add_test_entry("a.txt", "/host/sub/a.txt", "text_plain");
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        let mut saved = Vec::new();
+        store.save(&mut saved).unwrap();
+
+        let loaded = OrganizeFSStore::load(saved.as_slice()).unwrap();
+        assert_eq!(loaded.get_pattern(), "/{meta}");
+        assert_eq!(loaded.leaf_paths(), store.leaf_paths());
+        for path in store.leaf_paths() {
+            assert_eq!(loaded.entry(&path), store.entry(&path));
+        }
+
+        let mut other = OrganizeFSStore::new(PathBuf::from("/{mdate}"));
+        assert_eq!(other.restore(saved.as_slice()).unwrap(), 3);
+        assert_eq!(
+            other.leaf_paths(),
+            [
+                "/2023-08-04/1.jpeg",
+                "/2023-08-04/a (2).txt",
+                "/2023-08-04/a.txt"
+            ]
+            .map(PathBuf::from)
+        );
+        assert!(OrganizeFSStore::load(&b"{}"[..]).is_err());
+        let older = String::from_utf8(saved).unwrap().replacen(
+            &format!("\"version\":{SNAPSHOT_VERSION}"),
+            "\"version\":0",
+            1,
+        );
+        assert_eq!(
+            OrganizeFSStore::load(older.as_bytes())
+                .map(|_| ())
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn restored_store_rescans_changed() {
+        let root = std::env::temp_dir().join(format!("organizefs-restore-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        fs::write(root.join("b.txt"), "world").unwrap();
+        fs::write(root.join("d.txt"), "gone").unwrap();
+        let store = Arc::new(parking_lot::RwLock::new(OrganizeFSStore::new(
+            PathBuf::from("/{meta}"),
+        )));
+        let (tx, _rx) = tokio::sync::oneshot::channel();
+        OrganizeFS::new(root.to_str().unwrap(), store.clone(), tx);
+        {
+            let mut store = store.write();
+            let id = store.find_file(Path::new("/text_plain/a.txt")).unwrap();
+            let entry = store.entries[&id].to_owned();
+            store.remove_host_file(&root.join("a.txt"));
+            assert_eq!(store.insert_at(Path::new("/picked/a.txt"), entry), Ok(()));
+        }
+        let mut saved = Vec::new();
+        store.read().save(&mut saved).unwrap();
+
+        fs::write(root.join("b.txt"), "world, again").unwrap();
+        fs::remove_file(root.join("d.txt")).unwrap();
+        fs::write(root.join("c.txt"), "new").unwrap();
+        let restored = Arc::new(parking_lot::RwLock::new(
+            OrganizeFSStore::load(saved.as_slice()).unwrap(),
+        ));
+        let options = ScanOptions::default();
+        let (tx, _rx) = tokio::sync::oneshot::channel();
+        OrganizeFS::with_options(
+            root.to_str().unwrap(),
+            restored.clone(),
+            tx,
+            options.clone(),
+        );
+        assert_eq!(options.mime_cache.detections(), 2);
+        assert_eq!(
+            restored.read().leaf_paths(),
+            ["/picked/a.txt", "/text_plain/b.txt", "/text_plain/c.txt"].map(PathBuf::from)
+        );
+        assert_eq!(
+            restored
+                .read()
+                .entry(Path::new("/text_plain/b.txt"))
+                .map(|entry| entry.size),
+            Some(12)
+        );
+        assert!(restored
+            .read()
+            .host_path(Path::new("/picked/a.txt"))
+            .is_some());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn export_to_writer() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("b.txt", "/host/b.txt", "text_plain");
+        store.add_test_entry("2.jpeg", "/host/2.jpeg", "image_jpeg");
+        store.add_test_entry("a.txt", "/host/sub/a.txt", "text_plain");
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+
+        let mut manifest = Vec::new();
+        store.export_to_writer(&mut manifest).unwrap();
+        assert_eq!(
+            String::from_utf8(manifest).unwrap(),
+            "/image_jpeg/1.jpeg\t/host/1.jpeg\t0.00B\n\
+             /image_jpeg/2.jpeg\t/host/2.jpeg\t0.00B\n\
+             /text_plain/a.txt\t/host/sub/a.txt\t0.00B\n\
+             /text_plain/b.txt\t/host/b.txt\t0.00B\n"
+        );
+
+        // Tabs, newlines and backslashes in names can't split fields or lines.
+        let mut store = OrganizeFSStore::new(PathBuf::from("/"));
+        store.add_test_entry("a\tb\nc\\d", "/host/a\tb\nc\\d", "text_plain");
+        let mut manifest = Vec::new();
+        store.export_to_writer(&mut manifest).unwrap();
+        assert_eq!(
+            String::from_utf8(manifest).unwrap(),
+            "/a\\tb\\nc\\\\d\t/host/a\\tb\\nc\\\\d\t0.00B\n"
+        );
+        assert_eq!(
+            tsv_field(Path::new(OsStr::from_bytes(b"/bad\xff"))),
+            "/bad\\xff"
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn counter_per_directory() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}/{counter}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("2.jpeg", "/host/2.jpeg", "image_jpeg");
+        store.add_test_entry("a.txt", "/host/a.txt", "text_plain");
+        let filed = vec![
+            PathBuf::from("/image_jpeg/0/1.jpeg"),
+            PathBuf::from("/image_jpeg/1/2.jpeg"),
+            PathBuf::from("/text_plain/0/a.txt"),
+        ];
+        assert_eq!(store.leaf_paths(), filed);
+
+        // Re-filing goes in the order entries were added, so they get the same numbers.
+        store.set_pattern("/{counter}/{meta}");
+        assert_eq!(
+            store.leaf_paths(),
+            vec![
+                PathBuf::from("/0/image_jpeg/1.jpeg"),
+                PathBuf::from("/1/image_jpeg/2.jpeg"),
+                PathBuf::from("/2/text_plain/a.txt"),
+            ]
+        );
+        store.set_pattern("/{meta}/{counter}");
+        assert_eq!(store.leaf_paths(), filed);
+
+        store.add_test_entry("3.jpeg", "/host/3.jpeg", "image_jpeg");
+        assert!(store.entry(Path::new("/image_jpeg/2/3.jpeg")).is_some());
+    }
+
+    #[test]
+    #[traced_test]
+    fn name_counts() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}/{dir1}/{meta}"));
+        for (name, dir, mime) in [
+            ("1.jpeg", "photos", "image_jpeg"),
+            ("2.jpeg", "image_jpeg", "image_jpeg"),
+            ("a.txt", "photos", "text_plain"),
+        ] {
+            store.add_entry(OrganizeFSEntry {
+                name: name.into(),
+                host_path: Path::new("/host").join(dir).join(name),
+                mime: mime.into(),
+                dirs: vec![dir.into()],
+                ..Default::default()
+            });
+        }
+        // /image_jpeg/{photos,image_jpeg}/image_jpeg, /text_plain/photos/text_plain
+        assert_eq!(
+            store.name_counts(),
+            vec![
+                NameCount {
+                    name: "image_jpeg".into(),
+                    count: 4,
+                    depths: vec![1, 2, 3],
+                },
+                NameCount {
+                    name: "photos".into(),
+                    count: 2,
+                    depths: vec![2],
+                },
+                NameCount {
+                    name: "text_plain".into(),
+                    count: 2,
+                    depths: vec![1, 3],
+                },
+            ]
+        );
+        assert!(OrganizeFSStore::new(PathBuf::from("/"))
+            .name_counts()
+            .is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn entry() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+
+        let entry = store.entry(Path::new("/image_jpeg/1.jpeg")).unwrap();
+        assert_eq!(entry.name, "1.jpeg");
+        assert_eq!(entry.host_path, PathBuf::from("/host/1.jpeg"));
+        assert_eq!(entry.mime, "image_jpeg");
+        assert_eq!(entry.size, 0);
+        assert!(store.entry(Path::new("/image_jpeg")).is_none());
+        assert!(store.entry(Path::new("/image_jpeg/missing")).is_none());
+    }
+
+    #[test]
+    #[traced_test]
+    fn unsorted_dir() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}/{mdate}"));
+        store.add_test_entry("known.txt", "/host/known.txt", "text_plain");
+        store.add_test_entry("undetected", "/host/undetected", "");
+        assert!(store
+            .host_path(Path::new("/text_plain/2023-08-04/known.txt"))
+            .is_some());
+        assert_eq!(
+            store.host_path(Path::new("/_unsorted/undetected")),
+            Some(PathBuf::from("/host/undetected"))
+        );
+
+        // Literal components aren't placeholders, so never send an entry to the catch-all.
+        let mut store =
+            OrganizeFSStore::new(PathBuf::from("/all/{dir1}")).with_unsorted_dir("misc");
+        store.add_test_entry("undetected", "/host/undetected", "");
+        assert!(store
+            .host_path(Path::new("/all/unknown/undetected"))
+            .is_some());
+        store.set_pattern("/{meta}");
+        assert!(store.host_path(Path::new("/misc/undetected")).is_some());
+    }
+
+    #[test]
+    #[traced_test]
+    fn suggest_pattern() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("2.png", "/host/2.png", "image_png");
+        assert_eq!(store.suggest_pattern(), Ok("/{meta}/{year}".to_string()));
+    }
+
+    #[test]
+    #[traced_test]
+    fn nth_child() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("a.txt", "/host/a.txt", "text_plain");
+        store.add_test_entry("b.txt", "/host/b.txt", "text_plain");
+        store.add_test_entry("c.txt", "/host/c.txt", "text_plain");
+
+        let dir = Path::new("/text_plain");
+        assert_eq!(store.child_count(dir), Some(3));
+        assert_eq!(
+            store.nth_child(dir, 0),
+            Some(("a.txt".into(), FileType::RegularFile))
+        );
+        assert_eq!(
+            store.nth_child(dir, 1),
+            Some(("b.txt".into(), FileType::RegularFile))
+        );
+        assert_eq!(store.nth_child(dir, 3), None);
+        assert_eq!(
+            store.nth_child(Path::new("/"), 0),
+            Some(("image_jpeg".into(), FileType::Directory))
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn rebuild_with() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("a", "/host/a", "text_plain");
+        store.add_test_entry("b", "/host/b", "image_jpeg");
+        assert!(store.find(Path::new("/text_plain/a")).is_file());
+
+        store.rebuild_with(|entry| {
+            let mut entry = entry.clone();
+            if entry.mime == "text_plain" {
+                entry.mime = "text_markdown".into();
+            }
+            entry
+        });
+        assert_eq!(store.entries.len(), 2);
+        assert!(!store.find(Path::new("/text_plain")).is_directory());
+        assert!(store.find(Path::new("/text_markdown/a")).is_file());
+        assert!(store.find(Path::new("/image_jpeg/b")).is_file());
+    }
+
+    #[test]
+    #[traced_test]
+    fn try_add_entry_conflict() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{dir1?}"));
+        let entry = |name: &str, dirs: &[&str]| OrganizeFSEntry {
+            name: name.into(),
+            host_path: PathBuf::from("/host").join(dirs.join("/")).join(name),
+            dirs: dirs.iter().map(|dir| dir.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(store.try_add_entry(entry("photos", &[])).is_ok());
+        assert!(matches!(
+            store.try_add_entry(entry("a.jpg", &["photos"])),
+            Err(ArenaError::ParentIsLeaf)
+        ));
+        assert!(store.try_add_entry(entry("b.jpg", &["docs"])).is_ok());
+        assert!(matches!(
+            store.try_add_entry(entry("docs", &[])),
+            Err(ArenaError::Exists(_))
+        ));
+        assert_eq!(store.entries.len(), 2);
+        assert!(store.find(Path::new("/photos")).is_file());
+        assert!(store.find(Path::new("/docs/b.jpg")).is_file());
+
+        // The infallible path used by scans logs and skips instead of panicking.
+        store.add_entry(entry("c.jpg", &["photos"]));
+        assert_eq!(store.entries.len(), 2);
+        assert!(logs_contain("not added"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn try_add_entry_duplicates() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("photo.jpg", "/host/a/photo.jpg", "image_jpeg");
+        store.add_test_entry("photo.jpg", "/host/b/photo.jpg", "image_jpeg");
+        store.add_test_entry("photo.jpg", "/host/c/photo.jpg", "image_jpeg");
+        store.add_test_entry("README", "/host/a/README", "image_jpeg");
+        store.add_test_entry("README", "/host/b/README", "image_jpeg");
+        assert_eq!(store.entries.len(), 5);
+        assert_eq!(
+            store.leaf_paths(),
+            vec![
+                PathBuf::from("/image_jpeg/README"),
+                PathBuf::from("/image_jpeg/README (2)"),
+                PathBuf::from("/image_jpeg/photo (2).jpg"),
+                PathBuf::from("/image_jpeg/photo (3).jpg"),
+                PathBuf::from("/image_jpeg/photo.jpg"),
+            ]
+        );
+        assert_eq!(
+            store.host_path(Path::new("/image_jpeg/photo (3).jpg")),
+            Some(PathBuf::from("/host/c/photo.jpg"))
+        );
+
+        // A number freed isn't handed out again until the tree is rebuilt.
+        store.remove_host_file(Path::new("/host/b/photo.jpg"));
+        store.add_test_entry("photo.jpg", "/host/d/photo.jpg", "image_jpeg");
+        assert_eq!(
+            store.host_path(Path::new("/image_jpeg/photo (4).jpg")),
+            Some(PathBuf::from("/host/d/photo.jpg"))
+        );
+
+        // Numbered the same way again when the tree is rebuilt.
+        store.set_pattern("/{mime_type}");
+        assert_eq!(
+            store.host_path(Path::new("/image/photo (2).jpg")),
+            Some(PathBuf::from("/host/c/photo.jpg"))
+        );
+        assert_eq!(
+            store.host_path(Path::new("/image/photo (3).jpg")),
+            Some(PathBuf::from("/host/d/photo.jpg"))
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn insert_at() {
+        let fs = new_test_fs(MockLibcWrapper::new());
+        *fs.store.write() = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        let entry = |name: &str| OrganizeFSEntry {
+            name: name.into(),
+            host_path: PathBuf::from("/host").join(name),
+            mime: "image_jpeg".into(),
+            ..Default::default()
+        };
+        {
+            let mut store = fs.store.write();
+            store.add_entry(entry("a.jpg"));
+            let pinned = Path::new("/picked/best/b.jpg");
+            assert_eq!(store.insert_at(pinned, entry("b.jpg")), Ok(()));
+            assert_eq!(
+                store.paths_for_host(Path::new("/host/b.jpg")),
+                vec![pinned.to_path_buf()]
+            );
+
+            // Taken names clash rather than being numbered, and nothing is added.
+            assert_eq!(
+                store.insert_at(pinned, entry("c.jpg")),
+                Err(ArenaError::Exists("b.jpg".into()))
+            );
+            assert_eq!(
+                store.insert_at(Path::new("/image_jpeg/a.jpg"), entry("c.jpg")),
+                Err(ArenaError::Exists("a.jpg".into()))
+            );
+            assert_eq!(
+                store.insert_at(&pinned.join("c.jpg"), entry("c.jpg")),
+                Err(ArenaError::ParentIsLeaf)
+            );
+            assert_eq!(store.entries.len(), 2);
+
+            // The pattern has no say in where it is.
+            store.set_pattern("/sorted/{meta}");
+            assert_eq!(
+                store.leaf_paths(),
+                vec![
+                    PathBuf::from("/picked/best/b.jpg"),
+                    PathBuf::from("/sorted/image_jpeg/a.jpg")
+                ]
+            );
+        }
+
+        // It goes wherever it's moved, without pinning placeholders on the host.
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let (parent, newparent) = (Path::new("/picked/best"), Path::new("/picked"));
+        let name = OsStr::new("b.jpg");
+        assert!(fs.rename(req, parent, name, newparent, name).is_ok());
+        assert_eq!(
+            fs.store.read().paths_for_host(Path::new("/host/b.jpg")),
+            vec![PathBuf::from("/picked/b.jpg")]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn apply_rules() {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        for (name, dir) in [("a", "camera"), ("b", "phone")] {
+            store.add_entry(OrganizeFSEntry {
+                name: name.into(),
+                host_path: PathBuf::from("/host").join(dir).join(name),
+                mime: "image_jpeg".into(),
+                dirs: vec![dir.into()],
+                ..Default::default()
+            });
+        }
+
+        store.apply_rules(&Rules::new(vec![Rule::new(
+            ("dir1", "camera"),
+            ("meta", "photos"),
+        )]));
+        assert!(store.find(Path::new("/photos/a")).is_file());
+        assert!(store.find(Path::new("/image_jpeg/b")).is_file());
+
+        store.apply_rules(&Rules::new(vec![Rule::new(
+            ("dir1", "phone"),
+            ("meta", "photos"),
+        )]));
+        assert!(store.find(Path::new("/image_jpeg/a")).is_file());
+        assert!(store.find(Path::new("/photos/b")).is_file());
+
+        store.add_entry(OrganizeFSEntry {
+            name: "c".into(),
+            mime: "image_jpeg".into(),
+            dirs: vec!["phone".into()],
+            ..Default::default()
+        });
+        assert!(store.find(Path::new("/photos/c")).is_file());
+    }
+
+    // init tests
+    #[test]
+    #[traced_test]
+    fn init() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+
+        assert!(fs.init(req).is_ok());
+    }
+
+    // destroy tests
+    #[test]
+    #[traced_test]
+    fn destroy_nosignal() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        fs.destroy();
+    }
+
+    #[test]
+    #[traced_test]
+    fn destroy_signal() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
+        let mut fs = new_test_fs(libc_wrapper);
+        fs.shutdown_signal = Mutex::new(Some(tx));
+        fs.destroy();
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn destroy_stops_watching() {
+        let root = std::env::temp_dir().join(format!("organizefs-unwatch-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let store = Arc::new(parking_lot::RwLock::new(OrganizeFSStore::new(
+            PathBuf::from("/{meta}"),
+        )));
+        let options = ScanOptions {
+            watch: true,
+            ..ScanOptions::default()
+        };
+        let (tx, _rx) = tokio::sync::oneshot::channel();
+        let fs = OrganizeFS::with_options(root.to_str().unwrap(), store, tx, options);
+        // Returns once the watch thread has, rather than leaving it blocked.
+        fs.destroy();
+        assert!(fs.host_watcher.lock().unwrap().is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // statfs tests
+    #[test]
+    #[traced_test]
+    fn statfs_ok() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_statfs().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.f_blocks = 1024;
+                stat.f_bfree = 512;
+                stat.f_bavail = 500;
+                stat.f_files = 2048;
+                stat.f_ffree = 1000;
+                stat.f_bsize = 4096;
+                stat.f_namelen = 256;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let resp = fs.statfs(req, &PathBuf::from("/"));
+        assert!(resp.is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn statfs_cached() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_statfs().times(1).returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.f_blocks = 1024;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper).with_statfs_ttl(Duration::from_secs(60));
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        assert_eq!(fs.statfs(req, &PathBuf::from("/")).unwrap().blocks, 1024);
+        assert_eq!(fs.statfs(req, &PathBuf::from("/")).unwrap().blocks, 1024);
+    }
+
+    #[test]
+    #[traced_test]
+    fn statfs_expired() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_statfs().times(2).returning(|_| {
+                let s = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+                Ok(unsafe { s.assume_init() })
+            });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper).with_statfs_ttl(Duration::ZERO);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        assert!(fs.statfs(req, &PathBuf::from("/")).is_ok());
+        assert!(fs.statfs(req, &PathBuf::from("/")).is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn statfs_err() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_statfs()
+                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let resp = fs.statfs(req, &PathBuf::from("/"));
+        assert_eq!(resp.err(), Some(libc::EACCES));
+    }
+
+    // opendir tests
+    #[test]
+    #[traced_test]
+    fn opendir_present() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "test".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
+        let resp = fs.opendir(
+            req,
+            &PathBuf::from("/"),
+            libc::O_DIRECTORY.try_into().unwrap(),
+        );
+        let (fh, flags) = resp.unwrap();
+        assert_eq!(flags, 0);
+        assert!(fh >= DIR_HANDLE_BASE);
+        assert!(fs.dir_handles.lock().unwrap().contains(&fh));
+        let (next, _) = fs
+            .opendir(
+                req,
+                &PathBuf::from("/"),
+                libc::O_DIRECTORY.try_into().unwrap(),
+            )
+            .unwrap();
+        assert_ne!(next, fh);
+    }
+
+    #[test]
+    #[traced_test]
+    fn opendir_missing() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "test".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
+        let resp = fs.opendir(
+            req,
+            &PathBuf::from("/missing"),
+            libc::O_DIRECTORY.try_into().unwrap(),
+        );
+        assert_eq!(resp.err(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    #[traced_test]
+    fn readdir_changed_kind() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        {
+            let mut store = fs.store.write();
+            store.set_pattern("/{meta}");
+            store.add_test_entry("text_plain", "/host/text_plain", "text_plain");
+        }
+        let path = PathBuf::from("/text_plain");
+        assert!(fs
+            .opendir(req, &path, libc::O_DIRECTORY.try_into().unwrap())
+            .is_ok());
+        assert_eq!(fs.readdir(req, &path, 0).unwrap().len(), 3);
+
+        // Another thread re-patterns between `opendir` and `readdir`: now a file...
+        fs.store.write().set_pattern("/");
+        assert_eq!(fs.readdir(req, &path, 0).err(), Some(libc::ENOTDIR));
+
+        // ... and now gone.
+        fs.store.write().set_pattern("/{dir1}");
+        assert_eq!(fs.readdir(req, &path, 0).err(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    #[traced_test]
+    fn readdir_lazy() {
+        let root = std::env::temp_dir().join(format!("organizefs-lazy-{}", std::process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(root.join("c")).unwrap();
+        for file in ["a/x.txt", "a/b/y.txt", "c/z.txt"] {
+            fs::write(root.join(file), "text").unwrap();
+        }
+        let mut fs = new_test_fs(MockLibcWrapper::new());
+        fs.root = root.clone();
+        fs.options.lazy = true;
+        fs.store.write().set_pattern("/{dir1}/{meta}");
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let names = |listing: Vec<DirectoryEntry>| {
+            listing
+                .into_iter()
+                .map(|entry| entry.name.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // The root lists host directories without indexing what's in them.
+        let listing = fs.readdir(req, Path::new("/"), 0).unwrap();
+        assert_eq!(names(listing), vec![".", "..", "a", "c"]);
+        assert!(fs.store.read().entries.is_empty());
+
+        // A mirrored directory indexes its whole host subtree, once.
+        let listing = fs.readdir(req, Path::new("/a"), 0).unwrap();
+        assert_eq!(names(listing), vec![".", "..", "text_plain"]);
+        let listing = fs.readdir(req, Path::new("/a/text_plain"), 0).unwrap();
+        assert_eq!(names(listing), vec![".", "..", "x.txt", "y.txt"]);
+        fs.readdir(req, Path::new("/a"), 0).unwrap();
+        assert_eq!(fs.store.read().entries.len(), 2);
+        assert!(!fs
+            .store
+            .read()
+            .find(Path::new("/c/text_plain"))
+            .is_directory());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn readdir_lazy_strip_prefix() {
+        let base =
+            std::env::temp_dir().join(format!("organizefs-lazy-base-{}", std::process::id()));
+        let root = base.join("top");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(base.join("beside")).unwrap();
+        fs::write(root.join("a/x.txt"), "text").unwrap();
+        let mut fs = new_test_fs(MockLibcWrapper::new());
+        fs.root = root.clone();
+        fs.options.lazy = true;
+        fs.options.strip_prefix = Some(base.clone());
+        fs.store.write().set_pattern("/{dir1}/{dir2}/{meta}");
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let names = |path: &str| {
+            fs.readdir(req, Path::new(path), 0)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Levels count from the prefix, but only lead to the root, not beside it.
+        assert_eq!(names("/"), vec![".", "..", "top"]);
+        assert_eq!(names("/top"), vec![".", "..", "a"]);
+        assert_eq!(names("/top/a/text_plain"), vec![".", "..", "x.txt"]);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn apply_host_event() {
+        let root = std::env::temp_dir().join(format!("organizefs-hostev-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let store = parking_lot::RwLock::new(OrganizeFSStore::new(PathBuf::from("/{size}")));
+        let options = ScanOptions::default();
+        let apply =
+            |path: &Path, event| OrganizeFS::apply_host_event(&store, &root, &options, path, event);
+
+        // A new file is indexed, and indexed afresh once it's changed.
+        let file = root.join("a.txt");
+        fs::write(&file, "text").unwrap();
+        apply(&file, WatchEvent::Changed);
+        assert_eq!(
+            store.read().paths_for_host(&file),
+            vec![PathBuf::from("/4.00B/a.txt")]
+        );
+        fs::write(&file, "longer text").unwrap();
+        apply(&file, WatchEvent::Changed);
+        assert_eq!(
+            store.read().paths_for_host(&file),
+            vec![PathBuf::from("/11.00B/a.txt")]
+        );
+        assert_eq!(store.read().entries.len(), 1);
+
+        // A directory moved in has what's below it added, once.
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        fs::write(root.join("sub/deeper/b.txt"), "text").unwrap();
+        apply(&root.join("sub"), WatchEvent::Changed);
+        apply(&root.join("sub"), WatchEvent::Changed);
+        assert_eq!(store.read().entries.len(), 2);
+
+        // A changed directory is only listed, not walked below what's indexed.
+        fs::write(root.join("sub/deeper/c.txt"), "text").unwrap();
+        fs::write(root.join("d.txt"), "text").unwrap();
+        apply(&root, WatchEvent::Changed);
+        assert_eq!(store.read().entries.len(), 3);
+        assert_eq!(store.read().paths_for_host(&root.join("d.txt")).len(), 1);
+        fs::remove_file(root.join("d.txt")).unwrap();
+        apply(&root.join("d.txt"), WatchEvent::Removed);
+
+        // A change naming a path that's gone removes what was indexed from it.
+        fs::remove_dir_all(root.join("sub")).unwrap();
+        apply(&root.join("sub"), WatchEvent::Changed);
+        apply(&file, WatchEvent::Removed);
+        assert!(store.read().entries.is_empty());
+        assert_eq!(options.mime_cache.len(), 0);
+        assert!(store.read().list_dir(Path::new("/")).unwrap().is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn pin_survives_rescan() {
+        let root = std::env::temp_dir().join(format!("organizefs-pin-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let file = root.join("a.txt");
+        fs::write(&file, "text").unwrap();
+        let store = parking_lot::RwLock::new(OrganizeFSStore::new(PathBuf::from("/{meta}")));
+        let options = ScanOptions::default();
+        OrganizeFS::rescan(&root, &options, &store);
+        {
+            let mut store = store.write();
+            let id = store.find_file(Path::new("/text_plain/a.txt")).unwrap();
+            let entry = store.entries[&id].to_owned();
+            store.remove_host_file(&file);
+            assert_eq!(store.insert_at(Path::new("/picked/a.txt"), entry), Ok(()));
+        }
+        let pinned = || store.read().paths_for_host(&file);
+
+        OrganizeFS::rescan(&root, &options, &store);
+        assert_eq!(pinned(), vec![PathBuf::from("/picked/a.txt")]);
+
+        fs::write(&file, "changed").unwrap();
+        OrganizeFS::apply_host_event(&store, &root, &options, &file, WatchEvent::Changed);
+        assert_eq!(pinned(), vec![PathBuf::from("/picked/a.txt")]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn readdir_case_fold() {
+        let fs = new_test_fs(MockLibcWrapper::new());
+        *fs.store.write() =
+            OrganizeFSStore::new(PathBuf::from("/{meta}")).with_collation(Collation::CaseFold);
+        {
+            let mut store = fs.store.write();
+            store.add_test_entry("B.txt", "/host/B.txt", "text_plain");
+            store.add_test_entry("a.txt", "/host/a.txt", "text_plain");
+            // Collides with `a.txt` once case is folded, so is numbered.
+            assert!(store
+                .try_add_entry(OrganizeFSEntry {
+                    name: "A.TXT".into(),
+                    host_path: "/host/A.TXT".into(),
+                    mime: "text_plain".into(),
+                    ..Default::default()
+                })
+                .is_ok());
+        }
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let names = fs
+            .readdir(req, Path::new("/TEXT_PLAIN"), 0)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![".", "..", "A (2).TXT", "a.txt", "B.txt"]);
+        let store = fs.store.read();
+        assert_eq!(
+            store.host_path(Path::new("/Text_Plain/A.TXT")),
+            Some(PathBuf::from("/host/a.txt"))
+        );
+        assert_eq!(
+            store.list_dir(Path::new("/text_plain")).unwrap()[1].0,
+            OsString::from("a.txt")
+        );
+        // The manifest's order, and paging, agree with the listing.
+        assert_eq!(
+            store.leaf_paths(),
+            ["A (2).TXT", "a.txt", "B.txt"]
+                .map(|name| Path::new("/text_plain").join(name))
+                .to_vec()
+        );
+        assert_eq!(
+            store
+                .nth_child(Path::new("/text_plain"), 2)
+                .map(|(name, _)| name),
+            Some(OsString::from("B.txt"))
+        );
+        assert!(fs.config().case_fold);
+    }
+
+    // releasedir tests
+    #[test]
+    #[traced_test]
+    fn releasedir() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let fh = 1;
+        let resp = fs.releasedir(req, &PathBuf::from("/test"), fh, 0);
+        assert!(resp.is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn open_directory() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_open().never();
+            libc_wrapper.expect_close().never();
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper);
+        *fs.store.write() = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        fs.store
+            .write()
+            .add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let path = Path::new("/image_jpeg");
+        let flags = (libc::O_RDONLY | libc::O_DIRECTORY) as u32;
+        let (fh, _) = fs.open(req, path, flags).unwrap();
+        assert!(fh >= DIR_HANDLE_BASE);
+        assert!(fs.readdir(req, path, fh).is_ok());
+        assert!(fs.release(req, path, fh, 0, 0, false).is_ok());
+        assert!(fs.dir_handles.lock().unwrap().is_empty());
+
+        assert_eq!(
+            fs.open(req, path, libc::O_WRONLY as u32).unwrap_err(),
+            libc::EISDIR
+        );
+        assert_eq!(
+            fs.open(req, Path::new("/"), libc::O_RDWR as u32)
+                .unwrap_err(),
+            libc::EISDIR
+        );
+        assert!(fs.dir_handles.lock().unwrap().is_empty());
+    }
+
+    // getattr tests
+    #[test]
+    #[traced_test]
+    fn getattr_dir_handle() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_fstat().never();
+            libc_wrapper.expect_lstat().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFDIR + 0o0700;
+                stat.st_nlink = 1;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let path = PathBuf::from("/");
+        let (fh, _) = fs
+            .opendir(req, &path, libc::O_DIRECTORY.try_into().unwrap())
+            .unwrap();
+        let (_, attr) = fs.getattr(req, &path, Some(fh)).unwrap();
+        assert_eq!(attr.kind, FileType::Directory);
+        assert_eq!(attr.perm, 0o755);
+
+        assert!(fs.releasedir(req, &path, fh, 0).is_ok());
+        assert!(fs.dir_handles.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_dirs_share_root_lstat() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_lstat()
+                .withf(|path| path == Path::new("/"))
+                .times(1)
+                .returning(|_| {
+                    let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                    let stat = unsafe { s.assume_init_mut() };
+                    stat.st_mode = libc::S_IFDIR + 0o0700;
+                    stat.st_mtime = 1_700_000_000;
+                    Ok(stat.to_owned())
+                });
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper);
+        *fs.store.write() = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        fs.store
+            .write()
+            .add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        for path in ["/", "/image_jpeg"] {
+            let (_, attr) = fs.getattr(req, Path::new(path), None).unwrap();
+            assert_eq!(attr.kind, FileType::Directory, "{path}");
+            assert_eq!(attr.perm, 0o755, "{path}");
+            assert_eq!(
+                attr.mtime,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            fs.getattr(req, Path::new("/missing"), None).unwrap_err(),
+            libc::ENOENT
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_withfh_err() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_fstat()
+                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let fh = test_handle(&fs, 1);
+        let resp = fs.getattr(req, &PathBuf::from("/test"), Some(fh));
+        assert_eq!(resp.err(), Some(libc::EACCES));
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_withfh_ok() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_fstat().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFREG + 0o0644;
+                stat.st_size = 5;
+                stat.st_nlink = 1;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let fh = test_handle(&fs, 1);
+        let resp = fs.getattr(req, &PathBuf::from("/test"), Some(fh));
+        assert!(resp.is_ok());
+        assert!(!logs_contain("slow operation"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_slow_warns() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_fstat().returning(|_| {
+                std::thread::sleep(Duration::from_millis(20));
+                Err(io::Error::from_raw_os_error(libc::EACCES))
+            });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper).with_slow_op_threshold(Duration::from_millis(10));
+        let fh = test_handle(&fs, 1);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let resp = fs.getattr(req, &PathBuf::from("/slow"), Some(fh));
+        assert_eq!(resp.err(), Some(libc::EACCES));
+        assert!(logs_contain("slow operation"));
+        assert!(logs_contain("op=\"getattr\""));
+        assert!(logs_contain("/slow"));
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_nofh_missing() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let resp = fs.getattr(req, &PathBuf::from("/test"), None);
+        assert_eq!(resp.err(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_nofh_file_err() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_lstat()
+                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "test".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
+        let resp = fs.getattr(req, &PathBuf::from("/test"), None);
+        assert_eq!(resp.err(), Some(libc::EACCES));
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_nofh_file_ok() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_lstat().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFREG + 0o0644;
+                stat.st_size = 5;
+                stat.st_nlink = 1;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "test".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
+        let resp = fs.getattr(req, &PathBuf::from("/test"), None);
+        assert!(resp.is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn symlink_view() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_lstat().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFREG + 0o0644;
+                stat.st_size = 5;
+                stat.st_blocks = 8;
+                stat.st_nlink = 1;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper);
+        fs.store
+            .write()
+            .add_test_entry("a.txt", "/host/a.txt", "text_plain");
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let file = PathBuf::from("/a.txt");
+        let (_, attr) = fs.getattr(req, &file, None).unwrap();
+        assert_eq!((attr.kind, attr.size), (FileType::RegularFile, 5));
+        assert_eq!(fs.readlink(req, &file).err(), Some(libc::EINVAL));
+
+        let fs = fs.with_symlink_view(true);
+        let (_, attr) = fs.getattr(req, &file, None).unwrap();
+        assert_eq!(
+            (attr.kind, attr.size, attr.perm),
+            (FileType::Symlink, 11, 0o777)
+        );
+        assert_eq!(fs.readlink(req, &file).unwrap(), b"/host/a.txt");
+        assert_eq!(fs.readlink(req, Path::new("/")).err(), Some(libc::EINVAL));
+        assert_eq!(
+            fs.readlink(req, Path::new("/missing")).err(),
+            Some(libc::ENOENT)
+        );
+        let listing = fs.readdir(req, Path::new("/"), 0).unwrap();
+        assert_eq!(listing[2].name, "a.txt");
+        assert_eq!(listing[2].kind, FileType::Symlink);
+        assert!(fs.config().symlink_view);
+    }
+
+    #[test]
+    #[traced_test]
+    fn xattrs() {
+        let fs = new_test_fs(MockLibcWrapper::new());
+        {
+            let mut store = fs.store.write();
+            store.set_pattern("/{meta}");
+            store.add_test_entry("a.jpeg", "/host/a.jpeg", "image_jpeg");
+        }
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let data = |r: ResultXattr| match r {
+            Ok(Xattr::Data(data)) => Ok(data),
+            Ok(Xattr::Size(size)) => Ok(vec![0; size as usize]),
+            Err(e) => Err(e),
+        };
+        let file = PathBuf::from("/image_jpeg/a.jpeg");
+        let getxattr =
+            |path: &Path, name: &str, size| data(fs.getxattr(req, path, OsStr::new(name), size));
+        assert_eq!(
+            getxattr(&file, "user.organizefs.host_path", 64),
+            Ok(b"/host/a.jpeg".to_vec())
+        );
+        assert_eq!(
+            getxattr(&file, "user.organizefs.mime", 64),
+            Ok(b"image/jpeg".to_vec())
+        );
+        assert_eq!(
+            getxattr(&file, "user.organizefs.mime", 0).map(|d| d.len()),
+            Ok(10)
+        );
+        assert_eq!(
+            getxattr(&file, "user.organizefs.mime", 4),
+            Err(libc::ERANGE)
+        );
+        assert_eq!(getxattr(&file, "user.other", 64), Err(libc::ENODATA));
+        assert_eq!(
+            getxattr(Path::new("/image_jpeg"), "user.organizefs.mime", 64),
+            Err(libc::ENODATA)
+        );
+        assert_eq!(
+            getxattr(Path::new("/missing"), "user.organizefs.mime", 64),
+            Err(libc::ENOENT)
+        );
+
+        assert_eq!(
+            data(fs.listxattr(req, &file, 64)),
+            Ok(b"user.organizefs.host_path\0user.organizefs.mime\0".to_vec())
+        );
+        assert_eq!(
+            data(fs.listxattr(req, Path::new("/image_jpeg"), 64)),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_nofh_dir_err() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_lstat()
+                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "test".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
+        let resp = fs.getattr(req, &PathBuf::from("/"), None);
+        assert_eq!(resp.err(), Some(libc::EACCES));
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_nofh_dir_ok() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_lstat().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFDIR + 0o0755;
+                stat.st_size = 5;
+                stat.st_nlink = 1;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "test".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
+        let resp = fs.getattr(req, &PathBuf::from("/"), None);
+        assert!(resp.is_ok());
+    }
+
+    #[test]
+    #[traced_test]
+    fn getattr_dir_umask() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_lstat().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFDIR + 0o0700;
+                stat.st_nlink = 1;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let (_, attr) = fs.getattr(req, &PathBuf::from("/"), None).unwrap();
+        assert_eq!(attr.kind, FileType::Directory);
+        assert_eq!(attr.perm, 0o755);
+
+        let fs = fs.with_umask(0o027);
+        let (_, attr) = fs.getattr(req, &PathBuf::from("/"), None).unwrap();
+        assert_eq!(attr.perm, 0o750);
+    }
+
+    #[test]
+    #[traced_test]
+    fn config_effective() {
+        let fs = new_test_fs(MockLibcWrapper::new())
+            .with_umask(0o027)
+            .with_read_cache_bytes(1024)
+            .with_readahead_bytes(4096);
+        let config = fs.config();
+        assert_eq!(config.umask, "027");
+        assert_eq!(config.read_cache_bytes, 1024);
+        assert_eq!(config.readahead_bytes, 4096);
+        assert_eq!(config.slow_op_ms, 500);
+        assert_eq!(config.pattern, "/");
+        assert_eq!(config.unsorted_dir, DEFAULT_UNSORTED_DIR);
+
+        fs.store.write().set_pattern("/{meta}");
+        assert_eq!(fs.config().pattern, "/{meta}");
+    }
+
+    // open tests
+    #[test]
+    #[traced_test]
+    fn open_missing() {
+        let libc_wrapper = MockLibcWrapper::new();
+
+        let fs = new_test_fs(libc_wrapper);
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let parent = PathBuf::from("/");
+        let name = std::ffi::OsString::from("missing");
+        let r = fs.open(req, &parent.join(name), 0);
+        assert_eq!(r.err(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    #[traced_test]
+    fn open_present() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_open().returning(|_, _| Ok(1));
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper);
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "present".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
         }
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let parent = PathBuf::from("/");
+        let name = std::ffi::OsString::from("present");
+        let r = fs.open(req, &parent.join(name), 0);
+        assert!(r.is_ok());
     }
 
     #[test]
     #[traced_test]
-    fn organize_fsentry_new() {
-        let root = PathBuf::from("/test/data/path");
-        let entry = {
-            let mut entry = MockDirEntry::new();
-            entry.expect_path().return_const(PathBuf::from("path/"));
-            entry
-                .expect_file_name()
-                .return_const(OsString::from("file"));
-            entry
+    fn truncate_refiles() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_open()
+                .withf(|path, flags| path == Path::new("/host/present") && *flags == libc::O_WRONLY)
+                .returning(|_, _| Ok(3));
+            libc_wrapper
+                .expect_ftruncate()
+                .withf(|fd, size| *fd == 3 && *size == 0)
+                .times(1)
+                .returning(|_, _| Ok(()));
+            libc_wrapper
+                .expect_truncate()
+                .withf(|path, size| path == Path::new("/host/present") && *size == 2)
+                .times(1)
+                .returning(|_, _| Ok(()));
+            libc_wrapper
         };
-        let meta = {
-            let mut metadata = MockMetadata::new();
-            metadata
-                .expect_len()
-                .return_const(1024_u64 * 1024 * 1024 * 100);
-            metadata.expect_modified().returning(|| {
-                Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(40 * 365 * 24 * 60 * 60))
+        let fs = new_test_fs(libc_wrapper);
+        {
+            let mut store = fs.store.write();
+            store.add_entry(OrganizeFSEntry {
+                name: "present".into(),
+                host_path: "/host/present".into(),
+                size: 5,
+                ..Default::default()
             });
-            metadata
+            store.set_pattern("/{size}");
+        }
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
         };
-        let entry = OrganizeFSEntry::new(&root, &entry, &meta);
-        assert_eq!(entry.size, "107.37GB");
-        assert_eq!(entry.name, "file");
-        assert_eq!(entry.host_path, PathBuf::from("/test/data/path/path"));
-        assert_eq!(entry.modified_date, "2009-12-22");
-        assert_eq!(entry.mime, "");
-    }
-
-    #[test]
-    #[traced_test]
-    fn mode_to_filetype() {
-        assert_eq!(
-            OrganizeFS::mode_to_filetype(libc::S_IFDIR + 0o644),
-            FileType::Directory
-        );
+        // `: > present`, as the kernel sends it: the open without `O_TRUNC`, then a
+        // truncate through the handle.
+        let flags = libc::O_WRONLY as u32;
+        let r = fs.open(req, Path::new("/5.00B/present"), flags);
+        assert_eq!(r, Ok((1, flags)));
         assert_eq!(
-            OrganizeFS::mode_to_filetype(libc::S_IFREG + 0o644),
-            FileType::RegularFile
-        );
-        assert_eq!(
-            OrganizeFS::mode_to_filetype(libc::S_IFLNK + 0o644),
-            FileType::Symlink
-        );
-        assert_eq!(
-            OrganizeFS::mode_to_filetype(libc::S_IFBLK + 0o644),
-            FileType::BlockDevice
-        );
-        assert_eq!(
-            OrganizeFS::mode_to_filetype(libc::S_IFCHR + 0o644),
-            FileType::CharDevice
+            fs.truncate(req, Path::new("/5.00B/present"), Some(1), 0),
+            Ok(())
         );
+        {
+            let store = fs.store.read();
+            assert!(store.entry(Path::new("/5.00B/present")).is_none());
+            let entry = store.entry(Path::new("/0.00B/present")).unwrap();
+            assert_eq!(format_size(&entry.size), "0.00B");
+            assert_eq!(entry.host_path, PathBuf::from("/host/present"));
+        }
+
+        // `truncate -s 2 present`, with no handle.
         assert_eq!(
-            OrganizeFS::mode_to_filetype(libc::S_IFIFO + 0o644),
-            FileType::NamedPipe
+            fs.truncate(req, Path::new("/0.00B/present"), None, 2),
+            Ok(())
         );
+        assert!(fs.store.read().entry(Path::new("/2.00B/present")).is_some());
         assert_eq!(
-            OrganizeFS::mode_to_filetype(libc::S_IFSOCK + 0o644),
-            FileType::Socket
+            fs.truncate(req, Path::new("/2.00B/missing"), None, 0),
+            Err(libc::ENOENT)
         );
     }
 
     #[test]
     #[traced_test]
-    #[should_panic(expected = "unknown file type")]
-    fn mode_to_filetype_err() {
-        OrganizeFS::mode_to_filetype(0);
+    fn open_no_access() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_open()
+                .returning(|_, _| Err(io::Error::from_raw_os_error(libc::EACCES)));
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper);
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "present".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let parent = PathBuf::from("/");
+        let name = std::ffi::OsString::from("present");
+        let r = fs.open(req, &parent.join(name), 0);
+        assert_eq!(r.err(), Some(libc::EACCES));
     }
 
+    // read tests
     #[test]
     #[traced_test]
-    fn get_pattern() {
+    fn read_no_filehandle() {
         let libc_wrapper = MockLibcWrapper::new();
 
         let fs = new_test_fs(libc_wrapper);
-        let store = fs.store.read();
-        assert_eq!("/", store.get_pattern());
+        let r = fs.read_into_buffer(0, 0, 16, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.err(), Some(libc::ENOENT));
     }
 
     #[test]
     #[traced_test]
-    fn set_pattern() {
-        let libc_wrapper = MockLibcWrapper::new();
+    fn read_partial() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_read().returning(|_, offset, buf| {
+                assert_eq!(offset, 4);
+                assert_eq!(buf.len(), 16);
+                buf[..5].copy_from_slice(b"hello");
+                Ok(5)
+            });
+            libc_wrapper
+        };
 
         let fs = new_test_fs(libc_wrapper);
-        // Add file
-        {
-            let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "present".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
+        let fh = test_handle(&fs, 1);
+        let r = fs.read_into_buffer(fh, 4, 16, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.unwrap(), b"hello");
+        // Reusing the (now larger) buffer for a smaller read only exposes what was read.
+        let r = fs.read_into_buffer(fh, 4, 16, |r| r.map(|data| data.len()));
+        assert_eq!(r.unwrap(), 5);
+    }
+
+    #[test]
+    #[traced_test]
+    fn read_cache_hit() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_read().times(1).returning(|_, _, buf| {
+                buf[..5].copy_from_slice(b"hello");
+                Ok(5)
+            });
+            libc_wrapper.expect_close().returning(|_| Ok(()));
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper);
+        let fh = test_handle(&fs, 1);
+        let metrics = fs.read_metrics();
+        let r = fs.read_into_buffer(fh, 0, 16, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.unwrap(), b"hello");
+        assert_eq!((metrics.cache_hits(), metrics.cache_misses()), (0, 1));
+
+        let r = fs.read_into_buffer(fh, 0, 16, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.unwrap(), b"hello");
+        assert_eq!((metrics.cache_hits(), metrics.cache_misses()), (1, 1));
+        assert_eq!(metrics.bytes_from_cache(), 5);
+        assert_eq!(metrics.bytes_from_disk(), 5);
+
+        // A released handle's data goes with it.
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        fs.release(req, Path::new("/file"), fh, 0, 0, true).unwrap();
+        let r = fs.read_into_buffer(fh, 0, 16, |r| r.map(|data| data.len()));
+        assert_eq!(r.err(), Some(libc::EBADF));
+    }
+
+    #[test]
+    #[traced_test]
+    fn readahead_across_boundary() {
+        const CONTENT: [u8; 40] = {
+            let mut content = [0; 40];
+            let mut i = 0;
+            while i < content.len() {
+                content[i] = i as u8;
+                i += 1;
+            }
+            content
+        };
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            // The first read as asked, then 16 bytes at a time from where the buffer
+            // ran out, then the seek back to the start as asked.
+            let mut sizes = vec![(0, 6), (6, 16), (18, 16), (30, 16), (0, 6)].into_iter();
+            libc_wrapper
+                .expect_read()
+                .times(5)
+                .returning(move |_, offset, buf| {
+                    assert_eq!(sizes.next(), Some((offset, buf.len())));
+                    let rest = &CONTENT[(offset as usize).min(CONTENT.len())..];
+                    let count = buf.len().min(rest.len());
+                    buf[..count].copy_from_slice(&rest[..count]);
+                    Ok(count)
+                });
+            libc_wrapper
+        };
+
+        let fs = new_test_fs(libc_wrapper)
+            .with_read_cache_bytes(0)
+            .with_readahead_bytes(16);
+        let fh = test_handle(&fs, 1);
+        let mut read = Vec::new();
+        for offset in (0..=CONTENT.len() as u64).step_by(6) {
+            let data = fs.read_into_buffer(fh, offset, 6, |r| r.map(|data| data.to_vec()));
+            read.extend(data.unwrap());
         }
-        // Alter pattern
+        assert_eq!(read, CONTENT);
+
+        let r = fs.read_into_buffer(fh, 0, 6, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.unwrap(), &CONTENT[..6]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn readahead_forgets_changed_data() {
+        let content = Arc::new(Mutex::new(b"abcdefgh".to_vec()));
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            let mut next_fd = 10;
+            libc_wrapper.expect_open().returning(move |_, _| {
+                next_fd += 1;
+                Ok(next_fd)
+            });
+            let current = content.clone();
+            libc_wrapper.expect_read().returning(move |_, offset, buf| {
+                let content = current.lock().unwrap();
+                let rest = &content[(offset as usize).min(content.len())..];
+                let count = buf.len().min(rest.len());
+                buf[..count].copy_from_slice(&rest[..count]);
+                Ok(count)
+            });
+            let current = content.clone();
+            libc_wrapper
+                .expect_write()
+                .returning(move |_, offset, data| {
+                    let mut content = current.lock().unwrap();
+                    content[offset as usize..][..data.len()].copy_from_slice(data);
+                    Ok(data.len())
+                });
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper)
+            .with_read_cache_bytes(0)
+            .with_readahead_bytes(16);
+        fs.forget_changed_host_data();
+        fs.store
+            .write()
+            .add_test_entry("file", "/host/file", "text_plain");
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let path = Path::new("/file");
+        let reader = fs.open(req, path, libc::O_RDONLY as u32).unwrap().0;
+        let writer = fs.open(req, path, libc::O_WRONLY as u32).unwrap().0;
+        let read = |offset| fs.read_into_buffer(reader, offset, 2, |r| r.map(|d| d.to_vec()));
+
+        // Read ahead to the end of the file, which then grows.
+        assert_eq!(read(0).unwrap(), b"ab");
+        assert_eq!(read(2).unwrap(), b"cd");
+        content.lock().unwrap().extend(b"ij");
+        assert_eq!(read(4).unwrap(), b"ef");
+        assert_eq!(read(6).unwrap(), b"gh");
+        assert_eq!(read(8).unwrap(), b"ij");
+
+        // Written through another handle.
+        assert_eq!(read(0).unwrap(), b"ab");
+        assert_eq!(read(2).unwrap(), b"cd");
+        let written = fs.write(req, path, writer, 4, b"EF".to_vec(), 0);
+        assert_eq!(written, Ok(2));
+        assert_eq!(read(4).unwrap(), b"EF");
+
+        // Changed on the host.
+        assert_eq!(read(0).unwrap(), b"ab");
+        assert_eq!(read(2).unwrap(), b"cd");
+        content.lock().unwrap()[6..8].copy_from_slice(b"GH");
+        fs.store
+            .read()
+            .notify(StoreEvent::HostChanged(PathBuf::from("/host")));
+        assert_eq!(read(6).unwrap(), b"GH");
+    }
+
+    #[test]
+    #[traced_test]
+    fn write_invalidates_other_handles() {
+        let content = Arc::new(Mutex::new(b"abcd".to_vec()));
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            let mut next_fd = 10;
+            libc_wrapper.expect_open().returning(move |_, _| {
+                next_fd += 1;
+                Ok(next_fd)
+            });
+            let current = content.clone();
+            libc_wrapper.expect_read().returning(move |_, offset, buf| {
+                let content = current.lock().unwrap();
+                let rest = &content[(offset as usize).min(content.len())..];
+                let count = buf.len().min(rest.len());
+                buf[..count].copy_from_slice(&rest[..count]);
+                Ok(count)
+            });
+            let current = content.clone();
+            libc_wrapper
+                .expect_write()
+                .returning(move |_, offset, data| {
+                    let mut content = current.lock().unwrap();
+                    content[offset as usize..][..data.len()].copy_from_slice(data);
+                    Ok(data.len())
+                });
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper);
+        fs.store
+            .write()
+            .add_test_entry("file", "/host/file", "text_plain");
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let path = Path::new("/file");
+        let reader = fs.open(req, path, libc::O_RDONLY as u32).unwrap().0;
+        let writer = fs.open(req, path, libc::O_WRONLY as u32).unwrap().0;
+        let read = || fs.read_into_buffer(reader, 0, 4, |r| r.map(|d| d.to_vec()));
+
+        assert_eq!(read().unwrap(), b"abcd");
+        assert_eq!(read().unwrap(), b"abcd");
+        assert_eq!(fs.read_metrics.cache_hits(), 1);
+        assert_eq!(fs.write(req, path, writer, 0, b"AB".to_vec(), 0), Ok(2));
+        assert_eq!(read().unwrap(), b"ABcd");
+    }
+
+    #[test]
+    #[traced_test]
+    fn read_cache_forgets_host_changes() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            let mut next_fd = 10;
+            libc_wrapper.expect_open().returning(move |_, _| {
+                next_fd += 1;
+                Ok(next_fd)
+            });
+            libc_wrapper.expect_read().returning(|_, _, buf| {
+                buf[..4].copy_from_slice(b"data");
+                Ok(4)
+            });
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper);
+        fs.forget_changed_host_data();
         {
             let mut store = fs.store.write();
-            store.set_pattern("/t/{meta}/");
+            store.add_test_entry("a", "/host/a", "text_plain");
+            store.add_test_entry("b", "/host/b", "text_plain");
         }
-        let store = fs.store.read();
-        assert_eq!("/t/{meta}", store.get_pattern());
-        assert_eq!(store.entries.len(), 1);
-        let entry = store.arena.find(&PathBuf::from("/t/text_plain/present"));
-        assert!(entry.is_file());
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        let open = |name: &str| {
+            let path = Path::new("/").join(name);
+            fs.open(req, &path, libc::O_RDONLY as u32).unwrap().0
+        };
+        let (a, b) = (open("a"), open("b"));
+        let read = |fh| {
+            fs.read_into_buffer(fh, 0, 4, |r| r.map(|d| d.len()))
+                .unwrap();
+            (fs.read_metrics.cache_hits(), fs.read_metrics.cache_misses())
+        };
+        assert_eq!(read(a), (0, 1));
+        assert_eq!(read(b), (0, 2));
+        assert_eq!(read(a), (1, 2));
+
+        // A rescan finding `a` changed.
+        let mut entries = fs
+            .store
+            .read()
+            .entries
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for entry in &mut entries {
+            if entry.name == "a" {
+                entry.size = 4;
+            }
+        }
+        fs.store.write().replace_entries(entries);
+        assert_eq!(read(a), (1, 3));
+        assert_eq!(read(b), (2, 3));
+
+        // The watcher seeing `b` change.
+        fs.store
+            .read()
+            .notify(StoreEvent::HostChanged(PathBuf::from("/host/b")));
+        assert_eq!(read(b), (2, 4));
+        assert_eq!(read(a), (3, 4));
     }
 
-    // init tests
     #[test]
     #[traced_test]
-    fn init() {
-        let libc_wrapper = MockLibcWrapper::new();
+    fn read_sparse() {
+        const SIZE: i64 = 1 << 20;
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_fstat().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFREG + 0o0644;
+                stat.st_size = SIZE;
+                stat.st_blocks = 8;
+                stat.st_nlink = 1;
+                Ok(stat.to_owned())
+            });
+            // Data in the first and last 4KiB, a hole in between.
+            libc_wrapper.expect_read().returning(|_, offset, buf| {
+                let count = buf.len().min((SIZE - offset) as usize);
+                for (i, byte) in buf[..count].iter_mut().enumerate() {
+                    let at = offset + i as i64;
+                    *byte = if (4096..SIZE - 4096).contains(&at) {
+                        0
+                    } else {
+                        b'x'
+                    };
+                }
+                Ok(count)
+            });
+            libc_wrapper
+        };
 
         let fs = new_test_fs(libc_wrapper);
+        let fh = test_handle(&fs, 1);
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
+        let (_, attr) = fs.getattr(req, Path::new("/sparse"), Some(fh)).unwrap();
+        assert_eq!(attr.size, SIZE as u64);
+        assert!(attr.blocks * 512 < attr.size);
 
-        assert!(fs.init(req).is_ok());
+        let r = fs.read_into_buffer(fh, 0, 16, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.unwrap(), vec![b'x'; 16]);
+        let r = fs.read_into_buffer(fh, 8192, 4096, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.unwrap(), vec![0; 4096]);
+        let r = fs.read_into_buffer(fh, (SIZE - 8) as u64, 4096, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.unwrap(), vec![b'x'; 8]);
+        READ_BUFFER.with(|buffer| assert_eq!(buffer.borrow().len(), 4096));
     }
 
-    // destroy tests
     #[test]
     #[traced_test]
-    fn destroy_nosignal() {
-        let libc_wrapper = MockLibcWrapper::new();
-
+    fn read_after_shrink() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_read().returning(|_, offset, buf| {
+                let data = b"0123456789";
+                let start = (offset as usize).min(data.len());
+                let count = (data.len() - start).min(buf.len());
+                buf[..count].copy_from_slice(&data[start..start + count]);
+                Ok(count)
+            });
+            libc_wrapper.expect_fstat().times(1).returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFREG + 0o0644;
+                stat.st_size = 10;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
         let fs = new_test_fs(libc_wrapper);
-        fs.destroy();
+        let fh = test_handle(&fs, 1);
+        {
+            let mut store = fs.store.write();
+            store.add_entry(OrganizeFSEntry {
+                name: "shrunk".into(),
+                host_path: "/host/shrunk".into(),
+                size: 5000,
+                ..Default::default()
+            });
+            store.set_pattern("/{size}");
+        }
+
+        // A full read says nothing about the end of the file.
+        let path = Path::new("/5.00kB/shrunk");
+        let r = fs.read_file(path, fh, 0, 4, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.unwrap(), b"0123");
+        assert!(fs.store.read().entry(path).is_some());
+
+        let r = fs.read_file(path, fh, 4096, 4096, |r| r.map(|data| data.len()));
+        assert_eq!(r.unwrap(), 0);
+        let store = fs.store.read();
+        assert!(store.entry(path).is_none());
+        let entry = store.entry(Path::new("/10.00B/shrunk")).unwrap();
+        assert_eq!(format_size(&entry.size), "10.00B");
     }
 
     #[test]
     #[traced_test]
-    fn destroy_signal() {
-        let libc_wrapper = MockLibcWrapper::new();
+    fn read_error() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_read()
+                .returning(|_, _, _| Err(io::Error::from_raw_os_error(libc::EIO)));
+            libc_wrapper
+        };
 
-        let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
-        let mut fs = new_test_fs(libc_wrapper);
-        fs.shutdown_signal = Mutex::new(Some(tx));
-        fs.destroy();
-        assert!(rx.try_recv().is_ok());
+        let fs = new_test_fs(libc_wrapper);
+        let fh = test_handle(&fs, 1);
+        let r = fs.read_into_buffer(fh, 0, 16, |r| r.map(|data| data.to_vec()));
+        assert_eq!(r.err(), Some(libc::EIO));
     }
 
-    // statfs tests
+    // flush tests
     #[test]
     #[traced_test]
-    fn statfs_ok() {
+    fn write_resizes_on_release() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
-            libc_wrapper.expect_statfs().returning(|_| {
-                let mut s = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+            libc_wrapper
+                .expect_write()
+                .withf(|fd, offset, data| *fd == 3 && *offset == 0 && data == b"hello")
+                .times(1)
+                .returning(|_, _, data| Ok(data.len()));
+            libc_wrapper.expect_fstat().times(1).returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
                 let stat = unsafe { s.assume_init_mut() };
-                stat.f_blocks = 1024;
-                stat.f_bfree = 512;
-                stat.f_bavail = 500;
-                stat.f_files = 2048;
-                stat.f_ffree = 1000;
-                stat.f_bsize = 4096;
-                stat.f_namelen = 256;
+                stat.st_mode = libc::S_IFREG + 0o0644;
+                stat.st_size = 5;
                 Ok(stat.to_owned())
             });
+            libc_wrapper.expect_close().times(1).returning(|_| Ok(()));
             libc_wrapper
         };
-
         let fs = new_test_fs(libc_wrapper);
+        let fh = test_handle(&fs, 3);
+        *fs.store.write() = OrganizeFSStore::new(PathBuf::from("/{size}"));
+        fs.store
+            .write()
+            .add_test_entry("file", "/host/file", "text_plain");
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let resp = fs.statfs(req, &PathBuf::from("/"));
-        assert!(resp.is_ok());
+        let path = Path::new("/0.00B/file");
+        assert_eq!(fs.write(req, path, fh, 0, b"hello".to_vec(), 0), Ok(5));
+        assert!(fs.release(req, path, fh, 0, 0, true).is_ok());
+        let store = fs.store.read();
+        assert!(store.find_file(path).is_none());
+        let resized = Path::new("/").join(format_size(&5)).join("file");
+        assert!(store.find_file(&resized).is_some());
     }
 
     #[test]
     #[traced_test]
-    fn statfs_err() {
+    fn open_recycles_fds() {
+        let closed = Arc::new(Mutex::new(Vec::new()));
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
+            let mut next_fd = 10;
+            libc_wrapper.expect_open().returning(move |_, flags| {
+                assert_eq!(flags & libc::O_TRUNC, 0);
+                next_fd += 1;
+                Ok(next_fd)
+            });
+            let recorded = closed.clone();
+            libc_wrapper.expect_close().returning(move |fd| {
+                recorded.lock().unwrap().push(fd);
+                Ok(())
+            });
             libc_wrapper
-                .expect_statfs()
-                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+                .expect_read()
+                .returning(|fd, _, _| Ok(fd as usize));
             libc_wrapper
         };
-
-        let fs = new_test_fs(libc_wrapper);
+        let fs = new_test_fs(libc_wrapper).with_max_open_fds(2);
+        {
+            let mut store = fs.store.write();
+            for name in ["a", "b", "c"] {
+                store.add_test_entry(name, &format!("/host/{name}"), "text_plain");
+            }
+        }
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let resp = fs.statfs(req, &PathBuf::from("/"));
-        assert_eq!(resp.err(), Some(libc::EACCES));
+        let open = |name: &str| {
+            let path = Path::new("/").join(name);
+            fs.open(req, &path, libc::O_RDONLY as u32).unwrap().0
+        };
+        let read = |fh| fs.read_into_buffer(fh, 0, 16, |r| r.map(|data| data.len()));
+
+        // Past the cap, the least recently used descriptor is closed rather than failing.
+        let (a, b) = (open("a"), open("b"));
+        let c = open("c");
+        assert_eq!(*closed.lock().unwrap(), vec![11]);
+        assert_eq!(fs.host_handles.lock().unwrap().open_fds, 2);
+
+        // ... and reopened when its handle is next used, recycling the next oldest.
+        assert_eq!(read(a), Ok(14));
+        assert_eq!(*closed.lock().unwrap(), vec![11, 12]);
+
+        // A descriptor in use isn't recycled under the operation using it.
+        fs.with_fd(c, |fd| {
+            assert_eq!(fd, 13);
+            assert_eq!(read(b), Ok(15));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(*closed.lock().unwrap(), vec![11, 12, 14]);
+
+        for fh in [a, b, c] {
+            assert!(fs.release(req, Path::new("/"), fh, 0, 0, true).is_ok());
+        }
+        assert_eq!(*closed.lock().unwrap(), vec![11, 12, 14, 15, 13]);
+        assert_eq!(fs.host_handles.lock().unwrap().open_fds, 0);
     }
 
-    // opendir tests
     #[test]
     #[traced_test]
-    fn opendir_present() {
-        let libc_wrapper = MockLibcWrapper::new();
-
-        let fs = new_test_fs(libc_wrapper);
+    fn recycled_fds_follow_rename_and_unlink() {
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            let mut next_fd = 10;
+            let recorded = opened.clone();
+            libc_wrapper.expect_open().returning(move |path, _| {
+                recorded.lock().unwrap().push(path);
+                next_fd += 1;
+                Ok(next_fd)
+            });
+            let recorded = closed.clone();
+            libc_wrapper.expect_close().returning(move |fd| {
+                recorded.lock().unwrap().push(fd);
+                Ok(())
+            });
+            libc_wrapper
+                .expect_read()
+                .returning(|fd, _, _| Ok(fd as usize));
+            libc_wrapper
+                .expect_rename_noreplace()
+                .returning(|_, _| Ok(()));
+            libc_wrapper.expect_unlink().returning(|_| Ok(()));
+            libc_wrapper
+        };
+        let fs = new_test_fs(libc_wrapper).with_max_open_fds(1);
+        {
+            let mut store = fs.store.write();
+            for name in ["a", "b", "c"] {
+                store.add_test_entry(name, &format!("/host/{name}"), "text_plain");
+            }
+        }
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        {
-            let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "test".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
-        }
-        let resp = fs.opendir(
+        let open = |name: &str| {
+            let path = Path::new("/").join(name);
+            fs.open(req, &path, libc::O_RDONLY as u32).unwrap().0
+        };
+        let read = |fh| fs.read_into_buffer(fh, 0, 16, |r| r.map(|data| data.len()));
+
+        let (a, b) = (open("a"), open("b"));
+        assert_eq!(*closed.lock().unwrap(), vec![11]);
+
+        // A renamed file is reopened where it went.
+        let renamed = fs.rename(
             req,
-            &PathBuf::from("/"),
-            libc::O_DIRECTORY.try_into().unwrap(),
+            Path::new("/"),
+            OsStr::new("a"),
+            Path::new("/"),
+            OsStr::new("renamed"),
         );
-        assert!(resp.is_ok());
-        assert_eq!(resp.unwrap(), (0, 0));
+        assert_eq!(renamed, Ok(()));
+        assert_eq!(read(a), Ok(13));
+
+        // An unlinked file's recycled descriptor is reopened before it goes, and kept.
+        assert_eq!(fs.unlink(req, Path::new("/"), OsStr::new("b")), Ok(()));
+        let c = open("c");
+        assert_eq!(read(b), Ok(14));
+        assert_eq!(read(c), Ok(15));
+        assert_eq!(
+            *opened.lock().unwrap(),
+            ["/host/a", "/host/b", "/host/renamed", "/host/b", "/host/c"].map(PathBuf::from)
+        );
+        assert_eq!(*closed.lock().unwrap(), vec![11, 12, 13]);
+        assert_eq!(fs.host_handles.lock().unwrap().open_fds, 2);
+
+        // The next file created at the unlinked path doesn't share its descriptor.
+        fs.store
+            .write()
+            .add_test_entry("b", "/host/b", "text_plain");
+        assert_ne!(open("b"), b);
     }
 
     #[test]
     #[traced_test]
-    fn opendir_missing() {
+    fn write_errors() {
+        for errno in [libc::EACCES, libc::ENOSPC] {
+            let libc_wrapper = {
+                let mut libc_wrapper = MockLibcWrapper::new();
+                libc_wrapper
+                    .expect_write()
+                    .times(1)
+                    .returning(move |_, _, _| Err(io::Error::from_raw_os_error(errno)));
+                libc_wrapper
+            };
+            let fs = new_test_fs(libc_wrapper);
+            let fh = test_handle(&fs, 3);
+            let req: RequestInfo = RequestInfo {
+                unique: 0,
+                pid: 0,
+                gid: 0,
+                uid: 0,
+            };
+            let r = fs.write(req, Path::new("/file"), fh, 0, b"hello".to_vec(), 0);
+            assert_eq!(r, Err(errno));
+            assert!(fs.written.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn flush_unimplemented() {
         let libc_wrapper = MockLibcWrapper::new();
 
         let fs = new_test_fs(libc_wrapper);
@@ -875,29 +6760,15 @@ mod tests {
             gid: 0,
             uid: 0,
         };
-        {
-            let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "test".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
-        }
-        let resp = fs.opendir(
-            req,
-            &PathBuf::from("/missing"),
-            libc::O_DIRECTORY.try_into().unwrap(),
-        );
-        assert_eq!(resp.err(), Some(libc::ENOENT));
+        let path = PathBuf::from("/missing");
+        let r = fs.flush(req, &path, 0, 0);
+        assert_eq!(r.err(), Some(libc::ENOSYS));
     }
 
-    // releasedir tests
+    // release tests
     #[test]
     #[traced_test]
-    fn releasedir() {
+    fn release_no_filehandle() {
         let libc_wrapper = MockLibcWrapper::new();
 
         let fs = new_test_fs(libc_wrapper);
@@ -907,225 +6778,273 @@ mod tests {
             gid: 0,
             uid: 0,
         };
-        let fh = 1;
-        let resp = fs.releasedir(req, &PathBuf::from("/test"), fh, 0);
-        assert!(resp.is_ok());
+        let path = PathBuf::from("/missing");
+        let r = fs.release(req, &path, 0, 0, 0, true);
+        assert_eq!(r.err(), Some(libc::ENOENT));
     }
 
-    // getattr tests
     #[test]
     #[traced_test]
-    fn getattr_withfh_err() {
+    fn release_error() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
             libc_wrapper
-                .expect_fstat()
+                .expect_close()
                 .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
             libc_wrapper
         };
 
         let fs = new_test_fs(libc_wrapper);
+        let fh = test_handle(&fs, 1);
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let fh = 1;
-        let resp = fs.getattr(req, &PathBuf::from("/test"), Some(fh));
-        assert_eq!(resp.err(), Some(libc::EACCES));
+        let path = PathBuf::from("/missing");
+        let r = fs.release(req, &path, fh, 0, 0, true);
+        assert_eq!(r.err(), Some(libc::EACCES));
     }
 
     #[test]
     #[traced_test]
-    fn getattr_withfh_ok() {
+    fn release_ok() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
-            libc_wrapper.expect_fstat().returning(|_| {
-                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
-                let stat = unsafe { s.assume_init_mut() };
-                stat.st_mode = libc::S_IFREG + 0o0644;
-                stat.st_size = 5;
-                stat.st_nlink = 1;
-                Ok(stat.to_owned())
-            });
+            libc_wrapper.expect_close().returning(|_| Ok(()));
             libc_wrapper
         };
 
         let fs = new_test_fs(libc_wrapper);
+        let fh = test_handle(&fs, 1);
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let fh = 1;
-        let resp = fs.getattr(req, &PathBuf::from("/test"), Some(fh));
-        assert!(resp.is_ok());
-    }
-
-    #[test]
-    #[traced_test]
-    fn getattr_nofh_missing() {
-        let libc_wrapper = MockLibcWrapper::new();
-
-        let fs = new_test_fs(libc_wrapper);
-        let req: RequestInfo = RequestInfo {
-            unique: 0,
-            pid: 0,
-            gid: 0,
-            uid: 0,
-        };
-        let resp = fs.getattr(req, &PathBuf::from("/test"), None);
-        assert_eq!(resp.err(), Some(libc::ENOENT));
+        let path = PathBuf::from("/missing");
+        let r = fs.release(req, &path, fh, 0, 0, true);
+        assert!(r.is_ok());
     }
 
     #[test]
     #[traced_test]
-    fn getattr_nofh_file_err() {
+    fn release_shared_handle() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
+            let mut next_fd = 3;
+            libc_wrapper.expect_open().times(2).returning(move |_, _| {
+                next_fd += 1;
+                Ok(next_fd)
+            });
             libc_wrapper
-                .expect_lstat()
-                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+                .expect_close()
+                .withf(|fd| *fd == 4)
+                .times(1)
+                .returning(|_| Ok(()));
+            libc_wrapper
+                .expect_close()
+                .withf(|fd| *fd == 5)
+                .times(1)
+                .returning(|_| Ok(()));
             libc_wrapper
         };
-
         let fs = new_test_fs(libc_wrapper);
+        fs.store
+            .write()
+            .add_test_entry("file", "/host/file", "text_plain");
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        {
-            let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "test".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
-        }
-        let resp = fs.getattr(req, &PathBuf::from("/test"), None);
-        assert_eq!(resp.err(), Some(libc::EACCES));
+        let path = Path::new("/file");
+        let flags = libc::O_RDONLY as u32;
+        let (first, _) = fs.open(req, path, flags).unwrap();
+        let (second, _) = fs.open(req, path, flags).unwrap();
+        assert_eq!(first, second);
+        // A different mode gets its own descriptor.
+        let (other, _) = fs.open(req, path, libc::O_RDWR as u32).unwrap();
+        assert_ne!(other, first);
+
+        // The shared descriptor is closed by the last of its two releases only.
+        assert!(fs.release(req, path, first, flags, 0, true).is_ok());
+        assert!(fs.release(req, path, second, flags, 0, true).is_ok());
+        assert!(fs.release(req, path, other, 0, 0, true).is_ok());
+        assert!(fs.host_handles.lock().unwrap().handles.is_empty());
+    }
+
+    // unlink tests
+    fn dir_stat() -> io::Result<libc::stat> {
+        let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+        let stat = unsafe { s.assume_init_mut() };
+        stat.st_mode = libc::S_IFDIR + 0o0755;
+        stat.st_nlink = 1;
+        Ok(stat.to_owned())
     }
 
     #[test]
     #[traced_test]
-    fn getattr_nofh_file_ok() {
+    fn mkdir_rmdir_virtual() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
-            libc_wrapper.expect_lstat().returning(|_| {
-                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
-                let stat = unsafe { s.assume_init_mut() };
-                stat.st_mode = libc::S_IFREG + 0o0644;
-                stat.st_size = 5;
-                stat.st_nlink = 1;
-                Ok(stat.to_owned())
-            });
+            libc_wrapper.expect_lstat().returning(|_| dir_stat());
+            libc_wrapper.expect_mkdir().never();
+            libc_wrapper.expect_rmdir().never();
             libc_wrapper
         };
-
         let fs = new_test_fs(libc_wrapper);
+        fs.store.write().set_pattern("/{meta}");
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        {
-            let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "test".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
-        }
-        let resp = fs.getattr(req, &PathBuf::from("/test"), None);
-        assert!(resp.is_ok());
+        let root = PathBuf::from("/");
+        let (_, attr) = fs.mkdir(req, &root, OsStr::new("notes"), 0o755).unwrap();
+        assert_eq!(attr.kind, FileType::Directory);
+        assert_eq!(
+            fs.store.read().list_dir(&root),
+            Some(vec![("notes".into(), FileType::Directory)])
+        );
+        assert_eq!(
+            fs.mkdir(req, &root, OsStr::new("notes"), 0o755).err(),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(
+            fs.mkdir(req, Path::new("/missing"), OsStr::new("notes"), 0o755)
+                .err(),
+            Some(libc::ENOENT)
+        );
+
+        assert!(fs.rmdir(req, &root, OsStr::new("notes")).is_ok());
+        assert_eq!(fs.store.read().list_dir(&root), Some(vec![]));
+        assert_eq!(
+            fs.rmdir(req, &root, OsStr::new("notes")).err(),
+            Some(libc::ENOENT)
+        );
+
+        let deep = (0..arena::MAX_DEPTH).fold(root.clone(), |path, _| path.join("d"));
+        fs.store.write().add_dir(&deep).unwrap();
+        assert_eq!(
+            fs.mkdir(req, &deep, OsStr::new("notes"), 0o755).err(),
+            Some(libc::ENAMETOOLONG)
+        );
     }
 
     #[test]
     #[traced_test]
-    fn getattr_nofh_dir_err() {
+    fn mkdir_rmdir_mirrored() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_lstat().returning(|_| dir_stat());
             libc_wrapper
-                .expect_lstat()
-                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+                .expect_mkdir()
+                .withf(|path, mode| path == Path::new("/photos/2023") && *mode == 0o750)
+                .times(1)
+                .returning(|_, _| Ok(()));
+            libc_wrapper
+                .expect_rmdir()
+                .withf(|path| path == Path::new("/photos/2023"))
+                .times(1)
+                .returning(|_| Ok(()));
             libc_wrapper
         };
-
         let fs = new_test_fs(libc_wrapper);
+        {
+            let mut store = fs.store.write();
+            store.add_test_entry("1.jpeg", "/photos/1.jpeg", "image_jpeg");
+            store.rebuild_with(|entry| OrganizeFSEntry {
+                dirs: vec!["photos".into()],
+                ..entry.clone()
+            });
+            store.set_pattern("/{dir1}/{dir2}/{meta}");
+        }
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        {
-            let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "test".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
-        }
-        let resp = fs.getattr(req, &PathBuf::from("/"), None);
-        assert_eq!(resp.err(), Some(libc::EACCES));
+        let photos = PathBuf::from("/photos");
+        assert!(fs.mkdir(req, &photos, OsStr::new("2023"), 0o750).is_ok());
+        assert!(fs.rmdir(req, &photos, OsStr::new("2023")).is_ok());
     }
 
     #[test]
     #[traced_test]
-    fn getattr_nofh_dir_ok() {
+    fn mkdir_rmdir_above_root() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
-            libc_wrapper.expect_lstat().returning(|_| {
-                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
-                let stat = unsafe { s.assume_init_mut() };
-                stat.st_mode = libc::S_IFDIR + 0o0755;
-                stat.st_size = 5;
-                stat.st_nlink = 1;
-                Ok(stat.to_owned())
-            });
+            libc_wrapper.expect_lstat().returning(|_| dir_stat());
+            libc_wrapper.expect_mkdir().never();
+            libc_wrapper.expect_rmdir().never();
             libc_wrapper
         };
-
-        let fs = new_test_fs(libc_wrapper);
+        let mut fs = new_test_fs(libc_wrapper);
+        fs.root = PathBuf::from("/data/photos");
+        fs.options.strip_prefix = Some(PathBuf::from("/data"));
+        {
+            let mut store = fs.store.write();
+            store.add_test_entry("1.jpeg", "/data/photos/1.jpeg", "image_jpeg");
+            store.rebuild_with(|entry| OrganizeFSEntry {
+                dirs: vec!["photos".into()],
+                ..entry.clone()
+            });
+            store.set_pattern("/{dir1}/{meta}");
+        }
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
+        // `/music` would be `/data/music`, beside the root; `/photos` is the root itself.
+        let root = PathBuf::from("/");
+        assert!(fs.mkdir(req, &root, OsStr::new("music"), 0o750).is_ok());
+        assert!(fs.store.read().find_dir(Path::new("/music")).is_some());
+        assert!(fs.rmdir(req, &root, OsStr::new("music")).is_ok());
+        assert!(fs.store.read().find_dir(Path::new("/music")).is_none());
+        assert_eq!(
+            fs.mkdir(req, &root, OsStr::new("photos"), 0o750).err(),
+            Some(libc::EEXIST)
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn rmdir_not_empty() {
+        let fs = new_test_fs(MockLibcWrapper::new());
         {
             let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "test".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
+            store.add_test_entry("file.txt", "/host/file.txt", "text_plain");
+            store.set_pattern("/{meta}");
         }
-        let resp = fs.getattr(req, &PathBuf::from("/"), None);
-        assert!(resp.is_ok());
+        let req: RequestInfo = RequestInfo {
+            unique: 0,
+            pid: 0,
+            gid: 0,
+            uid: 0,
+        };
+        assert_eq!(
+            fs.rmdir(req, Path::new("/"), OsStr::new("text_plain"))
+                .err(),
+            Some(libc::ENOTEMPTY)
+        );
+        assert_eq!(
+            fs.rmdir(req, Path::new("/text_plain"), OsStr::new("file.txt"))
+                .err(),
+            Some(libc::ENOTDIR)
+        );
     }
 
-    // open tests
     #[test]
     #[traced_test]
-    fn open_missing() {
+    fn unlink_missing() {
         let libc_wrapper = MockLibcWrapper::new();
 
         let fs = new_test_fs(libc_wrapper);
@@ -1137,16 +7056,16 @@ mod tests {
         };
         let parent = PathBuf::from("/");
         let name = std::ffi::OsString::from("missing");
-        let r = fs.open(req, &parent.join(name), 0);
+        let r = fs.unlink(req, &parent, &name);
         assert_eq!(r.err(), Some(libc::ENOENT));
     }
 
     #[test]
     #[traced_test]
-    fn open_present() {
+    fn unlink_present() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
-            libc_wrapper.expect_open().returning(|_, _| Ok(1));
+            libc_wrapper.expect_unlink().returning(|_| Ok(()));
             libc_wrapper
         };
         let fs = new_test_fs(libc_wrapper);
@@ -1155,9 +7074,10 @@ mod tests {
             let entry = OrganizeFSEntry {
                 name: "present".into(),
                 host_path: "".into(),
-                size: "0 B".into(),
+                size: 0,
                 mime: "text_plain".into(),
                 modified_date: "2023-08-04".into(),
+                ..Default::default()
             };
             store.add_entry(entry);
         }
@@ -1169,31 +7089,35 @@ mod tests {
         };
         let parent = PathBuf::from("/");
         let name = std::ffi::OsString::from("present");
-        let r = fs.open(req, &parent.join(name), 0);
+        let r = fs.unlink(req, &parent, &name);
         assert!(r.is_ok());
+        {
+            let store = fs.store.read();
+            assert_eq!(store.arena.len(), 1);
+            assert!(store.entries.is_empty());
+        }
     }
 
     #[test]
     #[traced_test]
-    fn open_no_access() {
+    fn unlink_shared_host_file() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
             libc_wrapper
-                .expect_open()
-                .returning(|_, _| Err(io::Error::from_raw_os_error(libc::EACCES)));
+                .expect_unlink()
+                .withf(|path| path == Path::new("/host/file"))
+                .times(1)
+                .returning(|_| Ok(()));
             libc_wrapper
         };
         let fs = new_test_fs(libc_wrapper);
+        *fs.store.write() = OrganizeFSStore::new(PathBuf::from("/{meta}"));
         {
+            // One host file under two names, e.g. found by two scans as different types.
             let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "present".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
+            store.add_test_entry("file", "/host/file", "text_plain");
+            store.add_test_entry("file", "/host/file", "image_jpeg");
+            store.add_test_entry("other", "/host/other", "image_jpeg");
         }
         let req: RequestInfo = RequestInfo {
             unique: 0,
@@ -1201,130 +7125,294 @@ mod tests {
             gid: 0,
             uid: 0,
         };
-        let parent = PathBuf::from("/");
-        let name = std::ffi::OsString::from("present");
-        let r = fs.open(req, &parent.join(name), 0);
-        assert_eq!(r.err(), Some(libc::EACCES));
+        let r = fs.unlink(req, Path::new("/text_plain"), OsStr::new("file"));
+        assert!(r.is_ok());
+        let store = fs.store.read();
+        assert_eq!(store.entries.len(), 1);
+        assert!(store.host_path(Path::new("/image_jpeg/file")).is_none());
+        assert_eq!(
+            store.host_path(Path::new("/image_jpeg/other")),
+            Some(PathBuf::from("/host/other"))
+        );
     }
 
-    // flush tests
     #[test]
     #[traced_test]
-    fn flush_unimplemented() {
-        let libc_wrapper = MockLibcWrapper::new();
-
+    fn unlink_no_access() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_unlink()
+                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+            libc_wrapper
+        };
         let fs = new_test_fs(libc_wrapper);
+        {
+            let mut store = fs.store.write();
+            let entry = OrganizeFSEntry {
+                name: "present".into(),
+                host_path: "".into(),
+                size: 0,
+                mime: "text_plain".into(),
+                modified_date: "2023-08-04".into(),
+                ..Default::default()
+            };
+            store.add_entry(entry);
+        }
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let path = PathBuf::from("/missing");
-        let r = fs.flush(req, &path, 0, 0);
-        assert_eq!(r.err(), Some(libc::ENOSYS));
+        let parent = PathBuf::from("/");
+        let name = std::ffi::OsString::from("present");
+        let r = fs.unlink(req, &parent, &name);
+        assert_eq!(r.err(), Some(libc::EACCES));
     }
 
-    // release tests
+    // rename tests
     #[test]
     #[traced_test]
-    fn release_no_filehandle() {
-        let libc_wrapper = MockLibcWrapper::new();
-
-        let fs = new_test_fs(libc_wrapper);
+    fn rename_missing() {
+        let fs = new_test_fs(MockLibcWrapper::new());
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let path = PathBuf::from("/missing");
-        let r = fs.release(req, &path, 0, 0, 0, true);
+        let parent = PathBuf::from("/");
+        let r = fs.rename(
+            req,
+            &parent,
+            OsStr::new("missing"),
+            &parent,
+            OsStr::new("other"),
+        );
         assert_eq!(r.err(), Some(libc::ENOENT));
     }
 
     #[test]
     #[traced_test]
-    fn release_error() {
+    fn rename_leaf() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
             libc_wrapper
-                .expect_close()
-                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+                .expect_rename_noreplace()
+                .withf(|from, to| {
+                    from == Path::new("/host/present") && to == Path::new("/host/renamed")
+                })
+                .times(1)
+                .returning(|_, _| Ok(()));
             libc_wrapper
         };
-
         let fs = new_test_fs(libc_wrapper);
+        {
+            let mut store = fs.store.write();
+            store.add_test_entry("present", "/host/present", "text_plain");
+            store.set_pattern("/{meta}");
+        }
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let path = PathBuf::from("/missing");
-        let r = fs.release(req, &path, 1, 0, 0, true);
-        assert_eq!(r.err(), Some(libc::EACCES));
+        let parent = PathBuf::from("/text_plain");
+        let r = fs.rename(
+            req,
+            &parent,
+            OsStr::new("present"),
+            &parent,
+            OsStr::new("renamed"),
+        );
+        assert!(r.is_ok());
+        let store = fs.store.read();
+        assert_eq!(store.find_file(Path::new("/text_plain/present")), None);
+        assert_eq!(
+            store.host_path(Path::new("/text_plain/renamed")),
+            Some(PathBuf::from("/host/renamed"))
+        );
     }
 
     #[test]
     #[traced_test]
-    fn release_ok() {
+    fn rename_host_errors() {
+        for errno in [libc::EEXIST, libc::EXDEV] {
+            let libc_wrapper = {
+                let mut libc_wrapper = MockLibcWrapper::new();
+                libc_wrapper
+                    .expect_rename_noreplace()
+                    .times(1)
+                    .returning(move |_, _| Err(io::Error::from_raw_os_error(errno)));
+                libc_wrapper
+            };
+            let fs = new_test_fs(libc_wrapper);
+            {
+                let mut store = fs.store.write();
+                store.add_test_entry("present", "/host/present", "text_plain");
+                store.set_pattern("/{meta}");
+            }
+            let req: RequestInfo = RequestInfo {
+                unique: 0,
+                pid: 0,
+                gid: 0,
+                uid: 0,
+            };
+            let parent = PathBuf::from("/text_plain");
+            let r = fs.rename(
+                req,
+                &parent,
+                OsStr::new("present"),
+                &parent,
+                OsStr::new("renamed"),
+            );
+            assert_eq!(r.err(), Some(errno));
+            let store = fs.store.read();
+            assert_eq!(
+                store.host_path(Path::new("/text_plain/present")),
+                Some(PathBuf::from("/host/present"))
+            );
+            assert_eq!(store.find_file(Path::new("/text_plain/renamed")), None);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn rename_sidecar_failed_moves_back() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
-            libc_wrapper.expect_close().returning(|_| Ok(()));
+            libc_wrapper
+                .expect_rename_noreplace()
+                .withf(|from, to| {
+                    from == Path::new("/host/present") && to == Path::new("/host/renamed")
+                })
+                .times(1)
+                .returning(|_, _| Ok(()));
+            libc_wrapper
+                .expect_rename()
+                .withf(|from, to| {
+                    from == Path::new("/host/.present.organizefs")
+                        && to == Path::new("/host/.renamed.organizefs")
+                })
+                .times(1)
+                .returning(|_, _| Err(io::Error::from_raw_os_error(libc::EACCES)));
+            libc_wrapper
+                .expect_rename()
+                .withf(|from, to| {
+                    from == Path::new("/host/renamed") && to == Path::new("/host/present")
+                })
+                .times(1)
+                .returning(|_, _| Ok(()));
             libc_wrapper
         };
-
         let fs = new_test_fs(libc_wrapper);
+        {
+            let mut store = fs.store.write();
+            store.add_test_entry("present", "/host/present", "text_plain");
+            store.rebuild_with(|entry| OrganizeFSEntry {
+                overrides: BTreeMap::from([("meta".to_string(), "notes".to_string())]),
+                ..entry.clone()
+            });
+            store.set_pattern("/{meta}");
+        }
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let path = PathBuf::from("/missing");
-        let r = fs.release(req, &path, 1, 0, 0, true);
-        assert!(r.is_ok());
+        let parent = PathBuf::from("/notes");
+        let r = fs.rename(
+            req,
+            &parent,
+            OsStr::new("present"),
+            &parent,
+            OsStr::new("renamed"),
+        );
+        assert_eq!(r, Err(libc::EACCES));
+        let store = fs.store.read();
+        assert_eq!(
+            store.host_path(Path::new("/notes/present")),
+            Some(PathBuf::from("/host/present"))
+        );
+        assert_eq!(store.find_file(Path::new("/notes/renamed")), None);
     }
 
-    // unlink tests
     #[test]
     #[traced_test]
-    fn unlink_missing() {
-        let libc_wrapper = MockLibcWrapper::new();
-
+    fn rename_category_sidecar() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_write_file()
+                .withf(|path, contents| {
+                    path == Path::new("/host/.present.organizefs") && contents == b"meta=notes\n"
+                })
+                .times(1)
+                .returning(|_, _| Ok(()));
+            libc_wrapper.expect_rename().never();
+            libc_wrapper.expect_rename_noreplace().never();
+            libc_wrapper
+        };
         let fs = new_test_fs(libc_wrapper);
+        {
+            let mut store = fs.store.write();
+            store.add_test_entry("present", "/host/present", "text_plain");
+            store.set_pattern("/{meta}");
+        }
         let req: RequestInfo = RequestInfo {
             unique: 0,
             pid: 0,
             gid: 0,
             uid: 0,
         };
-        let parent = PathBuf::from("/");
-        let name = std::ffi::OsString::from("missing");
-        let r = fs.unlink(req, &parent, &name);
-        assert_eq!(r.err(), Some(libc::ENOENT));
+        let r = fs.rename(
+            req,
+            Path::new("/text_plain"),
+            OsStr::new("present"),
+            Path::new("/notes"),
+            OsStr::new("present"),
+        );
+        assert!(r.is_ok());
+        let store = fs.store.read();
+        assert_eq!(store.find_file(Path::new("/text_plain/present")), None);
+        assert_eq!(
+            store.host_path(Path::new("/notes/present")),
+            Some(PathBuf::from("/host/present"))
+        );
+
+        // The override keeps applying under a different pattern.
+        drop(store);
+        fs.store.write().set_pattern("/by/{meta}");
+        assert!(fs
+            .store
+            .read()
+            .host_path(Path::new("/by/notes/present"))
+            .is_some());
     }
 
     #[test]
     #[traced_test]
-    fn unlink_present() {
+    fn rename_category_known_keys() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
-            libc_wrapper.expect_unlink().returning(|_| Ok(()));
+            libc_wrapper
+                .expect_write_file()
+                .withf(|path, contents| {
+                    path == Path::new("/host/.present.organizefs")
+                        && contents == b"mime_subtype=markdown\n"
+                })
+                .times(1)
+                .returning(|_, _| Ok(()));
             libc_wrapper
         };
         let fs = new_test_fs(libc_wrapper);
         {
             let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "present".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
+            store.add_test_entry("present", "/host/present", "text_plain");
+            store.set_pattern("/{mime_type}/{mime_subtype?}");
         }
         let req: RequestInfo = RequestInfo {
             unique: 0,
@@ -1332,38 +7420,38 @@ mod tests {
             gid: 0,
             uid: 0,
         };
-        let parent = PathBuf::from("/");
-        let name = std::ffi::OsString::from("present");
-        let r = fs.unlink(req, &parent, &name);
-        assert!(r.is_ok());
-        {
-            let store = fs.store.read();
-            assert_eq!(store.arena.len(), 1);
-            assert!(store.entries.is_empty());
-        }
+        let r = fs.rename(
+            req,
+            Path::new("/text/plain"),
+            OsStr::new("present"),
+            Path::new("/text/markdown"),
+            OsStr::new("present"),
+        );
+        assert_eq!(r, Ok(()));
+        assert!(fs
+            .store
+            .read()
+            .host_path(Path::new("/text/markdown/present"))
+            .is_some());
     }
 
     #[test]
     #[traced_test]
-    fn unlink_no_access() {
+    fn rename_category_failed_leaves_no_sidecar() {
         let libc_wrapper = {
             let mut libc_wrapper = MockLibcWrapper::new();
             libc_wrapper
-                .expect_unlink()
-                .returning(|_| Err(io::Error::from_raw_os_error(libc::EACCES)));
+                .expect_rename_noreplace()
+                .times(1)
+                .returning(|_, _| Err(io::Error::from_raw_os_error(libc::EEXIST)));
+            libc_wrapper.expect_write_file().never();
             libc_wrapper
         };
         let fs = new_test_fs(libc_wrapper);
         {
             let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "present".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
+            store.add_test_entry("present", "/host/present", "text_plain");
+            store.set_pattern("/{meta}");
         }
         let req: RequestInfo = RequestInfo {
             unique: 0,
@@ -1371,29 +7459,29 @@ mod tests {
             gid: 0,
             uid: 0,
         };
-        let parent = PathBuf::from("/");
-        let name = std::ffi::OsString::from("present");
-        let r = fs.unlink(req, &parent, &name);
-        assert_eq!(r.err(), Some(libc::EACCES));
+        let r = fs.rename(
+            req,
+            Path::new("/text_plain"),
+            OsStr::new("present"),
+            Path::new("/notes"),
+            OsStr::new("renamed"),
+        );
+        assert_eq!(r, Err(libc::EEXIST));
+        assert!(fs
+            .store
+            .read()
+            .host_path(Path::new("/text_plain/present"))
+            .is_some());
     }
 
-    // rename tests
-    // TODO Proper implementation
     #[test]
     #[traced_test]
-    fn rename_unimplemented() {
-        let libc_wrapper = MockLibcWrapper::new();
-        let fs = new_test_fs(libc_wrapper);
+    fn rename_literal_component() {
+        let fs = new_test_fs(MockLibcWrapper::new());
         {
             let mut store = fs.store.write();
-            let entry = OrganizeFSEntry {
-                name: "present".into(),
-                host_path: "".into(),
-                size: "0 B".into(),
-                mime: "text_plain".into(),
-                modified_date: "2023-08-04".into(),
-            };
-            store.add_entry(entry);
+            store.add_test_entry("present", "/host/present", "text_plain");
+            store.set_pattern("/by-{meta}");
         }
         let req: RequestInfo = RequestInfo {
             unique: 0,
@@ -1401,11 +7489,13 @@ mod tests {
             gid: 0,
             uid: 0,
         };
-        let parent = PathBuf::from("/");
-        let name = std::ffi::OsString::from("present");
-        let newparent = PathBuf::from("/");
-        let newname = std::ffi::OsString::from("missing");
-        let r = fs.rename(req, &parent, &name, &newparent, &newname);
-        assert_eq!(r.err(), Some(libc::ENOSYS));
+        let r = fs.rename(
+            req,
+            Path::new("/by-text_plain"),
+            OsStr::new("present"),
+            Path::new("/elsewhere"),
+            OsStr::new("present"),
+        );
+        assert_eq!(r.err(), Some(libc::EXDEV));
     }
 }
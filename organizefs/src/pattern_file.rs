@@ -0,0 +1,103 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use parking_lot::RwLock;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    watcher::{watch_path, EventQueue, WatchEvent},
+    OrganizeFSStore,
+};
+
+/// Read the pattern from `path`, ignoring surrounding whitespace.
+pub fn read_pattern_file(path: &Path) -> std::io::Result<String> {
+    fs::read_to_string(path).map(|pattern| pattern.trim().to_string())
+}
+
+/// Apply the pattern in `path` to `store` if it is valid.
+///
+/// Rejected or unreadable patterns are logged and leave the current pattern in place.
+#[instrument(skip(store))]
+pub fn apply_pattern_file(store: &RwLock<OrganizeFSStore>, path: &Path) -> bool {
+    let pattern = match read_pattern_file(path) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            warn!(
+                path = debug(path),
+                error = display(e),
+                "pattern file unreadable"
+            );
+            return false;
+        }
+    };
+    if let Err(e) = store.read().validate_pattern(&pattern) {
+        warn!(pattern, error = display(e), "pattern file rejected");
+        return false;
+    }
+    info!(pattern, "pattern file applied");
+    store.write().set_pattern(&pattern);
+    true
+}
+
+/// Apply a change to the pattern file; a removal keeps the current pattern.
+fn handle_event(store: &RwLock<OrganizeFSStore>, path: &Path, event: WatchEvent) {
+    match event {
+        WatchEvent::Changed => {
+            apply_pattern_file(store, path);
+        }
+        WatchEvent::Removed => warn!(path = debug(path), "pattern file removed, keeping pattern"),
+    }
+}
+
+/// Re-apply the pattern whenever the file at `path` changes.
+pub fn watch_pattern_file(
+    store: Arc<RwLock<OrganizeFSStore>>,
+    path: PathBuf,
+) -> notify::Result<JoinHandle<()>> {
+    let queue = Arc::new(EventQueue::new(1));
+    let watcher = watch_path(&path, queue.clone())?;
+    Ok(thread::spawn(move || {
+        // Watching lasts as long as this thread.
+        let _watcher = watcher;
+        loop {
+            for (path, event) in queue.wait_drain() {
+                handle_event(&store, &path, event);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn pattern_file_change() {
+        let path = std::env::temp_dir().join(format!("organizefs-pattern-{}", std::process::id()));
+        let store = RwLock::new(OrganizeFSStore::new(PathBuf::from("/{meta}")));
+        let queue = EventQueue::new(1);
+
+        fs::write(&path, "/{mdate}/{meta}\n").unwrap();
+        queue.push(path.clone(), WatchEvent::Changed);
+        queue.process(|path, event| handle_event(&store, path, event));
+        assert_eq!(store.read().get_pattern(), "/{mdate}/{meta}");
+
+        fs::write(&path, "/../../etc/{meta}").unwrap();
+        queue.push(path.clone(), WatchEvent::Changed);
+        queue.process(|path, event| handle_event(&store, path, event));
+        assert_eq!(store.read().get_pattern(), "/{mdate}/{meta}");
+        assert!(logs_contain("pattern file rejected"));
+
+        fs::remove_file(&path).unwrap();
+        queue.push(path.clone(), WatchEvent::Removed);
+        queue.process(|path, event| handle_event(&store, path, event));
+        assert_eq!(store.read().get_pattern(), "/{mdate}/{meta}");
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    ffi::CString,
+    fmt::Display,
+    fs,
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use tracing::debug;
+
+const FUSE_DEVICE: &str = "/dev/fuse";
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// Why mounting would fail, found before trying; each says what to do about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreflightError {
+    NoFuseDevice(PathBuf),
+    FuseDeviceAccess(PathBuf),
+    NoMountpoint(PathBuf),
+    MountpointNotADirectory(PathBuf),
+    MountpointNotEmpty(PathBuf),
+    MountpointNotWritable(PathBuf),
+    /// `allow_other` was asked for, but only root may use it without `user_allow_other`.
+    AllowOtherNotPermitted(PathBuf),
+}
+
+impl Display for PreflightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoFuseDevice(device) => write!(
+                f,
+                "{device:?} not found: install FUSE and load its module (modprobe fuse)"
+            ),
+            Self::FuseDeviceAccess(device) => write!(
+                f,
+                "no read/write access to {device:?}: add yourself to its group (often 'fuse') or fix its permissions"
+            ),
+            Self::NoMountpoint(mountpoint) => {
+                write!(f, "mountpoint {mountpoint:?} not found: create it first")
+            }
+            Self::MountpointNotADirectory(mountpoint) => {
+                write!(f, "mountpoint {mountpoint:?} is not a directory")
+            }
+            Self::MountpointNotEmpty(mountpoint) => write!(
+                f,
+                "mountpoint {mountpoint:?} is not empty: empty it, or unmount what's already mounted there"
+            ),
+            Self::MountpointNotWritable(mountpoint) => write!(
+                f,
+                "no write access to mountpoint {mountpoint:?}: mount somewhere you own"
+            ),
+            Self::AllowOtherNotPermitted(conf) => write!(
+                f,
+                "allow_other needs 'user_allow_other' in {conf:?}, or running as root"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+/// Check that `mountpoint` can be mounted on, with `allow_other` if it's asked for, so
+/// a failure is explained rather than surfacing from the mount itself.
+pub fn preflight(mountpoint: &Path, allow_other: bool) -> Result<(), PreflightError> {
+    check_fuse_device(Path::new(FUSE_DEVICE))?;
+    check_mountpoint(mountpoint)?;
+    if allow_other {
+        let euid = unsafe { libc::geteuid() };
+        check_allow_other(Path::new(FUSE_CONF), euid)?;
+    }
+    Ok(())
+}
+
+fn accessible(path: &Path, mode: i32) -> bool {
+    let Ok(cstr) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(cstr.as_ptr(), mode) == 0 }
+}
+
+fn check_fuse_device(device: &Path) -> Result<(), PreflightError> {
+    if !device.exists() {
+        return Err(PreflightError::NoFuseDevice(device.to_path_buf()));
+    }
+    if !accessible(device, libc::R_OK | libc::W_OK) {
+        return Err(PreflightError::FuseDeviceAccess(device.to_path_buf()));
+    }
+    Ok(())
+}
+
+fn check_mountpoint(mountpoint: &Path) -> Result<(), PreflightError> {
+    let error = |e: fn(PathBuf) -> PreflightError| Err(e(mountpoint.to_path_buf()));
+    let Ok(meta) = fs::metadata(mountpoint) else {
+        return error(PreflightError::NoMountpoint);
+    };
+    if !meta.is_dir() {
+        return error(PreflightError::MountpointNotADirectory);
+    }
+    if fs::read_dir(mountpoint).is_ok_and(|mut entries| entries.next().is_some()) {
+        return error(PreflightError::MountpointNotEmpty);
+    }
+    if !accessible(mountpoint, libc::W_OK) {
+        return error(PreflightError::MountpointNotWritable);
+    }
+    Ok(())
+}
+
+fn check_allow_other(conf: &Path, euid: u32) -> Result<(), PreflightError> {
+    if euid == 0 {
+        return Ok(());
+    }
+    let contents = fs::read_to_string(conf).unwrap_or_default();
+    debug!(conf = debug(conf), contents, "fuse.conf");
+    if contents
+        .lines()
+        .any(|line| line.trim() == "user_allow_other")
+    {
+        Ok(())
+    } else {
+        Err(PreflightError::AllowOtherNotPermitted(conf.to_path_buf()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "organizefs-preflight-{name}-{}",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    #[traced_test]
+    fn fuse_device() {
+        let device = temp_path("fuse");
+        assert_eq!(
+            check_fuse_device(&device),
+            Err(PreflightError::NoFuseDevice(device.clone()))
+        );
+        fs::write(&device, "").unwrap();
+        assert_eq!(check_fuse_device(&device), Ok(()));
+        fs::remove_file(&device).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn mountpoint() {
+        let mountpoint = temp_path("mnt");
+        assert_eq!(
+            check_mountpoint(&mountpoint),
+            Err(PreflightError::NoMountpoint(mountpoint.clone()))
+        );
+
+        fs::write(&mountpoint, "").unwrap();
+        assert_eq!(
+            check_mountpoint(&mountpoint),
+            Err(PreflightError::MountpointNotADirectory(mountpoint.clone()))
+        );
+        fs::remove_file(&mountpoint).unwrap();
+
+        fs::create_dir(&mountpoint).unwrap();
+        assert_eq!(check_mountpoint(&mountpoint), Ok(()));
+
+        fs::write(mountpoint.join("file"), "").unwrap();
+        assert_eq!(
+            check_mountpoint(&mountpoint),
+            Err(PreflightError::MountpointNotEmpty(mountpoint.clone()))
+        );
+        fs::remove_dir_all(&mountpoint).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn allow_other() {
+        let conf = temp_path("fuse.conf");
+        let denied = Err(PreflightError::AllowOtherNotPermitted(conf.clone()));
+        assert_eq!(check_allow_other(&conf, 1000), denied);
+        assert_eq!(check_allow_other(&conf, 0), Ok(()));
+
+        fs::write(&conf, "# user_allow_other\nmount_max = 1000\n").unwrap();
+        assert_eq!(check_allow_other(&conf, 1000), denied);
+
+        fs::write(&conf, "mount_max = 1000\n  user_allow_other\n").unwrap();
+        assert_eq!(check_allow_other(&conf, 1000), Ok(()));
+        fs::remove_file(&conf).unwrap();
+    }
+}
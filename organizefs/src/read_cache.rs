@@ -0,0 +1,94 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Default bytes of file data kept for repeated reads.
+pub const DEFAULT_READ_CACHE_BYTES: usize = 4 * 1024 * 1024;
+
+/// A read, as the kernel asked for it: handle, offset and size.
+pub type ReadKey = (u64, u64, u32);
+
+/// Bounded cache of recent reads, evicting the oldest first.
+#[derive(Debug)]
+pub struct ReadCache {
+    capacity: usize,
+    used: usize,
+    order: VecDeque<ReadKey>,
+    blocks: HashMap<ReadKey, Vec<u8>>,
+}
+
+impl ReadCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            order: VecDeque::new(),
+            blocks: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get(&self, key: &ReadKey) -> Option<&[u8]> {
+        self.blocks.get(key).map(Vec::as_slice)
+    }
+
+    pub fn insert(&mut self, key: ReadKey, data: &[u8]) {
+        if data.len() > self.capacity || self.blocks.contains_key(&key) {
+            return;
+        }
+        while self.used + data.len() > self.capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.blocks.remove(&oldest) {
+                self.used -= evicted.len();
+            }
+        }
+        self.used += data.len();
+        self.order.push_back(key);
+        self.blocks.insert(key, data.to_vec());
+    }
+
+    /// Forget everything read through `fh`, whose number the host may hand out again.
+    pub fn invalidate(&mut self, fh: u64) {
+        self.order.retain(|key| key.0 != fh);
+        self.blocks.retain(|key, _| key.0 != fh);
+        self.used = self.blocks.values().map(Vec::len).sum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn evicts_oldest() {
+        let mut cache = ReadCache::new(8);
+        cache.insert((1, 0, 4), b"abcd");
+        cache.insert((1, 4, 4), b"efgh");
+        cache.insert((1, 8, 4), b"ijkl");
+        assert_eq!(cache.get(&(1, 0, 4)), None);
+        assert_eq!(cache.get(&(1, 4, 4)), Some(&b"efgh"[..]));
+        assert_eq!(cache.get(&(1, 8, 4)), Some(&b"ijkl"[..]));
+
+        cache.insert((2, 0, 16), &[0; 16]);
+        assert_eq!(cache.get(&(2, 0, 16)), None);
+    }
+
+    #[test]
+    #[traced_test]
+    fn invalidate_handle() {
+        let mut cache = ReadCache::new(8);
+        cache.insert((1, 0, 4), b"abcd");
+        cache.insert((2, 0, 4), b"efgh");
+        cache.invalidate(1);
+        assert_eq!(cache.get(&(1, 0, 4)), None);
+        cache.insert((3, 0, 4), b"ijkl");
+        assert_eq!(cache.get(&(2, 0, 4)), Some(&b"efgh"[..]));
+        assert_eq!(cache.get(&(3, 0, 4)), Some(&b"ijkl"[..]));
+    }
+}
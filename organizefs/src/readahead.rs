@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+/// Readahead per handle, off unless configured.
+pub const DEFAULT_READAHEAD_BYTES: usize = 0;
+
+/// Where a handle's reads have got to, and what was read ahead of them.
+#[derive(Debug, Default)]
+struct HandleReadahead {
+    /// Offset just past the last read, where a sequential one would start.
+    next: u64,
+    /// Offset of `data` in the file.
+    start: u64,
+    data: Vec<u8>,
+    /// Whether `data` ended where the file did when it was read. Only a hint, as the
+    /// file may have grown since.
+    eof: bool,
+}
+
+/// Larger reads for handles read sequentially, so clients asking for a few KiB at a
+/// time cost one host read per chunk. Each handle buffers at most one chunk, dropped
+/// as soon as the handle reads anywhere else.
+#[derive(Debug)]
+pub struct Readahead {
+    chunk: usize,
+    handles: HashMap<u64, HandleReadahead>,
+}
+
+impl Readahead {
+    pub fn new(chunk: usize) -> Self {
+        Self {
+            chunk,
+            handles: HashMap::new(),
+        }
+    }
+
+    /// Bytes read ahead at a time; 0 if off.
+    pub fn chunk(&self) -> usize {
+        self.chunk
+    }
+
+    /// The `size` bytes at `offset` through `fh`, if they're all buffered, or the
+    /// buffer runs to what was the end of the file. Anything else is a miss, and unless
+    /// it continues the last read the buffer is dropped. A read from the end of the
+    /// buffer always misses, so a file that has grown since shows its new data.
+    pub fn get(&mut self, fh: u64, offset: u64, size: u32) -> Option<&[u8]> {
+        let handle = self.handles.get_mut(&fh)?;
+        let end = handle.start + handle.data.len() as u64;
+        let wanted = offset.saturating_add(size.into());
+        if offset < handle.start || offset >= end || (wanted > end && !handle.eof) {
+            if offset != handle.next {
+                handle.data = Vec::new();
+            }
+            return None;
+        }
+        let from = (offset - handle.start) as usize;
+        let to = (wanted.min(end) - handle.start) as usize;
+        handle.next = offset + (to - from) as u64;
+        Some(&handle.data[from..to])
+    }
+
+    /// Whether a read at `offset` through `fh` picks up where the last one left off.
+    pub fn is_sequential(&self, fh: u64, offset: u64) -> bool {
+        self.handles
+            .get(&fh)
+            .is_some_and(|handle| handle.next == offset)
+    }
+
+    /// Note a read of `count` bytes at `offset` through `fh` that wasn't buffered.
+    pub fn record(&mut self, fh: u64, offset: u64, count: usize) {
+        let handle = self.handles.entry(fh).or_default();
+        handle.next = offset + count as u64;
+    }
+
+    /// Buffer `data`, read ahead from `offset` through `fh`; `eof` if the host had no
+    /// more to give.
+    pub fn fill(&mut self, fh: u64, offset: u64, data: Vec<u8>, eof: bool) {
+        self.handles.insert(
+            fh,
+            HandleReadahead {
+                next: offset,
+                start: offset,
+                data,
+                eof,
+            },
+        );
+    }
+
+    /// Forget `fh`, whose data may have changed or whose number may be handed out again.
+    pub fn invalidate(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn sequential_only() {
+        let mut readahead = Readahead::new(8);
+        assert!(!readahead.is_sequential(1, 0));
+        readahead.record(1, 0, 4);
+        assert!(readahead.is_sequential(1, 4));
+        assert!(!readahead.is_sequential(1, 8));
+        assert!(!readahead.is_sequential(2, 4));
+    }
+
+    #[test]
+    #[traced_test]
+    fn serves_buffered() {
+        let mut readahead = Readahead::new(8);
+        readahead.fill(1, 4, b"efghijkl".to_vec(), false);
+        assert_eq!(readahead.get(1, 4, 4), Some(&b"efgh"[..]));
+        assert_eq!(readahead.get(1, 8, 4), Some(&b"ijkl"[..]));
+        // Past the buffer, but the file goes on: a sequential miss.
+        assert_eq!(readahead.get(1, 12, 4), None);
+        assert!(readahead.is_sequential(1, 12));
+
+        readahead.fill(1, 12, b"mn".to_vec(), true);
+        assert_eq!(readahead.get(1, 12, 4), Some(&b"mn"[..]));
+        // At the end, in case the file grew.
+        assert_eq!(readahead.get(1, 14, 4), None);
+        assert!(readahead.is_sequential(1, 14));
+        readahead.fill(1, 14, Vec::new(), true);
+        assert_eq!(readahead.get(1, 14, 4), None);
+    }
+
+    #[test]
+    #[traced_test]
+    fn seek_drops_buffer() {
+        let mut readahead = Readahead::new(8);
+        readahead.fill(1, 4, b"efghijkl".to_vec(), false);
+        assert_eq!(readahead.get(1, 0, 4), None);
+        assert!(!readahead.is_sequential(1, 0));
+        assert_eq!(readahead.get(1, 4, 4), None);
+
+        readahead.fill(2, 0, b"abcd".to_vec(), false);
+        readahead.invalidate(2);
+        assert_eq!(readahead.get(2, 0, 4), None);
+    }
+}
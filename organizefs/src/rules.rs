@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use tracing::debug;
+
+use crate::{common::is_known_key, OrganizeFSEntry};
+
+/// Tag entries whose placeholder `when.0` is `when.1` with `set.0` = `set.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub when: (String, String),
+    pub set: (String, String),
+}
+
+impl Rule {
+    pub fn new(when: (&str, &str), set: (&str, &str)) -> Self {
+        Self {
+            when: (when.0.to_string(), when.1.to_string()),
+            set: (set.0.to_string(), set.1.to_string()),
+        }
+    }
+}
+
+/// Ordered tagging rules; when several set the same placeholder, the last match wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self(rules)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Placeholder values the rules set for an entry whose known placeholders are
+    /// looked up through `value`. Rules naming an unknown placeholder are skipped.
+    pub fn tags<F, S>(&self, value: F) -> BTreeMap<String, String>
+    where
+        F: Fn(&str) -> S,
+        S: AsRef<str>,
+    {
+        let mut tags = BTreeMap::new();
+        for rule in &self.0 {
+            if !is_known_key::<OrganizeFSEntry>(&rule.when.0)
+                || !is_known_key::<OrganizeFSEntry>(&rule.set.0)
+            {
+                debug!(rule = debug(rule), "unknown placeholder, skipped");
+                continue;
+            }
+            if value(&rule.when.0).as_ref() == rule.when.1 {
+                tags.insert(rule.set.0.clone(), rule.set.1.clone());
+            }
+        }
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn last_match_wins() {
+        let rules = Rules::new(vec![
+            Rule::new(("dir1", "camera"), ("meta", "photos")),
+            Rule::new(("dir1", "camera"), ("meta", "pictures")),
+            Rule::new(("dir1", "phone"), ("meta", "calls")),
+            Rule::new(("colour", "red"), ("meta", "red")),
+        ]);
+        let tags = rules.tags(|key| match key {
+            "dir1" => "camera",
+            _ => "",
+        });
+        assert_eq!(
+            tags,
+            BTreeMap::from([("meta".to_string(), "pictures".to_string())])
+        );
+    }
+}
@@ -1,20 +1,92 @@
-use std::sync::Arc;
+use std::{future::Future, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use axum::{
-    extract::State,
+    extract::{Query, State},
+    http::StatusCode,
     routing::{get, post},
-    Router,
+    Json, Router,
 };
+use hyper::server::{conn::AddrIncoming, Builder};
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot::Receiver;
 
-use crate::OrganizeFSStore;
+use crate::{
+    libc_wrapper::LibcWrapperReal, EntryDetail, NameCount, OrganizeFS, OrganizeFSStore,
+    OrganizeFsConfig, ReadMetrics, ScanOptions, TreeNode,
+};
 
 type Stats = Arc<RwLock<OrganizeFSStore>>;
 type AxumState = State<Stats>;
 
-/// Setup REST endpoints
-pub async fn server(stats: Stats, rx: Receiver<()>) -> Result<(), hyper::Error> {
+/// Levels `/tree.json` descends by default, and at most, to bound the payload.
+const DEFAULT_TREE_DEPTH: usize = 4;
+const MAX_TREE_DEPTH: usize = 16;
+
+/// Names `/names` lists by default, the most frequent first.
+const DEFAULT_NAMES_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+struct EntryQuery {
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TreeQuery {
+    path: Option<PathBuf>,
+    depth: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NamesQuery {
+    limit: Option<usize>,
+}
+
+/// `/stats.json`: the store's pattern and counts, for tooling.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct StatsJson {
+    pattern: String,
+    leaf_count: usize,
+    node_count: usize,
+}
+
+/// Where the REST endpoints listen.
+pub const SERVER_ADDR: &str = "0.0.0.0:3000";
+
+/// Bind the REST endpoints' listener, before mounting, so a port already in use fails
+/// without leaving a mount behind.
+pub fn bind(addr: &SocketAddr) -> Result<Builder<AddrIncoming>, hyper::Error> {
+    axum::Server::try_bind(addr)
+}
+
+/// Serve until `server` ends. Once it ends in error nothing will signal the mount to
+/// stop, so `unmount` is given the mount; otherwise it's handed back to be joined, the
+/// filesystem having shut the server down.
+pub async fn serve_mounted<M, E>(
+    mount: M,
+    server: impl Future<Output = Result<(), E>>,
+    unmount: impl FnOnce(M),
+) -> Result<M, E> {
+    match server.await {
+        Ok(()) => Ok(mount),
+        Err(e) => {
+            unmount(mount);
+            Err(e)
+        }
+    }
+}
+
+/// Setup REST endpoints on `listener`. `options` are what the root in `config` was
+/// scanned with, for `/rescan`.
+pub async fn server(
+    listener: Builder<AddrIncoming>,
+    stats: Stats,
+    read_metrics: Arc<ReadMetrics>,
+    config: OrganizeFsConfig,
+    options: ScanOptions,
+    rx: Receiver<()>,
+) -> Result<(), hyper::Error> {
+    let root = config.root.clone();
     let app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .route(
@@ -24,24 +96,431 @@ pub async fn server(stats: Stats, rx: Receiver<()>) -> Result<(), hyper::Error>
                 format!("{:?}", *stats)
             }),
         )
+        .route("/stats.json", get(stats_json))
         .route(
-            "/pattern",
-            get(|s: AxumState| async move { s.read().get_pattern() }),
+            "/metrics",
+            get(move || async move { read_metrics.render() }),
+        )
+        .route(
+            "/config",
+            get(move |s: AxumState| effective_config(s, config.clone())),
         )
+        .route("/entry", get(entry))
+        .route("/tree.json", get(tree))
+        .route("/names", get(names))
+        .route("/fsck", get(fsck))
+        .route("/prune", post(prune))
+        .route(
+            "/rescan",
+            post(move |s: AxumState| rescan(s, root.clone(), options.clone())),
+        )
+        .route("/manifest", get(manifest))
         .route(
             "/pattern",
-            post(|s: AxumState, body: String| async move {
-                // TODO reduce write lock time
-                s.write().set_pattern(&body);
-            }),
+            get(|s: AxumState| async move { s.read().get_pattern() }),
         )
+        .route("/pattern", post(set_pattern))
+        .route("/pattern/suggest", get(suggest_pattern))
         .with_state(stats.clone());
+    #[cfg(feature = "webdav")]
+    let app = app.merge(crate::webdav::router(
+        stats.clone(),
+        Arc::new(crate::libc_wrapper::LibcWrapperReal::new()),
+    ));
 
-    // run it with hyper on localhost:3000
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
+    listener
         .serve(app.into_make_service())
         .with_graceful_shutdown(async {
             rx.await.ok();
         })
         .await
 }
+
+async fn stats_json(s: AxumState) -> Json<StatsJson> {
+    let stats = s.read();
+    Json(StatsJson {
+        pattern: stats.get_pattern(),
+        leaf_count: stats.len(),
+        node_count: stats.node_count(),
+    })
+}
+
+async fn names(s: AxumState, Query(query): Query<NamesQuery>) -> Json<Vec<NameCount>> {
+    let mut names = s.read().name_counts();
+    names.truncate(query.limit.unwrap_or(DEFAULT_NAMES_LIMIT));
+    Json(names)
+}
+
+async fn entry(
+    s: AxumState,
+    Query(query): Query<EntryQuery>,
+) -> Result<Json<EntryDetail>, StatusCode> {
+    s.read()
+        .entry_detail(&query.path)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn tree(s: AxumState, Query(query): Query<TreeQuery>) -> Result<Json<TreeNode>, StatusCode> {
+    let path = query.path.unwrap_or_else(|| PathBuf::from("/"));
+    let depth = query
+        .depth
+        .unwrap_or(DEFAULT_TREE_DEPTH)
+        .min(MAX_TREE_DEPTH);
+    s.read()
+        .tree(&path, depth)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn effective_config(s: AxumState, mut config: OrganizeFsConfig) -> Json<OrganizeFsConfig> {
+    config.refresh(&s.read());
+    Json(config)
+}
+
+/// Replies with the pattern as it's now in effect, which is the normalized form of `body`.
+async fn set_pattern(s: AxumState, body: String) -> Result<String, (StatusCode, String)> {
+    s.read()
+        .validate_pattern(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    // TODO reduce write lock time
+    let mut store = s.write();
+    store.set_pattern(&body);
+    Ok(store.get_pattern())
+}
+
+async fn suggest_pattern(s: AxumState) -> Result<String, (StatusCode, String)> {
+    s.read()
+        .suggest_pattern()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+async fn manifest(s: AxumState) -> Result<Vec<u8>, StatusCode> {
+    let mut manifest = Vec::new();
+    s.read()
+        .export_to_writer(&mut manifest)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(manifest)
+}
+
+/// Drop entries whose host files have gone; replies with how many.
+async fn prune(s: AxumState) -> Result<String, (StatusCode, String)> {
+    let State(stats) = s;
+    tokio::task::spawn_blocking(move || {
+        OrganizeFSStore::prune_missing(&stats, &LibcWrapperReal::new())
+    })
+    .await
+    .map(|pruned| pruned.to_string())
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Rebuild the store from the host tree, replying with how many files it now has.
+async fn rescan(
+    s: AxumState,
+    root: PathBuf,
+    options: ScanOptions,
+) -> Result<String, (StatusCode, String)> {
+    if options.lazy {
+        return Err((
+            StatusCode::CONFLICT,
+            "lazy, directories are indexed as they're listed".to_string(),
+        ));
+    }
+    let State(stats) = s;
+    tokio::task::spawn_blocking(move || OrganizeFS::rescan(&root, &options, &stats))
+        .await
+        .map(|leaves| leaves.to_string())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+async fn fsck(s: AxumState) -> (StatusCode, String) {
+    match s.read().validate_integrity() {
+        Ok(()) => (StatusCode::OK, "ok".to_string()),
+        Err(errors) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            errors.iter().map(|e| format!("{e}\n")).collect::<String>(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    fn new_test_stats() -> Stats {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("2.jpeg", "/host/2.jpeg", "image_jpeg");
+        Arc::new(RwLock::new(store))
+    }
+
+    fn query(path: &str) -> Query<EntryQuery> {
+        Query(EntryQuery {
+            path: PathBuf::from(path),
+        })
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn server_failure_unmounts() {
+        let unmounted = std::cell::Cell::new(false);
+        let served = serve_mounted("mount", async { Err("address in use") }, |mount| {
+            assert_eq!(mount, "mount");
+            unmounted.set(true);
+        })
+        .await;
+        assert_eq!(served, Err("address in use"));
+        assert!(unmounted.get());
+
+        let served = serve_mounted("mount", async { Ok::<_, ()>(()) }, |_| unreachable!()).await;
+        assert_eq!(served, Ok("mount"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn bind_in_use() {
+        let listener = bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let serving = listener.serve(Router::new().into_make_service());
+        assert!(bind(&serving.local_addr()).is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn entry_file() {
+        let stats = new_test_stats();
+        let Json(detail) = entry(State(stats), query("/image_jpeg/1.jpeg"))
+            .await
+            .unwrap();
+        assert_eq!(
+            detail,
+            EntryDetail::File {
+                name: "1.jpeg".into(),
+                host_path: PathBuf::from("/host/1.jpeg"),
+                size: "0.00B".into(),
+                mime: "image_jpeg".into(),
+                modified_date: "2023-08-04".into(),
+            }
+        );
+        let json = serde_json::to_value(&detail).unwrap();
+        assert_eq!(json["kind"], "file");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn entry_directory() {
+        let stats = new_test_stats();
+        let Json(detail) = entry(State(stats), query("/image_jpeg")).await.unwrap();
+        assert_eq!(detail, EntryDetail::Directory { children: 2 });
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn tree_nested() {
+        let stats = new_test_stats();
+        stats
+            .write()
+            .add_test_entry("a.txt", "/host/a.txt", "text_plain");
+        let Json(node) = tree(State(stats.clone()), Query(TreeQuery::default()))
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&node).unwrap(),
+            serde_json::json!({
+                "kind": "directory",
+                "name": "/",
+                "children": [
+                    {
+                        "kind": "directory",
+                        "name": "image_jpeg",
+                        "children": [
+                            {"kind": "file", "name": "1.jpeg"},
+                            {"kind": "file", "name": "2.jpeg"},
+                        ],
+                    },
+                    {
+                        "kind": "directory",
+                        "name": "text_plain",
+                        "children": [{"kind": "file", "name": "a.txt"}],
+                    },
+                ],
+            })
+        );
+
+        let query = TreeQuery {
+            path: Some(PathBuf::from("/")),
+            depth: Some(1),
+        };
+        let Json(node) = tree(State(stats.clone()), Query(query)).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&node).unwrap()["children"][0],
+            serde_json::json!({"kind": "directory", "name": "image_jpeg"})
+        );
+
+        let query = TreeQuery {
+            path: Some(PathBuf::from("/image_jpeg/1.jpeg")),
+            depth: None,
+        };
+        let resp = tree(State(stats), Query(query)).await;
+        assert_eq!(resp.err(), Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn manifest_sorted() {
+        let stats = new_test_stats();
+        let manifest = manifest(State(stats)).await.unwrap();
+        assert_eq!(
+            String::from_utf8(manifest).unwrap(),
+            "/image_jpeg/1.jpeg\t/host/1.jpeg\t0.00B\n/image_jpeg/2.jpeg\t/host/2.jpeg\t0.00B\n"
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn suggest_pattern_images() {
+        let stats = new_test_stats();
+        let pattern = suggest_pattern(State(stats)).await.unwrap();
+        assert_eq!(pattern, "/{meta}/{year}");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn set_pattern_escaping_root() {
+        let stats = new_test_stats();
+        let resp = set_pattern(State(stats.clone()), "/../../etc/{meta}".into()).await;
+        assert_eq!(resp.unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert_eq!(stats.read().get_pattern(), "/{meta}");
+
+        let (status, message) = set_pattern(State(stats.clone()), "/{colour}".into())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.contains("{colour}"));
+        assert_eq!(stats.read().get_pattern(), "/{meta}");
+
+        let applied = set_pattern(State(stats.clone()), "/s/../t/./{meta}/".into())
+            .await
+            .unwrap();
+        assert_eq!(applied, "/t/{meta}");
+        assert_eq!(stats.read().get_pattern(), "/t/{meta}");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn config_reflects_pattern() {
+        let stats = new_test_stats();
+        let config = OrganizeFsConfig {
+            umask: "027".into(),
+            ..OrganizeFsConfig::default()
+        };
+        let Json(effective) = effective_config(State(stats.clone()), config.clone()).await;
+        assert_eq!(effective.pattern, "/{meta}");
+        assert_eq!(effective.umask, "027");
+
+        set_pattern(State(stats.clone()), "/t/{meta}".into())
+            .await
+            .unwrap();
+        let Json(effective) = effective_config(State(stats), config).await;
+        assert_eq!(effective.pattern, "/t/{meta}");
+        assert_eq!(
+            serde_json::to_value(&effective).unwrap()["parent_dir_policy"],
+            "reject"
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn prune_gone() {
+        // The test entries' host files don't exist.
+        let stats = new_test_stats();
+        assert_eq!(prune(State(stats.clone())).await.unwrap(), "2");
+        assert!(stats.read().leaf_paths().is_empty());
+        assert_eq!(prune(State(stats)).await.unwrap(), "0");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn rescan_root() {
+        let root = std::env::temp_dir().join(format!("organizefs-rescan-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "text").unwrap();
+        std::fs::write(root.join("b.txt"), "text").unwrap();
+        let stats = new_test_stats();
+        stats.write().add_dir(Path::new("/made")).unwrap();
+
+        let options = ScanOptions::default();
+        let resp = rescan(State(stats.clone()), root.clone(), options.clone()).await;
+        assert_eq!(resp.unwrap(), "2");
+        assert_eq!(stats.read().get_pattern(), "/{meta}");
+        assert_eq!(
+            stats.read().leaf_paths(),
+            vec![
+                PathBuf::from("/text_plain/a.txt"),
+                PathBuf::from("/text_plain/b.txt")
+            ]
+        );
+
+        let lazy = ScanOptions {
+            lazy: true,
+            ..options
+        };
+        let resp = rescan(State(stats), root.clone(), lazy).await;
+        assert_eq!(resp.unwrap_err().0, StatusCode::CONFLICT);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn stats_as_json() {
+        let stats = new_test_stats();
+        let Json(json) = stats_json(State(stats)).await;
+        // The root, `/image_jpeg` and its two files.
+        assert_eq!(
+            json,
+            StatsJson {
+                pattern: "/{meta}".to_string(),
+                leaf_count: 2,
+                node_count: 4,
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&json).unwrap(),
+            r#"{"pattern":"/{meta}","leaf_count":2,"node_count":4}"#
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn names_limited() {
+        let stats = new_test_stats();
+        stats.write().set_pattern("/{meta}/{mime_type}");
+        let Json(all) = names(State(stats.clone()), Query(NamesQuery::default())).await;
+        assert_eq!(
+            serde_json::to_string(&all).unwrap(),
+            r#"[{"name":"image","count":1,"depths":[2]},{"name":"image_jpeg","count":1,"depths":[1]}]"#
+        );
+        let Json(limited) = names(State(stats), Query(NamesQuery { limit: Some(1) })).await;
+        assert_eq!(limited, all[..1]);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn fsck_ok() {
+        let stats = new_test_stats();
+        let (status, body) = fsck(State(stats)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn entry_missing() {
+        let stats = new_test_stats();
+        let resp = entry(State(stats), query("/image_png/1.png")).await;
+        assert_eq!(resp.err(), Some(StatusCode::NOT_FOUND));
+    }
+}
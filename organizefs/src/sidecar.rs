@@ -0,0 +1,73 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+/// Suffix of the hidden file, next to a host file, that pins placeholder values for it.
+pub const SIDECAR_SUFFIX: &str = ".organizefs";
+
+/// `dir/file.jpg` => `dir/.file.jpg.organizefs`
+pub fn sidecar_path(host_path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(host_path.file_name().unwrap_or_default());
+    name.push(SIDECAR_SUFFIX);
+    host_path.with_file_name(name)
+}
+
+pub fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(SIDECAR_SUFFIX))
+}
+
+/// One `key=value` override per line; blank and malformed lines are ignored.
+pub fn parse(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+pub fn format(overrides: &BTreeMap<String, String>) -> String {
+    overrides
+        .iter()
+        .map(|(key, value)| format!("{key}={value}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn sidecar_path_hidden_sibling() {
+        assert_eq!(
+            sidecar_path(Path::new("/host/dir/file.jpg")),
+            PathBuf::from("/host/dir/.file.jpg.organizefs")
+        );
+        assert!(is_sidecar(&sidecar_path(Path::new("/host/file.jpg"))));
+        assert!(!is_sidecar(Path::new("/host/file.jpg")));
+    }
+
+    #[test]
+    #[traced_test]
+    fn round_trip() {
+        let overrides = BTreeMap::from([
+            ("meta".to_string(), "text_plain".to_string()),
+            ("dir1".to_string(), "photos".to_string()),
+        ]);
+        let contents = format(&overrides);
+        assert_eq!(contents, "dir1=photos\nmeta=text_plain\n");
+        assert_eq!(parse(&contents), overrides);
+        assert_eq!(
+            parse("junk\n=x\nmeta = a\n"),
+            BTreeMap::from([("meta".into(), "a".into())])
+        );
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use notify::{
+    event::{AccessKind, AccessMode},
+    EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use parking_lot::{Condvar, Mutex};
+use tracing::{debug, instrument, warn};
+
+/// Default number of distinct paths buffered between the watcher and the processor.
+pub const DEFAULT_EVENT_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchEvent {
+    Changed,
+    Removed,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    order: VecDeque<PathBuf>,
+    events: HashMap<PathBuf, WatchEvent>,
+    last_push: Option<Instant>,
+    closed: bool,
+}
+
+/// Bounded, coalescing queue of host path events.
+///
+/// Multiple events for the same path collapse into the latest one, so an event storm
+/// for a single file costs one slot. When `capacity` distinct paths are pending,
+/// `push` blocks until the processor drains the queue.
+pub struct EventQueue {
+    capacity: usize,
+    pending: Mutex<Pending>,
+    not_full: Condvar,
+    not_empty: Condvar,
+}
+
+impl Debug for EventQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventQueue")
+            .field("capacity", &self.capacity)
+            .field("pending", &self.len())
+            .finish()
+    }
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            pending: Mutex::new(Pending::default()),
+            not_full: Condvar::new(),
+            not_empty: Condvar::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.lock().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub fn push(&self, path: PathBuf, event: WatchEvent) {
+        let mut pending = self.pending.lock();
+        pending.last_push = Some(Instant::now());
+        loop {
+            if let Some(existing) = pending.events.get_mut(&path) {
+                debug!(
+                    path = debug(&path),
+                    from = debug(*existing),
+                    to = debug(event),
+                    "coalesce"
+                );
+                *existing = event;
+                return;
+            }
+            if pending.order.len() < self.capacity {
+                break;
+            }
+            self.not_full.wait(&mut pending);
+        }
+        pending.order.push_back(path.clone());
+        pending.events.insert(path, event);
+        self.not_empty.notify_one();
+    }
+
+    /// Stop waiting for events for good: waiters wake, and from now on `wait_drain` and
+    /// `wait_quiet` take whatever is pending without blocking, nothing once it's gone.
+    pub fn close(&self) {
+        self.pending.lock().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    /// Take every pending event, in first-seen order, without waiting.
+    pub fn drain(&self) -> Vec<(PathBuf, WatchEvent)> {
+        let mut pending = self.pending.lock();
+        let Pending { order, events, .. } = &mut *pending;
+        let drained = order
+            .drain(..)
+            .filter_map(|path| events.remove(&path).map(|event| (path, event)))
+            .collect::<Vec<_>>();
+        self.not_full.notify_all();
+        drained
+    }
+
+    /// Block until at least one event is pending, then take them all.
+    pub fn wait_drain(&self) -> Vec<(PathBuf, WatchEvent)> {
+        {
+            let mut pending = self.pending.lock();
+            while pending.order.is_empty() && !pending.closed {
+                self.not_empty.wait(&mut pending);
+            }
+        }
+        self.drain()
+    }
+
+    /// Block until at least one event is pending and none has arrived for `quiet`, then
+    /// take them all, so a burst of events is handled once it's over. A full queue is
+    /// taken straight away, as pushes are blocked on it.
+    pub fn wait_quiet(&self, quiet: Duration) -> Vec<(PathBuf, WatchEvent)> {
+        {
+            let mut pending = self.pending.lock();
+            while pending.order.is_empty() && !pending.closed {
+                self.not_empty.wait(&mut pending);
+            }
+            while pending.order.len() < self.capacity && !pending.closed {
+                let since = pending.last_push.map_or(quiet, |last| last.elapsed());
+                if since >= quiet {
+                    break;
+                }
+                self.not_empty.wait_for(&mut pending, quiet - since);
+            }
+        }
+        self.drain()
+    }
+
+    /// Apply every pending event through `apply`, once per distinct path.
+    pub fn process<F>(&self, mut apply: F) -> usize
+    where
+        F: FnMut(&Path, WatchEvent),
+    {
+        let events = self.drain();
+        for (path, event) in &events {
+            apply(path, *event);
+        }
+        events.len()
+    }
+}
+
+/// What a host change means for the paths it names; `None` for reads, which change
+/// nothing (and include the filesystem's own reads of the files it serves).
+fn watch_event(kind: &EventKind) -> Option<WatchEvent> {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => Some(WatchEvent::Changed),
+        EventKind::Access(_) => None,
+        EventKind::Remove(_) => Some(WatchEvent::Removed),
+        _ => Some(WatchEvent::Changed),
+    }
+}
+
+/// Queue an event for every path changed anywhere below `root`, until the returned
+/// watcher is dropped. A rename queues both its old and new paths as changed; whoever
+/// processes them checks which still exists.
+pub fn watch_tree(root: &Path, queue: Arc<EventQueue>) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(error = display(e), "watch error");
+                return;
+            }
+        };
+        if let Some(watch_event) = watch_event(&event.kind) {
+            for path in event.paths {
+                queue.push(path, watch_event);
+            }
+        }
+    })?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+/// Queue an event whenever the file at `path` changes, until the returned watcher is
+/// dropped. Its directory is watched rather than the file itself, so the file is still
+/// followed when it's replaced by a rename, as editors often save.
+pub fn watch_path(path: &Path, queue: Arc<EventQueue>) -> notify::Result<RecommendedWatcher> {
+    let path = env::current_dir()?.join(path);
+    let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(error = display(e), "watch error");
+                return;
+            }
+        };
+        let Some(watch_event) = watch_event(&event.kind) else {
+            return;
+        };
+        if event.paths.contains(&path) {
+            // A rename away names the file as changed; whether it's still there says which.
+            let watch_event = if path.exists() {
+                watch_event
+            } else {
+                WatchEvent::Removed
+            };
+            queue.push(path.clone(), watch_event);
+        }
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn coalesce_single_path() {
+        let queue = EventQueue::new(8);
+        for _ in 0..1000 {
+            queue.push(PathBuf::from("/root/file"), WatchEvent::Changed);
+        }
+        queue.push(PathBuf::from("/root/file"), WatchEvent::Removed);
+        assert_eq!(queue.len(), 1);
+
+        let mut updates = Vec::new();
+        let processed = queue.process(|path, event| updates.push((path.to_path_buf(), event)));
+        assert_eq!(processed, 1);
+        assert_eq!(
+            updates,
+            vec![(PathBuf::from("/root/file"), WatchEvent::Removed)]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    #[traced_test]
+    fn drain_keeps_order() {
+        let queue = EventQueue::new(8);
+        queue.push(PathBuf::from("/b"), WatchEvent::Changed);
+        queue.push(PathBuf::from("/a"), WatchEvent::Changed);
+        queue.push(PathBuf::from("/b"), WatchEvent::Changed);
+        let drained = queue.drain();
+        assert_eq!(
+            drained,
+            vec![
+                (PathBuf::from("/b"), WatchEvent::Changed),
+                (PathBuf::from("/a"), WatchEvent::Changed)
+            ]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn push_blocks_when_full() {
+        let queue = Arc::new(EventQueue::new(1));
+        queue.push(PathBuf::from("/a"), WatchEvent::Changed);
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.push(PathBuf::from("/b"), WatchEvent::Changed))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.wait_drain().len(), 1);
+        producer.join().unwrap();
+        assert_eq!(
+            queue.drain(),
+            vec![(PathBuf::from("/b"), WatchEvent::Changed)]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn wait_quiet_burst() {
+        let queue = Arc::new(EventQueue::new(8));
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    queue.push(PathBuf::from("/a"), WatchEvent::Changed);
+                    thread::sleep(Duration::from_millis(10));
+                }
+                queue.push(PathBuf::from("/b"), WatchEvent::Removed);
+            })
+        };
+        // One batch, after the burst: nothing arrived for longer than 10ms until it ended.
+        assert_eq!(
+            queue.wait_quiet(Duration::from_millis(100)),
+            vec![
+                (PathBuf::from("/a"), WatchEvent::Changed),
+                (PathBuf::from("/b"), WatchEvent::Removed)
+            ]
+        );
+        producer.join().unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn close_wakes_waiter() {
+        let queue = Arc::new(EventQueue::new(8));
+        let waiter = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.wait_quiet(Duration::from_secs(60)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.push(PathBuf::from("/a"), WatchEvent::Changed);
+        queue.close();
+        assert_eq!(
+            waiter.join().unwrap(),
+            vec![(PathBuf::from("/a"), WatchEvent::Changed)]
+        );
+        assert_eq!(queue.wait_drain(), vec![]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn watch_tree_change() {
+        let root = std::env::temp_dir().join(format!("organizefs-watch-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let queue = Arc::new(EventQueue::new(8));
+        let _watcher = watch_tree(&root, queue.clone()).unwrap();
+
+        let file = root.join("file");
+        fs::write(&file, "a").unwrap();
+        let events = queue.wait_quiet(Duration::from_millis(50));
+        assert_eq!(events, vec![(file.clone(), WatchEvent::Changed)]);
+
+        // Reading changes nothing.
+        fs::read(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        let events = queue.wait_quiet(Duration::from_millis(50));
+        assert_eq!(events, vec![(file, WatchEvent::Removed)]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[traced_test]
+    fn watch_path_change() {
+        let dir =
+            std::env::temp_dir().join(format!("organizefs-watch-path-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pattern");
+        fs::write(&path, "a").unwrap();
+        let queue = Arc::new(EventQueue::new(8));
+        let _watcher = watch_path(&path, queue.clone()).unwrap();
+
+        // Other files in the directory are ignored.
+        fs::write(dir.join("other"), "a").unwrap();
+        fs::write(&path, "b").unwrap();
+        assert_eq!(
+            queue.wait_quiet(Duration::from_millis(50)),
+            vec![(path.clone(), WatchEvent::Changed)]
+        );
+
+        // Replaced by a rename, as an editor saves it.
+        fs::write(dir.join("pattern.new"), "c").unwrap();
+        fs::rename(dir.join("pattern.new"), &path).unwrap();
+        assert_eq!(
+            queue.wait_quiet(Duration::from_millis(50)),
+            vec![(path.clone(), WatchEvent::Changed)]
+        );
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            queue.wait_quiet(Duration::from_millis(50)),
+            vec![(path, WatchEvent::Removed)]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
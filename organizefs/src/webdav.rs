@@ -0,0 +1,359 @@
+use std::{
+    ffi::OsStr,
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use fuse_mt::FileType;
+use parking_lot::RwLock;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use tracing::{debug, instrument, warn};
+
+use crate::{libc_wrapper::LibcWrapper, OrganizeFSStore};
+
+/// Mount point of the WebDAV view within the REST server.
+pub const PREFIX: &str = "/dav";
+
+const READ_CHUNK: usize = 128 * 1024;
+
+const HREF: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?');
+
+#[derive(Clone)]
+pub struct DavState {
+    store: Arc<RwLock<OrganizeFSStore>>,
+    libc_wrapper: Arc<dyn LibcWrapper + Send + Sync>,
+}
+
+/// Read-only WebDAV (PROPFIND and GET) over the same store the FUSE layer serves.
+pub fn router(
+    store: Arc<RwLock<OrganizeFSStore>>,
+    libc_wrapper: Arc<dyn LibcWrapper + Send + Sync>,
+) -> Router {
+    Router::new()
+        .route(PREFIX, any(dav))
+        .route(&format!("{PREFIX}/"), any(dav))
+        .route(&format!("{PREFIX}/*path"), any(dav))
+        .with_state(DavState {
+            store,
+            libc_wrapper,
+        })
+}
+
+#[instrument(skip(state))]
+async fn dav(
+    State(state): State<DavState>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let path = virtual_path(uri.path());
+    match method.as_str() {
+        "PROPFIND" => {
+            let depth_zero = headers
+                .get("Depth")
+                .is_some_and(|depth| depth.as_bytes() == b"0");
+            propfind(&state, &path, depth_zero).await
+        }
+        "GET" => get(&state, &path).await,
+        "OPTIONS" => (
+            StatusCode::OK,
+            [
+                ("DAV", "1"),
+                (header::ALLOW.as_str(), "OPTIONS, GET, PROPFIND"),
+            ],
+        )
+            .into_response(),
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+fn virtual_path(uri_path: &str) -> PathBuf {
+    let path = uri_path.strip_prefix(PREFIX).unwrap_or(uri_path);
+    let path = percent_decode_str(path).collect::<Vec<_>>();
+    Path::new("/").join(OsStr::from_bytes(&path))
+}
+
+fn href(path: &Path, is_dir: bool) -> String {
+    let path = path.to_string_lossy();
+    let mut href = format!("{PREFIX}{}", utf8_percent_encode(&path, HREF));
+    if is_dir && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A resource to describe: its virtual path, kind, and for files the host file.
+type Resource = (PathBuf, FileType, Option<PathBuf>);
+
+fn response_xml(libc_wrapper: &dyn LibcWrapper, (path, kind, host_path): &Resource) -> String {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let props = match kind {
+        FileType::Directory => "<D:resourcetype><D:collection/></D:resourcetype>".to_string(),
+        _ => {
+            let length = host_path
+                .as_ref()
+                .and_then(|host| libc_wrapper.lstat(host.to_owned()).ok())
+                .map_or(0, |stat| stat.st_size);
+            format!("<D:resourcetype/><D:getcontentlength>{length}</D:getcontentlength>")
+        }
+    };
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(&href(path, *kind == FileType::Directory)),
+        escape(&name),
+        props
+    )
+}
+
+/// What's listed is collected under the store's lock; the host files are stat'd after
+/// it's released, off the async workers, so a large directory holds up neither.
+async fn propfind(state: &DavState, path: &Path, depth_zero: bool) -> Response {
+    let resources = {
+        let store = state.store.read();
+        if let Some(children) = store.list_dir(path) {
+            let mut resources = vec![(path.to_path_buf(), FileType::Directory, None)];
+            if !depth_zero {
+                resources.extend(children.into_iter().map(|(name, kind)| {
+                    let path = path.join(name);
+                    let host_path = store.host_path(&path);
+                    (path, kind, host_path)
+                }));
+            }
+            resources
+        } else if let Some(host_path) = store.host_path(path) {
+            vec![(path.to_path_buf(), FileType::RegularFile, Some(host_path))]
+        } else {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+    let libc_wrapper = state.libc_wrapper.clone();
+    let Ok(responses) = tokio::task::spawn_blocking(move || {
+        resources
+            .iter()
+            .map(|resource| response_xml(libc_wrapper.as_ref(), resource))
+            .collect::<String>()
+    })
+    .await
+    else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    debug!(path = debug(path), responses, "propfind");
+
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{responses}</D:multistatus>"
+    );
+    (
+        StatusCode::MULTI_STATUS,
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/xml; charset=utf-8"),
+        )],
+        body,
+    )
+        .into_response()
+}
+
+async fn get(state: &DavState, path: &Path) -> Response {
+    let Some(host_path) = state.store.read().host_path(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let libc_wrapper = state.libc_wrapper.clone();
+    let opened = tokio::task::spawn_blocking(move || {
+        libc_wrapper
+            .open(host_path, libc::O_RDONLY)
+            .map(|fd| (libc_wrapper, fd))
+    })
+    .await;
+    let (libc_wrapper, fd) = match opened {
+        Ok(Ok(opened)) => opened,
+        Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOENT) => {
+            return StatusCode::NOT_FOUND.into_response()
+        }
+        Ok(Err(e)) if e.raw_os_error() == Some(libc::EACCES) => {
+            return StatusCode::FORBIDDEN.into_response()
+        }
+        _ => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let (sender, body) = hyper::Body::channel();
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || stream(libc_wrapper.as_ref(), fd, sender, &runtime));
+    (StatusCode::OK, axum::body::boxed(body)).into_response()
+}
+
+/// Send `fd`'s content a chunk at a time, off the async workers, then close it. A read
+/// error aborts the body, so the client sees a truncated response rather than a short
+/// file.
+fn stream(
+    libc_wrapper: &dyn LibcWrapper,
+    fd: i32,
+    mut sender: hyper::body::Sender,
+    runtime: &tokio::runtime::Handle,
+) {
+    let mut offset = 0;
+    let mut buffer = vec![0; READ_CHUNK];
+    loop {
+        match libc_wrapper.read(fd, offset, &mut buffer) {
+            Ok(0) => break,
+            Ok(count) => {
+                offset += count as i64;
+                let chunk = hyper::body::Bytes::copy_from_slice(&buffer[..count]);
+                if runtime.block_on(sender.send_data(chunk)).is_err() {
+                    debug!(fd, "client went away");
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!(fd, error = display(e), "read failed");
+                sender.abort();
+                break;
+            }
+        }
+    }
+    if let Err(e) = libc_wrapper.close(fd) {
+        warn!(fd, error = display(e), "close failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::libc_wrapper::MockLibcWrapper;
+
+    use super::*;
+
+    fn new_test_state(libc_wrapper: MockLibcWrapper) -> DavState {
+        let mut store = OrganizeFSStore::new(PathBuf::from("/{meta}"));
+        store.add_test_entry("1.jpeg", "/host/1.jpeg", "image_jpeg");
+        store.add_test_entry("a b.txt", "/host/a b.txt", "text_plain");
+        DavState {
+            store: Arc::new(RwLock::new(store)),
+            libc_wrapper: Arc::new(libc_wrapper),
+        }
+    }
+
+    async fn body(resp: Response) -> String {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn propfind_root() {
+        let state = new_test_state(MockLibcWrapper::new());
+        let resp = dav(
+            State(state),
+            Method::from_bytes(b"PROPFIND").unwrap(),
+            Uri::from_static("/dav/"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = body(resp).await;
+        assert!(body.contains("<D:href>/dav/</D:href>"));
+        assert!(body.contains("<D:href>/dav/image_jpeg/</D:href>"));
+        assert!(body.contains("<D:href>/dav/text_plain/</D:href>"));
+        assert!(body.find("image_jpeg").unwrap() < body.find("text_plain").unwrap());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn propfind_file() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper.expect_lstat().returning(|_| {
+                let mut s = std::mem::MaybeUninit::<libc::stat>::zeroed();
+                let stat = unsafe { s.assume_init_mut() };
+                stat.st_mode = libc::S_IFREG + 0o0644;
+                stat.st_size = 5;
+                Ok(stat.to_owned())
+            });
+            libc_wrapper
+        };
+        let state = new_test_state(libc_wrapper);
+        let resp = dav(
+            State(state),
+            Method::from_bytes(b"PROPFIND").unwrap(),
+            Uri::from_static("/dav/text_plain/a%20b.txt"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = body(resp).await;
+        assert!(body.contains("<D:href>/dav/text_plain/a%20b.txt</D:href>"));
+        assert!(body.contains("<D:getcontentlength>5</D:getcontentlength>"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn propfind_missing() {
+        let state = new_test_state(MockLibcWrapper::new());
+        let resp = dav(
+            State(state),
+            Method::from_bytes(b"PROPFIND").unwrap(),
+            Uri::from_static("/dav/missing"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn get_file() {
+        let libc_wrapper = {
+            let mut libc_wrapper = MockLibcWrapper::new();
+            libc_wrapper
+                .expect_open()
+                .withf(|path, _| path == Path::new("/host/1.jpeg"))
+                .returning(|_, _| Ok(3));
+            libc_wrapper
+                .expect_read()
+                .returning(|_, offset, buf| match offset {
+                    0 => {
+                        buf[..5].copy_from_slice(b"hello");
+                        Ok(5)
+                    }
+                    _ => Ok(0),
+                });
+            libc_wrapper.expect_close().times(1).returning(|_| Ok(()));
+            libc_wrapper
+        };
+        let state = new_test_state(libc_wrapper);
+        let resp = dav(
+            State(state),
+            Method::GET,
+            Uri::from_static("/dav/image_jpeg/1.jpeg"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await, "hello");
+    }
+}